@@ -0,0 +1,134 @@
+"""Benchmark per-pixel effects on a large in-memory image.
+
+Times `image_processor_rust.process_raw` on a synthetic RGB image, once per
+rayon thread count, so the speedup from running on more cores shows up
+directly. Each thread count runs in a fresh interpreter because rayon reads
+`RAYON_NUM_THREADS` only when its thread pool starts. When Pillow is
+installed, its equivalent operations are timed for comparison.
+
+Build the extension in release mode first (`pip install -e .`), then run:
+
+    python benchmarks/bench_effects.py --megapixels 40
+"""
+
+import argparse
+import json
+import os
+import subprocess
+import sys
+import time
+from typing import Callable, Dict, List
+
+__all__ = ['main']
+
+# Pillow equivalent of the `sepia` effect's color matrix.
+SEPIA_MATRIX = (
+    0.393, 0.769, 0.189, 0,
+    0.349, 0.686, 0.168, 0,
+    0.272, 0.534, 0.131, 0,
+)
+
+
+def synthetic_rgb(width: int, height: int) -> bytes:
+    """Return packed RGB samples with enough variation to defeat shortcuts.
+
+    Args:
+        width: Image width in pixels
+        height: Image height in pixels
+    """
+    row = bytes((x * 7 + (x >> 3)) & 0xFF for x in range(width * 3))
+    return b''.join(row[y % 251:] + row[:y % 251] for y in range(height))
+
+
+def best_time(run: Callable[[], object], repeat: int) -> float:
+    """Return the fastest of `repeat` runs of `run`, in seconds."""
+    times = []
+    for _ in range(repeat):
+        start = time.perf_counter()
+        run()
+        times.append(time.perf_counter() - start)
+    return min(times)
+
+
+def measure(width: int, height: int, effects: List[str], repeat: int) -> Dict[str, float]:
+    """Time each effect in this process.
+
+    `identity` is timed as well: it only copies the pixels in and out, so
+    subtracting it leaves the time spent in the effect itself.
+    """
+    import image_processor_rust
+
+    data = synthetic_rgb(width, height)
+
+    def rust(effect: str) -> Callable[[], object]:
+        return lambda: image_processor_rust.process_raw(
+            data, width, height, 3, effect, lambda _: None
+        )
+
+    timings = {name: best_time(rust(name), repeat) for name in ['identity', *effects]}
+
+    try:
+        from PIL import Image, ImageOps
+    except ImportError:
+        return timings
+    image = Image.frombytes('RGB', (width, height), data)
+    pillow = {
+        'sepia': lambda: image.convert('RGB', SEPIA_MATRIX),
+        'invert': lambda: ImageOps.invert(image),
+        'grayscale': lambda: image.convert('L'),
+    }
+    for name in effects:
+        if name in pillow:
+            timings[f'pillow:{name}'] = best_time(pillow[name], repeat)
+    return timings
+
+
+def main() -> None:
+    """Run the benchmark and print a table of timings."""
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument('--megapixels', type=float, default=12.0)
+    parser.add_argument('--effects', nargs='+', default=['sepia', 'invert'])
+    parser.add_argument('--repeat', type=int, default=3)
+    parser.add_argument(
+        '--threads', type=int, nargs='+',
+        default=sorted({1, os.cpu_count() or 1}),
+        help='rayon thread counts to compare',
+    )
+    parser.add_argument('--worker', action='store_true', help=argparse.SUPPRESS)
+    args = parser.parse_args()
+
+    side = int((args.megapixels * 1_000_000) ** 0.5)
+    width, height = side * 4 // 3, side * 3 // 4
+    if args.worker:
+        print(json.dumps(measure(width, height, args.effects, args.repeat)))
+        return
+
+    results = {}
+    for threads in args.threads:
+        env = dict(os.environ, RAYON_NUM_THREADS=str(threads))
+        command = [
+            sys.executable, __file__, '--worker',
+            '--megapixels', str(args.megapixels),
+            '--repeat', str(args.repeat),
+            '--effects', *args.effects,
+        ]
+        output = subprocess.run(command, env=env, check=True, capture_output=True, text=True)
+        results[threads] = json.loads(output.stdout)
+
+    print(f'{width}x{height} RGB ({width * height / 1e6:.1f} MP), best of {args.repeat}')
+    print(f"{'effect':<18}" + ''.join(f'{t:>3} thread(s)' for t in args.threads))
+    for name in ['identity', *args.effects]:
+        cells = []
+        for threads in args.threads:
+            timings = results[threads]
+            overhead = timings['identity'] if name != 'identity' else 0.0
+            cells.append(f'{(timings[name] - overhead) * 1000:>11.1f}ms')
+        print(f'{name:<18}' + ''.join(cells))
+    pillow = [k for k in results[args.threads[0]] if k.startswith('pillow:')]
+    for name in pillow:
+        print(f'{name:<18}{results[args.threads[0]][name] * 1000:>11.1f}ms')
+    print('Rust timings exclude the identity copy overhead.')
+
+
+if __name__ == '__main__':
+    main()
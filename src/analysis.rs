@@ -0,0 +1,265 @@
+use image::DynamicImage;
+use imageproc::contrast::otsu_level;
+use imageproc::gradients::sobel_gradients;
+
+use crate::quantize;
+use crate::resize;
+
+/// Longest side of the copy that dominant colors are computed on.
+const DOMINANT_SAMPLE_SIZE: u32 = 256;
+
+/// Longest side of the copy that skew is estimated on.
+const SKEW_SAMPLE_SIZE: u32 = 1024;
+
+/// Longest side of the copy that saliency is measured on.
+const SALIENCY_SAMPLE_SIZE: u32 = 256;
+
+/// Per-channel value counts of an image.
+pub struct Histogram {
+    pub luma: [u64; 256],
+    pub red: [u64; 256],
+    pub green: [u64; 256],
+    pub blue: [u64; 256],
+}
+
+/// Count how often each 8-bit value occurs in the luma and RGB channels.
+///
+/// Grayscale inputs are expanded to RGB first, so their red, green and blue
+/// histograms are identical to each other and to the luma histogram.
+pub fn histogram(image: &DynamicImage) -> Histogram {
+    let mut hist = Histogram {
+        luma: [0; 256],
+        red: [0; 256],
+        green: [0; 256],
+        blue: [0; 256],
+    };
+
+    for pixel in image.to_luma8().pixels() {
+        hist.luma[pixel[0] as usize] += 1;
+    }
+    for pixel in image.to_rgb8().pixels() {
+        hist.red[pixel[0] as usize] += 1;
+        hist.green[pixel[1] as usize] += 1;
+        hist.blue[pixel[2] as usize] += 1;
+    }
+
+    hist
+}
+
+/// Fractions of pixels at 0 (`clipped_low`) and 255 (`clipped_high`) in the
+/// red, green and blue channels, measured on the [`histogram`].
+pub struct Clipping {
+    pub clipped_low: [f64; 3],
+    pub clipped_high: [f64; 3],
+}
+
+pub fn clipping(image: &DynamicImage) -> Clipping {
+    let hist = histogram(image);
+    let total = (image.width() as u64 * image.height() as u64).max(1) as f64;
+    let channels = [&hist.red, &hist.green, &hist.blue];
+    Clipping {
+        clipped_low: channels.map(|counts| counts[0] as f64 / total),
+        clipped_high: channels.map(|counts| counts[255] as f64 / total),
+    }
+}
+
+/// The `n` most common colors of an image, with the fraction of pixels each covers.
+///
+/// Colors are found by k-means on a downsampled copy, which keeps this fast
+/// on large photos without noticeably changing the palette. Results are
+/// sorted by coverage, largest first, and may hold fewer than `n` entries
+/// when the image has fewer distinct colors.
+pub fn dominant_colors(image: &DynamicImage, n: usize) -> Vec<([u8; 3], f32)> {
+    let small = image.thumbnail(DOMINANT_SAMPLE_SIZE, DOMINANT_SAMPLE_SIZE).to_rgb8();
+    let pixels: Vec<[u8; 3]> = small.pixels().map(|p| p.0).collect();
+    if pixels.is_empty() {
+        return Vec::new();
+    }
+
+    let centroids = quantize::kmeans(&pixels, n, 20, 0);
+    let mut counts = vec![0usize; centroids.len()];
+    for p in &pixels {
+        counts[quantize::nearest(&centroids, &p.map(|c| c as f32))] += 1;
+    }
+
+    let mut colors: Vec<([u8; 3], f32)> = centroids
+        .iter()
+        .zip(counts)
+        .filter(|(_, count)| *count > 0)
+        .map(|(c, count)| {
+            let color = c.map(|v| v.round().clamp(0.0, 255.0) as u8);
+            (color, count as f32 / pixels.len() as f32)
+        })
+        .collect();
+    colors.sort_by(|a, b| b.1.total_cmp(&a.1));
+    colors
+}
+
+/// Variance of the 3x3 Laplacian of the image's luma.
+///
+/// Sharp images have strong, varied second derivatives at their edges; blur
+/// flattens them, so lower values mean a softer image. Border pixels, which
+/// lack a full neighbourhood, are left out.
+pub fn laplacian_variance(image: &DynamicImage) -> f64 {
+    let luma = image.to_luma8();
+    let (width, height) = luma.dimensions();
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+
+    let at = |x: u32, y: u32| luma.get_pixel(x, y)[0] as f64;
+    let mut sum = 0.0;
+    let mut sum_sq = 0.0;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let lap = at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y);
+            sum += lap;
+            sum_sq += lap * lap;
+        }
+    }
+    let n = ((width - 2) * (height - 2)) as f64;
+    let mean = sum / n;
+    sum_sq / n - mean * mean
+}
+
+/// Fraction of pixels whose luma is within `threshold` of the median luma.
+///
+/// On a scan the median is the paper color, whatever its shade, so this is
+/// the share of the page that is background. Images with no pixels give 1.
+pub fn blankness(image: &DynamicImage, threshold: u8) -> f64 {
+    let luma = histogram(image).luma;
+    let total: u64 = luma.iter().sum();
+    if total == 0 {
+        return 1.0;
+    }
+
+    let mut running = 0;
+    let median = luma
+        .iter()
+        .position(|&count| {
+            running += count;
+            running * 2 >= total
+        })
+        .unwrap_or(0);
+    let low = median.saturating_sub(threshold as usize);
+    let high = (median + threshold as usize).min(255);
+    luma[low..=high].iter().sum::<u64>() as f64 / total as f64
+}
+
+/// Estimate how far the text lines of a document are rotated clockwise, in
+/// degrees, searching no further than `max_angle` either way.
+///
+/// Dark pixels (or light ones, if they are the majority, as in white text on
+/// black) are projected onto the axis perpendicular to each candidate angle.
+/// Lines of text line up with that axis at the true skew, which makes the
+/// projection profile most peaked, so the angle with the largest sum of
+/// squared differences between neighbouring bins wins. A coarse scan is
+/// refined to a twentieth of a degree. Rotating by the negated angle
+/// straightens the page. Images without contrast give 0.
+pub fn skew_angle(image: &DynamicImage, max_angle: f32) -> f32 {
+    let luma = image.thumbnail(SKEW_SAMPLE_SIZE, SKEW_SAMPLE_SIZE).to_luma8();
+    let level = otsu_level(&luma);
+    let dark = luma.pixels().filter(|p| p[0] <= level).count();
+    let ink_is_dark = dark * 2 <= luma.len();
+    let ink: Vec<(f32, f32)> = luma
+        .enumerate_pixels()
+        .filter(|(_, _, p)| (p[0] <= level) == ink_is_dark)
+        .map(|(x, y, _)| (x as f32, y as f32))
+        .collect();
+    if ink.is_empty() || ink.len() == luma.len() {
+        return 0.0;
+    }
+
+    let diagonal = (luma.width() as f32).hypot(luma.height() as f32);
+    let mut bins = vec![0u32; 2 * diagonal.ceil() as usize + 2];
+    let mut score = |angle: f32| {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        bins.iter_mut().for_each(|b| *b = 0);
+        for &(x, y) in &ink {
+            bins[(y * cos - x * sin + diagonal) as usize] += 1;
+        }
+        bins.windows(2).map(|w| (w[1] as f64 - w[0] as f64).powi(2)).sum::<f64>()
+    };
+
+    let mut best = (0.0f32, score(0.0));
+    let mut scan = |best: &mut (f32, f64), center: f32, half_width: f32, step: f32| {
+        let steps = (half_width / step).round() as i32;
+        for i in -steps..=steps {
+            let angle = (center + i as f32 * step).clamp(-max_angle, max_angle);
+            let value = score(angle);
+            if value > best.1 {
+                *best = (angle, value);
+            }
+        }
+    };
+    scan(&mut best, 0.0, max_angle, 0.5);
+    let center = best.0;
+    scan(&mut best, center, 0.5, 0.05);
+    best.0
+}
+
+/// The `(x, y, width, height)` crop with the given width-to-height `aspect`
+/// that holds the most detail.
+///
+/// The crop is as large as the aspect allows, so it spans the full width or
+/// height and only slides along the other axis. Detail is the Sobel gradient
+/// magnitude of the luma, summed across the crop; `center_bias` (0 to 1)
+/// discounts positions toward the edges so that, at 1, a crop at the very
+/// edge needs twice the detail of a centered one to win.
+pub fn saliency_window(
+    image: &DynamicImage,
+    aspect: f32,
+    center_bias: f32,
+) -> (u32, u32, u32, u32) {
+    let (width, height) = (image.width(), image.height());
+    let (crop_width, crop_height) = resize::largest_with_aspect(width, height, aspect);
+    if crop_width == width && crop_height == height {
+        return (0, 0, width, height);
+    }
+
+    let small = image.thumbnail(SALIENCY_SAMPLE_SIZE, SALIENCY_SAMPLE_SIZE).to_luma8();
+    let gradients = sobel_gradients(&small);
+    let horizontal = crop_width < width;
+    let (length, full, crop) = if horizontal {
+        (small.width(), width, crop_width)
+    } else {
+        (small.height(), height, crop_height)
+    };
+    let mut profile = vec![0.0f64; length as usize];
+    for (x, y, g) in gradients.enumerate_pixels() {
+        profile[if horizontal { x } else { y } as usize] += g[0] as f64;
+    }
+
+    let window = (crop as f64 * length as f64 / full as f64).round() as usize;
+    let window = window.clamp(1, profile.len());
+    let offset = best_offset(&profile, window, center_bias as f64);
+    let start = ((offset as f64 * full as f64 / length as f64).round() as u32).min(full - crop);
+    if horizontal {
+        (start, 0, crop_width, crop_height)
+    } else {
+        (0, start, crop_width, crop_height)
+    }
+}
+
+/// Start of the `window`-long run of `profile` with the largest sum, after
+/// weighting each position by how close the run sits to the middle.
+fn best_offset(profile: &[f64], window: usize, center_bias: f64) -> usize {
+    let mut prefix = vec![0.0];
+    for value in profile {
+        prefix.push(prefix[prefix.len() - 1] + value);
+    }
+    let last = profile.len() - window;
+    let weight = |offset: usize| {
+        if last == 0 {
+            return 1.0;
+        }
+        let from_center = (offset as f64 / last as f64 - 0.5).abs() * 2.0;
+        1.0 - 0.5 * center_bias * from_center * from_center
+    };
+    (0..=last)
+        .max_by(|&a, &b| {
+            let score = |o: usize| (prefix[o + window] - prefix[o]) * weight(o);
+            score(a).total_cmp(&score(b))
+        })
+        .unwrap_or(0)
+}
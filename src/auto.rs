@@ -0,0 +1,81 @@
+use image::DynamicImage;
+use imageproc::geometric_transformations::Interpolation;
+
+use crate::analysis;
+use crate::curve;
+use crate::effects::ImageEffect;
+
+/// Longest side of the copy that statistics are gathered on.
+const SAMPLE_SIZE: u32 = 256;
+
+/// Pick one enhancement for `image` from simple statistics, returning the
+/// effect's name with the effect itself.
+///
+/// In order of precedence: a portrait, light, mostly blank and nearly
+/// colorless image is taken as a scanned document and deskewed; clearly unequal channel
+/// averages are a color cast for `auto_white_balance`; a very dark or very
+/// bright image gets `normalize_exposure`; and a narrow tonal range is
+/// stretched to the full range with a `curve`. Anything else is left as is
+/// with `identity`.
+pub fn choose(image: &DynamicImage) -> (&'static str, ImageEffect) {
+    let small = image.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE);
+    let rgb = small.to_rgb8();
+    let pixels = rgb.pixels().len().max(1) as f64;
+
+    let mut sums = [0.0f64; 3];
+    let mut saturation = 0.0;
+    for p in rgb.pixels() {
+        for c in 0..3 {
+            sums[c] += p[c] as f64;
+        }
+        saturation += (p.0.iter().max().unwrap() - p.0.iter().min().unwrap()) as f64;
+    }
+    let means = sums.map(|s| s / pixels);
+    let saturation = saturation / pixels;
+
+    let luma = analysis::histogram(&small).luma;
+    let mean = luma.iter().enumerate().map(|(v, &n)| v as f64 * n as f64).sum::<f64>() / pixels;
+
+    let portrait = image.height() > image.width();
+    let paper = mean > 150.0 && saturation < 20.0 && analysis::blankness(&small, 32) > 0.6;
+    if portrait && paper {
+        let effect = ImageEffect::Deskew {
+            max_angle: 10.0,
+            fill: [255, 255, 255, 255],
+            interpolation: Interpolation::Bilinear,
+        };
+        return ("deskew", effect);
+    }
+
+    let cast = means.iter().fold(f64::MIN, |a, &b| a.max(b))
+        - means.iter().fold(f64::MAX, |a, &b| a.min(b));
+    if cast > 25.0 {
+        return ("auto_white_balance", ImageEffect::AutoWhiteBalance { clip_percent: 1.0 });
+    }
+
+    if !(70.0..=190.0).contains(&mean) {
+        let effect = ImageEffect::NormalizeExposure { target_mean: 118.0, gamma: true };
+        return ("normalize_exposure", effect);
+    }
+
+    let (low, high) = (percentile(&luma, 0.01), percentile(&luma, 0.99));
+    if high > low && high - low < 160 {
+        let lut = curve::lut(&[(low, 0), (high, 255)], false);
+        return ("curve", ImageEffect::Curve { luts: Box::new([lut; 3]) });
+    }
+
+    ("identity", ImageEffect::Identity)
+}
+
+/// The smallest value with at least `fraction` of the counts at or below it.
+fn percentile(histogram: &[u64; 256], fraction: f64) -> u8 {
+    let total: u64 = histogram.iter().sum();
+    let mut running = 0;
+    for (value, &count) in histogram.iter().enumerate() {
+        running += count;
+        if running as f64 >= fraction * total as f64 {
+            return value as u8;
+        }
+    }
+    255
+}
@@ -0,0 +1,150 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use pyo3::exceptions::{PyTimeoutError, PyValueError};
+use pyo3::prelude::*;
+
+use crate::errors::ProcessingCancelled;
+
+/// State shared between a [`ProcessingHandle`] and the thread doing its work.
+#[derive(Default)]
+struct Shared {
+    percent: AtomicU32,
+    cancelled: Arc<AtomicBool>,
+    outcome: Mutex<Option<PyResult<String>>>,
+    finished: Condvar,
+}
+
+/// Handle on an image being processed on a background thread, as returned
+/// by `process_image_async`.
+///
+/// Nothing is called back from the worker thread: `poll` and `result` pass
+/// the latest progress on to the `progress_callback` given to
+/// `process_image_async`, on whichever thread calls them, so a GUI can poll
+/// from a timer on its main loop and update widgets directly.
+#[pyclass(module = "image_processor_rust")]
+pub struct ProcessingHandle {
+    shared: Arc<Shared>,
+    progress_callback: Option<PyObject>,
+    reported: Option<u32>,
+}
+
+impl ProcessingHandle {
+    /// A handle on `work`, started on a new thread. Its first argument is a
+    /// progress callback that records updates for the handle.
+    pub fn spawn(
+        py: Python,
+        progress_callback: Option<PyObject>,
+        work: impl FnOnce(Python, PyObject) -> PyResult<String> + Send + 'static,
+    ) -> PyResult<Self> {
+        let shared = Arc::new(Shared::default());
+        let progress = Py::new(py, WorkerProgress { shared: Arc::clone(&shared) })?.into_py(py);
+        let worker = Arc::clone(&shared);
+        std::thread::spawn(move || {
+            let outcome = Python::with_gil(|py| work(py, progress));
+            *worker.outcome.lock().unwrap() = Some(outcome);
+            worker.finished.notify_all();
+        });
+        Ok(Self { shared, progress_callback, reported: None })
+    }
+
+    fn is_done(&self) -> bool {
+        self.shared.outcome.lock().unwrap().is_some()
+    }
+
+    /// Call the progress callback if progress has moved since it was last
+    /// called.
+    fn report(&mut self, py: Python) -> PyResult<()> {
+        let percent = self.shared.percent.load(Ordering::Relaxed);
+        if let Some(callback) = &self.progress_callback {
+            if self.reported != Some(percent) {
+                self.reported = Some(percent);
+                callback.call1(py, (percent,))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl ProcessingHandle {
+    /// Whether the work has finished, successfully or not. Passes progress
+    /// made since the last call on to the progress callback first.
+    fn poll(&mut self, py: Python) -> PyResult<bool> {
+        self.report(py)?;
+        Ok(self.is_done())
+    }
+
+    /// The latest progress reported by the work, 0-100.
+    fn progress(&self) -> u32 {
+        self.shared.percent.load(Ordering::Relaxed)
+    }
+
+    /// Ask the work to stop. It does so at its next progress update or
+    /// between the rows or bands of the effect it is running, after which
+    /// `result` raises `ProcessingCancelled` and no output is written; work
+    /// that has already finished is unaffected.
+    fn cancel(&self) {
+        self.shared.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Wait for the work to finish and return the output path, or raise the
+    /// error it failed with. With `timeout` (seconds), `TimeoutError` is
+    /// raised if it hasn't finished by then; the work carries on regardless.
+    #[pyo3(signature = (timeout = None))]
+    fn result(&mut self, py: Python, timeout: Option<f64>) -> PyResult<String> {
+        if timeout.is_some_and(|t| !t.is_finite() || t < 0.0) {
+            return Err(PyValueError::new_err("timeout must be a non-negative number"));
+        }
+        let shared = Arc::clone(&self.shared);
+        let finished = py.allow_threads(|| {
+            let outcome = shared.outcome.lock().unwrap();
+            let not_done = |outcome: &mut Option<_>| outcome.is_none();
+            let outcome = match timeout {
+                None => shared.finished.wait_while(outcome, not_done).unwrap(),
+                Some(seconds) => {
+                    let timeout = Duration::from_secs_f64(seconds);
+                    shared.finished.wait_timeout_while(outcome, timeout, not_done).unwrap().0
+                }
+            };
+            outcome.is_some()
+        });
+        if !finished {
+            return Err(PyTimeoutError::new_err("Processing has not finished yet"));
+        }
+        self.report(py)?;
+        match self.shared.outcome.lock().unwrap().as_ref() {
+            Some(Ok(path)) => Ok(path.clone()),
+            Some(Err(err)) => Err(err.clone_ref(py)),
+            None => unreachable!("waited until the work finished"),
+        }
+    }
+}
+
+/// Progress callback of the worker thread: records each update for the
+/// handle and raises `ProcessingCancelled` once the handle is cancelled.
+#[pyclass]
+struct WorkerProgress {
+    shared: Arc<Shared>,
+}
+
+/// The flag a [`ProcessingHandle`]'s `cancel` sets, if `callback` is the
+/// progress callback of its worker thread.
+pub fn cancel_flag(callback: &PyAny) -> Option<Arc<AtomicBool>> {
+    let worker = callback.downcast::<PyCell<WorkerProgress>>().ok()?;
+    let flag = Arc::clone(&worker.borrow().shared.cancelled);
+    Some(flag)
+}
+
+#[pymethods]
+impl WorkerProgress {
+    fn __call__(&self, percent: f64) -> PyResult<()> {
+        if self.shared.cancelled.load(Ordering::Relaxed) {
+            return Err(ProcessingCancelled::new_err("Processing was cancelled"));
+        }
+        let percent = percent.clamp(0.0, 100.0).round() as u32;
+        self.shared.percent.store(percent, Ordering::Relaxed);
+        Ok(())
+    }
+}
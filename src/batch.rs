@@ -0,0 +1,359 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+use image::{DynamicImage, ImageFormat};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use rayon::prelude::*;
+
+use crate::color::ColorSpace;
+use crate::decode;
+use crate::effects::ImageEffect;
+use crate::encode::{self, EncodeOptions};
+use crate::errors;
+use crate::params::Params;
+use crate::progress::NoProgress;
+
+/// Image files found under a directory, plus how many other files were skipped.
+#[derive(Debug, Default)]
+pub struct DirectoryListing {
+    pub images: Vec<PathBuf>,
+    pub skipped: usize,
+}
+
+/// List the images in `dir`, descending into subdirectories when `recursive`.
+///
+/// Files are recognised by extension and, given a `pattern`, must have a name
+/// matching it as well (see [`matches_pattern`]). Anything under `exclude` is
+/// left out so that an output directory nested inside the input isn't fed
+/// back in. Paths are sorted so that batches run in a stable order.
+pub fn list_images(
+    dir: &Path,
+    recursive: bool,
+    pattern: Option<&str>,
+    exclude: Option<&Path>,
+) -> io::Result<DirectoryListing> {
+    let mut listing = DirectoryListing::default();
+    walk(dir, recursive, pattern, exclude, &mut listing)?;
+    listing.images.sort();
+    Ok(listing)
+}
+
+fn walk(
+    dir: &Path,
+    recursive: bool,
+    pattern: Option<&str>,
+    exclude: Option<&Path>,
+    listing: &mut DirectoryListing,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if exclude.is_some_and(|ex| path.canonicalize().is_ok_and(|p| p.starts_with(ex))) {
+            continue;
+        }
+        if path.is_dir() {
+            if recursive {
+                walk(&path, recursive, pattern, exclude, listing)?;
+            }
+        } else if is_supported(&path) && pattern.is_none_or(|p| matches_pattern(p, &path)) {
+            listing.images.push(path);
+        } else {
+            listing.skipped += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the file name of `path` matches the glob `pattern`, in which `*`
+/// stands for any run of characters and `?` for any one. Letters match
+/// either case, so `*.jpg` picks up `IMG_0001.JPG` as well.
+pub fn matches_pattern(pattern: &str, path: &Path) -> bool {
+    let name = path.file_name().map(|n| n.to_string_lossy().to_lowercase());
+    let name: Vec<char> = name.unwrap_or_default().chars().collect();
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    // Match greedily, going back to just after the last `*` on a mismatch.
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_n)) => {
+                    (p, n) = (star_p, star_n + 1);
+                    backtrack = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Output file name for `path` from a `template` such as
+/// `"{stem}_{effect}.{ext}"`: `{stem}` is the input's name without its
+/// extension, `{ext}` that extension and `{effect}` is `effect`. Unknown
+/// placeholders are rejected by [`check_template`] beforehand.
+pub fn render_template(template: &str, path: &Path, effect: &str) -> String {
+    let lossy = |part: Option<&OsStr>| part.map_or(String::new(), |p| p.to_string_lossy().into());
+    template
+        .replace("{stem}", &lossy(path.file_stem()))
+        .replace("{ext}", &lossy(path.extension()))
+        .replace("{effect}", effect)
+}
+
+/// Fail unless `template` is non-empty and every `{...}` placeholder in it is
+/// one that [`render_template`] fills in.
+pub fn check_template(template: &str) -> PyResult<()> {
+    let invalid = |message: String| Err(errors::invalid_parameter("name_template", message));
+    if template.is_empty() {
+        return invalid("name_template must not be empty".to_string());
+    }
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        let end = rest[open..].find('}').map_or(rest.len(), |close| open + close + 1);
+        let placeholder = &rest[open..end];
+        if !matches!(placeholder, "{stem}" | "{ext}" | "{effect}") {
+            return invalid(format!(
+                "Unknown placeholder '{}' in name_template, expected {{stem}}, {{ext}} or \
+                 {{effect}}",
+                placeholder
+            ));
+        }
+        rest = &rest[end..];
+    }
+    Ok(())
+}
+
+fn is_supported(path: &Path) -> bool {
+    ImageFormat::from_path(path).is_ok_and(|format| format.can_read() && format.can_write())
+}
+
+/// Share of overall batch progress, in percent, that each file accounts for.
+///
+/// Files count equally unless `by_size` is set, in which case each is weighted
+/// by its size on disk so that one huge scan isn't reported like one icon.
+pub fn progress_weights(paths: &[PathBuf], by_size: bool) -> Vec<f64> {
+    let weights: Vec<f64> = if by_size {
+        paths
+            .iter()
+            .map(|p| fs::metadata(p).map_or(0.0, |m| m.len() as f64))
+            .collect()
+    } else {
+        vec![1.0; paths.len()]
+    };
+    let total: f64 = weights.iter().sum();
+    if total <= 0.0 {
+        return vec![100.0 / paths.len().max(1) as f64; paths.len()];
+    }
+    weights.iter().map(|w| w * 100.0 / total).collect()
+}
+
+/// Encodes and writes finished images on rayon's thread pool, so that saving
+/// one file overlaps processing the next instead of holding up the batch.
+///
+/// At most `limit` writes are in flight at once, which bounds both the memory
+/// held by finished images and the number of output files open together. A
+/// failed write doesn't stop the others; [`WriteQueue::finish`] reports them.
+pub struct WriteQueue {
+    limit: usize,
+    state: Arc<(Mutex<WriteState>, Condvar)>,
+}
+
+#[derive(Default)]
+struct WriteState {
+    in_flight: usize,
+    /// Failed writes and the paths they were for, which are attached to the
+    /// errors once the GIL is at hand.
+    failures: Vec<(PyErr, String)>,
+}
+
+impl WriteQueue {
+    pub fn new(limit: usize) -> Self {
+        Self { limit: limit.max(1), state: Arc::default() }
+    }
+
+    /// Queue `image` to be saved to `path`, waiting for a free slot first.
+    pub fn push(&self, py: Python, image: DynamicImage, path: String, options: &EncodeOptions) {
+        py.allow_threads(|| {
+            let (lock, ready) = &*self.state;
+            let mut state = lock.lock().unwrap();
+            while state.in_flight >= self.limit {
+                state = ready.wait(state).unwrap();
+            }
+            state.in_flight += 1;
+        });
+
+        let state = Arc::clone(&self.state);
+        let options = options.clone();
+        rayon::spawn(move || {
+            // The GIL holder may be the thread waiting on this write, so it
+            // can't be taken here to decorate the error.
+            let result = encode::write_file(&image, &path, &options);
+            let (lock, ready) = &*state;
+            let mut state = lock.lock().unwrap();
+            state.in_flight -= 1;
+            if let Err(e) = result {
+                state.failures.push((e, path));
+            }
+            ready.notify_all();
+        });
+    }
+
+    /// Wait for every queued write, returning the paths that failed to be
+    /// written, in the order they failed, and why.
+    pub fn finish(&self, py: Python) -> Vec<(String, PyErr)> {
+        let failures = py.allow_threads(|| {
+            let (lock, ready) = &*self.state;
+            let mut state = lock.lock().unwrap();
+            while state.in_flight > 0 {
+                state = ready.wait(state).unwrap();
+            }
+            std::mem::take(&mut state.failures)
+        });
+        failures.into_iter().map(|(e, path)| (path.clone(), errors::with_path(e, &path))).collect()
+    }
+}
+
+/// Writes still running when the batch bails out early carry on to completion
+/// before the queue goes away, so no file is left half written behind the
+/// caller's back.
+impl Drop for WriteQueue {
+    fn drop(&mut self) {
+        let (lock, ready) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while state.in_flight > 0 {
+            state = ready.wait(state).unwrap();
+        }
+    }
+}
+
+/// One image of a `process_batch` call, validated up front.
+pub struct Job {
+    input_path: String,
+    output_path: String,
+    effect: ImageEffect,
+    color_space: ColorSpace,
+}
+
+impl Job {
+    /// Parse a job dict with `input_path`, `output_path` and `effect` keys and
+    /// optional `params` and `color_space`.
+    pub fn from_dict(job: &PyDict) -> PyResult<Self> {
+        let required = |key: &str| -> PyResult<String> {
+            job.get_item(key)
+                .ok_or_else(|| PyValueError::new_err(format!("job needs an '{}'", key)))?
+                .extract()
+        };
+        let (input_path, output_path) = (required("input_path")?, required("output_path")?);
+        let name = required("effect")?;
+        let params = match job.get_item("params") {
+            Some(params) if !params.is_none() => Some(params.downcast::<PyDict>()?),
+            _ => None,
+        };
+        let color_space = match job.get_item("color_space") {
+            Some(name) => ColorSpace::parse(name.extract()?)?,
+            None => ColorSpace::Srgb,
+        };
+        for key in job.keys() {
+            let key: &str = key.extract()?;
+            let known = ["input_path", "output_path", "effect", "params", "color_space"];
+            if !known.contains(&key) {
+                return Err(PyValueError::new_err(format!("Unknown job key '{}'", key)));
+            }
+        }
+
+        let effect = ImageEffect::parse(&name, &mut Params::from_dict(params)?)?;
+        Ok(Self { input_path, output_path, effect, color_space })
+    }
+}
+
+/// Run `jobs` on a pool of `num_threads` threads (0 for one per core),
+/// returning each job's outcome in order.
+///
+/// The GIL is released while the batch runs; workers take it back only to
+/// run the parts of an effect that need it and to report progress, which
+/// `progress_callback` receives as the percentage of jobs finished. A job
+/// that fails doesn't stop the others, but once `progress_callback` raises
+/// no further job is started and its error is returned.
+///
+/// An update is skipped while another worker is still in the callback, as a
+/// callback that lets go of the GIL could otherwise be entered twice; the
+/// final 100 is sent regardless.
+pub fn run_jobs(
+    py: Python,
+    jobs: &[Job],
+    num_threads: usize,
+    progress_callback: &PyObject,
+) -> PyResult<Vec<PyResult<()>>> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()
+        .map_err(|e| PyValueError::new_err(format!("Failed to start worker threads: {}", e)))?;
+    let finished = AtomicUsize::new(0);
+    let progress_error = Mutex::new(None);
+    let stopped = AtomicBool::new(false);
+    let reporting = Mutex::new(());
+    let completed = AtomicBool::new(false);
+
+    let results = py.allow_threads(|| {
+        pool.install(|| {
+            jobs.par_iter()
+                .map(|job| {
+                    if stopped.load(Ordering::SeqCst) {
+                        // The batch fails with the progress error, so this is never seen.
+                        return Ok(());
+                    }
+                    let result = run_job(job);
+                    let done = finished.fetch_add(1, Ordering::SeqCst) + 1;
+                    let percent = (100 * done / jobs.len()) as u32;
+                    Python::with_gil(|py| {
+                        let Ok(_reporting) = reporting.try_lock() else {
+                            return;
+                        };
+                        if stopped.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        match progress_callback.call1(py, (percent,)) {
+                            Ok(_) if percent == 100 => completed.store(true, Ordering::SeqCst),
+                            Ok(_) => {}
+                            Err(e) => {
+                                progress_error.lock().unwrap().get_or_insert(e);
+                                stopped.store(true, Ordering::SeqCst);
+                            }
+                        }
+                    });
+                    result
+                })
+                .collect()
+        })
+    });
+    if let Some(e) = progress_error.into_inner().unwrap() {
+        return Err(e);
+    }
+    if !completed.into_inner() {
+        progress_callback.call1(py, (100,))?;
+    }
+    Ok(results)
+}
+
+fn run_job(job: &Job) -> PyResult<()> {
+    let image = decode::open(&job.input_path)?;
+    let resolved = job.effect.resolve_orientation(|| decode::file_orientation(&job.input_path))?;
+    let processed = Python::with_gil(|py| {
+        let progress = Py::new(py, NoProgress)?.into_py(py);
+        resolved.as_ref().unwrap_or(&job.effect).apply(py, image, job.color_space, &progress)
+    })?;
+    encode::save(&processed, &job.output_path, &EncodeOptions::default())
+}
@@ -0,0 +1,155 @@
+use image::{imageops, DynamicImage, Rgba32FImage, RgbaImage};
+use rayon::prelude::*;
+
+use crate::resize;
+
+/// How an overlay pixel is combined with the base pixel beneath it.
+#[derive(Debug, Clone, Copy)]
+pub enum BlendMode {
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+}
+
+impl BlendMode {
+    pub const NAMES: &'static str = "normal, multiply, screen, overlay";
+
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "normal" => Some(Self::Normal),
+            "multiply" => Some(Self::Multiply),
+            "screen" => Some(Self::Screen),
+            "overlay" => Some(Self::Overlay),
+            _ => None,
+        }
+    }
+
+    /// Blend one channel, with both values in [0, 1].
+    fn apply(self, base: f32, top: f32) -> f32 {
+        match self {
+            Self::Normal => top,
+            Self::Multiply => base * top,
+            Self::Screen => 1.0 - (1.0 - base) * (1.0 - top),
+            Self::Overlay if base < 0.5 => 2.0 * base * top,
+            Self::Overlay => 1.0 - 2.0 * (1.0 - base) * (1.0 - top),
+        }
+    }
+}
+
+/// Composite `overlay` onto `base`, which must have the same dimensions.
+///
+/// The blended color is mixed into the base by `opacity` times the overlay's
+/// own alpha. The base's alpha is kept, and so is its channel layout: an
+/// opaque base yields an RGB result.
+pub fn blend(
+    base: &DynamicImage,
+    overlay: &RgbaImage,
+    mode: BlendMode,
+    opacity: f32,
+) -> DynamicImage {
+    let mut out = base.to_rgba8();
+    out.par_chunks_exact_mut(4)
+        .zip(overlay.par_chunks_exact(4))
+        .for_each(|(pixel, top)| {
+            let weight = opacity * top[3] as f32 / 255.0;
+            for c in 0..3 {
+                let b = pixel[c] as f32 / 255.0;
+                let blended = mode.apply(b, top[c] as f32 / 255.0);
+                let mixed = b + (blended - b) * weight;
+                pixel[c] = (mixed * 255.0).round().clamp(0.0, 255.0) as u8;
+            }
+        });
+
+    if base.color().has_alpha() {
+        DynamicImage::ImageRgba8(out)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(out).to_rgb8())
+    }
+}
+
+/// Stretch `overlay` to `width` x `height`.
+pub fn fit(overlay: &DynamicImage, width: u32, height: u32) -> RgbaImage {
+    imageops::resize(&overlay.to_rgba8(), width, height, imageops::FilterType::Lanczos3)
+}
+
+/// Composite `overlay` onto `base` with its top-left corner at (`x`, `y`).
+/// The overlay may reach past any edge of the base; only the part over it is
+/// drawn, so nothing changes when they don't overlap at all.
+///
+/// Alpha follows the source-over rule, with `opacity` scaling the overlay's:
+/// over opaque pixels the blended color is mixed in as in [`blend`], over
+/// transparent ones the overlay shows as it is, and the result's alpha
+/// covers both. `base` must be RGB or RGBA, of any depth, and keeps its
+/// layout.
+pub fn composite(
+    base: &mut DynamicImage,
+    overlay: &Rgba32FImage,
+    x: i64,
+    y: i64,
+    mode: BlendMode,
+    opacity: f32,
+) {
+    let (left, top) = (x.max(0), y.max(0));
+    let right = x.saturating_add(overlay.width().into()).min(base.width().into());
+    let bottom = y.saturating_add(overlay.height().into()).min(base.height().into());
+    if left >= right || top >= bottom {
+        return;
+    }
+
+    // Only the covered region is converted to float and back.
+    let (left, top) = (left as u32, top as u32);
+    let (width, height) = ((right - i64::from(left)) as u32, (bottom - i64::from(top)) as u32);
+    let mut patch = base.crop_imm(left, top, width, height).to_rgba32f();
+    let (offset_x, offset_y) = ((i64::from(left) - x) as u32, (i64::from(top) - y) as u32);
+    patch
+        .par_chunks_exact_mut(width as usize * 4)
+        .enumerate()
+        .for_each(|(row, line)| {
+            for (col, pixel) in line.chunks_exact_mut(4).enumerate() {
+                let above = overlay.get_pixel(offset_x + col as u32, offset_y + row as u32);
+                source_over(pixel, &above.0, mode, opacity);
+            }
+        });
+
+    let patch = resize::to_color(DynamicImage::ImageRgba32F(patch), base.color());
+    paste(base, &patch, left, top);
+}
+
+/// Composite one RGBA pixel, with all values in [0, 1].
+fn source_over(pixel: &mut [f32], top: &[f32; 4], mode: BlendMode, opacity: f32) {
+    let base_alpha = pixel[3];
+    let top_alpha = top[3] * opacity;
+    let alpha = top_alpha + base_alpha * (1.0 - top_alpha);
+    for c in 0..3 {
+        let (b, t) = (pixel[c], top[c].clamp(0.0, 1.0));
+        let shown = (1.0 - base_alpha) * t + base_alpha * mode.apply(b, t);
+        let mixed = top_alpha * shown + (1.0 - top_alpha) * base_alpha * b;
+        pixel[c] = if alpha > 0.0 { mixed / alpha } else { 0.0 };
+    }
+    pixel[3] = alpha;
+}
+
+/// Copy `patch`, in the same layout as `base`, into it at (`x`, `y`).
+fn paste(base: &mut DynamicImage, patch: &DynamicImage, x: u32, y: u32) {
+    let (x, y) = (i64::from(x), i64::from(y));
+    match (base, patch) {
+        (DynamicImage::ImageRgb8(b), DynamicImage::ImageRgb8(p)) => imageops::replace(b, p, x, y),
+        (DynamicImage::ImageRgba8(b), DynamicImage::ImageRgba8(p)) => {
+            imageops::replace(b, p, x, y)
+        }
+        (DynamicImage::ImageRgb16(b), DynamicImage::ImageRgb16(p)) => {
+            imageops::replace(b, p, x, y)
+        }
+        (DynamicImage::ImageRgba16(b), DynamicImage::ImageRgba16(p)) => {
+            imageops::replace(b, p, x, y)
+        }
+        (DynamicImage::ImageRgb32F(b), DynamicImage::ImageRgb32F(p)) => {
+            imageops::replace(b, p, x, y)
+        }
+        (DynamicImage::ImageRgba32F(b), DynamicImage::ImageRgba32F(p)) => {
+            imageops::replace(b, p, x, y)
+        }
+        (base, _) => unreachable!("composite() needs an RGB or RGBA base, got {:?}", base.color()),
+    }
+}
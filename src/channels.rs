@@ -0,0 +1,71 @@
+use image::{DynamicImage, ImageBuffer, Luma, Primitive};
+
+/// Suffixes of the files `split` writes, in channel order.
+pub const SUFFIXES: [&str; 4] = ["r", "g", "b", "a"];
+
+/// Split `image` into one grayscale image per channel: red, green, blue and,
+/// when the image has alpha, alpha. 16-bit images give 16-bit channels.
+pub fn split(image: &DynamicImage) -> Vec<DynamicImage> {
+    let count = if image.color().has_alpha() { 4 } else { 3 };
+    let sixteen_bit = image.color().bytes_per_pixel() / image.color().channel_count() > 1;
+    if sixteen_bit {
+        let rgba = image.to_rgba16();
+        planes(rgba.width(), rgba.height(), rgba.as_raw(), count)
+            .into_iter()
+            .map(DynamicImage::ImageLuma16)
+            .collect()
+    } else {
+        let rgba = image.to_rgba8();
+        planes(rgba.width(), rgba.height(), rgba.as_raw(), count)
+            .into_iter()
+            .map(DynamicImage::ImageLuma8)
+            .collect()
+    }
+}
+
+fn planes<T: Primitive>(
+    width: u32,
+    height: u32,
+    rgba: &[T],
+    count: usize,
+) -> Vec<ImageBuffer<Luma<T>, Vec<T>>> {
+    (0..count)
+        .map(|c| {
+            let data = rgba.iter().skip(c).step_by(4).copied().collect();
+            ImageBuffer::from_raw(width, height, data).unwrap()
+        })
+        .collect()
+}
+
+/// Combine grayscale planes, in [`SUFFIXES`] order, into an RGB or, given a
+/// fourth plane, RGBA image. Planes must all be the same size, which the
+/// caller checks. The result is 16-bit if any plane is.
+pub fn merge(planes: &[DynamicImage]) -> DynamicImage {
+    let sixteen_bit = planes
+        .iter()
+        .any(|p| p.color().bytes_per_pixel() / p.color().channel_count() > 1);
+    let (width, height) = (planes[0].width(), planes[0].height());
+
+    if sixteen_bit {
+        let planes: Vec<_> = planes.iter().map(|p| p.to_luma16().into_raw()).collect();
+        let data = interleave(&planes);
+        if planes.len() == 4 {
+            DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, data).unwrap())
+        } else {
+            DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, data).unwrap())
+        }
+    } else {
+        let planes: Vec<_> = planes.iter().map(|p| p.to_luma8().into_raw()).collect();
+        let data = interleave(&planes);
+        if planes.len() == 4 {
+            DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, data).unwrap())
+        } else {
+            DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, data).unwrap())
+        }
+    }
+}
+
+fn interleave<T: Primitive>(planes: &[Vec<T>]) -> Vec<T> {
+    let len = planes[0].len();
+    (0..len).flat_map(|i| planes.iter().map(move |p| p[i])).collect()
+}
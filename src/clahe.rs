@@ -0,0 +1,87 @@
+use rayon::prelude::*;
+
+/// Contrast-limited adaptive histogram equalization of an 8-bit plane.
+///
+/// The plane is divided into a `tiles` x `tiles` grid (fewer where it is
+/// smaller than that) and each tile gets its own equalization curve.
+/// Before building a curve, every histogram bin is capped at `clip_limit`
+/// times the count a flat histogram would have and the excess is spread
+/// across all bins. That bounds the curve's slope, and so how much noise in
+/// flat areas is amplified: lower limits give gentler results.
+/// Each sample is mapped through the four nearest tiles' curves, blended
+/// bilinearly by distance to the tile centers, so no seams show between
+/// tiles.
+pub fn equalize(
+    plane: &[u8],
+    width: usize,
+    height: usize,
+    tiles: usize,
+    clip_limit: f32,
+) -> Vec<u8> {
+    let (tiles_x, tiles_y) = (tiles.min(width).max(1), tiles.min(height).max(1));
+    let bounds = |i: usize, n: usize, len: usize| (i * len / n, (i + 1) * len / n);
+
+    let curves: Vec<[f32; 256]> = (0..tiles_x * tiles_y)
+        .into_par_iter()
+        .map(|t| {
+            let (x0, x1) = bounds(t % tiles_x, tiles_x, width);
+            let (y0, y1) = bounds(t / tiles_x, tiles_y, height);
+            let mut hist = [0u32; 256];
+            for row in plane[y0 * width..y1 * width].chunks_exact(width) {
+                for &v in &row[x0..x1] {
+                    hist[v as usize] += 1;
+                }
+            }
+            curve(&mut hist, ((x1 - x0) * (y1 - y0)) as u32, clip_limit)
+        })
+        .collect();
+
+    // Position of a sample in tile-center units, split into the lower tile
+    // index and the weight of the next one.
+    let locate = |v: usize, len: usize, n: usize| {
+        let pos = ((v as f32 + 0.5) * n as f32 / len as f32 - 0.5).max(0.0);
+        let low = (pos as usize).min(n - 1);
+        (low, (low + 1).min(n - 1), (pos - low as f32).min(1.0))
+    };
+
+    let mut out = vec![0u8; plane.len()];
+    out.par_chunks_exact_mut(width).enumerate().for_each(|(y, row)| {
+        let (ty0, ty1, fy) = locate(y, height, tiles_y);
+        for (x, value) in row.iter_mut().enumerate() {
+            let (tx0, tx1, fx) = locate(x, width, tiles_x);
+            let v = plane[y * width + x] as usize;
+            let at = |tx: usize, ty: usize| curves[ty * tiles_x + tx][v];
+            let top = at(tx0, ty0) * (1.0 - fx) + at(tx1, ty0) * fx;
+            let bottom = at(tx0, ty1) * (1.0 - fx) + at(tx1, ty1) * fx;
+            *value = (top * (1.0 - fy) + bottom * fy).round().clamp(0.0, 255.0) as u8;
+        }
+    });
+    out
+}
+
+/// Clip `hist` and turn it into a 0-255 equalization curve.
+fn curve(hist: &mut [u32; 256], total: u32, clip_limit: f32) -> [f32; 256] {
+    let limit = ((clip_limit * total as f32 / 256.0) as u32).max(1);
+    let mut excess = 0;
+    for count in hist.iter_mut() {
+        if *count > limit {
+            excess += *count - limit;
+            *count = limit;
+        }
+    }
+    // What doesn't divide evenly goes to every `step`th bin, so that it isn't
+    // piled onto the dark end.
+    let (share, remainder) = (excess / 256, (excess % 256) as usize);
+    let step = 256 / remainder.max(1);
+    for (i, count) in hist.iter_mut().enumerate() {
+        *count += share + u32::from(i % step == 0 && i / step < remainder);
+    }
+
+    let mut curve = [0.0; 256];
+    let mut cumulative = 0;
+    for (value, &count) in curve.iter_mut().zip(hist.iter()) {
+        cumulative += count;
+        *value = cumulative as f32 * 255.0 / total.max(1) as f32;
+    }
+    curve
+}
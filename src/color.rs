@@ -0,0 +1,198 @@
+use image::{DynamicImage, ImageBuffer, Rgb, Rgba};
+use pyo3::PyResult;
+
+use crate::errors;
+
+/// Color space an effect performs its arithmetic in.
+///
+/// Only effects that average neighbouring pixels are affected, since those are
+/// the ones that darken edges and shift hues when run on gamma-encoded values:
+/// `blur`, `sharpen`, `resize`, `thumbnail` and `max_megapixels`. Every other
+/// effect ignores this setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+}
+
+impl ColorSpace {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "srgb" => Some(Self::Srgb),
+            "linear" => Some(Self::Linear),
+            _ => None,
+        }
+    }
+
+    /// The color space named `s`, or an `InvalidParameterError` about
+    /// `color_space` for an unknown name.
+    pub fn parse(s: &str) -> PyResult<Self> {
+        Self::from_str(s).ok_or_else(|| {
+            let message = format!("Unknown color space '{}'; expected 'srgb' or 'linear'", s);
+            errors::invalid_parameter("color_space", message)
+        })
+    }
+}
+
+/// How an adjustment brings values that overshoot the displayable range back
+/// into it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GamutMapping {
+    /// Cut off at the limit. Values below it are untouched, but everything
+    /// past it lands on the same level, which shows up as flat patches.
+    Clip,
+    /// Roll off smoothly towards the limit. Values up to a knee at 80% of the
+    /// limit pass through; above it, `v` maps to
+    /// `knee + (limit - knee) * tanh((v - knee) / (limit - knee))`. The curve
+    /// leaves the knee with slope 1 and approaches the limit without reaching
+    /// it, so overshooting values keep their order and some separation rather
+    /// than collapsing. The price is that the top fifth of the in-range values
+    /// is compressed slightly too.
+    Soft,
+}
+
+/// Fraction of the limit below which [`GamutMapping::Soft`] changes nothing.
+const SOFT_KNEE: f32 = 0.8;
+
+impl GamutMapping {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "clip" => Some(Self::Clip),
+            "soft" => Some(Self::Soft),
+            _ => None,
+        }
+    }
+
+    /// Map a non-negative `value` into `[0, limit]`.
+    pub fn apply(self, value: f32, limit: f32) -> f32 {
+        let knee = limit * SOFT_KNEE;
+        match self {
+            Self::Clip => value.min(limit),
+            Self::Soft if value <= knee => value,
+            Self::Soft => knee + (limit - knee) * ((value - knee) / (limit - knee)).tanh(),
+        }
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    decode_srgb(value as f32 / 255.0)
+}
+
+fn linear_to_srgb(value: u16) -> u8 {
+    encode_srgb(value as f32 / 65535.0)
+}
+
+/// Linear light for an sRGB-encoded value, both on a 0-1 scale.
+pub fn decode_srgb(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Encode linear light on a 0-1 scale as an 8-bit sRGB value, clipping
+/// anything outside that range.
+pub fn encode_srgb(c: f32) -> u8 {
+    (encode_srgb_unit(c) * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+/// sRGB encoding of linear light, both on a 0-1 scale.
+pub fn encode_srgb_unit(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Decode an 8-bit sRGB image into 16-bit linear light.
+///
+/// The extra precision keeps the shadows from banding once the result is
+/// encoded back with [`from_linear`]. Alpha, when present, is carried over
+/// unchanged.
+pub fn to_linear(image: &DynamicImage) -> DynamicImage {
+    let lut: Vec<u16> = (0..=255u8)
+        .map(|v| (srgb_to_linear(v) * 65535.0).round() as u16)
+        .collect();
+
+    if image.color().has_alpha() {
+        let rgba = image.to_rgba8();
+        let linear = ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| {
+            let p = rgba.get_pixel(x, y);
+            Rgba([
+                lut[p[0] as usize],
+                lut[p[1] as usize],
+                lut[p[2] as usize],
+                p[3] as u16 * 257,
+            ])
+        });
+        DynamicImage::ImageRgba16(linear)
+    } else {
+        let rgb = image.to_rgb8();
+        let linear = ImageBuffer::from_fn(rgb.width(), rgb.height(), |x, y| {
+            let p = rgb.get_pixel(x, y);
+            Rgb([lut[p[0] as usize], lut[p[1] as usize], lut[p[2] as usize]])
+        });
+        DynamicImage::ImageRgb16(linear)
+    }
+}
+
+/// Encode a linear-light image produced by [`to_linear`] back to 8-bit sRGB.
+pub fn from_linear(image: &DynamicImage) -> DynamicImage {
+    if image.color().has_alpha() {
+        let rgba = image.to_rgba16();
+        let encoded = ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| {
+            let p = rgba.get_pixel(x, y);
+            Rgba([
+                linear_to_srgb(p[0]),
+                linear_to_srgb(p[1]),
+                linear_to_srgb(p[2]),
+                (p[3] / 257) as u8,
+            ])
+        });
+        DynamicImage::ImageRgba8(encoded)
+    } else {
+        let rgb = image.to_rgb16();
+        let encoded = ImageBuffer::from_fn(rgb.width(), rgb.height(), |x, y| {
+            let p = rgb.get_pixel(x, y);
+            Rgb([linear_to_srgb(p[0]), linear_to_srgb(p[1]), linear_to_srgb(p[2])])
+        });
+        DynamicImage::ImageRgb8(encoded)
+    }
+}
+
+/// Convert 0-255 RGB to HSV: hue in degrees `[0, 360)`, saturation in
+/// `[0, 1]` and value on the same 0-255 scale as the input.
+pub fn rgb_to_hsv([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    if delta <= 0.0 {
+        return [0.0, 0.0, max];
+    }
+    let hue = if max == r {
+        ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+    [hue * 60.0, delta / max, max]
+}
+
+/// Inverse of [`rgb_to_hsv`]. The hue may be any angle.
+pub fn hsv_to_rgb([hue, saturation, value]: [f32; 3]) -> [f32; 3] {
+    let sector = hue.rem_euclid(360.0) / 60.0;
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    let m = value - chroma;
+    let [r, g, b] = match sector as u32 {
+        0 => [chroma, x, 0.0],
+        1 => [x, chroma, 0.0],
+        2 => [0.0, chroma, x],
+        3 => [0.0, x, chroma],
+        4 => [x, 0.0, chroma],
+        _ => [chroma, 0.0, x],
+    };
+    [r + m, g + m, b + m]
+}
@@ -0,0 +1,78 @@
+/// Built-in colormaps for turning a single channel into color.
+#[derive(Debug, Clone, Copy)]
+pub enum Colormap {
+    Viridis,
+    Magma,
+    Jet,
+}
+
+/// matplotlib's viridis, sampled at nine evenly spaced points.
+const VIRIDIS: [[u8; 3]; 9] = [
+    [68, 1, 84],
+    [72, 40, 120],
+    [62, 73, 137],
+    [49, 104, 142],
+    [38, 130, 142],
+    [31, 158, 137],
+    [53, 183, 121],
+    [110, 206, 88],
+    [253, 231, 37],
+];
+
+/// matplotlib's magma, sampled at nine evenly spaced points.
+const MAGMA: [[u8; 3]; 9] = [
+    [0, 0, 4],
+    [28, 16, 68],
+    [79, 18, 123],
+    [129, 37, 129],
+    [181, 54, 122],
+    [229, 80, 100],
+    [251, 135, 97],
+    [254, 194, 135],
+    [252, 253, 191],
+];
+
+impl Colormap {
+    pub const NAMES: &'static str = "viridis, magma, jet";
+
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "viridis" => Some(Self::Viridis),
+            "magma" => Some(Self::Magma),
+            "jet" => Some(Self::Jet),
+            _ => None,
+        }
+    }
+
+    /// Color for every 8-bit input value.
+    pub fn lut(self) -> [[u8; 3]; 256] {
+        let mut lut = [[0; 3]; 256];
+        for (i, entry) in lut.iter_mut().enumerate() {
+            let t = i as f32 / 255.0;
+            *entry = match self {
+                Self::Viridis => interpolate(&VIRIDIS, t),
+                Self::Magma => interpolate(&MAGMA, t),
+                Self::Jet => jet(t),
+            };
+        }
+        lut
+    }
+}
+
+/// Piecewise-linear interpolation between evenly spaced `stops`.
+fn interpolate(stops: &[[u8; 3]], t: f32) -> [u8; 3] {
+    let position = t * (stops.len() - 1) as f32;
+    let index = (position as usize).min(stops.len() - 2);
+    let frac = position - index as f32;
+    let (a, b) = (stops[index], stops[index + 1]);
+    [0, 1, 2].map(|c| (a[c] as f32 + (b[c] as f32 - a[c] as f32) * frac).round() as u8)
+}
+
+/// The classic MATLAB jet ramp: blue through cyan, yellow and red.
+fn jet(t: f32) -> [u8; 3] {
+    let ramp = |center: f32| {
+        let level = (1.5 - (4.0 * t - center).abs()).clamp(0.0, 1.0);
+        (level * 255.0).round() as u8
+    };
+    [ramp(3.0), ramp(2.0), ramp(1.0)]
+}
@@ -0,0 +1,149 @@
+use image::{ImageBuffer, Rgba32FImage};
+use rayon::prelude::*;
+
+use crate::progress;
+
+/// Largest kernel side accepted, which bounds the work per pixel.
+pub const MAX_SIZE: usize = 31;
+
+/// Names of the built-in kernels, for error messages.
+pub const PRESETS: &[&str] =
+    &["emboss", "sobel_x", "sobel_y", "laplacian", "box_blur", "motion_blur"];
+
+/// A square convolution kernel with an odd side, entries in row-major order.
+///
+/// Entry (`row`, `column`) weights the pixel that far from the one being
+/// computed, the center entry weighting that pixel itself, so kernels are
+/// applied as written rather than mirrored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Kernel {
+    size: usize,
+    weights: Vec<f32>,
+}
+
+impl Kernel {
+    /// A kernel from its rows, or why they don't make one.
+    pub fn from_rows(rows: Vec<Vec<f32>>) -> Result<Self, String> {
+        let size = rows.len();
+        if size.is_multiple_of(2) || size > MAX_SIZE {
+            return Err(format!("must have an odd number of rows, at most {}", MAX_SIZE));
+        }
+        if rows.iter().any(|row| row.len() != size) {
+            return Err("must be square, each row as long as there are rows".into());
+        }
+        let weights: Vec<f32> = rows.into_iter().flatten().collect();
+        if weights.iter().any(|w| !w.is_finite()) {
+            return Err("entries must be finite numbers".into());
+        }
+        Ok(Self { size, weights })
+    }
+
+    /// The built-in kernel `name` with its default offset (in 0-255 units),
+    /// or `None` for an unknown name. `size` is the side of `box_blur` and
+    /// `motion_blur` (odd, defaulting to 3 and 9); `angle` is the direction
+    /// of `motion_blur`, in degrees counter-clockwise from horizontal.
+    pub fn preset(name: &str, size: usize, angle: f32) -> Option<(Self, f32)> {
+        let fixed = |weights: [f32; 9]| Self { size: 3, weights: weights.to_vec() };
+        let preset = match name {
+            "emboss" => (fixed([-1.0, -1.0, 0.0, -1.0, 0.0, 1.0, 0.0, 1.0, 1.0]), 128.0),
+            "sobel_x" => (fixed([-1.0, 0.0, 1.0, -2.0, 0.0, 2.0, -1.0, 0.0, 1.0]), 0.0),
+            "sobel_y" => (fixed([-1.0, -2.0, -1.0, 0.0, 0.0, 0.0, 1.0, 2.0, 1.0]), 0.0),
+            "laplacian" => (fixed([0.0, 1.0, 0.0, 1.0, -4.0, 1.0, 0.0, 1.0, 0.0]), 0.0),
+            "box_blur" => (Self { size, weights: vec![1.0; size * size] }, 0.0),
+            "motion_blur" => (Self::line(size, angle), 0.0),
+            _ => return None,
+        };
+        Some(preset)
+    }
+
+    /// Ones along the line through the center at `angle` degrees.
+    fn line(size: usize, angle: f32) -> Self {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let center = (size / 2) as f32;
+        let weights = (0..size * size)
+            .map(|i| {
+                let (dx, dy) = ((i % size) as f32 - center, center - (i / size) as f32);
+                // Distance from the line, which runs through the center.
+                let off_line = (dx * sin - dy * cos).abs();
+                if off_line <= 0.5 {
+                    1.0
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+        Self { size, weights }
+    }
+
+    /// The sum of the weights, which a kernel is divided by unless told
+    /// otherwise, or 1 when they cancel out.
+    pub fn default_divisor(&self) -> f32 {
+        let sum: f32 = self.weights.iter().sum();
+        if sum.abs() > f32::EPSILON {
+            sum
+        } else {
+            1.0
+        }
+    }
+
+    /// How far the kernel reaches from the pixel being computed.
+    pub fn radius(&self) -> u32 {
+        (self.size / 2) as u32
+    }
+
+    /// Whether convolving with this kernel alone returns the image as it is.
+    pub fn is_identity(&self) -> bool {
+        let center = self.weights.len() / 2;
+        self.weights.iter().enumerate().all(|(i, &w)| w == if i == center { 1.0 } else { 0.0 })
+    }
+}
+
+/// Convolve the color channels of `image` with `kernel`, dividing each sum
+/// by `divisor` and adding `offset` (on the 0-1 scale of the samples).
+/// Pixels past the edges repeat the nearest edge pixel, and alpha is kept.
+pub fn convolve(
+    image: &Rgba32FImage,
+    kernel: &Kernel,
+    divisor: f32,
+    offset: f32,
+) -> Rgba32FImage {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let stride = width * 4;
+    let radius = (kernel.size / 2) as isize;
+    let source = image.as_raw();
+    // Only non-zero weights cost anything, which matters for motion blur.
+    let taps: Vec<(isize, isize, f32)> = kernel
+        .weights
+        .iter()
+        .enumerate()
+        .filter(|&(_, &w)| w != 0.0)
+        .map(|(i, &w)| {
+            let (dx, dy) = ((i % kernel.size) as isize, (i / kernel.size) as isize);
+            (dx - radius, dy - radius, w / divisor)
+        })
+        .collect();
+
+    let mut out = vec![0.0; source.len()];
+    let stop = progress::current_stop();
+    out.par_chunks_exact_mut(stride.max(1)).enumerate().for_each(|(y, row)| {
+        if stop.is_stopped() {
+            return;
+        }
+        let clamp = |v: isize, len: usize| v.clamp(0, len as isize - 1) as usize;
+        for x in 0..width {
+            let mut sum = [offset; 3];
+            for &(dx, dy, w) in &taps {
+                let sx = clamp(x as isize + dx, width);
+                let sy = clamp(y as isize + dy, height);
+                let pixel = &source[sy * stride + sx * 4..][..3];
+                for (s, &p) in sum.iter_mut().zip(pixel) {
+                    *s += w * p;
+                }
+            }
+            let out_pixel = &mut row[x * 4..x * 4 + 4];
+            out_pixel[..3].copy_from_slice(&sum);
+            out_pixel[3] = source[y * stride + x * 4 + 3];
+        }
+    });
+    ImageBuffer::from_raw(image.width(), image.height(), out).unwrap()
+}
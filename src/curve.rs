@@ -0,0 +1,73 @@
+/// A tone curve through `(input, output)` control points, as a lookup table.
+///
+/// `points` must be sorted by strictly increasing input. Inputs outside the
+/// first and last point hold those points' outputs. With `monotone`, the
+/// points are joined by Fritsch-Carlson monotone cubic segments, which are
+/// smooth but never overshoot between points; otherwise by straight lines.
+pub fn lut(points: &[(u8, u8)], monotone: bool) -> [u8; 256] {
+    let xs: Vec<f32> = points.iter().map(|p| p.0 as f32).collect();
+    let ys: Vec<f32> = points.iter().map(|p| p.1 as f32).collect();
+    let tangents = if monotone { monotone_tangents(&xs, &ys) } else { Vec::new() };
+
+    std::array::from_fn(|i| {
+        let x = i as f32;
+        let n = xs.len();
+        let y = if n == 1 || x <= xs[0] {
+            ys[0]
+        } else if x >= xs[n - 1] {
+            ys[n - 1]
+        } else {
+            let k = xs.windows(2).position(|w| x < w[1]).unwrap_or(n - 2);
+            let h = xs[k + 1] - xs[k];
+            let t = (x - xs[k]) / h;
+            if monotone {
+                // Cubic Hermite basis.
+                let (t2, t3) = (t * t, t * t * t);
+                (2.0 * t3 - 3.0 * t2 + 1.0) * ys[k]
+                    + (t3 - 2.0 * t2 + t) * h * tangents[k]
+                    + (-2.0 * t3 + 3.0 * t2) * ys[k + 1]
+                    + (t3 - t2) * h * tangents[k + 1]
+            } else {
+                ys[k] + (ys[k + 1] - ys[k]) * t
+            }
+        };
+        y.round().clamp(0.0, 255.0) as u8
+    })
+}
+
+/// Per-point tangents for a monotone cubic interpolant (Fritsch-Carlson).
+fn monotone_tangents(xs: &[f32], ys: &[f32]) -> Vec<f32> {
+    let n = xs.len();
+    if n < 2 {
+        return vec![0.0; n];
+    }
+    let slopes: Vec<f32> = (0..n - 1).map(|k| (ys[k + 1] - ys[k]) / (xs[k + 1] - xs[k])).collect();
+
+    let mut tangents = vec![0.0; n];
+    tangents[0] = slopes[0];
+    tangents[n - 1] = slopes[n - 2];
+    for k in 1..n - 1 {
+        tangents[k] = if slopes[k - 1] * slopes[k] <= 0.0 {
+            0.0
+        } else {
+            (slopes[k - 1] + slopes[k]) / 2.0
+        };
+    }
+
+    // Shrink tangents that would make a segment overshoot.
+    for k in 0..n - 1 {
+        if slopes[k] == 0.0 {
+            tangents[k] = 0.0;
+            tangents[k + 1] = 0.0;
+            continue;
+        }
+        let (a, b) = (tangents[k] / slopes[k], tangents[k + 1] / slopes[k]);
+        let norm = a * a + b * b;
+        if norm > 9.0 {
+            let tau = 3.0 / norm.sqrt();
+            tangents[k] = tau * a * slopes[k];
+            tangents[k + 1] = tau * b * slopes[k];
+        }
+    }
+    tangents
+}
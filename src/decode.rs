@@ -0,0 +1,370 @@
+use std::io::Cursor;
+use std::sync::OnceLock;
+
+use image::codecs::bmp::BmpDecoder;
+use image::codecs::gif::GifDecoder;
+use image::codecs::hdr::HdrDecoder;
+use image::codecs::jpeg::JpegDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::tiff::TiffDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::io::Reader;
+use image::{
+    ColorType, DynamicImage, ImageBuffer, ImageDecoder, ImageError, ImageFormat, ImageResult, Rgb,
+};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use crate::errors::{self, ImageLoadError, UnsupportedFormatError};
+use crate::exif;
+
+/// A file an effect reads, such as an overlay image or a font, named when the
+/// effect is parsed and read the first time it's applied.
+///
+/// Parsing and validating an effect thus never touch the file, and an effect
+/// applied to many images reads it only once.
+#[derive(Debug, Clone)]
+pub struct LazyFile<T> {
+    path: String,
+    contents: OnceLock<T>,
+}
+
+impl<T> LazyFile<T> {
+    pub fn new(path: String) -> Self {
+        Self { path, contents: OnceLock::new() }
+    }
+
+    /// A file that's been read already, or contents that come from no file.
+    pub fn with_contents(contents: T) -> Self {
+        Self { path: String::new(), contents: OnceLock::from(contents) }
+    }
+
+    /// The contents, read from the file with `load` on first use.
+    pub fn get(&self, load: impl FnOnce(&str) -> PyResult<T>) -> PyResult<&T> {
+        if let Some(contents) = self.contents.get() {
+            return Ok(contents);
+        }
+        let contents = load(&self.path)?;
+        Ok(self.contents.get_or_init(|| contents))
+    }
+}
+
+/// Decode the image at `path`, with the format taken from its extension.
+pub fn open(path: &str) -> PyResult<DynamicImage> {
+    read_file(path).map_err(|e| errors::with_path(e, path))
+}
+
+fn read_file(path: &str) -> PyResult<DynamicImage> {
+    let reader = Reader::open(path).map_err(load_error)?;
+    #[cfg(not(feature = "avif"))]
+    if reader.format() == Some(ImageFormat::Avif) {
+        return Err(avif_unavailable());
+    }
+    if reader.format() == Some(ImageFormat::Jpeg) {
+        let bytes = std::fs::read(path).map_err(load_error)?;
+        return decode_jpeg(&bytes);
+    }
+    if reader.format() == Some(ImageFormat::Hdr) {
+        let bytes = std::fs::read(path).map_err(load_error)?;
+        return decode_hdr(&bytes);
+    }
+    reader.decode().map_err(decode_error)
+}
+
+/// Decode `bytes`, the contents of the file at `path`, as [`open`] would,
+/// for callers that need the raw file too. Without an extension naming a
+/// format, the format is guessed from the contents.
+pub fn from_file_bytes(path: &str, bytes: &[u8]) -> PyResult<DynamicImage> {
+    let decoded = match ImageFormat::from_path(path) {
+        Ok(format) => decode_as(bytes, format),
+        Err(_) => from_memory(bytes),
+    };
+    decoded.map_err(|e| errors::with_path(e, path))
+}
+
+fn decode_as(bytes: &[u8], format: ImageFormat) -> PyResult<DynamicImage> {
+    #[cfg(not(feature = "avif"))]
+    if format == ImageFormat::Avif {
+        return Err(avif_unavailable());
+    }
+    match format {
+        ImageFormat::Jpeg => decode_jpeg(bytes),
+        ImageFormat::Hdr => decode_hdr(bytes),
+        _ => image::load_from_memory_with_format(bytes, format).map_err(decode_error),
+    }
+}
+
+/// Decode an in-memory image, guessing the format from its contents.
+pub fn from_memory(bytes: &[u8]) -> PyResult<DynamicImage> {
+    #[cfg(not(feature = "avif"))]
+    if image::guess_format(bytes).ok() == Some(ImageFormat::Avif) {
+        return Err(avif_unavailable());
+    }
+    if image::guess_format(bytes).ok() == Some(ImageFormat::Jpeg) {
+        return decode_jpeg(bytes);
+    }
+    if image::guess_format(bytes).ok() == Some(ImageFormat::Hdr) {
+        return decode_hdr(bytes);
+    }
+    image::load_from_memory(bytes).map_err(decode_error)
+}
+
+/// Wrap packed 8-bit samples, row-major with no padding, as an image with
+/// 1 (gray), 2 (gray and alpha), 3 (RGB) or 4 (RGBA) `channels`.
+pub fn from_raw(data: &[u8], width: u32, height: u32, channels: u8) -> PyResult<DynamicImage> {
+    if !(1..=4).contains(&channels) {
+        return Err(PyValueError::new_err("channels must be 1, 2, 3 or 4"));
+    }
+    let expected = width as u64 * height as u64 * channels as u64;
+    if data.len() as u64 != expected {
+        return Err(PyValueError::new_err(format!(
+            "Raw data holds {} bytes but a {}x{} image with {} channels needs {}",
+            data.len(),
+            width,
+            height,
+            channels,
+            expected
+        )));
+    }
+
+    let (data, w, h) = (data.to_vec(), width, height);
+    Ok(match channels {
+        1 => DynamicImage::ImageLuma8(ImageBuffer::from_raw(w, h, data).unwrap()),
+        2 => DynamicImage::ImageLumaA8(ImageBuffer::from_raw(w, h, data).unwrap()),
+        3 => DynamicImage::ImageRgb8(ImageBuffer::from_raw(w, h, data).unwrap()),
+        _ => DynamicImage::ImageRgba8(ImageBuffer::from_raw(w, h, data).unwrap()),
+    })
+}
+
+/// Decode a Radiance HDR file to floating-point linear RGB.
+///
+/// `image`'s generic path squeezes these into 8 bits, throwing away
+/// everything above white that `tonemap` is there to bring back.
+fn decode_hdr(bytes: &[u8]) -> PyResult<DynamicImage> {
+    let decoder = HdrDecoder::new(bytes).map_err(decode_error)?;
+    let meta = decoder.metadata();
+    let pixels = decoder.read_image_hdr().map_err(decode_error)?;
+    let data = pixels.into_iter().flat_map(|p| p.0).collect();
+    let rgb = ImageBuffer::from_raw(meta.width, meta.height, data).unwrap();
+    Ok(DynamicImage::ImageRgb32F(rgb))
+}
+
+fn decode_jpeg(bytes: &[u8]) -> PyResult<DynamicImage> {
+    match decode_cmyk_jpeg(bytes)? {
+        Some(image) => Ok(image),
+        None => image::load_from_memory_with_format(bytes, ImageFormat::Jpeg).map_err(decode_error),
+    }
+}
+
+/// Decode a CMYK JPEG to RGB, or return `None` for any other JPEG.
+///
+/// `jpeg-decoder` always undoes the inverted storage that Adobe applications
+/// use for CMYK, which is only right for files that carry an Adobe APP14
+/// marker. Files without one store plain ink values, so for those the
+/// inversion is reverted before converting to RGB.
+fn decode_cmyk_jpeg(bytes: &[u8]) -> PyResult<Option<DynamicImage>> {
+    let mut decoder = jpeg_decoder::Decoder::new(bytes);
+    // Leave malformed files to the regular decoder so its error is reported.
+    if decoder.read_info().is_err() {
+        return Ok(None);
+    }
+    let info = match decoder.info() {
+        Some(info) if info.pixel_format == jpeg_decoder::PixelFormat::CMYK32 => info,
+        _ => return Ok(None),
+    };
+
+    let mut data = decoder
+        .decode()
+        .map_err(|e| load_error(format!("unsupported CMYK JPEG: {}", e)))?;
+    let expected = info.width as usize * info.height as usize * 4;
+    if data.len() != expected {
+        return Err(load_error(format!(
+            "unsupported CMYK JPEG: expected {} bytes of pixel data, got {}",
+            expected,
+            data.len()
+        )));
+    }
+
+    if !has_adobe_marker(bytes) {
+        for v in data.iter_mut() {
+            *v = 255 - *v;
+        }
+    }
+
+    let rgb = ImageBuffer::from_fn(info.width as u32, info.height as u32, |x, y| {
+        let i = (y as usize * info.width as usize + x as usize) * 4;
+        let k = 255 - data[i + 3] as u32;
+        let channel = |ink: u8| ((255 - ink as u32) * k / 255) as u8;
+        Rgb([channel(data[i]), channel(data[i + 1]), channel(data[i + 2])])
+    });
+    Ok(Some(DynamicImage::ImageRgb8(rgb)))
+}
+
+/// Where a decoded image came from, so that effects needing its metadata can
+/// read it afterwards.
+pub enum Source<'a> {
+    File(&'a str),
+    Memory(&'a [u8]),
+    /// Bare pixels, or an image whose metadata has already been applied.
+    Pixels,
+}
+
+impl Source<'_> {
+    /// The EXIF orientation recorded in the source, if any.
+    pub fn orientation(&self) -> PyResult<Option<u16>> {
+        match *self {
+            Self::File(path) => file_orientation(path),
+            Self::Memory(bytes) => Ok(orientation(bytes)),
+            Self::Pixels => Ok(None),
+        }
+    }
+}
+
+/// The EXIF orientation (1-8) recorded in an encoded JPEG or PNG, if any.
+pub fn orientation(bytes: &[u8]) -> Option<u16> {
+    exif_block(bytes).and_then(exif::orientation)
+}
+
+/// [`orientation`] of the image file at `path`.
+pub fn file_orientation(path: &str) -> PyResult<Option<u16>> {
+    Ok(orientation(&read_bytes(path)?))
+}
+
+/// The still encoded contents of the image file at `path`.
+pub fn read_bytes(path: &str) -> PyResult<Vec<u8>> {
+    std::fs::read(path).map_err(|e| errors::with_path(load_error(e), path))
+}
+
+/// What an image's header records, read without decoding the pixels.
+pub struct Header {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    /// The pixel format the image decodes to.
+    pub color: ColorType,
+    pub icc_profile: Option<Vec<u8>>,
+}
+
+/// Read the header of an encoded image, with the format guessed from its
+/// contents. Formats without a header reader here are decoded in full.
+pub fn header(bytes: &[u8]) -> PyResult<Header> {
+    let format = image::guess_format(bytes).map_err(decode_error)?;
+    let bytes = Cursor::new(bytes);
+    let read = match format {
+        ImageFormat::Png => read_header(PngDecoder::new(bytes)),
+        ImageFormat::Jpeg => read_header(JpegDecoder::new(bytes)),
+        ImageFormat::Gif => read_header(GifDecoder::new(bytes)),
+        ImageFormat::WebP => read_header(WebPDecoder::new(bytes)),
+        ImageFormat::Tiff => read_header(TiffDecoder::new(bytes)),
+        ImageFormat::Bmp => read_header(BmpDecoder::new(bytes)),
+        _ => {
+            let image = from_memory(bytes.into_inner())?;
+            Ok((image.width(), image.height(), image.color(), None))
+        }
+    };
+    let (width, height, color, icc_profile) = read?;
+    Ok(Header { format, width, height, color, icc_profile })
+}
+
+type HeaderFields = (u32, u32, ColorType, Option<Vec<u8>>);
+
+fn read_header<'a, D: ImageDecoder<'a>>(decoder: ImageResult<D>) -> PyResult<HeaderFields> {
+    let mut decoder = decoder.map_err(decode_error)?;
+    let (width, height) = decoder.dimensions();
+    Ok((width, height, decoder.color_type(), decoder.icc_profile()))
+}
+
+/// Whether the JPEG header contains an Adobe APP14 segment.
+fn has_adobe_marker(bytes: &[u8]) -> bool {
+    jpeg_segment(bytes, 0xEE, b"Adobe").is_some()
+}
+
+/// Raw EXIF data (a TIFF structure) embedded in a JPEG's APP1 segment, a
+/// PNG's `eXIf` chunk or a WebP's `EXIF` chunk.
+pub fn exif_block(bytes: &[u8]) -> Option<&[u8]> {
+    const EXIF_HEADER: &[u8] = b"Exif\0\0";
+    if bytes.starts_with(&[0xFF, 0xD8]) {
+        let payload = jpeg_segment(bytes, 0xE1, EXIF_HEADER)?;
+        return Some(&payload[EXIF_HEADER.len()..]);
+    }
+    if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        let mut pos = 12;
+        while pos + 8 <= bytes.len() {
+            let length = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            let data = bytes.get(pos + 8..(pos + 8).saturating_add(length))?;
+            if &bytes[pos..pos + 4] == b"EXIF" {
+                return Some(data.strip_prefix(EXIF_HEADER).unwrap_or(data));
+            }
+            // Chunks are padded to an even length.
+            pos += 8 + length + length % 2;
+        }
+        return None;
+    }
+    if !bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return None;
+    }
+    let mut pos = 8;
+    while pos + 8 <= bytes.len() {
+        let length = u32::from_be_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+        let kind = &bytes[pos + 4..pos + 8];
+        let data = bytes.get(pos + 8..(pos + 8).saturating_add(length))?;
+        match kind {
+            // Some writers keep the JPEG-style header in the chunk too.
+            b"eXIf" => return Some(data.strip_prefix(EXIF_HEADER).unwrap_or(data)),
+            b"IDAT" | b"IEND" => return None,
+            _ => pos += 12 + length,
+        }
+    }
+    None
+}
+
+/// Payload of the first JPEG header segment with `marker` whose payload
+/// starts with `tag`.
+fn jpeg_segment<'a>(bytes: &'a [u8], marker: u8, tag: &[u8]) -> Option<&'a [u8]> {
+    let mut pos = 2;
+    while pos + 4 <= bytes.len() {
+        if bytes[pos] != 0xFF {
+            return None;
+        }
+        let current = bytes[pos + 1];
+        if current == 0xFF {
+            // Fill byte before the marker.
+            pos += 1;
+            continue;
+        }
+        // Start of scan: the header is over.
+        if current == 0xDA {
+            return None;
+        }
+        let length = u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+        let payload = bytes.get(pos + 4..(pos + 2 + length).min(bytes.len())).unwrap_or(&[]);
+        if current == marker && payload.starts_with(tag) {
+            return Some(payload);
+        }
+        pos += 2 + length;
+    }
+    None
+}
+
+pub fn load_error(e: impl std::fmt::Display) -> PyErr {
+    ImageLoadError::new_err(format!("Failed to load image: {}", e))
+}
+
+/// [`load_error`], except that formats `image` can't decode are reported as
+/// unsupported rather than unreadable.
+fn decode_error(e: ImageError) -> PyErr {
+    match e {
+        ImageError::Unsupported(_) => {
+            UnsupportedFormatError::new_err(format!("Failed to load image: {}", e))
+        }
+        _ => load_error(e),
+    }
+}
+
+#[cfg(not(feature = "avif"))]
+fn avif_unavailable() -> PyErr {
+    UnsupportedFormatError::new_err(format!(
+        "Failed to load image: {}",
+        crate::encode::AVIF_UNAVAILABLE
+    ))
+}
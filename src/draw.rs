@@ -0,0 +1,206 @@
+use image::{Rgba, Rgba32FImage};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use rusttype::{point, Font, Scale};
+
+use crate::decode::LazyFile;
+
+/// Largest distance from the origin, in pixels, of the positions and radii
+/// the `draw_*` effects take. Up to it `f32` still places edges to a small
+/// fraction of a pixel.
+pub const MAX_COORDINATE: f32 = 1e6;
+
+/// A shape or text drawn onto an image by the `draw_*` effects.
+///
+/// Coordinates are in pixels with pixel centers at whole numbers. Edges that
+/// don't fall on them are antialiased.
+#[derive(Debug, Clone)]
+pub enum Shape {
+    /// Axis-aligned rectangle with its top-left pixel at (`x`, `y`).
+    /// Outlines are `thickness` pixels wide and lie inside the rectangle.
+    Rect { x: i64, y: i64, width: u32, height: u32, thickness: u32, fill: bool },
+    /// Line segment `thickness` pixels wide, with round ends.
+    Line { from: (f32, f32), to: (f32, f32), thickness: u32 },
+    /// Circle around `center`. Outlines are `thickness` pixels wide and lie
+    /// inside the radius.
+    Circle { center: (f32, f32), radius: f32, thickness: u32, fill: bool },
+    /// Text `size` pixels high whose first line starts at (`x`, `y`), top
+    /// left. Each `\n` starts another line.
+    Text { text: String, font: LazyFile<Font<'static>>, size: f32, x: i64, y: i64 },
+}
+
+/// Read a TrueType or OpenType font file.
+pub fn load_font(path: &str) -> PyResult<Font<'static>> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| PyValueError::new_err(format!("Failed to load font: {}", e)))?;
+    Font::try_from_vec(bytes).ok_or_else(|| {
+        PyValueError::new_err(format!("Failed to load font: '{}' is not a font file", path))
+    })
+}
+
+impl Shape {
+    /// The shape in `color` on a transparent layer, clipped to a `width` x
+    /// `height` image, and where the layer's top-left corner goes. `None`
+    /// when nothing of it lands on the image. Fails only when the font of
+    /// text can't be loaded.
+    pub fn render(
+        &self,
+        color: [u8; 4],
+        width: u32,
+        height: u32,
+    ) -> PyResult<Option<(Rgba32FImage, i64, i64)>> {
+        let bounds = Bounds { width: width.into(), height: height.into() };
+        let layer = match *self {
+            Self::Rect { x, y, width, height, thickness, fill } => {
+                let (right, bottom) =
+                    (x.saturating_add(width.into()), y.saturating_add(height.into()));
+                let border = i64::from(thickness);
+                bounds.rasterize((x, y, right, bottom), color, |px, py| {
+                    let (px, py) = (px as i64, py as i64);
+                    let inner = px >= x + border
+                        && px < right - border
+                        && py >= y + border
+                        && py < bottom - border;
+                    if fill || !inner {
+                        1.0
+                    } else {
+                        0.0
+                    }
+                })
+            }
+            Self::Line { from, to, thickness } => {
+                let half = thickness as f32 / 2.0;
+                let area = (
+                    ((from.0.min(to.0) - half).floor() as i64).saturating_sub(1),
+                    ((from.1.min(to.1) - half).floor() as i64).saturating_sub(1),
+                    ((from.0.max(to.0) + half).ceil() as i64).saturating_add(2),
+                    ((from.1.max(to.1) + half).ceil() as i64).saturating_add(2),
+                );
+                bounds.rasterize(area, color, |px, py| {
+                    (half + 0.5 - segment_distance((px, py), from, to)).clamp(0.0, 1.0)
+                })
+            }
+            Self::Circle { center, radius, thickness, fill } => {
+                let inner = if fill { 0.0 } else { radius - thickness as f32 };
+                let area = (
+                    ((center.0 - radius).floor() as i64).saturating_sub(1),
+                    ((center.1 - radius).floor() as i64).saturating_sub(1),
+                    ((center.0 + radius).ceil() as i64).saturating_add(2),
+                    ((center.1 + radius).ceil() as i64).saturating_add(2),
+                );
+                bounds.rasterize(area, color, |px, py| {
+                    let d = (px - center.0).hypot(py - center.1);
+                    let outside = (radius + 0.5 - d).clamp(0.0, 1.0);
+                    if inner <= 0.0 {
+                        outside
+                    } else {
+                        outside * (d - inner + 0.5).clamp(0.0, 1.0)
+                    }
+                })
+            }
+            Self::Text { ref text, ref font, size, x, y } => {
+                render_text(text, font.get(load_font)?, size, (x, y), color, &bounds)
+            }
+        };
+        Ok(layer)
+    }
+}
+
+/// The image a shape is clipped to.
+struct Bounds {
+    width: i64,
+    height: i64,
+}
+
+impl Bounds {
+    /// A layer covering `area` (left, top, right, bottom; right and bottom
+    /// exclusive) where it overlaps the image, with each pixel in `color` at
+    /// the opacity `coverage` gives for its center.
+    fn rasterize(
+        &self,
+        area: (i64, i64, i64, i64),
+        color: [u8; 4],
+        coverage: impl Fn(f32, f32) -> f32,
+    ) -> Option<(Rgba32FImage, i64, i64)> {
+        let (left, top) = (area.0.max(0), area.1.max(0));
+        let (right, bottom) = (area.2.min(self.width), area.3.min(self.height));
+        if left >= right || top >= bottom {
+            return None;
+        }
+        let [r, g, b, a] = color.map(|c| c as f32 / 255.0);
+        let (width, height) = ((right - left) as u32, (bottom - top) as u32);
+        let layer = Rgba32FImage::from_fn(width, height, |px, py| {
+            let cover = coverage((left + i64::from(px)) as f32, (top + i64::from(py)) as f32);
+            Rgba([r, g, b, a * cover])
+        });
+        Some((layer, left, top))
+    }
+}
+
+/// Distance from `p` to the segment from `a` to `b`.
+fn segment_distance(p: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length_squared = dx * dx + dy * dy;
+    let t = if length_squared > 0.0 {
+        (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / length_squared).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    (p.0 - (a.0 + t * dx)).hypot(p.1 - (a.1 + t * dy))
+}
+
+fn render_text(
+    text: &str,
+    font: &Font<'static>,
+    size: f32,
+    (x, y): (i64, i64),
+    color: [u8; 4],
+    bounds: &Bounds,
+) -> Option<(Rgba32FImage, i64, i64)> {
+    let scale = Scale::uniform(size);
+    let metrics = font.v_metrics(scale);
+    let line_height = metrics.ascent - metrics.descent + metrics.line_gap;
+    let glyphs: Vec<_> = text
+        .lines()
+        .enumerate()
+        .flat_map(|(i, line)| {
+            let baseline = y as f32 + metrics.ascent + i as f32 * line_height;
+            font.layout(line, scale, point(x as f32, baseline))
+        })
+        .filter_map(|glyph| glyph.pixel_bounding_box().map(|rect| (glyph, rect)))
+        .collect();
+
+    // Glyph outlines give coverage directly; keep the most of overlapping ones.
+    let area = glyphs.iter().fold(None, |area, (_, rect)| {
+        let (min, max) = (rect.min, rect.max);
+        let (left, top, right, bottom) = area.unwrap_or((i64::MAX, i64::MAX, i64::MIN, i64::MIN));
+        Some((
+            left.min(min.x.into()),
+            top.min(min.y.into()),
+            right.max(max.x.into()),
+            bottom.max(max.y.into()),
+        ))
+    })?;
+    let (left, top) = (area.0.max(0), area.1.max(0));
+    let (width, height) = (area.2.min(bounds.width) - left, area.3.min(bounds.height) - top);
+    if width <= 0 || height <= 0 {
+        return None;
+    }
+    let mut coverage = vec![0.0f32; (width * height) as usize];
+    for (glyph, rect) in &glyphs {
+        glyph.draw(|gx, gy, v| {
+            let px = i64::from(rect.min.x) + i64::from(gx) - left;
+            let py = i64::from(rect.min.y) + i64::from(gy) - top;
+            if (0..width).contains(&px) && (0..height).contains(&py) {
+                let cell = &mut coverage[(py * width + px) as usize];
+                *cell = cell.max(v);
+            }
+        });
+    }
+
+    let [r, g, b, a] = color.map(|c| c as f32 / 255.0);
+    let layer = Rgba32FImage::from_fn(width as u32, height as u32, |px, py| {
+        Rgba([r, g, b, a * coverage[(py as i64 * width + px as i64) as usize]])
+    });
+    Some((layer, left, top))
+}
@@ -0,0 +1,2621 @@
+use std::cell::RefCell;
+
+use image::{
+    imageops, ColorType, DynamicImage, GrayImage, ImageBuffer, Luma, Primitive, Rgb, Rgba,
+    Rgba32FImage, RgbaImage,
+};
+use imageproc::definitions::Clamp;
+use imageproc::distance_transform::Norm;
+use imageproc::edges::canny;
+use imageproc::filter::filter3x3;
+use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
+use imageproc::seam_carving::{find_vertical_seam, remove_vertical_seam};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use rayon::prelude::*;
+
+use crate::analysis;
+use crate::auto;
+use crate::blend::{self, BlendMode};
+use crate::clahe;
+use crate::color::{self, ColorSpace, GamutMapping};
+use crate::colormap::Colormap;
+use crate::convolve::{self, Kernel};
+use crate::curve;
+use crate::decode::{self, LazyFile};
+use crate::draw::{self, Shape};
+use crate::errors;
+use crate::exif;
+use crate::params::{self, Params};
+use crate::progress::{self, ScaledProgress, StopCheck};
+use crate::quantize;
+use crate::resize::{self, Filter};
+use crate::rng::SplitMix64;
+use crate::tiles;
+use crate::wavelet;
+
+/// What effects measured on the image while applying, for the `stats` of
+/// the processing calls. Where several steps of a pipeline measure the same
+/// thing, the last one's value is kept.
+#[derive(Debug, Clone, Default)]
+pub struct Findings {
+    /// The skew `deskew` corrected, in degrees clockwise.
+    pub skew_angle: Option<f32>,
+    /// The name of the effect `auto` chose.
+    pub auto_effect: Option<&'static str>,
+}
+
+impl Findings {
+    /// Put what was found in `dict`, leaving out what no effect measured.
+    pub fn set_items(&self, dict: &PyDict) -> PyResult<()> {
+        if let Some(angle) = self.skew_angle {
+            dict.set_item("skew_angle", angle)?;
+        }
+        if let Some(name) = self.auto_effect {
+            dict.set_item("auto_effect", name)?;
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    static FINDINGS: RefCell<Findings> = RefCell::default();
+}
+
+/// Run `work` and return, with its result, the [`Findings`] of the effects
+/// it applied on this thread.
+pub fn collect_findings<R>(work: impl FnOnce() -> R) -> (R, Findings) {
+    let previous = FINDINGS.with(|findings| findings.take());
+    let result = work();
+    (result, FINDINGS.with(|findings| findings.replace(previous)))
+}
+
+/// Record something an effect measured, for [`collect_findings`].
+fn record(update: impl FnOnce(&mut Findings)) {
+    FINDINGS.with(|findings| update(&mut findings.borrow_mut()));
+}
+
+/// Which edges of the image an effect acts on.
+#[derive(Debug, Clone, Copy)]
+pub struct Sides {
+    pub top: bool,
+    pub bottom: bool,
+    pub left: bool,
+    pub right: bool,
+}
+
+impl Sides {
+    pub const ALL: Self = Self { top: true, bottom: true, left: true, right: true };
+}
+
+/// Noise reduction run on the luma before edge detection.
+#[derive(Debug, Clone, Copy)]
+pub enum EdgeSmoothing {
+    None,
+    Gaussian { sigma: f32 },
+    /// Edge-preserving: pixels more than about `sigma_color` levels apart
+    /// are barely averaged together.
+    Bilateral { sigma: f32, sigma_color: f32 },
+}
+
+/// An effect with its parameters, parsed from the name and params the caller
+/// passed.
+///
+/// Grayscale input is treated alike by every effect. `grayscale` and the
+/// effects that only crop, resize or move pixels (`identity`, `crop`,
+/// `autocrop`, `trim_uniform_background`, `resize`, `thumbnail`,
+/// `max_megapixels`, `flip_h`, `flip_v`, `auto_orient`, and `rotate` by quarter
+/// turns with `expand`) keep the input's pixel layout. All others expand it to gray RGB
+/// first and return RGB, or RGBA where they keep alpha, so tints such as
+/// `sepia` and `colorize` show up just as they would on a color image.
+#[derive(Debug)]
+pub enum ImageEffect {
+    EdgeDetect {
+        invert_output: bool,
+        smoothing: EdgeSmoothing,
+        low_threshold: f32,
+        high_threshold: f32,
+    },
+    Blur { sigma: f32 },
+    Sharpen { sigma: f32, threshold: u32, amount: f32, luma_only: bool },
+    Grayscale,
+    Sepia,
+    Invert,
+    Identity,
+    AutoWhiteBalance { clip_percent: f32 },
+    Crop { x: u32, y: u32, width: u32, height: u32 },
+    KmeansQuantize { k: u32, max_iterations: u32, seed: u64 },
+    ChromaKey { color: [u8; 3], tolerance: f32, softness: f32 },
+    Comic { levels: u32, low_threshold: f32, high_threshold: f32, edge_thickness: u32 },
+    Autocrop { color: [u8; 3], tolerance: u32 },
+    Vintage { strength: f32 },
+    BrightnessHsv { factor: f32, gamut: GamutMapping, dither: bool },
+    HueRotate { degrees: f32, dither: bool },
+    Saturation { factor: f32, gamut: GamutMapping, dither: bool },
+    Rotate { angle: f32, fill: [u8; 4], interpolation: Interpolation, expand: bool },
+    FlipHorizontal,
+    FlipVertical,
+    TrimBackground { color: Option<[u8; 3]>, tolerance: u32, sides: Sides },
+    LaplacianSharpen { amount: f32 },
+    Colorize { colormap: Colormap },
+    SeamCarve { width: Option<u32>, height: Option<u32> },
+    Resize { width: Option<u32>, height: Option<u32>, filter: Filter, only_if_larger: bool },
+    Thumbnail { size: u32, filter: Filter, only_if_larger: bool },
+    MaxMegapixels { megapixels: f32, filter: Filter },
+    ClipWarning { low_color: [u8; 3], high_color: [u8; 3], dim: f32 },
+    NormalizeExposure { target_mean: f32, gamma: bool },
+    Curve { luts: Box<[[u8; 256]; 3]> },
+    Deskew { max_angle: f32, fill: [u8; 4], interpolation: Interpolation },
+    SaliencyCrop { aspect_ratio: f32, center_bias: f32 },
+    CropAspect { aspect_ratio: f32 },
+    Clahe { tiles: u32, clip_limit: f32 },
+    Tonemap { operator: ToneOperator, exposure: f32 },
+    FixChromaticAberration { r_scale: f32, b_scale: f32 },
+    WaveletDenoise { threshold: f32, levels: u32, chroma: bool },
+    Difference { reference: LazyFile<DynamicImage>, amplify: f32 },
+    SelectiveColor { hue: f32, tolerance: f32, softness: f32 },
+    Brightness { amount: f32 },
+    Contrast { factor: f32 },
+    Gamma { gamma: f32 },
+    Exposure { stops: f32 },
+    Levels { black: u32, white: u32, gamma: f32 },
+    EqualizeHistogram,
+    /// Composite another image on top with its top-left corner at (`x`, `y`).
+    Overlay { overlay: LazyFile<Rgba32FImage>, x: i64, y: i64, opacity: f32, mode: BlendMode },
+    /// `draw_rect`, `draw_line`, `draw_circle` and `draw_text`.
+    Draw { shape: Box<Shape>, color: [u8; 4] },
+    /// Turn the image upright by an EXIF orientation (1-8). `None` stands for
+    /// the input's own, see [`ImageEffect::resolve_orientation`].
+    AutoOrient { orientation: Option<u16> },
+    /// Convolve the color channels with `kernel`, dividing by `divisor` and
+    /// adding `offset` (0-255) to each result.
+    Convolve { kernel: Kernel, divisor: f32, offset: f32 },
+    /// Whichever effect [`auto::choose`] picks for the image.
+    Auto,
+}
+
+/// Curve that `tonemap` compresses scene brightness with.
+#[derive(Debug, Clone, Copy)]
+pub enum ToneOperator {
+    /// `L / (1 + L)` on the luminance, scaling the color channels alike.
+    Reinhard,
+    /// Krzysztof Narkowicz's fit of the ACES filmic curve, per channel. It
+    /// has more contrast than Reinhard and rolls bright colors off to white.
+    Aces,
+}
+
+/// A speed-versus-smoothness setting shared by every effect that resamples,
+/// for callers that would rather not pick a filter per effect.
+#[derive(Debug, Clone, Copy)]
+enum Quality {
+    Fast,
+    Balanced,
+    Best,
+}
+
+fn take_quality(params: &mut Params) -> PyResult<Option<Quality>> {
+    let name = params.take_str("quality", "")?;
+    match name.as_str() {
+        "" => Ok(None),
+        "fast" => Ok(Some(Quality::Fast)),
+        "balanced" => Ok(Some(Quality::Balanced)),
+        "best" => Ok(Some(Quality::Best)),
+        _ => Err(params::invalid("quality", "expected 'fast', 'balanced' or 'best'")),
+    }
+}
+
+/// Read `filter`, or pick from `by_quality` (fast, balanced, best) given a
+/// `quality`. The best choice is the default.
+fn take_filter(params: &mut Params, by_quality: [Filter; 3]) -> PyResult<Filter> {
+    let quality = take_quality(params)?;
+    let name = params.take_str("filter", "")?;
+    if name.is_empty() {
+        return Ok(match quality {
+            Some(Quality::Fast) => by_quality[0],
+            Some(Quality::Balanced) => by_quality[1],
+            Some(Quality::Best) | None => by_quality[2],
+        });
+    }
+    if quality.is_some() {
+        return Err(params::invalid("quality", "cannot be combined with 'filter'"));
+    }
+    Filter::from_str(&name).ok_or_else(|| {
+        let message = format!("unknown filter '{}', expected one of: {}", name, Filter::NAMES);
+        params::invalid("filter", &message)
+    })
+}
+
+/// Read `gamma`, the exponent a tone curve is raised to the inverse of, so
+/// that values above 1 brighten the midtones.
+fn take_gamma(params: &mut Params) -> PyResult<f32> {
+    let gamma = params.take_f32("gamma", 1.0)?;
+    if !(gamma > 0.0 && gamma.is_finite()) {
+        return Err(params::invalid("gamma", "must be a positive number"));
+    }
+    Ok(gamma)
+}
+
+/// Read `gamut`, how the color adjustments treat values they push past the
+/// displayable range.
+fn take_gamut(params: &mut Params) -> PyResult<GamutMapping> {
+    let name = params.take_str("gamut", "clip")?;
+    GamutMapping::from_str(&name)
+        .ok_or_else(|| params::invalid("gamut", "expected 'clip' or 'soft'"))
+}
+
+/// Read the `low_threshold` and `high_threshold` gradient strengths for
+/// Canny edge detection. Edges weaker than the low one are dropped, and
+/// those between the two kept only where they connect to a stronger edge.
+fn take_canny_thresholds(params: &mut Params) -> PyResult<(f32, f32)> {
+    let low_threshold = params.take_f32("low_threshold", 25.0)?;
+    let high_threshold = params.take_f32("high_threshold", 75.0)?;
+    if low_threshold < 0.0 || high_threshold < low_threshold {
+        return Err(params::invalid(
+            "high_threshold",
+            "thresholds must satisfy 0 <= low_threshold <= high_threshold",
+        ));
+    }
+    Ok((low_threshold, high_threshold))
+}
+
+/// Interpolation for rotations: bilinear unless a `quality` says otherwise.
+fn take_interpolation(params: &mut Params) -> PyResult<Interpolation> {
+    Ok(match take_quality(params)? {
+        Some(Quality::Fast) => Interpolation::Nearest,
+        Some(Quality::Balanced) | None => Interpolation::Bilinear,
+        Some(Quality::Best) => Interpolation::Bicubic,
+    })
+}
+
+/// Line width of the `draw_*` effects, at least 1 pixel.
+fn take_thickness(params: &mut Params) -> PyResult<u32> {
+    let thickness = params.take_u32("thickness", 1)?;
+    if thickness == 0 {
+        return Err(params::invalid("thickness", "must be at least 1"));
+    }
+    Ok(thickness)
+}
+
+/// Color of the `draw_*` effects; opaque red unless given.
+fn take_draw_color(params: &mut Params) -> PyResult<[u8; 4]> {
+    params.take_rgba("color", [255, 0, 0, 255])
+}
+
+/// Check the Gaussian `sigma` of `blur` or `sharpen`.
+fn take_sigma(sigma: f32) -> PyResult<f32> {
+    if !(sigma > 0.0 && sigma <= tiles::MAX_SIGMA) {
+        let message = format!("must be above 0 and at most {}", tiles::MAX_SIGMA);
+        return Err(params::invalid("sigma", &message));
+    }
+    Ok(sigma)
+}
+
+/// A required position of the `draw_*` effects, in pixels.
+fn take_coordinate(params: &mut Params, key: &str) -> PyResult<f32> {
+    let value = params.require_f32(key)?;
+    if !(-draw::MAX_COORDINATE..=draw::MAX_COORDINATE).contains(&value) {
+        let message = format!("must be a number between -{0} and {0}", draw::MAX_COORDINATE);
+        return Err(params::invalid(key, &message));
+    }
+    Ok(value)
+}
+
+/// Read a list of tone curve control points, checking they are usable.
+fn take_curve_points(params: &mut Params, key: &str) -> PyResult<Option<Vec<(u8, u8)>>> {
+    let points = params.take_opt_points(key)?;
+    if let Some(points) = &points {
+        if points.is_empty() {
+            return Err(params::invalid(key, "needs at least one point"));
+        }
+        if points.windows(2).any(|w| w[1].0 <= w[0].0) {
+            return Err(params::invalid(key, "points must be sorted by strictly increasing input"));
+        }
+    }
+    Ok(points)
+}
+
+impl ImageEffect {
+    /// Look up an effect by name, consuming the parameters it understands.
+    ///
+    /// Bad parameters raise `InvalidParameterError` naming the effect.
+    pub fn parse(name: &str, params: &mut Params) -> PyResult<Self> {
+        Self::parse_params(name, params).map_err(|e| errors::with_effect(e, name))
+    }
+
+    fn parse_params(name: &str, params: &mut Params) -> PyResult<Self> {
+        let effect = match name {
+            "edge_detect" => {
+                let invert_output = params.take_bool("invert_output", true)?;
+                let sigma = params.take_f32("smoothing_sigma", 1.4)?;
+                if !(sigma > 0.0 && sigma.is_finite()) {
+                    return Err(params::invalid("smoothing_sigma", "must be a positive number"));
+                }
+                let sigma_color = params.take_f32("smoothing_color_sigma", 25.0)?;
+                if !(sigma_color > 0.0 && sigma_color.is_finite()) {
+                    return Err(params::invalid(
+                        "smoothing_color_sigma",
+                        "must be a positive number",
+                    ));
+                }
+                let smoothing = match params.take_str("smoothing", "none")?.as_str() {
+                    "none" => EdgeSmoothing::None,
+                    "gaussian" => EdgeSmoothing::Gaussian { sigma },
+                    "bilateral" => EdgeSmoothing::Bilateral { sigma, sigma_color },
+                    _ => {
+                        return Err(params::invalid(
+                            "smoothing",
+                            "expected 'none', 'gaussian' or 'bilateral'",
+                        ))
+                    }
+                };
+                let (low_threshold, high_threshold) = take_canny_thresholds(params)?;
+                Self::EdgeDetect { invert_output, smoothing, low_threshold, high_threshold }
+            }
+            "blur" => {
+                // Nearly all of a gaussian's weight lies within three sigmas.
+                let max_radius = (3.0 * tiles::MAX_SIGMA) as u32;
+                let sigma = match (params.take_opt_f32("sigma")?, params.take_opt_u32("radius")?) {
+                    (Some(_), Some(_)) => {
+                        return Err(params::invalid("radius", "cannot be combined with 'sigma'"))
+                    }
+                    (Some(sigma), None) => take_sigma(sigma)?,
+                    (None, Some(radius)) if !(1..=max_radius).contains(&radius) => {
+                        let message = format!("must be between 1 and {}", max_radius);
+                        return Err(params::invalid("radius", &message));
+                    }
+                    (None, Some(radius)) => radius as f32 / 3.0,
+                    (None, None) => 2.0,
+                };
+                Self::Blur { sigma }
+            }
+            "sharpen" => {
+                let sigma = take_sigma(params.take_f32("sigma", 1.0)?)?;
+                let threshold = params.take_u32("threshold", 5)?;
+                if threshold > 255 {
+                    return Err(params::invalid("threshold", "must be between 0 and 255"));
+                }
+                let amount = params.take_f32("amount", 1.0)?;
+                if !(amount >= 0.0 && amount.is_finite()) {
+                    return Err(params::invalid("amount", "must be a non-negative number"));
+                }
+                let luma_only = params.take_bool("luma_only", false)?;
+                Self::Sharpen { sigma, threshold, amount, luma_only }
+            }
+            "grayscale" => Self::Grayscale,
+            "sepia" => Self::Sepia,
+            "invert" => Self::Invert,
+            "identity" => Self::Identity,
+            "auto_white_balance" => {
+                let clip_percent = params.take_f32("clip_percent", 1.0)?;
+                if !(0.0..50.0).contains(&clip_percent) {
+                    return Err(params::invalid("clip_percent", "must be in [0, 50)"));
+                }
+                Self::AutoWhiteBalance { clip_percent }
+            }
+            "crop" => {
+                let x = params.take_u32("x", 0)?;
+                let y = params.take_u32("y", 0)?;
+                let width = params.require_u32("width")?;
+                let height = params.require_u32("height")?;
+                if width == 0 {
+                    return Err(params::invalid("width", "must be positive"));
+                }
+                if height == 0 {
+                    return Err(params::invalid("height", "must be positive"));
+                }
+                Self::Crop { x, y, width, height }
+            }
+            "kmeans_quantize" => {
+                let k = params.take_u32("k", 8)?;
+                if !(1..=256).contains(&k) {
+                    return Err(params::invalid("k", "must be between 1 and 256"));
+                }
+                let max_iterations = params.take_u32("max_iterations", 20)?;
+                if max_iterations == 0 {
+                    return Err(params::invalid("max_iterations", "must be positive"));
+                }
+                let seed = params.take_u64("seed", 0)?;
+                Self::KmeansQuantize { k, max_iterations, seed }
+            }
+            "chroma_key" => {
+                let color = params.take_rgb("color", [0, 255, 0])?;
+                let tolerance = params.take_f32("tolerance", 60.0)?;
+                if tolerance < 0.0 {
+                    return Err(params::invalid("tolerance", "must not be negative"));
+                }
+                let softness = params.take_f32("softness", 0.0)?;
+                if softness < 0.0 {
+                    return Err(params::invalid("softness", "must not be negative"));
+                }
+                Self::ChromaKey { color, tolerance, softness }
+            }
+            "comic" => {
+                let levels = params.take_u32("levels", 4)?;
+                if !(2..=256).contains(&levels) {
+                    return Err(params::invalid("levels", "must be between 2 and 256"));
+                }
+                let (low_threshold, high_threshold) = take_canny_thresholds(params)?;
+                let edge_thickness = params.take_u32("edge_thickness", 1)?;
+                if !(1..=16).contains(&edge_thickness) {
+                    return Err(params::invalid("edge_thickness", "must be between 1 and 16"));
+                }
+                Self::Comic { levels, low_threshold, high_threshold, edge_thickness }
+            }
+            "autocrop" => {
+                let color = params.take_rgb("color", [0, 0, 0])?;
+                let tolerance = params.take_u32("tolerance", 24)?;
+                if tolerance > 255 {
+                    return Err(params::invalid("tolerance", "must be between 0 and 255"));
+                }
+                Self::Autocrop { color, tolerance }
+            }
+            "vintage" => {
+                let strength = params.take_f32("strength", 1.0)?;
+                if !(0.0..=1.0).contains(&strength) {
+                    return Err(params::invalid("strength", "must be between 0 and 1"));
+                }
+                Self::Vintage { strength }
+            }
+            "brightness_hsv" => {
+                let factor = params.take_f32("factor", 1.0)?;
+                if !factor.is_finite() || factor < 0.0 {
+                    return Err(params::invalid("factor", "must not be negative"));
+                }
+                let gamut = take_gamut(params)?;
+                let dither = params.take_bool("dither", false)?;
+                Self::BrightnessHsv { factor, gamut, dither }
+            }
+            "hue_rotate" => {
+                let degrees = params.take_f32("degrees", 0.0)?;
+                if !degrees.is_finite() {
+                    return Err(params::invalid("degrees", "must be a finite number"));
+                }
+                let dither = params.take_bool("dither", false)?;
+                Self::HueRotate { degrees, dither }
+            }
+            "saturation" => {
+                let factor = params.take_f32("factor", 1.0)?;
+                if !factor.is_finite() || factor < 0.0 {
+                    return Err(params::invalid("factor", "must not be negative"));
+                }
+                let gamut = take_gamut(params)?;
+                let dither = params.take_bool("dither", false)?;
+                Self::Saturation { factor, gamut, dither }
+            }
+            "rotate" => {
+                let angle = params.take_f32("angle", 0.0)?;
+                if !angle.is_finite() {
+                    return Err(params::invalid("angle", "must be a finite number of degrees"));
+                }
+                let fill = params.take_rgba("fill", [0, 0, 0, 255])?;
+                let interpolation = take_interpolation(params)?;
+                let expand = params.take_bool("expand", false)?;
+                Self::Rotate { angle, fill, interpolation, expand }
+            }
+            "flip_h" => Self::FlipHorizontal,
+            "flip_v" => Self::FlipVertical,
+            "trim_uniform_background" => {
+                let color = params.take_opt_rgb("color")?;
+                let tolerance = params.take_u32("tolerance", 24)?;
+                if tolerance > 255 {
+                    return Err(params::invalid("tolerance", "must be between 0 and 255"));
+                }
+                let sides = Sides {
+                    top: params.take_bool("trim_top", true)?,
+                    bottom: params.take_bool("trim_bottom", true)?,
+                    left: params.take_bool("trim_left", true)?,
+                    right: params.take_bool("trim_right", true)?,
+                };
+                Self::TrimBackground { color, tolerance, sides }
+            }
+            "laplacian_sharpen" => {
+                let amount = params.take_f32("amount", 1.0)?;
+                if !amount.is_finite() || amount < 0.0 {
+                    return Err(params::invalid("amount", "must not be negative"));
+                }
+                Self::LaplacianSharpen { amount }
+            }
+            "colorize" => {
+                let name = params.take_str("colormap", "viridis")?;
+                let colormap = Colormap::from_str(&name).ok_or_else(|| {
+                    let message =
+                        format!("unknown colormap '{}', expected one of: {}", name, Colormap::NAMES);
+                    params::invalid("colormap", &message)
+                })?;
+                Self::Colorize { colormap }
+            }
+            "seam_carve" => {
+                let width = params.take_opt_u32("width")?;
+                let height = params.take_opt_u32("height")?;
+                if width.is_none() && height.is_none() {
+                    let message = "seam_carve needs a target 'width', 'height' or both".to_string();
+                    return Err(errors::invalid_parameter("width", message));
+                }
+                if width == Some(0) {
+                    return Err(params::invalid("width", "must be positive"));
+                }
+                if height == Some(0) {
+                    return Err(params::invalid("height", "must be positive"));
+                }
+                Self::SeamCarve { width, height }
+            }
+            "resize" => {
+                let width = params.take_opt_u32("width")?;
+                let height = params.take_opt_u32("height")?;
+                if width.is_none() && height.is_none() {
+                    let message = "resize needs a 'width', 'height' or both".to_string();
+                    return Err(errors::invalid_parameter("width", message));
+                }
+                if width == Some(0) {
+                    return Err(params::invalid("width", "must be positive"));
+                }
+                if height == Some(0) {
+                    return Err(params::invalid("height", "must be positive"));
+                }
+                let filter =
+                    take_filter(params, [Filter::Triangle, Filter::CatmullRom, Filter::Lanczos3])?;
+                let only_if_larger = params.take_bool("only_if_larger", false)?;
+                Self::Resize { width, height, filter, only_if_larger }
+            }
+            "thumbnail" => {
+                let size = params.take_u32("size", 256)?;
+                if size == 0 {
+                    return Err(params::invalid("size", "must be positive"));
+                }
+                let filter =
+                    take_filter(params, [Filter::Triangle, Filter::CatmullRom, Filter::Area])?;
+                let only_if_larger = params.take_bool("only_if_larger", true)?;
+                Self::Thumbnail { size, filter, only_if_larger }
+            }
+            "max_megapixels" => {
+                let megapixels = params.require_f32("megapixels")?;
+                if !megapixels.is_finite() || megapixels <= 0.0 {
+                    return Err(params::invalid("megapixels", "must be positive"));
+                }
+                let filter =
+                    take_filter(params, [Filter::Triangle, Filter::CatmullRom, Filter::Area])?;
+                Self::MaxMegapixels { megapixels, filter }
+            }
+            "clip_warning" => {
+                let low_color = params.take_rgb("low_color", [0, 0, 255])?;
+                let high_color = params.take_rgb("high_color", [255, 0, 0])?;
+                let dim = params.take_f32("dim", 0.5)?;
+                if !(0.0..=1.0).contains(&dim) {
+                    return Err(params::invalid("dim", "must be between 0 and 1"));
+                }
+                Self::ClipWarning { low_color, high_color, dim }
+            }
+            "normalize_exposure" => {
+                let target_mean = params.take_f32("target_mean", 118.0)?;
+                if !(1.0..=254.0).contains(&target_mean) {
+                    return Err(params::invalid("target_mean", "must be between 1 and 254"));
+                }
+                let gamma = match params.take_str("method", "linear")?.as_str() {
+                    "linear" => false,
+                    "gamma" => true,
+                    _ => return Err(params::invalid("method", "expected 'linear' or 'gamma'")),
+                };
+                Self::NormalizeExposure { target_mean, gamma }
+            }
+            "curve" => {
+                let monotone = match params.take_str("interpolation", "monotone")?.as_str() {
+                    "monotone" => true,
+                    "linear" => false,
+                    _ => {
+                        return Err(params::invalid(
+                            "interpolation",
+                            "expected 'monotone' or 'linear'",
+                        ))
+                    }
+                };
+                let points = take_curve_points(params, "points")?;
+                let mut luts = Box::new([[0u8; 256]; 3]);
+                let keys = ["red_points", "green_points", "blue_points"];
+                for (lut, key) in luts.iter_mut().zip(keys) {
+                    let channel_points = match take_curve_points(params, key)? {
+                        Some(p) => p,
+                        None => points.clone().unwrap_or_else(|| vec![(0, 0), (255, 255)]),
+                    };
+                    *lut = curve::lut(&channel_points, monotone);
+                }
+                Self::Curve { luts }
+            }
+            "deskew" => {
+                let max_angle = params.take_f32("max_angle", 10.0)?;
+                if !(max_angle > 0.0 && max_angle <= 45.0) {
+                    return Err(params::invalid("max_angle", "must be above 0 and at most 45"));
+                }
+                let fill = params.take_rgba("fill", [255, 255, 255, 255])?;
+                let interpolation = take_interpolation(params)?;
+                Self::Deskew { max_angle, fill, interpolation }
+            }
+            "saliency_crop" => {
+                let aspect_ratio = params.take_f32("aspect_ratio", 1.0)?;
+                if !(aspect_ratio > 0.0 && aspect_ratio.is_finite()) {
+                    return Err(params::invalid("aspect_ratio", "must be a positive number"));
+                }
+                let center_bias = params.take_f32("center_bias", 0.25)?;
+                if !(0.0..=1.0).contains(&center_bias) {
+                    return Err(params::invalid("center_bias", "must be between 0 and 1"));
+                }
+                Self::SaliencyCrop { aspect_ratio, center_bias }
+            }
+            "crop_aspect" => {
+                let aspect_w = params.require_f32("aspect_w")?;
+                if !(aspect_w > 0.0 && aspect_w.is_finite()) {
+                    return Err(params::invalid("aspect_w", "must be a positive number"));
+                }
+                let aspect_h = params.require_f32("aspect_h")?;
+                if !(aspect_h > 0.0 && aspect_h.is_finite()) {
+                    return Err(params::invalid("aspect_h", "must be a positive number"));
+                }
+                Self::CropAspect { aspect_ratio: aspect_w / aspect_h }
+            }
+            "remove_noise_wavelet" => {
+                let threshold = params.take_f32("threshold", 10.0)?;
+                if !(threshold >= 0.0 && threshold.is_finite()) {
+                    return Err(params::invalid("threshold", "must be a non-negative number"));
+                }
+                let levels = params.take_u32("levels", 3)?;
+                if !(1..=8).contains(&levels) {
+                    return Err(params::invalid("levels", "must be between 1 and 8"));
+                }
+                let chroma = params.take_bool("chroma", true)?;
+                Self::WaveletDenoise { threshold, levels, chroma }
+            }
+            "clahe" => {
+                let tiles = params.take_u32("tiles", 8)?;
+                if !(1..=64).contains(&tiles) {
+                    return Err(params::invalid("tiles", "must be between 1 and 64"));
+                }
+                let clip_limit = params.take_f32("clip_limit", 2.0)?;
+                if !(clip_limit >= 1.0 && clip_limit.is_finite()) {
+                    return Err(params::invalid("clip_limit", "must be at least 1"));
+                }
+                Self::Clahe { tiles, clip_limit }
+            }
+            "tonemap" => {
+                let operator = match params.take_str("operator", "reinhard")?.as_str() {
+                    "reinhard" => ToneOperator::Reinhard,
+                    "aces" => ToneOperator::Aces,
+                    _ => return Err(params::invalid("operator", "expected 'reinhard' or 'aces'")),
+                };
+                let exposure = params.take_f32("exposure", 0.0)?;
+                if !exposure.is_finite() {
+                    return Err(params::invalid("exposure", "must be a finite number"));
+                }
+                Self::Tonemap { operator, exposure }
+            }
+            "fix_chromatic_aberration" => {
+                let mut take_scale = |key: &str| {
+                    let scale = params.take_f32(key, 1.0)?;
+                    if !(0.9..=1.1).contains(&scale) {
+                        return Err(params::invalid(key, "must be between 0.9 and 1.1"));
+                    }
+                    Ok(scale)
+                };
+                let (r_scale, b_scale) = (take_scale("r_scale")?, take_scale("b_scale")?);
+                Self::FixChromaticAberration { r_scale, b_scale }
+            }
+            "difference" => {
+                let reference = LazyFile::new(params.require_str("reference_path")?);
+                let amplify = params.take_f32("amplify", 1.0)?;
+                if !(amplify > 0.0 && amplify.is_finite()) {
+                    return Err(params::invalid("amplify", "must be a positive number"));
+                }
+                Self::Difference { reference, amplify }
+            }
+            "selective_color" => {
+                let hue = params.require_f32("hue")?;
+                if !hue.is_finite() {
+                    return Err(params::invalid("hue", "must be a finite number"));
+                }
+                let tolerance = params.take_f32("tolerance", 30.0)?;
+                if !(0.0..=180.0).contains(&tolerance) {
+                    return Err(params::invalid("tolerance", "must be between 0 and 180"));
+                }
+                let softness = params.take_f32("softness", 10.0)?;
+                if !(softness >= 0.0 && softness.is_finite()) {
+                    return Err(params::invalid("softness", "must not be negative"));
+                }
+                Self::SelectiveColor { hue, tolerance, softness }
+            }
+            "brightness" => {
+                let amount = params.take_f32("amount", 0.0)?;
+                if !(-1.0..=1.0).contains(&amount) {
+                    return Err(params::invalid("amount", "must be between -1 and 1"));
+                }
+                Self::Brightness { amount }
+            }
+            "contrast" => {
+                let factor = params.take_f32("factor", 1.0)?;
+                if !(factor >= 0.0 && factor.is_finite()) {
+                    return Err(params::invalid("factor", "must be a non-negative number"));
+                }
+                Self::Contrast { factor }
+            }
+            "gamma" => Self::Gamma { gamma: take_gamma(params)? },
+            "exposure" => {
+                let stops = params.take_f32("stops", 0.0)?;
+                if !(-16.0..=16.0).contains(&stops) {
+                    return Err(params::invalid("stops", "must be between -16 and 16"));
+                }
+                Self::Exposure { stops }
+            }
+            "levels" => {
+                let black = params.take_u32("black", 0)?;
+                let white = params.take_u32("white", 255)?;
+                if white > 255 {
+                    return Err(params::invalid("white", "must be between 0 and 255"));
+                }
+                if black >= white {
+                    return Err(params::invalid("black", "must be below 'white'"));
+                }
+                Self::Levels { black, white, gamma: take_gamma(params)? }
+            }
+            "equalize_histogram" => Self::EqualizeHistogram,
+            "auto_orient" => {
+                let orientation = params.take_opt_u32("orientation")?;
+                if orientation.is_some_and(|o| !(1..=8).contains(&o)) {
+                    return Err(params::invalid("orientation", "must be between 1 and 8"));
+                }
+                Self::AutoOrient { orientation: orientation.map(|o| o as u16) }
+            }
+            "overlay" => {
+                let overlay = LazyFile::new(params.require_str("overlay_path")?);
+                let (x, y) = (params.take_i64("x", 0)?, params.take_i64("y", 0)?);
+                let opacity = params.take_f32("opacity", 1.0)?;
+                if !(0.0..=1.0).contains(&opacity) {
+                    return Err(params::invalid("opacity", "must be between 0 and 1"));
+                }
+                let name = params.take_str("blend_mode", "normal")?;
+                let mode = BlendMode::from_str(&name).ok_or_else(|| {
+                    let message = format!(
+                        "unknown blend mode '{}', expected one of: {}",
+                        name,
+                        BlendMode::NAMES
+                    );
+                    params::invalid("blend_mode", &message)
+                })?;
+                Self::Overlay { overlay, x, y, opacity, mode }
+            }
+            "draw_rect" => {
+                let (x, y) = (params.take_i64("x", 0)?, params.take_i64("y", 0)?);
+                let mut take_side = |key: &str| match params.require_u32(key)? {
+                    0 => Err(params::invalid(key, "must be positive")),
+                    side => Ok(side),
+                };
+                let (width, height) = (take_side("width")?, take_side("height")?);
+                let thickness = take_thickness(params)?;
+                let fill = params.take_bool("fill", false)?;
+                let shape = Shape::Rect { x, y, width, height, thickness, fill };
+                Self::Draw { shape: Box::new(shape), color: take_draw_color(params)? }
+            }
+            "draw_line" => {
+                let mut take_point = |x: &str, y: &str| -> PyResult<(f32, f32)> {
+                    Ok((take_coordinate(params, x)?, take_coordinate(params, y)?))
+                };
+                let (from, to) = (take_point("x1", "y1")?, take_point("x2", "y2")?);
+                let thickness = take_thickness(params)?;
+                let shape = Shape::Line { from, to, thickness };
+                Self::Draw { shape: Box::new(shape), color: take_draw_color(params)? }
+            }
+            "draw_circle" => {
+                let center = (take_coordinate(params, "x")?, take_coordinate(params, "y")?);
+                let radius = params.require_f32("radius")?;
+                if !(radius > 0.0 && radius <= draw::MAX_COORDINATE) {
+                    let message = format!("must be above 0 and at most {}", draw::MAX_COORDINATE);
+                    return Err(params::invalid("radius", &message));
+                }
+                let thickness = take_thickness(params)?;
+                let fill = params.take_bool("fill", false)?;
+                let shape = Shape::Circle { center, radius, thickness, fill };
+                Self::Draw { shape: Box::new(shape), color: take_draw_color(params)? }
+            }
+            "draw_text" => {
+                let text = params.require_str("text")?;
+                let font = LazyFile::new(params.require_str("font_path")?);
+                let size = params.take_f32("size", 16.0)?;
+                if !(size > 0.0 && size.is_finite()) {
+                    return Err(params::invalid("size", "must be a positive number"));
+                }
+                let (x, y) = (params.take_i64("x", 0)?, params.take_i64("y", 0)?);
+                let shape = Shape::Text { text, font, size, x, y };
+                Self::Draw { shape: Box::new(shape), color: take_draw_color(params)? }
+            }
+            "convolve" => {
+                let matrix = params.take_opt_matrix("kernel")?;
+                let preset = params.take_opt_str("preset")?;
+                let size = params.take_opt_u32("size")?;
+                let angle = params.take_opt_f32("angle")?;
+                let (kernel, default_offset) = match (matrix, preset) {
+                    (Some(rows), None) => {
+                        let kernel = Kernel::from_rows(rows)
+                            .map_err(|message| params::invalid("kernel", &message))?;
+                        (kernel, 0.0)
+                    }
+                    (None, Some(preset)) => {
+                        if !convolve::PRESETS.contains(&preset.as_str()) {
+                            let message = format!(
+                                "unknown preset '{}', expected one of: {}",
+                                preset,
+                                convolve::PRESETS.join(", ")
+                            );
+                            return Err(params::invalid("preset", &message));
+                        }
+                        if size.is_some() && !matches!(&*preset, "box_blur" | "motion_blur") {
+                            let message = "only applies to presets 'box_blur' and 'motion_blur'";
+                            return Err(params::invalid("size", message));
+                        }
+                        if angle.is_some() && preset != "motion_blur" {
+                            return Err(params::invalid("angle", "only applies to 'motion_blur'"));
+                        }
+                        let size = size.unwrap_or(if preset == "box_blur" { 3 } else { 9 });
+                        if size.is_multiple_of(2) || size as usize > convolve::MAX_SIZE {
+                            let message =
+                                format!("must be an odd number, at most {}", convolve::MAX_SIZE);
+                            return Err(params::invalid("size", &message));
+                        }
+                        Kernel::preset(&preset, size as usize, angle.unwrap_or(0.0))
+                            .expect("preset names are checked above")
+                    }
+                    _ => {
+                        return Err(errors::InvalidParameterError::new_err(
+                            "convolve needs exactly one of 'kernel' or 'preset'",
+                        ))
+                    }
+                };
+                let divisor = params.take_f32("divisor", kernel.default_divisor())?;
+                if divisor == 0.0 || !divisor.is_finite() {
+                    return Err(params::invalid("divisor", "must be a non-zero number"));
+                }
+                let offset = params.take_f32("offset", default_offset)?;
+                if !offset.is_finite() {
+                    return Err(params::invalid("offset", "must be a finite number"));
+                }
+                Self::Convolve { kernel, divisor, offset }
+            }
+            "auto" => Self::Auto,
+            _ => return Err(errors::InvalidParameterError::new_err("Unknown effect type")),
+        };
+        params.finish(name)?;
+        Ok(effect)
+    }
+
+    /// Whether the effect leaves every pixel as it is, as `identity` does and
+    /// others do at neutral settings such as a `brightness_hsv` factor of 1.
+    pub fn is_noop(&self) -> bool {
+        match *self {
+            Self::Identity => true,
+            // Dither noise is added even when the adjustment itself is neutral.
+            Self::BrightnessHsv { factor, gamut, dither } => {
+                factor == 1.0 && gamut == GamutMapping::Clip && !dither
+            }
+            Self::HueRotate { degrees, dither } => degrees % 360.0 == 0.0 && !dither,
+            Self::Saturation { factor, gamut, dither } => {
+                factor == 1.0 && gamut == GamutMapping::Clip && !dither
+            }
+            Self::Vintage { strength } => strength == 0.0,
+            // Without `expand` a see-through fill still gives the output alpha.
+            Self::Rotate { angle, fill, expand, .. } => {
+                angle % 360.0 == 0.0 && (expand || fill[3] == 255)
+            }
+            Self::Sharpen { amount, .. } => amount == 0.0,
+            Self::Brightness { amount } => amount == 0.0,
+            Self::Contrast { factor } => factor == 1.0,
+            Self::Gamma { gamma } => gamma == 1.0,
+            Self::Exposure { stops } => stops == 0.0,
+            Self::Levels { black, white, gamma } => black == 0 && white == 255 && gamma == 1.0,
+            Self::AutoOrient { orientation } => orientation == Some(1),
+            Self::Overlay { opacity, .. } => opacity == 0.0,
+            Self::LaplacianSharpen { amount } => amount == 0.0,
+            Self::FixChromaticAberration { r_scale, b_scale } => r_scale == 1.0 && b_scale == 1.0,
+            Self::Curve { ref luts } => {
+                luts.iter().all(|lut| lut.iter().enumerate().all(|(i, &v)| v as usize == i))
+            }
+            Self::Convolve { ref kernel, divisor, offset } => {
+                kernel.is_identity() && divisor == 1.0 && offset == 0.0
+            }
+            _ => false,
+        }
+    }
+
+    /// Rows of context above and below a horizontal strip that the effect
+    /// needs to give the strip the same pixels as processing the whole image
+    /// would: none for effects that work pixel by pixel, the reach of the
+    /// filter for blurs. `None` for effects that change the image's size or
+    /// depend on the image as a whole, which can't be applied strip by strip.
+    pub fn strip_overlap(&self) -> Option<u32> {
+        match *self {
+            Self::Identity
+            | Self::Grayscale
+            | Self::Sepia
+            | Self::Invert
+            | Self::Brightness { .. }
+            | Self::Contrast { .. }
+            | Self::Gamma { .. }
+            | Self::Exposure { .. }
+            | Self::Levels { .. }
+            | Self::Curve { .. } => Some(0),
+            Self::Blur { sigma } | Self::Sharpen { sigma, .. } => {
+                Some(tiles::gaussian_overlap(sigma))
+            }
+            Self::Convolve { ref kernel, .. } => Some(kernel.radius()),
+            _ => None,
+        }
+    }
+
+    /// For an `auto_orient` left to the input's own orientation, the same
+    /// effect with the one `source` reads filled in; any other effect needs
+    /// nothing and gets `None`. `source` is only called in the first case.
+    /// Without a recorded orientation the image is taken to be upright.
+    pub fn resolve_orientation(
+        &self,
+        source: impl FnOnce() -> PyResult<Option<u16>>,
+    ) -> PyResult<Option<Self>> {
+        match self {
+            Self::AutoOrient { orientation: None } => {
+                Ok(Some(Self::AutoOrient { orientation: Some(source()?.unwrap_or(1)) }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Apply the effect to `img`. When the [`StopCheck`] of
+    /// `progress_callback` cuts the work short, this fails rather than
+    /// returning a partly processed image.
+    pub fn apply(
+        &self,
+        py: Python,
+        img: DynamicImage,
+        color_space: ColorSpace,
+        progress_callback: &PyObject,
+    ) -> PyResult<DynamicImage> {
+        let stop = StopCheck::of(py, progress_callback);
+        let output = progress::with_stop(stop.clone(), || {
+            self.apply_unchecked(py, img, color_space, progress_callback)
+        })?;
+        stop.check()?;
+        Ok(output)
+    }
+
+    fn apply_unchecked(
+        &self,
+        py: Python,
+        img: DynamicImage,
+        color_space: ColorSpace,
+        progress_callback: &PyObject,
+    ) -> PyResult<DynamicImage> {
+        match *self {
+            Self::EdgeDetect { invert_output, smoothing, low_threshold, high_threshold } => {
+                apply_edge_detection(
+                    py,
+                    img,
+                    invert_output,
+                    smoothing,
+                    (low_threshold, high_threshold),
+                    progress_callback,
+                )
+            }
+            Self::Blur { sigma } => apply_blur(py, img, sigma, color_space, progress_callback),
+            Self::Sharpen { sigma, threshold, amount, luma_only } => apply_sharpen(
+                py,
+                img,
+                sigma,
+                threshold as i32,
+                amount,
+                luma_only,
+                color_space,
+                progress_callback,
+            ),
+            Self::Grayscale => apply_grayscale(py, img, progress_callback),
+            Self::Sepia => apply_sepia(py, img, progress_callback),
+            Self::Invert => apply_invert(py, img, progress_callback),
+            Self::Identity => apply_identity(py, img, progress_callback),
+            Self::AutoWhiteBalance { clip_percent } => {
+                apply_auto_white_balance(py, img, clip_percent, progress_callback)
+            }
+            Self::Crop { x, y, width, height } => {
+                apply_crop(py, img, (x, y, width, height), progress_callback)
+            }
+            Self::KmeansQuantize { k, max_iterations, seed } => {
+                apply_kmeans_quantize(py, img, k, max_iterations, seed, progress_callback)
+            }
+            Self::ChromaKey { color, tolerance, softness } => {
+                apply_chroma_key(py, img, color, tolerance, softness, progress_callback)
+            }
+            Self::Comic { levels, low_threshold, high_threshold, edge_thickness } => apply_comic(
+                py,
+                img,
+                levels,
+                (low_threshold, high_threshold),
+                edge_thickness,
+                progress_callback,
+            ),
+            Self::Autocrop { color, tolerance } => {
+                apply_autocrop(py, img, color, tolerance as u8, progress_callback)
+            }
+            Self::Vintage { strength } => apply_vintage(py, img, strength, progress_callback),
+            Self::BrightnessHsv { factor, gamut, dither } => {
+                apply_brightness_hsv(py, img, factor, gamut, dither, progress_callback)
+            }
+            Self::HueRotate { degrees, dither } => {
+                apply_hue_rotate(py, img, degrees, dither, progress_callback)
+            }
+            Self::Saturation { factor, gamut, dither } => {
+                apply_saturation(py, img, factor, gamut, dither, progress_callback)
+            }
+            Self::Rotate { angle, fill, interpolation, expand } => {
+                apply_rotate(py, img, angle, fill, interpolation, expand, progress_callback)
+            }
+            Self::FlipHorizontal => apply_flip(py, img, false, progress_callback),
+            Self::FlipVertical => apply_flip(py, img, true, progress_callback),
+            Self::TrimBackground { color, tolerance, sides } => {
+                apply_trim(py, img, color, tolerance as u8, sides, progress_callback)
+            }
+            Self::LaplacianSharpen { amount } => {
+                apply_laplacian_sharpen(py, img, amount, progress_callback)
+            }
+            Self::Colorize { colormap } => apply_colorize(py, img, colormap, progress_callback),
+            Self::SeamCarve { width, height } => {
+                apply_seam_carve(py, img, width, height, progress_callback)
+            }
+            Self::Resize { width, height, filter, only_if_larger } => {
+                let fits = |max: Option<u32>, v: u32| max.is_none_or(|max| v <= max);
+                if only_if_larger && fits(width, img.width()) && fits(height, img.height()) {
+                    progress_callback.call1(py, (100,))?;
+                    return Ok(img);
+                }
+                let target = resize::scale_to(img.width(), img.height(), width, height);
+                apply_resize(py, img, target, filter, color_space, progress_callback)
+            }
+            Self::Thumbnail { size, filter, only_if_larger } => {
+                let target = if only_if_larger {
+                    resize::fit_within(img.width(), img.height(), size, size)
+                } else {
+                    resize::scale_to_fit(img.width(), img.height(), size, size)
+                };
+                apply_resize(py, img, target, filter, color_space, progress_callback)
+            }
+            Self::MaxMegapixels { megapixels, filter } => {
+                let max_pixels = megapixels as f64 * 1e6;
+                let target = resize::fit_pixels(img.width(), img.height(), max_pixels);
+                apply_resize(py, img, target, filter, color_space, progress_callback)
+            }
+            Self::ClipWarning { low_color, high_color, dim } => {
+                apply_clip_warning(py, img, (low_color, high_color), dim, progress_callback)
+            }
+            Self::NormalizeExposure { target_mean, gamma } => {
+                apply_normalize_exposure(py, img, target_mean, gamma, progress_callback)
+            }
+            Self::Curve { ref luts } => apply_curve(py, img, luts, progress_callback),
+            Self::Deskew { max_angle, fill, interpolation } => {
+                let angle = py.allow_threads(|| analysis::skew_angle(&img, max_angle));
+                record(|findings| findings.skew_angle = Some(angle));
+                apply_rotate(py, img, -angle, fill, interpolation, false, progress_callback)
+            }
+            Self::SaliencyCrop { aspect_ratio, center_bias } => {
+                let rect = analysis::saliency_window(&img, aspect_ratio, center_bias);
+                apply_crop(py, img, rect, progress_callback)
+            }
+            Self::CropAspect { aspect_ratio } => {
+                let (width, height) =
+                    resize::largest_with_aspect(img.width(), img.height(), aspect_ratio);
+                let (x, y) = ((img.width() - width) / 2, (img.height() - height) / 2);
+                apply_crop(py, img, (x, y, width, height), progress_callback)
+            }
+            Self::WaveletDenoise { threshold, levels, chroma } => {
+                apply_wavelet_denoise(py, img, threshold, levels, chroma, progress_callback)
+            }
+            Self::Clahe { tiles, clip_limit } => {
+                apply_clahe(py, img, tiles, clip_limit, progress_callback)
+            }
+            Self::Tonemap { operator, exposure } => {
+                apply_tonemap(py, img, operator, exposure, progress_callback)
+            }
+            Self::FixChromaticAberration { r_scale, b_scale } => {
+                apply_fix_chromatic_aberration(py, img, r_scale, b_scale, progress_callback)
+            }
+            Self::Difference { ref reference, amplify } => {
+                let reference = py.allow_threads(|| reference.get(decode::open))?;
+                apply_difference(py, img, reference, amplify, progress_callback)
+            }
+            Self::SelectiveColor { hue, tolerance, softness } => {
+                apply_selective_color(py, img, hue, tolerance, softness, progress_callback)
+            }
+            Self::Overlay { ref overlay, x, y, opacity, mode } => {
+                let load = |path: &str| Ok(decode::open(path)?.to_rgba32f());
+                let overlay = py.allow_threads(|| overlay.get(load))?;
+                apply_overlay(py, img, overlay, (x, y), opacity, mode, progress_callback)
+            }
+            Self::Draw { ref shape, color } => apply_draw(py, img, shape, color, progress_callback),
+            Self::Brightness { amount } => apply_tone(py, img, &|v| v + amount, progress_callback),
+            Self::Contrast { factor } => {
+                apply_tone(py, img, &|v| (v - 0.5) * factor + 0.5, progress_callback)
+            }
+            Self::Gamma { gamma } => {
+                apply_tone(py, img, &|v| v.powf(1.0 / gamma), progress_callback)
+            }
+            Self::Exposure { stops } => {
+                let gain = stops.exp2();
+                let expose = |v: f32| color::encode_srgb_unit(color::decode_srgb(v) * gain);
+                apply_tone(py, img, &expose, progress_callback)
+            }
+            Self::Levels { black, white, gamma } => {
+                let (black, range) = (black as f32 / 255.0, (white - black) as f32 / 255.0);
+                let levels = |v: f32| ((v - black) / range).clamp(0.0, 1.0).powf(1.0 / gamma);
+                apply_tone(py, img, &levels, progress_callback)
+            }
+            Self::EqualizeHistogram => apply_equalize_histogram(py, img, progress_callback),
+            Self::Convolve { ref kernel, divisor, offset } => {
+                apply_convolve(py, img, kernel, divisor, offset, color_space, progress_callback)
+            }
+            Self::AutoOrient { orientation } => {
+                let output = exif::apply_orientation(img, orientation.unwrap_or(1));
+                progress_callback.call1(py, (100,))?;
+                Ok(output)
+            }
+            Self::Auto => {
+                let (name, effect) = py.allow_threads(|| auto::choose(&img));
+                record(|findings| findings.auto_effect = Some(name));
+                effect.apply(py, img, color_space, progress_callback)
+            }
+        }
+    }
+}
+
+/// Canny edges, drawn black on white or, without `invert_output`, white on
+/// black. `thresholds` are Canny's low and high gradient strengths.
+fn apply_edge_detection(
+    py: Python,
+    image: DynamicImage,
+    invert_output: bool,
+    smoothing: EdgeSmoothing,
+    thresholds: (f32, f32),
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    // Canny runs as a single call, so it is reported as the first half of the
+    // job and the per-pixel conversion below as the second.
+    progress_callback.call1(py, (0,))?;
+
+    let gray_image = image.to_luma8();
+    let gray_image = match smoothing {
+        EdgeSmoothing::None => gray_image,
+        EdgeSmoothing::Gaussian { sigma } => {
+            imageproc::filter::gaussian_blur_f32(&gray_image, sigma)
+        }
+        EdgeSmoothing::Bilateral { sigma, sigma_color } => {
+            // Weights beyond three sigmas are negligible.
+            let window = 2 * (3.0 * sigma).ceil() as u32 + 1;
+            imageproc::filter::bilateral_filter(&gray_image, window, sigma_color, sigma)
+        }
+    };
+    
+    // Apply Canny edge detection
+    let edges = canny(&gray_image, thresholds.0, thresholds.1);
+    progress_callback.call1(py, (50,))?;
+
+    // Convert to RGB for better visibility
+    let mut rgb_image = DynamicImage::ImageLuma8(edges).to_rgb8();
+    let row_len = rgb_image.width() as usize * 3;
+    let conversion = ScaledProgress::new(progress_callback.clone_ref(py), 50.0, 50.0);
+    let conversion = Py::new(py, conversion)?.into_py(py);
+    progress::par_rows(py, &mut rgb_image, row_len, &conversion, |row| {
+        if invert_output {
+            row.iter_mut().for_each(|v| *v = 255 - *v);
+        }
+    })?;
+
+    Ok(DynamicImage::ImageRgb8(rgb_image))
+}
+
+/// Gaussian blur, split into row bands that run in parallel without the GIL
+/// and a strip of bands at a time, reporting progress after each strip.
+///
+/// 16-bit input stays 16-bit in sRGB. Alpha is kept and blurred along with
+/// the color, which is weighted by it so that the color of fully transparent
+/// pixels doesn't bleed into their neighbours.
+fn apply_blur(
+    py: Python,
+    image: DynamicImage,
+    sigma: f32,
+    color_space: ColorSpace,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let image = promote_gray(image);
+    match color_space {
+        ColorSpace::Srgb => blur_strips(py, image, sigma, progress_callback),
+        ColorSpace::Linear => {
+            let linear = py.allow_threads(|| color::to_linear(&image));
+            let blurred = blur_strips(py, linear, sigma, progress_callback)?;
+            Ok(py.allow_threads(|| color::from_linear(&blurred)))
+        }
+    }
+}
+
+/// The strip-wise gaussian of [`apply_blur`] on an RGB(A) image of any depth,
+/// keeping its pixel format. Images with alpha are blurred premultiplied.
+fn blur_strips(
+    py: Python,
+    image: DynamicImage,
+    sigma: f32,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let overlap = tiles::gaussian_overlap(sigma);
+    macro_rules! blur {
+        ($buffer:expr) => {
+            tiles::filter_strips(
+                py,
+                $buffer,
+                overlap,
+                |band| imageproc::filter::gaussian_blur_f32(band, sigma),
+                progress_callback,
+            )?
+        };
+    }
+
+    let blurred = match image {
+        DynamicImage::ImageRgb8(buffer) => DynamicImage::ImageRgb8(blur!(&buffer)),
+        DynamicImage::ImageRgb16(buffer) => DynamicImage::ImageRgb16(blur!(&buffer)),
+        DynamicImage::ImageRgb32F(buffer) => DynamicImage::ImageRgb32F(blur!(&buffer)),
+        other => {
+            let color = other.color();
+            let mut rgba = other.into_rgba32f();
+            py.allow_threads(|| {
+                rgba.par_chunks_exact_mut(4).for_each(|p| {
+                    p[0] *= p[3];
+                    p[1] *= p[3];
+                    p[2] *= p[3];
+                })
+            });
+            let mut blurred = blur!(&rgba);
+            py.allow_threads(|| {
+                blurred.par_chunks_exact_mut(4).for_each(|p| {
+                    let scale = if p[3] > 0.0 { 1.0 / p[3] } else { 0.0 };
+                    p[0] = (p[0] * scale).min(1.0);
+                    p[1] = (p[1] * scale).min(1.0);
+                    p[2] = (p[2] * scale).min(1.0);
+                })
+            });
+            resize::to_color(DynamicImage::ImageRgba32F(blurred), color)
+        }
+    };
+    Ok(blurred)
+}
+
+/// Unsharp mask with a gaussian of `sigma`, banded and parallelized like
+/// [`apply_blur`]. Differences below `threshold` 8-bit steps are left alone.
+///
+/// The sharpened result is then mixed with the original by `amount`: 0 gives
+/// back the original, 1 the plain unsharp mask and larger values push the
+/// edges further.
+///
+/// With `luma_only`, only the brightness of color images is sharpened and the
+/// chroma is left as is, which avoids colored halos along high-contrast edges.
+#[allow(clippy::too_many_arguments)]
+fn apply_sharpen(
+    py: Python,
+    image: DynamicImage,
+    sigma: f32,
+    threshold: i32,
+    amount: f32,
+    luma_only: bool,
+    color_space: ColorSpace,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let overlap = tiles::gaussian_overlap(sigma);
+    let image = promote_gray(image);
+
+    let sharpened = py.allow_threads(|| {
+        // `scaled_threshold` is in the units of the buffer being sharpened.
+        let unsharpen = |img: &DynamicImage, scaled_threshold: i32| {
+            let sharpened = if luma_only && img.color().has_color() {
+                sharpen_luma(img, sigma, threshold, overlap)
+            } else {
+                tiles::filter_dynamic_bands!(img, overlap, |band| {
+                    imageops::unsharpen(band, sigma, scaled_threshold)
+                })
+            };
+            mix(img, sharpened, amount)
+        };
+        match color_space {
+            ColorSpace::Srgb => unsharpen(&image, threshold),
+            // The threshold is expressed in 8-bit steps, so scale it to the 16-bit buffer.
+            ColorSpace::Linear => {
+                color::from_linear(&unsharpen(&color::to_linear(&image), threshold * 257))
+            }
+        }
+    });
+    progress_callback.call1(py, (100,))?;
+    Ok(sharpened)
+}
+
+/// Unsharp mask on the BT.601 luma of `image`, keeping chroma constant.
+///
+/// Shifting R, G and B by the same amount changes Y by that amount and leaves
+/// Cb and Cr untouched, so the sharpened luma is recombined by adding its
+/// difference to every color channel. `threshold` is in 8-bit steps whatever
+/// the depth of `image`.
+fn sharpen_luma(image: &DynamicImage, sigma: f32, threshold: i32, overlap: u32) -> DynamicImage {
+    let rgba = image.to_rgba32f();
+    let (width, height) = rgba.dimensions();
+    let luma_of = |p: &Rgba<f32>| 0.299 * p[0] + 0.587 * p[1] + 0.114 * p[2];
+
+    let luma: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::from_fn(width, height, |x, y| {
+        Luma([(luma_of(rgba.get_pixel(x, y)).clamp(0.0, 1.0) * 65535.0).round() as u16])
+    });
+    let sharpened = tiles::filter_bands(&luma, overlap, |band| {
+        imageops::unsharpen(band, sigma, threshold * 257)
+    });
+
+    let combined = ImageBuffer::from_fn(width, height, |x, y| {
+        let p = rgba.get_pixel(x, y);
+        let delta = sharpened.get_pixel(x, y)[0] as f32 / 65535.0 - luma_of(p);
+        Rgba([
+            (p[0] + delta).clamp(0.0, 1.0),
+            (p[1] + delta).clamp(0.0, 1.0),
+            (p[2] + delta).clamp(0.0, 1.0),
+            p[3],
+        ])
+    });
+    let combined = DynamicImage::ImageRgba32F(combined);
+
+    let color = image.color();
+    let eight_bit = color.bytes_per_pixel() == color.channel_count();
+    match (color.has_alpha(), eight_bit) {
+        (true, true) => DynamicImage::ImageRgba8(combined.to_rgba8()),
+        (false, true) => DynamicImage::ImageRgb8(combined.to_rgb8()),
+        (true, false) => DynamicImage::ImageRgba16(combined.to_rgba16()),
+        (false, false) => DynamicImage::ImageRgb16(combined.to_rgb16()),
+    }
+}
+
+/// Move `original` toward `target` by `amount`, which may overshoot past 1.
+/// The result has `target`'s pixel format.
+fn mix(original: &DynamicImage, target: DynamicImage, amount: f32) -> DynamicImage {
+    if amount == 1.0 {
+        return target;
+    }
+    let color = target.color();
+    let original = original.to_rgba32f();
+    let mut mixed = target.into_rgba32f();
+    mixed.par_iter_mut().zip(original.par_iter()).for_each(|(m, &o)| {
+        *m = (o + amount * (*m - o)).clamp(0.0, 1.0);
+    });
+    resize::to_color(DynamicImage::ImageRgba32F(mixed), color)
+}
+
+/// Expand a grayscale image to RGB(A) of the same bit depth; see [`ImageEffect`].
+fn promote_gray(image: DynamicImage) -> DynamicImage {
+    match image {
+        DynamicImage::ImageLuma8(_) => DynamicImage::ImageRgb8(image.to_rgb8()),
+        DynamicImage::ImageLumaA8(_) => DynamicImage::ImageRgba8(image.to_rgba8()),
+        DynamicImage::ImageLuma16(_) => DynamicImage::ImageRgb16(image.to_rgb16()),
+        DynamicImage::ImageLumaA16(_) => DynamicImage::ImageRgba16(image.to_rgba16()),
+        other => other,
+    }
+}
+
+fn apply_grayscale(py: Python, image: DynamicImage, progress_callback: &PyObject) -> PyResult<DynamicImage> {
+    let grayscale = image.grayscale();
+    progress_callback.call1(py, (100,))?;
+    Ok(grayscale)
+}
+
+/// Run `$work::<T, CHANNELS>(row)`, generic over the sample type and with
+/// the channel count known at compile time so the per-pixel loop can be
+/// unrolled and vectorized, on every row of an [`integer_rgb`] image with
+/// [`progress::par_rows`], and return the image.
+macro_rules! map_rows {
+    ($py:expr, $image:expr, $progress_callback:expr, $work:ident) => {{
+        let mut image = $image;
+        let row_len = image.width() as usize * image.color().channel_count() as usize;
+        match &mut image {
+            DynamicImage::ImageRgb8(buffer) => {
+                progress::par_rows($py, buffer, row_len, $progress_callback, $work::<_, 3>)?
+            }
+            DynamicImage::ImageRgba8(buffer) => {
+                progress::par_rows($py, buffer, row_len, $progress_callback, $work::<_, 4>)?
+            }
+            DynamicImage::ImageRgb16(buffer) => {
+                progress::par_rows($py, buffer, row_len, $progress_callback, $work::<_, 3>)?
+            }
+            DynamicImage::ImageRgba16(buffer) => {
+                progress::par_rows($py, buffer, row_len, $progress_callback, $work::<_, 4>)?
+            }
+            _ => unreachable!("integer_rgb only returns 8 and 16-bit RGB(A)"),
+        }
+        Ok(image)
+    }};
+}
+
+/// Sepia tone. Alpha is left untouched and 16-bit input stays 16-bit.
+fn apply_sepia(py: Python, image: DynamicImage, progress_callback: &PyObject) -> PyResult<DynamicImage> {
+    fn sepia<T: Primitive + Into<f32> + Clamp<f32>, const CHANNELS: usize>(row: &mut [T]) {
+        for pixel in row.chunks_exact_mut(CHANNELS) {
+            let r: f32 = pixel[0].into();
+            let g: f32 = pixel[1].into();
+            let b: f32 = pixel[2].into();
+
+            pixel[0] = T::clamp(0.393 * r + 0.769 * g + 0.189 * b);
+            pixel[1] = T::clamp(0.349 * r + 0.686 * g + 0.168 * b);
+            pixel[2] = T::clamp(0.272 * r + 0.534 * g + 0.131 * b);
+        }
+    }
+    map_rows!(py, integer_rgb(image), progress_callback, sepia)
+}
+
+/// Invert the color channels. Alpha, when present, is left untouched so that
+/// transparent regions stay transparent; 16-bit input stays 16-bit.
+fn apply_invert(py: Python, image: DynamicImage, progress_callback: &PyObject) -> PyResult<DynamicImage> {
+    fn invert<T: Primitive, const CHANNELS: usize>(row: &mut [T]) {
+        if CHANNELS == 3 {
+            // Without alpha every sample is inverted, as one flat loop.
+            row.iter_mut().for_each(|v| *v = T::DEFAULT_MAX_VALUE - *v);
+            return;
+        }
+        for pixel in row.chunks_exact_mut(CHANNELS) {
+            pixel[0] = T::DEFAULT_MAX_VALUE - pixel[0];
+            pixel[1] = T::DEFAULT_MAX_VALUE - pixel[1];
+            pixel[2] = T::DEFAULT_MAX_VALUE - pixel[2];
+        }
+    }
+    map_rows!(py, integer_rgb(image), progress_callback, invert)
+}
+
+/// `image` in one of the layouts the per-pixel effects work on: 8 or 16-bit
+/// RGB, with alpha where it has some. Gray is promoted and float images are
+/// brought to 16 bits.
+fn integer_rgb(image: DynamicImage) -> DynamicImage {
+    match promote_gray(image) {
+        image @ (DynamicImage::ImageRgb8(_)
+        | DynamicImage::ImageRgba8(_)
+        | DynamicImage::ImageRgb16(_)
+        | DynamicImage::ImageRgba16(_)) => image,
+        other if other.color().has_alpha() => DynamicImage::ImageRgba16(other.to_rgba16()),
+        other => DynamicImage::ImageRgb16(other.to_rgb16()),
+    }
+}
+
+/// Pass the image through untouched, leaving only the decode/encode round trip.
+///
+/// Useful for format conversion (the output format follows the output path)
+/// and for exercising the I/O path in tests.
+fn apply_identity(
+    py: Python,
+    image: DynamicImage,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    progress_callback.call1(py, (100,))?;
+    Ok(image)
+}
+
+/// Stretch R, G and B independently so that the darkest and brightest
+/// `clip_percent` of each channel map to 0 and 255.
+///
+/// Because every channel ends up spanning the full range, a cast that lifts
+/// one channel relative to the others (indoor tungsten light, for example)
+/// is neutralized.
+fn apply_auto_white_balance(
+    py: Python,
+    image: DynamicImage,
+    clip_percent: f32,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let mut rgb = image.to_rgb8();
+
+    let mut hist = [[0u64; 256]; 3];
+    for pixel in rgb.pixels() {
+        for c in 0..3 {
+            hist[c][pixel[c] as usize] += 1;
+        }
+    }
+
+    let total = rgb.width() as u64 * rgb.height() as u64;
+    let clip = (total as f64 * clip_percent as f64 / 100.0) as u64;
+    let luts = hist.map(|h| {
+        let (low, high) = percentile_bounds(&h, clip);
+        stretch_lut(low, high)
+    });
+
+    let row_len = rgb.width() as usize * 3;
+    progress::par_rows(py, &mut rgb, row_len, progress_callback, |row| {
+        for pixel in row.chunks_exact_mut(3) {
+            for c in 0..3 {
+                pixel[c] = luts[c][pixel[c] as usize];
+            }
+        }
+    })?;
+    Ok(DynamicImage::ImageRgb8(rgb))
+}
+
+/// Lowest and highest values left after discarding `clip` samples from each end.
+fn percentile_bounds(hist: &[u64; 256], clip: u64) -> (u8, u8) {
+    let mut seen = 0;
+    let mut low = 0;
+    for (value, &count) in hist.iter().enumerate() {
+        seen += count;
+        if seen > clip {
+            low = value;
+            break;
+        }
+    }
+
+    seen = 0;
+    let mut high = 255;
+    for (value, &count) in hist.iter().enumerate().rev() {
+        seen += count;
+        if seen > clip {
+            high = value;
+            break;
+        }
+    }
+
+    (low as u8, high as u8)
+}
+
+/// Lookup table mapping `[low, high]` linearly onto `[0, 255]`.
+fn stretch_lut(low: u8, high: u8) -> [u8; 256] {
+    let mut lut = [0u8; 256];
+    if high <= low {
+        for (i, v) in lut.iter_mut().enumerate() {
+            *v = i as u8;
+        }
+        return lut;
+    }
+
+    let scale = 255.0 / (high - low) as f32;
+    for (i, v) in lut.iter_mut().enumerate() {
+        *v = ((i as f32 - low as f32) * scale).round().clamp(0.0, 255.0) as u8;
+    }
+    lut
+}
+
+/// Cut out the `(x, y, width, height)` rectangle, raising `GeometryError` if it
+/// doesn't fit inside the image.
+fn apply_crop(
+    py: Python,
+    image: DynamicImage,
+    rect: (u32, u32, u32, u32),
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    errors::check_region(py, rect, (image.width(), image.height()))?;
+    let (x, y, width, height) = rect;
+    let cropped = image.crop_imm(x, y, width, height);
+    progress_callback.call1(py, (100,))?;
+    Ok(cropped)
+}
+
+/// Reduce the image to `k` colors chosen by k-means clustering in RGB space.
+fn apply_kmeans_quantize(
+    py: Python,
+    image: DynamicImage,
+    k: u32,
+    max_iterations: u32,
+    seed: u64,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let mut rgb = image.to_rgb8();
+    let pixels: Vec<[u8; 3]> = rgb.pixels().map(|p| p.0).collect();
+
+    let centroids = quantize::kmeans(&pixels, k as usize, max_iterations, seed);
+    progress_callback.call1(py, (50,))?;
+
+    quantize::apply_palette(&mut rgb, &centroids);
+    progress_callback.call1(py, (100,))?;
+    Ok(DynamicImage::ImageRgb8(rgb))
+}
+
+/// Make pixels close to `key` transparent, producing an RGBA image.
+///
+/// Pixels within `tolerance` (Euclidean distance in RGB) of the key color get
+/// alpha 0. With a non-zero `softness`, alpha then ramps linearly back up over
+/// the next `softness` units of distance, which feathers the matte's edges.
+/// Existing transparency is never reduced.
+fn apply_chroma_key(
+    py: Python,
+    image: DynamicImage,
+    key: [u8; 3],
+    tolerance: f32,
+    softness: f32,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let mut rgba = image.to_rgba8();
+
+    let row_len = rgba.width() as usize * 4;
+    progress::par_rows(py, &mut rgba, row_len, progress_callback, |row| {
+        for pixel in row.chunks_exact_mut(4) {
+            let dr = pixel[0] as f32 - key[0] as f32;
+            let dg = pixel[1] as f32 - key[1] as f32;
+            let db = pixel[2] as f32 - key[2] as f32;
+            let distance = (dr * dr + dg * dg + db * db).sqrt();
+
+            let coverage = if distance <= tolerance {
+                0.0
+            } else if distance < tolerance + softness {
+                (distance - tolerance) / softness
+            } else {
+                1.0
+            };
+            pixel[3] = (pixel[3] as f32 * coverage).round() as u8;
+        }
+    })?;
+    Ok(DynamicImage::ImageRgba8(rgba))
+}
+
+/// Posterize to `levels` values per channel and draw Canny edges on top in black.
+fn apply_comic(
+    py: Python,
+    image: DynamicImage,
+    levels: u32,
+    thresholds: (f32, f32),
+    edge_thickness: u32,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let mut edges = canny(&image.to_luma8(), thresholds.0, thresholds.1);
+    if edge_thickness > 1 {
+        edges = imageproc::morphology::dilate(&edges, Norm::LInf, (edge_thickness - 1) as u8);
+    }
+    progress_callback.call1(py, (50,))?;
+
+    let step = 255.0 / (levels - 1) as f32;
+    let mut lut = [0u8; 256];
+    for (i, v) in lut.iter_mut().enumerate() {
+        *v = ((i as f32 / step).round() * step).round() as u8;
+    }
+
+    let mut rgb = image.to_rgb8();
+    for (pixel, edge) in rgb.pixels_mut().zip(edges.pixels()) {
+        if edge[0] > 0 {
+            *pixel = Rgb([0, 0, 0]);
+        } else {
+            *pixel = Rgb(pixel.0.map(|c| lut[c as usize]));
+        }
+    }
+
+    progress_callback.call1(py, (100,))?;
+    Ok(DynamicImage::ImageRgb8(rgb))
+}
+
+/// Crop away a uniform border of `color`, working inward from each edge.
+///
+/// A row or column belongs to the border when every pixel in it is within
+/// `tolerance` of `color` on each channel. An image that is border all the
+/// way through is returned unchanged.
+fn apply_autocrop(
+    py: Python,
+    image: DynamicImage,
+    color: [u8; 3],
+    tolerance: u8,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    apply_trim(py, image, Some(color), tolerance, Sides::ALL, progress_callback)
+}
+
+/// Like [`apply_autocrop`], but only the enabled `sides` are trimmed, and
+/// without a `color` the background is taken from the top-left pixel.
+fn apply_trim(
+    py: Python,
+    image: DynamicImage,
+    color: Option<[u8; 3]>,
+    tolerance: u8,
+    sides: Sides,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let rgb = image.to_rgb8();
+    let (width, height) = rgb.dimensions();
+    if width == 0 || height == 0 {
+        progress_callback.call1(py, (100,))?;
+        return Ok(image);
+    }
+    let color = color.unwrap_or(rgb.get_pixel(0, 0).0);
+    let is_border = |x: u32, y: u32| {
+        let Rgb(p) = *rgb.get_pixel(x, y);
+        (0..3).all(|c| p[c].abs_diff(color[c]) <= tolerance)
+    };
+    let row_is_border = |y: u32| (0..width).all(|x| is_border(x, y));
+
+    let content_top = (0..height).find(|&y| !row_is_border(y));
+    let content_top = match content_top {
+        Some(top) => top,
+        None => {
+            progress_callback.call1(py, (100,))?;
+            return Ok(image);
+        }
+    };
+    let top = if sides.top { content_top } else { 0 };
+    let bottom = if sides.bottom {
+        (top..height).rev().find(|&y| !row_is_border(y)).unwrap_or(top)
+    } else {
+        height - 1
+    };
+    let column_is_border = |x: u32| (top..=bottom).all(|y| is_border(x, y));
+    let left = if sides.left { (0..width).find(|&x| !column_is_border(x)).unwrap_or(0) } else { 0 };
+    let right = if sides.right {
+        (left..width).rev().find(|&x| !column_is_border(x)).unwrap_or(left)
+    } else {
+        width - 1
+    };
+
+    let cropped = image.crop_imm(left, top, right - left + 1, bottom - top + 1);
+    progress_callback.call1(py, (100,))?;
+    Ok(cropped)
+}
+
+/// Sepia toning, a darkened vignette and fine film grain in a single pass.
+///
+/// `strength` scales all three together: 0 leaves the image unchanged and 1
+/// gives the full look. The grain pattern is fixed per pixel position, so the
+/// output is deterministic.
+fn apply_vintage(
+    py: Python,
+    image: DynamicImage,
+    strength: f32,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    const VIGNETTE_DEPTH: f32 = 0.4;
+    const GRAIN_AMPLITUDE: f32 = 18.0;
+
+    let mut rgb = image.to_rgb8();
+    let (width, height) = rgb.dimensions();
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+    let max_distance = (cx * cx + cy * cy).sqrt().max(1.0);
+
+    let row_len = (width as usize * 3).max(1);
+    py.allow_threads(|| {
+        rgb.par_chunks_exact_mut(row_len).enumerate().for_each(|(y, row)| {
+            for (x, pixel) in row.chunks_exact_mut(3).enumerate() {
+                let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(|c| c as f32);
+                let sepia = [
+                    0.393 * r + 0.769 * g + 0.189 * b,
+                    0.349 * r + 0.686 * g + 0.168 * b,
+                    0.272 * r + 0.534 * g + 0.131 * b,
+                ];
+
+                let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+                let d = (dx * dx + dy * dy).sqrt() / max_distance;
+                let vignette = 1.0 - strength * VIGNETTE_DEPTH * d * d * (3.0 - 2.0 * d);
+
+                let index = y as u64 * width as u64 + x as u64;
+                let noise = SplitMix64::new(index).next_f64() as f32 - 0.5;
+                let grain = noise * strength * GRAIN_AMPLITUDE;
+
+                for (c, value) in [r, g, b].into_iter().enumerate() {
+                    let toned = value + (sepia[c] - value) * strength;
+                    pixel[c] = (toned * vignette + grain).round().clamp(0.0, 255.0) as u8;
+                }
+            }
+        })
+    });
+
+    progress_callback.call1(py, (100,))?;
+    Ok(DynamicImage::ImageRgb8(rgb))
+}
+
+/// Scale the V channel of HSV by `factor`, keeping hue and saturation.
+///
+/// Scaling V is the same as scaling all three channels by the same amount,
+/// so that is done directly; when the brightest channel would clip, the
+/// scale is capped there instead, which holds the hue and saturation at the
+/// cost of the pixel brightening less than requested. A soft `gamut` eases V
+/// towards 255 instead of stopping at it. Alpha is preserved.
+fn apply_brightness_hsv(
+    py: Python,
+    image: DynamicImage,
+    factor: f32,
+    gamut: GamutMapping,
+    dither: bool,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let output = adjust_rgb(image, dither, |rgb| {
+        let value = rgb[0].max(rgb[1]).max(rgb[2]);
+        if value == 0.0 {
+            return rgb;
+        }
+        let scale = gamut.apply(value * factor, 255.0) / value;
+        rgb.map(|c| c * scale)
+    });
+
+    progress_callback.call1(py, (100,))?;
+    Ok(output)
+}
+
+/// Turn every hue by `degrees` around the HSV color wheel, leaving saturation
+/// and value alone.
+fn apply_hue_rotate(
+    py: Python,
+    image: DynamicImage,
+    degrees: f32,
+    dither: bool,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let output = adjust_rgb(image, dither, |rgb| {
+        let [hue, saturation, value] = color::rgb_to_hsv(rgb);
+        color::hsv_to_rgb([hue + degrees, saturation, value])
+    });
+
+    progress_callback.call1(py, (100,))?;
+    Ok(output)
+}
+
+/// Multiply HSV saturation by `factor`, bringing it back to at most fully
+/// saturated as `gamut` says.
+fn apply_saturation(
+    py: Python,
+    image: DynamicImage,
+    factor: f32,
+    gamut: GamutMapping,
+    dither: bool,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let output = adjust_rgb(image, dither, |rgb| {
+        let [hue, saturation, value] = color::rgb_to_hsv(rgb);
+        color::hsv_to_rgb([hue, gamut.apply(saturation * factor, 1.0), value])
+    });
+
+    progress_callback.call1(py, (100,))?;
+    Ok(output)
+}
+
+/// Run `adjust` on the color channels of every pixel as 0-255 floats and
+/// round the result back to 8 bits, keeping alpha.
+///
+/// With `dither`, triangular noise of up to one level either way is added
+/// before rounding, so a smooth gradient that the adjustment stretched comes
+/// out as fine grain instead of visible bands. The noise is seeded by pixel
+/// position, so the output is the same on every run.
+fn adjust_rgb(
+    image: DynamicImage,
+    dither: bool,
+    adjust: impl Fn([f32; 3]) -> [f32; 3],
+) -> DynamicImage {
+    let apply = |(index, pixel): (usize, &mut [u8])| {
+        let rgb = adjust([pixel[0] as f32, pixel[1] as f32, pixel[2] as f32]);
+        let mut rng = SplitMix64::new(index as u64);
+        for (c, value) in pixel[..3].iter_mut().zip(rgb) {
+            let noise = if dither { (rng.next_f64() + rng.next_f64() - 1.0) as f32 } else { 0.0 };
+            *c = (value + noise).round().clamp(0.0, 255.0) as u8;
+        }
+    };
+
+    if image.color().has_alpha() {
+        let mut rgba = image.to_rgba8();
+        rgba.chunks_exact_mut(4).enumerate().for_each(apply);
+        DynamicImage::ImageRgba8(rgba)
+    } else {
+        let mut rgb = image.to_rgb8();
+        rgb.chunks_exact_mut(3).enumerate().for_each(apply);
+        DynamicImage::ImageRgb8(rgb)
+    }
+}
+
+/// Rotate clockwise by `angle` degrees about the center, keeping the canvas
+/// size. Corners uncovered by the rotation are painted with `fill`.
+///
+/// With `expand`, the canvas instead grows to hold the whole rotated image.
+/// Quarter turns are then exact: pixels are only moved, never resampled, and
+/// the input's pixel layout is kept.
+///
+/// Otherwise the result is RGBA when the source has alpha or `fill` is not
+/// fully opaque, so a transparent fill stays transparent; else it is RGB.
+#[allow(clippy::too_many_arguments)]
+fn apply_rotate(
+    py: Python,
+    image: DynamicImage,
+    angle: f32,
+    fill: [u8; 4],
+    interpolation: Interpolation,
+    expand: bool,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    if expand && angle % 90.0 == 0.0 {
+        let output = match angle.rem_euclid(360.0) as u32 {
+            90 => image.rotate90(),
+            180 => image.rotate180(),
+            270 => image.rotate270(),
+            _ => image,
+        };
+        progress_callback.call1(py, (100,))?;
+        return Ok(output);
+    }
+
+    let keep_alpha = image.color().has_alpha() || fill[3] < 255;
+    let source = if expand {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let (width, height) = (image.width() as f32, image.height() as f32);
+        // Shave off float noise so that e.g. a 45 degree turn of a square
+        // doesn't gain a stray row.
+        let fit = |size: f32| (size - 1e-3).ceil().max(1.0) as u32;
+        let canvas_width = fit(width * cos.abs() + height * sin.abs());
+        let canvas_height = fit(width * sin.abs() + height * cos.abs());
+        let mut canvas = RgbaImage::from_pixel(canvas_width, canvas_height, Rgba(fill));
+        let x = (canvas_width as i64 - image.width() as i64) / 2;
+        let y = (canvas_height as i64 - image.height() as i64) / 2;
+        imageops::replace(&mut canvas, &image.to_rgba8(), x, y);
+        canvas
+    } else {
+        image.to_rgba8()
+    };
+    let rotated = rotate_about_center(&source, angle.to_radians(), interpolation, Rgba(fill));
+
+    let output = DynamicImage::ImageRgba8(rotated);
+    let output = if keep_alpha { output } else { DynamicImage::ImageRgb8(output.to_rgb8()) };
+    progress_callback.call1(py, (100,))?;
+    Ok(output)
+}
+
+/// Sharpen by adding `amount` times the negated 4-neighbour Laplacian.
+///
+/// The filter runs in floating point and each channel is rounded and clamped
+/// once at the end, so strong amounts saturate instead of wrapping. Alpha,
+/// when present, is carried over unfiltered.
+fn apply_laplacian_sharpen(
+    py: Python,
+    image: DynamicImage,
+    amount: f32,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let a = amount;
+    let kernel = [
+        0.0, -a, 0.0,
+        -a, 1.0 + 4.0 * a, -a,
+        0.0, -a, 0.0,
+    ];
+    let filtered = py.allow_threads(|| filter3x3::<_, f32, f32>(&image.to_rgb8(), &kernel));
+    let channel = |v: f32| v.round().clamp(0.0, 255.0) as u8;
+
+    let output = if image.color().has_alpha() {
+        let mut rgba = image.to_rgba8();
+        for (pixel, sharp) in rgba.pixels_mut().zip(filtered.pixels()) {
+            let Rgb([r, g, b]) = *sharp;
+            *pixel = Rgba([channel(r), channel(g), channel(b), pixel[3]]);
+        }
+        DynamicImage::ImageRgba8(rgba)
+    } else {
+        let data = filtered.into_raw().into_iter().map(channel).collect();
+        DynamicImage::ImageRgb8(ImageBuffer::from_raw(image.width(), image.height(), data).unwrap())
+    };
+
+    progress_callback.call1(py, (100,))?;
+    Ok(output)
+}
+
+/// Map the image's luma through `colormap`. Alpha, when present, is kept.
+fn apply_colorize(
+    py: Python,
+    image: DynamicImage,
+    colormap: Colormap,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let lut = colormap.lut();
+    let output = if image.color().has_alpha() {
+        let luma = image.to_luma_alpha8();
+        DynamicImage::ImageRgba8(ImageBuffer::from_fn(luma.width(), luma.height(), |x, y| {
+            let [l, a] = luma.get_pixel(x, y).0;
+            let [r, g, b] = lut[l as usize];
+            Rgba([r, g, b, a])
+        }))
+    } else {
+        let luma = image.to_luma8();
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(luma.width(), luma.height(), |x, y| {
+            Rgb(lut[luma.get_pixel(x, y)[0] as usize])
+        }))
+    };
+
+    progress_callback.call1(py, (100,))?;
+    Ok(output)
+}
+
+/// Content-aware shrink to `width` x `height` by repeatedly removing the
+/// lowest-energy seam, by gradient magnitude. A missing target keeps that
+/// dimension. Height is reduced by carving the transposed image.
+fn apply_seam_carve(
+    py: Python,
+    image: DynamicImage,
+    width: Option<u32>,
+    height: Option<u32>,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let width = width.unwrap_or(image.width());
+    let height = height.unwrap_or(image.height());
+    if width > image.width() {
+        return Err(params::invalid("width", "seam carving can only shrink the image"));
+    }
+    if height > image.height() {
+        return Err(params::invalid("height", "seam carving can only shrink the image"));
+    }
+
+    let total = (image.width() - width + image.height() - height).max(1);
+    let mut removed = 0;
+    let stop = progress::current_stop();
+    let report = |removed: u32| -> PyResult<()> {
+        stop.check()?;
+        if removed.is_multiple_of((total / 20).max(1)) {
+            progress_callback.call1(py, (100 * removed / total,))?;
+        }
+        Ok(())
+    };
+
+    macro_rules! carve {
+        ($buffer:expr) => {{
+            let mut buffer = $buffer;
+            while buffer.width() > width {
+                buffer = remove_vertical_seam(&buffer, &find_vertical_seam(&buffer));
+                removed += 1;
+                report(removed)?;
+            }
+            let mut buffer = imageops::rotate90(&buffer);
+            while buffer.width() > height {
+                buffer = remove_vertical_seam(&buffer, &find_vertical_seam(&buffer));
+                removed += 1;
+                report(removed)?;
+            }
+            imageops::rotate270(&buffer)
+        }};
+    }
+
+    let output = if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(carve!(image.to_rgba8()))
+    } else {
+        DynamicImage::ImageRgb8(carve!(image.to_rgb8()))
+    };
+
+    progress_callback.call1(py, (100,))?;
+    Ok(output)
+}
+
+/// Resample to exactly `size`, in linear light when `color_space` asks for it.
+/// An image that already has that size is passed through untouched.
+fn apply_resize(
+    py: Python,
+    image: DynamicImage,
+    size: (u32, u32),
+    filter: Filter,
+    color_space: ColorSpace,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    if size == (image.width(), image.height()) {
+        progress_callback.call1(py, (100,))?;
+        return Ok(image);
+    }
+    let (width, height) = size;
+    let resized = py.allow_threads(|| match color_space {
+        ColorSpace::Srgb => resize::resize(&image, width, height, filter),
+        ColorSpace::Linear => {
+            let linear = color::to_linear(&image);
+            color::from_linear(&resize::resize(&linear, width, height, filter))
+        }
+    });
+    progress_callback.call1(py, (100,))?;
+    Ok(resized)
+}
+
+/// False-color view of clipping: pixels with any channel at 255 are painted
+/// `colors.1`, those with any channel at 0 `colors.0`, and the rest dimmed by
+/// `dim` so the markers stand out. A pixel clipped both ways counts as high.
+fn apply_clip_warning(
+    py: Python,
+    image: DynamicImage,
+    colors: ([u8; 3], [u8; 3]),
+    dim: f32,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let (low_color, high_color) = colors;
+    let mut rgb = image.to_rgb8();
+    let row_len = rgb.width() as usize * 3;
+    progress::par_rows(py, &mut rgb, row_len, progress_callback, |row| {
+        for pixel in row.chunks_exact_mut(3) {
+            let channels = [pixel[0], pixel[1], pixel[2]];
+            let marked = if channels.contains(&255) {
+                high_color
+            } else if channels.contains(&0) {
+                low_color
+            } else {
+                channels.map(|c| (c as f32 * (1.0 - dim)).round() as u8)
+            };
+            pixel.copy_from_slice(&marked);
+        }
+    })?;
+    Ok(DynamicImage::ImageRgb8(rgb))
+}
+
+/// Adjust brightness so that the mean luma lands on `target_mean`.
+///
+/// The linear method multiplies every channel by one factor, clipping
+/// highlights when brightening; the `gamma` method instead bends the tone
+/// curve, keeping black and white fixed. Either way the factor or exponent is
+/// solved for, accounting for clipping, so the result hits the target as
+/// closely as 8-bit output allows. Alpha is preserved.
+fn apply_normalize_exposure(
+    py: Python,
+    image: DynamicImage,
+    target_mean: f32,
+    gamma: bool,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    // The Rec. 709 weights `image` uses when converting to luma.
+    const LUMA_WEIGHTS: [f64; 3] = [0.2126, 0.7152, 0.0722];
+
+    let mut hists = [[0u64; 256]; 3];
+    let rgb = image.to_rgb8();
+    for pixel in rgb.pixels() {
+        for c in 0..3 {
+            hists[c][pixel[c] as usize] += 1;
+        }
+    }
+    let total = (rgb.width() as f64 * rgb.height() as f64).max(1.0);
+    let curve = |k: f64| {
+        move |v: f64| {
+            let out = if gamma { 255.0 * (v / 255.0).powf(k) } else { v * k };
+            out.clamp(0.0, 255.0)
+        }
+    };
+    // Mean luma once `curve(k)` is applied to every channel. It rises with the
+    // linear factor and falls with the gamma exponent.
+    let mean_after = |k: f64| {
+        let f = curve(k);
+        (0..3)
+            .map(|c| {
+                let sum: f64 =
+                    hists[c].iter().enumerate().map(|(v, &n)| f(v as f64) * n as f64).sum();
+                LUMA_WEIGHTS[c] * sum / total
+            })
+            .sum::<f64>()
+    };
+
+    let (mut low, mut high) = (1e-3f64, 1e3f64);
+    for _ in 0..60 {
+        let mid = (low * high).sqrt();
+        if (mean_after(mid) < target_mean as f64) != gamma {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    let f = curve((low * high).sqrt());
+    let lut: [u8; 256] = std::array::from_fn(|v| f(v as f64).round() as u8);
+
+    if image.color().has_alpha() {
+        let mut rgba = image.to_rgba8();
+        let row_len = rgba.width() as usize * 4;
+        progress::par_rows(py, &mut rgba, row_len, progress_callback, |row| {
+            let pixels = row.chunks_exact_mut(4);
+            pixels.for_each(|p| p[..3].iter_mut().for_each(|c| *c = lut[*c as usize]));
+        })?;
+        Ok(DynamicImage::ImageRgba8(rgba))
+    } else {
+        let mut rgb = image.to_rgb8();
+        let row_len = rgb.width() as usize * 3;
+        progress::par_rows(py, &mut rgb, row_len, progress_callback, |row| {
+            row.iter_mut().for_each(|c| *c = lut[*c as usize]);
+        })?;
+        Ok(DynamicImage::ImageRgb8(rgb))
+    }
+}
+
+/// Map each color channel through its tone curve lookup table, keeping alpha.
+fn apply_curve(
+    py: Python,
+    image: DynamicImage,
+    luts: &[[u8; 256]; 3],
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let map = |pixel: &mut [u8]| {
+        for (c, lut) in luts.iter().enumerate() {
+            pixel[c] = lut[pixel[c] as usize];
+        }
+    };
+
+    if image.color().has_alpha() {
+        let mut rgba = image.to_rgba8();
+        let row_len = rgba.width() as usize * 4;
+        progress::par_rows(py, &mut rgba, row_len, progress_callback, |row| {
+            row.chunks_exact_mut(4).for_each(map)
+        })?;
+        Ok(DynamicImage::ImageRgba8(rgba))
+    } else {
+        let mut rgb = image.to_rgb8();
+        let row_len = rgb.width() as usize * 3;
+        progress::par_rows(py, &mut rgb, row_len, progress_callback, |row| {
+            row.chunks_exact_mut(3).for_each(map)
+        })?;
+        Ok(DynamicImage::ImageRgb8(rgb))
+    }
+}
+
+/// Wavelet denoise of the BT.601 luma and, with `chroma`, of the color
+/// difference channels too, where low-light photos carry much of their noise.
+///
+/// `threshold` is in 8-bit levels: differences between neighbouring pixels
+/// smaller than about that are treated as noise. Alpha is kept.
+fn apply_wavelet_denoise(
+    py: Python,
+    image: DynamicImage,
+    threshold: f32,
+    levels: u32,
+    chroma: bool,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let mut rgba = image.to_rgba8();
+    let (width, height) = (rgba.width() as usize, rgba.height() as usize);
+
+    let mut planes = [Vec::new(), Vec::new(), Vec::new()];
+    for p in rgba.pixels() {
+        let [r, g, b] = [p[0], p[1], p[2]].map(|c| c as f32);
+        planes[0].push(0.299 * r + 0.587 * g + 0.114 * b);
+        planes[1].push(-0.168_736 * r - 0.331_264 * g + 0.5 * b);
+        planes[2].push(0.5 * r - 0.418_688 * g - 0.081_312 * b);
+    }
+    let [luma, cb, cr] = planes;
+    let (luma, cb, cr) = py.allow_threads(|| {
+        let denoise = |plane: Vec<f32>| wavelet::denoise(&plane, width, height, threshold, levels);
+        let luma = denoise(luma);
+        if chroma {
+            (luma, denoise(cb), denoise(cr))
+        } else {
+            (luma, cb, cr)
+        }
+    });
+
+    for (i, p) in rgba.pixels_mut().enumerate() {
+        let (y, cb, cr) = (luma[i], cb[i], cr[i]);
+        let rgb = [y + 1.402 * cr, y - 0.344_136 * cb - 0.714_136 * cr, y + 1.772 * cb];
+        for (c, v) in rgb.into_iter().enumerate() {
+            p[c] = v.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+
+    let output = if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(rgba)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).to_rgb8())
+    };
+    progress_callback.call1(py, (100,))?;
+    Ok(output)
+}
+
+/// CLAHE (see [`clahe::equalize`]) on the BT.601 luma, keeping Cb and Cr.
+fn apply_clahe(
+    py: Python,
+    image: DynamicImage,
+    tiles: u32,
+    clip_limit: f32,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    equalize_luma(py, image, progress_callback, |plane, width, height| {
+        clahe::equalize(plane, width, height, tiles as usize, clip_limit)
+    })
+}
+
+/// Global histogram equalization of the BT.601 luma, keeping Cb and Cr.
+fn apply_equalize_histogram(
+    py: Python,
+    image: DynamicImage,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    equalize_luma(py, image, progress_callback, |plane, width, height| {
+        let gray = GrayImage::from_raw(width as u32, height as u32, plane.to_vec())
+            .expect("plane has one sample per pixel");
+        imageproc::contrast::equalize_histogram(&gray).into_raw()
+    })
+}
+
+/// Replace the BT.601 luma of `image` with `equalize(plane, width, height)`
+/// of its 8-bit plane, keeping Cb and Cr. Alpha, when present, is carried over.
+fn equalize_luma(
+    py: Python,
+    image: DynamicImage,
+    progress_callback: &PyObject,
+    equalize: impl FnOnce(&[u8], usize, usize) -> Vec<u8> + Send,
+) -> PyResult<DynamicImage> {
+    let mut rgba = image.to_rgba8();
+    let (width, height) = (rgba.width() as usize, rgba.height() as usize);
+
+    let luma_of = |p: &Rgba<u8>| 0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32;
+    let luma: Vec<f32> = rgba.pixels().map(luma_of).collect();
+    let plane: Vec<u8> = luma.iter().map(|&y| y.round() as u8).collect();
+    let equalized = py.allow_threads(|| equalize(&plane, width, height));
+
+    // Keeping Cb and Cr while replacing Y is the same as shifting R, G and B
+    // by the change in Y.
+    for ((p, &y), &new) in rgba.pixels_mut().zip(&luma).zip(&equalized) {
+        let delta = new as f32 - y;
+        for c in &mut p.0[..3] {
+            *c = (*c as f32 + delta).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+
+    let output = if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(rgba)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).to_rgb8())
+    };
+    progress_callback.call1(py, (100,))?;
+    Ok(output)
+}
+
+/// Compress a high-dynamic-range image to 8-bit sRGB with `operator`, after
+/// scaling it by `exposure` stops.
+///
+/// Floating-point images, as decoded from OpenEXR and Radiance HDR files, are
+/// taken to hold linear light with 1.0 as reference white; anything else is
+/// decoded from sRGB first. Alpha, when present, is carried over.
+fn apply_tonemap(
+    py: Python,
+    image: DynamicImage,
+    operator: ToneOperator,
+    exposure: f32,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let linear_input = matches!(image.color(), ColorType::Rgb32F | ColorType::Rgba32F);
+    let gain = exposure.exp2();
+    let has_alpha = image.color().has_alpha();
+    let source = image.into_rgba32f();
+
+    let mut rgba = RgbaImage::new(source.width(), source.height());
+    for (out, p) in rgba.pixels_mut().zip(source.pixels()) {
+        let linear = |c: f32| if linear_input { c.max(0.0) } else { color::decode_srgb(c) };
+        let rgb = [p[0], p[1], p[2]].map(|c| linear(c) * gain);
+        let mapped = match operator {
+            ToneOperator::Reinhard => {
+                let luminance = 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2];
+                rgb.map(|c| c / (1.0 + luminance))
+            }
+            ToneOperator::Aces => {
+                rgb.map(|x| (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14))
+            }
+        };
+        let [r, g, b] = mapped.map(color::encode_srgb);
+        *out = Rgba([r, g, b, (p[3] * 255.0).round().clamp(0.0, 255.0) as u8]);
+    }
+
+    let output = if has_alpha {
+        DynamicImage::ImageRgba8(rgba)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).to_rgb8())
+    };
+    progress_callback.call1(py, (100,))?;
+    Ok(output)
+}
+
+/// Re-register the red and blue planes with green to undo lateral chromatic
+/// aberration, which shows as colored fringes that widen toward the edges.
+///
+/// Each plane is scaled about the image center: a scale above 1 enlarges it
+/// and one below 1 shrinks it. A lens that renders red slightly larger than
+/// green, giving red fringes on the outer side of edges, is corrected with an
+/// `r_scale` just below 1; typical values are within half a percent of 1.
+/// Samples are interpolated bilinearly and the bit depth is kept.
+fn apply_fix_chromatic_aberration(
+    py: Python,
+    image: DynamicImage,
+    r_scale: f32,
+    b_scale: f32,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let image = promote_gray(image);
+    let source = image.to_rgba32f();
+    let (width, height) = source.dimensions();
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+
+    // Value of `channel` at the point that lands on pixel (x, y) once the
+    // plane is scaled by `scale`.
+    let sample = |x: u32, y: u32, channel: usize, scale: f32| {
+        let sx = (cx + (x as f32 + 0.5 - cx) / scale - 0.5).clamp(0.0, (width - 1) as f32);
+        let sy = (cy + (y as f32 + 0.5 - cy) / scale - 0.5).clamp(0.0, (height - 1) as f32);
+        let (x0, y0) = (sx as u32, sy as u32);
+        let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+        let (fx, fy) = (sx - x0 as f32, sy - y0 as f32);
+        let at = |x, y| source.get_pixel(x, y)[channel];
+        let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+        let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    };
+
+    let corrected = py.allow_threads(|| {
+        ImageBuffer::from_fn(width, height, |x, y| {
+            let p = source.get_pixel(x, y);
+            Rgba([sample(x, y, 0, r_scale), p[1], sample(x, y, 2, b_scale), p[3]])
+        })
+    });
+    let output = resize::to_color(DynamicImage::ImageRgba32F(corrected), image.color());
+    progress_callback.call1(py, (100,))?;
+    Ok(output)
+}
+
+/// Absolute per-channel difference from `reference`, times `amplify` so that
+/// small changes are visible. Alpha is kept from the input.
+fn apply_difference(
+    py: Python,
+    image: DynamicImage,
+    reference: &DynamicImage,
+    amplify: f32,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    if (reference.width(), reference.height()) != (image.width(), image.height()) {
+        return Err(PyValueError::new_err(format!(
+            "Reference image is {}x{} but the input is {}x{}",
+            reference.width(),
+            reference.height(),
+            image.width(),
+            image.height()
+        )));
+    }
+    let image = promote_gray(image);
+    let (source, reference) = (image.to_rgba32f(), reference.to_rgba32f());
+
+    let difference = py.allow_threads(|| {
+        let mut output = source.clone();
+        for (p, r) in output.pixels_mut().zip(reference.pixels()) {
+            for c in 0..3 {
+                p[c] = ((p[c] - r[c]).abs() * amplify).min(1.0);
+            }
+        }
+        output
+    });
+    let output = resize::to_color(DynamicImage::ImageRgba32F(difference), image.color());
+    progress_callback.call1(py, (100,))?;
+    Ok(output)
+}
+
+/// Grayscale except for colors within `tolerance` degrees of `hue`, which
+/// keep their color. Over the next `softness` degrees colors fade to gray
+/// gradually, so the kept areas don't end in hard edges.
+fn apply_selective_color(
+    py: Python,
+    image: DynamicImage,
+    hue: f32,
+    tolerance: f32,
+    softness: f32,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let output = py.allow_threads(|| {
+        adjust_rgb(image, false, |rgb| {
+            let distance = (color::rgb_to_hsv(rgb)[0] - hue).rem_euclid(360.0);
+            let distance = distance.min(360.0 - distance);
+            let keep = if distance <= tolerance {
+                1.0
+            } else if distance < tolerance + softness {
+                1.0 - (distance - tolerance) / softness
+            } else {
+                0.0
+            };
+            let gray = 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2];
+            rgb.map(|v| gray + (v - gray) * keep)
+        })
+    });
+
+    progress_callback.call1(py, (100,))?;
+    Ok(output)
+}
+
+/// Mirror the image left to right, or top to bottom when `vertical`.
+fn apply_flip(
+    py: Python,
+    image: DynamicImage,
+    vertical: bool,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let flipped = if vertical { image.flipv() } else { image.fliph() };
+    progress_callback.call1(py, (100,))?;
+    Ok(flipped)
+}
+
+/// Map every color channel through `tone`, a curve on a 0-1 scale, with a
+/// lookup table at the image's depth. Results are clipped to the range, alpha
+/// is left untouched and 16-bit input stays 16-bit.
+fn apply_tone(
+    py: Python,
+    image: DynamicImage,
+    tone: &(dyn Fn(f32) -> f32 + Sync),
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let mut image = integer_rgb(image);
+    let channels = image.color().channel_count() as usize;
+    let row_len = image.width() as usize * channels;
+    match &mut image {
+        DynamicImage::ImageRgb8(buffer) => {
+            tone_rows(py, buffer, row_len, channels, tone, progress_callback)?
+        }
+        DynamicImage::ImageRgba8(buffer) => {
+            tone_rows(py, buffer, row_len, channels, tone, progress_callback)?
+        }
+        DynamicImage::ImageRgb16(buffer) => {
+            tone_rows(py, buffer, row_len, channels, tone, progress_callback)?
+        }
+        DynamicImage::ImageRgba16(buffer) => {
+            tone_rows(py, buffer, row_len, channels, tone, progress_callback)?
+        }
+        _ => unreachable!("integer_rgb only returns 8 and 16-bit RGB(A)"),
+    }
+    Ok(image)
+}
+
+fn tone_rows<T>(
+    py: Python,
+    data: &mut [T],
+    row_len: usize,
+    channels: usize,
+    tone: &(dyn Fn(f32) -> f32 + Sync),
+    progress_callback: &PyObject,
+) -> PyResult<()>
+where
+    T: Primitive + Into<f32> + Into<usize> + Clamp<f32> + Send + Sync,
+{
+    let max: f32 = T::DEFAULT_MAX_VALUE.into();
+    let lut: Vec<T> = (0..=max as usize)
+        .map(|v| T::clamp((tone(v as f32 / max).clamp(0.0, 1.0) * max).round()))
+        .collect();
+    progress::par_rows(py, data, row_len, progress_callback, |row| {
+        for pixel in row.chunks_exact_mut(channels) {
+            for c in &mut pixel[..3] {
+                *c = lut[Into::<usize>::into(*c)];
+            }
+        }
+    })
+}
+
+/// Composite `overlay` onto the image at `position`; see [`blend::composite`].
+fn apply_overlay(
+    py: Python,
+    image: DynamicImage,
+    overlay: &Rgba32FImage,
+    position: (i64, i64),
+    opacity: f32,
+    mode: BlendMode,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let mut image = promote_gray(image);
+    let (x, y) = position;
+    py.allow_threads(|| blend::composite(&mut image, overlay, x, y, mode, opacity));
+    progress_callback.call1(py, (100,))?;
+    Ok(image)
+}
+
+/// Draw `shape` onto the image in `color`, blending by the color's alpha.
+fn apply_draw(
+    py: Python,
+    image: DynamicImage,
+    shape: &Shape,
+    color: [u8; 4],
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let mut image = promote_gray(image);
+    py.allow_threads(|| {
+        if let Some((layer, x, y)) = shape.render(color, image.width(), image.height())? {
+            blend::composite(&mut image, &layer, x, y, BlendMode::Normal, 1.0);
+        }
+        PyResult::Ok(())
+    })?;
+    progress_callback.call1(py, (100,))?;
+    Ok(image)
+}
+
+/// Convolve with `kernel`; see [`convolve::convolve`]. In linear light the
+/// sums, and so `offset`, are taken on linear values.
+fn apply_convolve(
+    py: Python,
+    image: DynamicImage,
+    kernel: &Kernel,
+    divisor: f32,
+    offset: f32,
+    color_space: ColorSpace,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let image = promote_gray(image);
+    let color = image.color();
+    let output = py.allow_threads(|| {
+        let filter = |image: &DynamicImage| {
+            let filtered = convolve::convolve(&image.to_rgba32f(), kernel, divisor, offset / 255.0);
+            DynamicImage::ImageRgba32F(filtered)
+        };
+        let filtered = match color_space {
+            ColorSpace::Srgb => filter(&image),
+            ColorSpace::Linear => color::from_linear(&filter(&color::to_linear(&image))),
+        };
+        resize::to_color(filtered, color)
+    });
+    progress_callback.call1(py, (100,))?;
+    Ok(output)
+}
@@ -0,0 +1,554 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Write};
+
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::codecs::webp::WebPEncoder;
+use image::{ColorType, DynamicImage, ImageEncoder, ImageError, ImageFormat};
+use pyo3::prelude::*;
+
+use crate::errors::{self, EncodeError, UnsupportedFormatError};
+use crate::metadata::Metadata;
+use crate::provenance;
+
+/// Quality used for JPEG output unless the caller picks one, matching what
+/// `DynamicImage::save` picks.
+const JPEG_QUALITY: u8 = 75;
+
+/// Quality used for AVIF output unless the caller picks one.
+#[cfg(feature = "avif")]
+const AVIF_QUALITY: u8 = 80;
+
+/// AVIF encoder speed, from 1 (slowest, smallest files) to 10.
+#[cfg(feature = "avif")]
+const AVIF_SPEED: u8 = 6;
+
+/// Why AVIF files can't be read or written by this build.
+#[cfg(not(feature = "avif"))]
+pub const AVIF_UNAVAILABLE: &str =
+    "AVIF support is not compiled in; rebuild with the 'avif' feature enabled";
+
+/// Smallest side a JPEG is shrunk to while trying to meet a size budget.
+const MIN_FIT_SIDE: u32 = 16;
+
+/// Pixel format to convert to just before encoding.
+#[derive(Debug, Clone, Copy)]
+pub enum OutputColor {
+    Rgb8,
+    Rgba8,
+    Luma8,
+    Luma16,
+}
+
+impl OutputColor {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "rgb8" => Some(Self::Rgb8),
+            "rgba8" => Some(Self::Rgba8),
+            "luma8" => Some(Self::Luma8),
+            "luma16" => Some(Self::Luma16),
+            _ => None,
+        }
+    }
+
+    fn convert(self, image: &DynamicImage) -> DynamicImage {
+        match self {
+            Self::Rgb8 => DynamicImage::ImageRgb8(image.to_rgb8()),
+            Self::Rgba8 => DynamicImage::ImageRgba8(image.to_rgba8()),
+            Self::Luma8 => DynamicImage::ImageLuma8(image.to_luma8()),
+            Self::Luma16 => DynamicImage::ImageLuma16(image.to_luma16()),
+        }
+    }
+}
+
+/// Encoder settings applied when writing the processed image.
+///
+/// Settings for a format other than the one being written are ignored, so a
+/// single set of options can be shared across a batch with mixed outputs.
+#[derive(Debug, Clone, Default)]
+pub struct EncodeOptions {
+    /// Encoding to write instead of the one named by the output path or the
+    /// caller's `format` argument.
+    pub format: Option<ImageFormat>,
+    pub jpeg_quality: Option<u8>,
+    pub png_compression: CompressionType,
+    pub png_filter: FilterType,
+    pub jpeg_progressive: bool,
+    pub output_color: Option<OutputColor>,
+    #[cfg_attr(not(feature = "avif"), allow(dead_code))]
+    pub avif_quality: Option<u8>,
+    /// JSON recorded in a PNG text chunk; see [`crate::provenance`].
+    pub provenance: Option<String>,
+    /// Copy the source's EXIF data and ICC profile into the output. Callers
+    /// that have a source read it into `metadata`.
+    pub keep_metadata: bool,
+    /// Embedded in JPEG, PNG and WebP output.
+    pub metadata: Metadata,
+}
+
+impl EncodeOptions {
+    /// Build options from the string arguments accepted by the Python API.
+    pub fn from_args(
+        png_compression: Option<&str>,
+        png_filter: Option<&str>,
+        jpeg_progressive: bool,
+        output_color: Option<&str>,
+        avif_quality: Option<u8>,
+    ) -> PyResult<Self> {
+        if avif_quality.is_some_and(|q| !(1..=100).contains(&q)) {
+            return Err(errors::invalid_parameter(
+                "avif_quality",
+                "avif_quality must be between 1 and 100".to_string(),
+            ));
+        }
+        let mut options = Self {
+            jpeg_progressive,
+            avif_quality,
+            ..Self::default()
+        };
+
+        if let Some(name) = png_compression {
+            options.png_compression = match name {
+                "default" => CompressionType::Default,
+                "fast" => CompressionType::Fast,
+                "best" => CompressionType::Best,
+                _ => {
+                    return Err(errors::invalid_parameter(
+                        "png_compression",
+                        format!(
+                            "Unknown PNG compression '{}', expected one of: default, fast, best",
+                            name
+                        ),
+                    ))
+                }
+            };
+        }
+
+        if let Some(name) = png_filter {
+            options.png_filter = match name {
+                "none" => FilterType::NoFilter,
+                "sub" => FilterType::Sub,
+                "up" => FilterType::Up,
+                "avg" => FilterType::Avg,
+                "paeth" => FilterType::Paeth,
+                "adaptive" => FilterType::Adaptive,
+                _ => {
+                    return Err(errors::invalid_parameter(
+                        "png_filter",
+                        format!(
+                            "Unknown PNG filter '{}', expected one of: none, sub, up, avg, paeth, \
+                             adaptive",
+                            name
+                        ),
+                    ))
+                }
+            };
+        }
+
+        if let Some(name) = output_color {
+            options.output_color = Some(OutputColor::from_str(name).ok_or_else(|| {
+                errors::invalid_parameter(
+                    "output_color",
+                    format!(
+                        "Unknown output color '{}', expected one of: rgb8, rgba8, luma8, luma16",
+                        name
+                    ),
+                )
+            })?);
+        }
+
+        Ok(options)
+    }
+
+    /// Use the settings of `output` instead, when given, keeping
+    /// `provenance`. Combining it with encoder arguments of their own is
+    /// refused rather than guessing which should win.
+    pub fn or_output_options(self, output: Option<&OutputOptions>) -> PyResult<Self> {
+        let Some(output) = output else {
+            return Ok(self);
+        };
+        let default = Self::default();
+        if self.png_compression != default.png_compression
+            || self.png_filter != default.png_filter
+            || self.jpeg_progressive
+            || self.output_color.is_some()
+            || self.avif_quality.is_some()
+        {
+            return Err(errors::invalid_parameter(
+                "output_options",
+                "pass encoder settings either in output_options or as separate arguments, not both"
+                    .to_string(),
+            ));
+        }
+        Ok(Self { provenance: self.provenance, ..output.options.clone() })
+    }
+
+    /// Fill in `metadata` from `read` when the options keep it.
+    pub fn with_source_metadata(
+        mut self,
+        read: impl FnOnce() -> PyResult<Metadata>,
+    ) -> PyResult<Self> {
+        if self.keep_metadata {
+            self.metadata = read()?;
+        }
+        Ok(self)
+    }
+
+    /// The encoding to write: the options' own `format` if set, otherwise the
+    /// one named by `format` as in [`output_format`].
+    pub fn format_or(&self, format: &str) -> PyResult<ImageFormat> {
+        match self.format {
+            Some(format) => Ok(format),
+            None => output_format(format),
+        }
+    }
+
+    /// Apply `output_color`, borrowing the image unchanged when it is unset.
+    pub fn convert<'a>(&self, image: &'a DynamicImage) -> Cow<'a, DynamicImage> {
+        match self.output_color {
+            Some(color) => Cow::Owned(color.convert(image)),
+            None => Cow::Borrowed(image),
+        }
+    }
+}
+
+/// Encoder settings for the processing functions, gathered in one object
+///
+/// `format` names the encoding by file extension, as in `process_to_datauri`,
+/// and takes precedence over the output path's extension or a `format`
+/// argument. `jpeg_quality` (1-100, default 75) sets the quality of JPEG
+/// output, baseline or progressive. The other settings work as the
+/// `process_image` arguments of the same name.
+///
+/// WebP output is lossless, and images the encoder can't take directly are
+/// written as 8-bit RGB or RGBA. `webp_lossless` and `webp_quality` (1-100)
+/// are accepted for lossy output, but the bundled encoder has no lossy mode,
+/// so `webp_lossless=False` or any `webp_quality` raises
+/// `InvalidParameterError`.
+///
+/// With `keep_metadata`, the input's EXIF data and ICC color profile are
+/// copied into JPEG, PNG and WebP output instead of being dropped, so that
+/// wide-gamut photos keep their colors. An EXIF orientation is reset to
+/// upright when `auto_orient` has applied it.
+#[pyclass(module = "image_processor_rust")]
+#[derive(Clone)]
+pub struct OutputOptions {
+    options: EncodeOptions,
+}
+
+#[pymethods]
+impl OutputOptions {
+    #[new]
+    #[pyo3(signature = (
+        format = None,
+        jpeg_quality = None,
+        jpeg_progressive = false,
+        png_compression = None,
+        png_filter = None,
+        output_color = None,
+        avif_quality = None,
+        keep_metadata = false,
+        webp_lossless = true,
+        webp_quality = None
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        format: Option<&str>,
+        jpeg_quality: Option<u8>,
+        jpeg_progressive: bool,
+        png_compression: Option<&str>,
+        png_filter: Option<&str>,
+        output_color: Option<&str>,
+        avif_quality: Option<u8>,
+        keep_metadata: bool,
+        webp_lossless: bool,
+        webp_quality: Option<u8>,
+    ) -> PyResult<Self> {
+        if webp_quality.is_some_and(|q| !(1..=100).contains(&q)) {
+            return Err(errors::invalid_parameter(
+                "webp_quality",
+                "webp_quality must be between 1 and 100".to_string(),
+            ));
+        }
+        let lossy = match (webp_lossless, webp_quality) {
+            (false, _) => Some("webp_lossless"),
+            (true, Some(_)) => Some("webp_quality"),
+            (true, None) => None,
+        };
+        if let Some(parameter) = lossy {
+            return Err(errors::invalid_parameter(
+                parameter,
+                "lossy WebP output is not supported; WebP is always written lossless".to_string(),
+            ));
+        }
+        if jpeg_quality.is_some_and(|q| !(1..=100).contains(&q)) {
+            return Err(errors::invalid_parameter(
+                "jpeg_quality",
+                "jpeg_quality must be between 1 and 100".to_string(),
+            ));
+        }
+        let mut options = EncodeOptions::from_args(
+            png_compression,
+            png_filter,
+            jpeg_progressive,
+            output_color,
+            avif_quality,
+        )?;
+        options.format = format.map(output_format).transpose()?;
+        options.jpeg_quality = jpeg_quality;
+        options.keep_metadata = keep_metadata;
+        Ok(Self { options })
+    }
+}
+
+/// The encoding named by a file extension such as `"png"` or `"jpeg"`.
+pub fn output_format(format: &str) -> PyResult<ImageFormat> {
+    ImageFormat::from_extension(format)
+        .filter(|f| f.can_write())
+        .ok_or_else(|| {
+            UnsupportedFormatError::new_err(format!("Unsupported output format '{}'", format))
+        })
+}
+
+/// Write `image` to `path`, inferring the format from the extension unless
+/// the options name one.
+pub fn save(image: &DynamicImage, path: &str, options: &EncodeOptions) -> PyResult<()> {
+    write_file(image, path, options).map_err(|e| errors::with_path(e, path))
+}
+
+/// [`save`] without recording `path` on the error, which takes the GIL. For
+/// writes on threads that the GIL holder may be waiting on.
+pub fn write_file(image: &DynamicImage, path: &str, options: &EncodeOptions) -> PyResult<()> {
+    let format = match options.format {
+        Some(format) => format,
+        None => ImageFormat::from_path(path).map_err(image_error)?,
+    };
+    if !options.metadata.is_empty() {
+        return std::fs::write(path, encode(image, format, options)?).map_err(save_error);
+    }
+    let converted = options.convert(image);
+    let image = &storable(&converted, format);
+    let create = || File::create(path).map(BufWriter::new).map_err(save_error);
+    match format {
+        ImageFormat::Png => write_png(image, create()?, options),
+        ImageFormat::Jpeg if options.jpeg_progressive || options.jpeg_quality.is_some() => {
+            write_jpeg(image, create()?, options)
+        }
+        ImageFormat::WebP => write_webp(image, create()?),
+        ImageFormat::Avif => std::fs::write(path, avif(image, options)?).map_err(save_error),
+        _ => image.save_with_format(path, format).map_err(image_error),
+    }
+}
+
+/// `image` in a pixel format that `format` can hold. Float pixels, as
+/// decoded from Radiance HDR, keep 16 bits in PNG and TIFF, which have no
+/// float samples, and are reduced to 8 bits for other formats but OpenEXR.
+fn storable(image: &DynamicImage, format: ImageFormat) -> Cow<'_, DynamicImage> {
+    let float = matches!(image, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_));
+    if !float || format == ImageFormat::OpenExr {
+        return Cow::Borrowed(image);
+    }
+    let sixteen_bit = matches!(format, ImageFormat::Png | ImageFormat::Tiff);
+    Cow::Owned(match (sixteen_bit, image.color().has_alpha()) {
+        (true, false) => DynamicImage::ImageRgb16(image.to_rgb16()),
+        (true, true) => DynamicImage::ImageRgba16(image.to_rgba16()),
+        (false, false) => DynamicImage::ImageRgb8(image.to_rgb8()),
+        (false, true) => DynamicImage::ImageRgba8(image.to_rgba8()),
+    })
+}
+
+/// Encode `image` in memory as `format`.
+pub fn encode(
+    image: &DynamicImage,
+    format: ImageFormat,
+    options: &EncodeOptions,
+) -> PyResult<Vec<u8>> {
+    let converted = options.convert(image);
+    let image = &storable(&converted, format);
+    let mut bytes = Vec::new();
+    match format {
+        ImageFormat::Png => write_png(image, &mut bytes, options)?,
+        ImageFormat::Jpeg if options.jpeg_progressive || options.jpeg_quality.is_some() => {
+            write_jpeg(image, &mut bytes, options)?
+        }
+        ImageFormat::WebP => write_webp(image, &mut bytes)?,
+        ImageFormat::Avif => bytes = avif(image, options)?,
+        _ => image
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .map_err(image_error)?,
+    }
+    Ok(options.metadata.embed(bytes, format))
+}
+
+/// Encode as a baseline JPEG at `quality` (1-100).
+fn jpeg_with_quality(image: &DynamicImage, quality: u8) -> PyResult<Vec<u8>> {
+    let mut bytes = Vec::new();
+    write_baseline_jpeg(image, &mut bytes, quality)?;
+    Ok(bytes)
+}
+
+/// Write a JPEG at the options' quality, progressive if they ask for it.
+fn write_jpeg<W: Write>(image: &DynamicImage, writer: W, options: &EncodeOptions) -> PyResult<()> {
+    let quality = options.jpeg_quality.unwrap_or(JPEG_QUALITY);
+    if options.jpeg_progressive {
+        write_progressive_jpeg(image, writer, quality)
+    } else {
+        write_baseline_jpeg(image, writer, quality)
+    }
+}
+
+fn write_baseline_jpeg<W: Write>(image: &DynamicImage, writer: W, quality: u8) -> PyResult<()> {
+    let encoder = JpegEncoder::new_with_quality(writer, quality);
+    if image.color().has_color() {
+        encoder.write_image(&image.to_rgb8(), image.width(), image.height(), ColorType::Rgb8)
+    } else {
+        encoder.write_image(&image.to_luma8(), image.width(), image.height(), ColorType::L8)
+    }
+    .map_err(save_error)
+}
+
+/// Find the highest JPEG quality at or above `min_quality` whose output fits
+/// in `max_bytes`, shrinking the image by a quarter at a time if even
+/// `min_quality` is too big and `allow_downscale` is set.
+///
+/// Returns the encoded bytes and the quality used.
+pub fn fit_jpeg(
+    image: &DynamicImage,
+    max_bytes: usize,
+    min_quality: u8,
+    allow_downscale: bool,
+) -> PyResult<(Vec<u8>, u8)> {
+    let mut current = image.clone();
+    loop {
+        let smallest = jpeg_with_quality(&current, min_quality)?;
+        if smallest.len() <= max_bytes {
+            // Binary search for the best quality that still fits.
+            let (mut best, mut best_quality) = (smallest, min_quality);
+            let (mut low, mut high) = (min_quality + 1, 100u8);
+            while low <= high {
+                let quality = low + (high - low) / 2;
+                let bytes = jpeg_with_quality(&current, quality)?;
+                if bytes.len() <= max_bytes {
+                    (best, best_quality) = (bytes, quality);
+                    low = quality + 1;
+                } else {
+                    high = quality - 1;
+                }
+            }
+            return Ok((best, best_quality));
+        }
+
+        let (width, height) = (current.width() * 3 / 4, current.height() * 3 / 4);
+        if !allow_downscale || width.min(height) < MIN_FIT_SIDE {
+            return Err(save_error(format!(
+                "cannot fit a JPEG in {} bytes; quality {} at {}x{} still needs {}",
+                max_bytes,
+                min_quality,
+                current.width(),
+                current.height(),
+                smallest.len()
+            )));
+        }
+        current = current.resize_exact(width, height, image::imageops::FilterType::Lanczos3);
+    }
+}
+
+fn write_png<W: Write>(
+    image: &DynamicImage,
+    mut writer: W,
+    options: &EncodeOptions,
+) -> PyResult<()> {
+    let Some(text) = &options.provenance else {
+        return PngEncoder::new_with_quality(writer, options.png_compression, options.png_filter)
+            .write_image(image.as_bytes(), image.width(), image.height(), image.color())
+            .map_err(save_error);
+    };
+
+    // `image` has no way to add text chunks, so encode to memory and splice
+    // the chunk in.
+    let plain = EncodeOptions { provenance: None, ..options.clone() };
+    let mut png = Vec::new();
+    write_png(image, &mut png, &plain)?;
+    writer
+        .write_all(&provenance::insert_text(&png, provenance::KEYWORD, text))
+        .map_err(save_error)
+}
+
+/// Encode as AVIF at the options' quality. The encoder takes 8-bit samples
+/// only, so deeper images are reduced first.
+#[cfg(feature = "avif")]
+fn avif(image: &DynamicImage, options: &EncodeOptions) -> PyResult<Vec<u8>> {
+    use image::codecs::avif::AvifEncoder;
+
+    let quality = options.avif_quality.unwrap_or(AVIF_QUALITY);
+    let mut bytes = Vec::new();
+    let encoder = AvifEncoder::new_with_speed_quality(&mut bytes, AVIF_SPEED, quality);
+    let (width, height) = (image.width(), image.height());
+    if image.color().has_alpha() {
+        encoder.write_image(&image.to_rgba8(), width, height, ColorType::Rgba8)
+    } else {
+        encoder.write_image(&image.to_rgb8(), width, height, ColorType::Rgb8)
+    }
+    .map_err(save_error)?;
+    Ok(bytes)
+}
+
+#[cfg(not(feature = "avif"))]
+fn avif(_image: &DynamicImage, _options: &EncodeOptions) -> PyResult<Vec<u8>> {
+    Err(UnsupportedFormatError::new_err(format!("Failed to save image: {}", AVIF_UNAVAILABLE)))
+}
+
+/// Encode as lossless WebP. The encoder takes 8-bit RGB or RGBA only, so
+/// other layouts are converted first.
+fn write_webp<W: Write>(image: &DynamicImage, writer: W) -> PyResult<()> {
+    let encoder = WebPEncoder::new_lossless(writer);
+    let (width, height) = (image.width(), image.height());
+    match image {
+        DynamicImage::ImageRgb8(rgb) => encoder.write_image(rgb, width, height, ColorType::Rgb8),
+        DynamicImage::ImageRgba8(rgba) => {
+            encoder.write_image(rgba, width, height, ColorType::Rgba8)
+        }
+        _ if image.color().has_alpha() => {
+            encoder.write_image(&image.to_rgba8(), width, height, ColorType::Rgba8)
+        }
+        _ => encoder.write_image(&image.to_rgb8(), width, height, ColorType::Rgb8),
+    }
+    .map_err(save_error)
+}
+
+/// `image`'s own JPEG encoder only emits baseline files, so progressive
+/// output goes through `jpeg-encoder` instead.
+fn write_progressive_jpeg<W: Write>(image: &DynamicImage, writer: W, quality: u8) -> PyResult<()> {
+    let (width, height) = match (u16::try_from(image.width()), u16::try_from(image.height())) {
+        (Ok(w), Ok(h)) => (w, h),
+        _ => return Err(save_error("image is too large for JPEG")),
+    };
+
+    let (data, color_type) = if image.color().has_color() {
+        (image.to_rgb8().into_raw(), jpeg_encoder::ColorType::Rgb)
+    } else {
+        (image.to_luma8().into_raw(), jpeg_encoder::ColorType::Luma)
+    };
+
+    let mut encoder = jpeg_encoder::Encoder::new(writer, quality);
+    encoder.set_progressive(true);
+    encoder
+        .encode(&data, width, height, color_type)
+        .map_err(save_error)
+}
+
+pub fn save_error(e: impl std::fmt::Display) -> PyErr {
+    EncodeError::new_err(format!("Failed to save image: {}", e))
+}
+
+/// [`save_error`], except that formats `image` can't encode are reported as
+/// unsupported rather than failed.
+fn image_error(e: ImageError) -> PyErr {
+    match e {
+        ImageError::Unsupported(_) => {
+            UnsupportedFormatError::new_err(format!("Failed to save image: {}", e))
+        }
+        _ => save_error(e),
+    }
+}
@@ -0,0 +1,198 @@
+use image::ColorType;
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+
+create_exception!(
+    image_processor_rust,
+    GeometryError,
+    PyValueError,
+    "A region does not fit inside the image. Carries `rect`, `image_size` and `edge` attributes."
+);
+
+create_exception!(
+    image_processor_rust,
+    LossyConversionError,
+    PyValueError,
+    "An effect would drop alpha or bit depth in strict mode. Carries `input_color` and \
+     `output_color` attributes."
+);
+
+create_exception!(
+    image_processor_rust,
+    ProcessingCancelled,
+    PyException,
+    "The caller's `cancel_event` was set while processing."
+);
+
+create_exception!(
+    image_processor_rust,
+    ImageLoadError,
+    PyValueError,
+    "An input image could not be read or decoded. Carries a `path` attribute, `None` for \
+     in-memory input."
+);
+
+create_exception!(
+    image_processor_rust,
+    UnsupportedFormatError,
+    PyValueError,
+    "An image format is not recognised or not available in this build. Carries a `path` \
+     attribute, `None` when no file is involved."
+);
+
+create_exception!(
+    image_processor_rust,
+    EncodeError,
+    PyValueError,
+    "An output image could not be encoded or written. Carries a `path` attribute, `None` for \
+     in-memory output."
+);
+
+create_exception!(
+    image_processor_rust,
+    InvalidParameterError,
+    PyValueError,
+    "An effect or encoder parameter is unknown, missing, mistyped or out of range. Carries \
+     `effect` and `parameter` attributes, either of which may be `None`."
+);
+
+/// Give the attributes of the exceptions above class-level `None` defaults,
+/// so that an error raised before its details were attached still has them.
+pub fn add_attribute_defaults(py: Python) -> PyResult<()> {
+    let classes = [
+        (py.get_type::<ImageLoadError>(), &["path"][..]),
+        (py.get_type::<UnsupportedFormatError>(), &["path"]),
+        (py.get_type::<EncodeError>(), &["path"]),
+        (py.get_type::<InvalidParameterError>(), &["effect", "parameter"]),
+    ];
+    for (class, attributes) in classes {
+        for attribute in attributes {
+            class.setattr(*attribute, py.None())?;
+        }
+    }
+    Ok(())
+}
+
+/// Record the file a load, format or encode error is about.
+///
+/// This takes the GIL, so a thread that the GIL holder may be waiting on has
+/// to hand its error back first and attach the path from there.
+pub fn with_path(err: PyErr, path: &str) -> PyErr {
+    Python::with_gil(|py| {
+        let is_file_error = err.is_instance_of::<ImageLoadError>(py)
+            || err.is_instance_of::<UnsupportedFormatError>(py)
+            || err.is_instance_of::<EncodeError>(py);
+        if is_file_error {
+            if let Err(e) = err.value(py).setattr("path", path) {
+                return e;
+            }
+        }
+        err
+    })
+}
+
+/// An `InvalidParameterError` about `parameter`.
+pub fn invalid_parameter(parameter: &str, message: String) -> PyErr {
+    let err = InvalidParameterError::new_err(message);
+    Python::with_gil(|py| match err.value(py).setattr("parameter", parameter) {
+        Ok(()) => err,
+        Err(e) => e,
+    })
+}
+
+/// Record which effect an `InvalidParameterError` came from.
+pub fn with_effect(err: PyErr, effect: &str) -> PyErr {
+    Python::with_gil(|py| {
+        if err.is_instance_of::<InvalidParameterError>(py) {
+            if let Err(e) = err.value(py).setattr("effect", effect) {
+                return e;
+            }
+        }
+        err
+    })
+}
+
+/// Side of the image that a requested region crosses.
+#[derive(Debug, Clone, Copy)]
+pub enum Edge {
+    Left,
+    Top,
+    Right,
+    Bottom,
+}
+
+impl Edge {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Left => "left",
+            Self::Top => "top",
+            Self::Right => "right",
+            Self::Bottom => "bottom",
+        }
+    }
+}
+
+/// Check that `rect` (`x, y, width, height`) lies inside an image of `size`.
+///
+/// On failure the raised `GeometryError` exposes the rectangle, the image size
+/// and the first offending edge as attributes so callers don't have to parse
+/// the message.
+pub fn check_region(py: Python, rect: (u32, u32, u32, u32), size: (u32, u32)) -> PyResult<()> {
+    let (x, y, w, h) = rect;
+    let edge = if x >= size.0 {
+        Edge::Left
+    } else if y >= size.1 {
+        Edge::Top
+    } else if x as u64 + w as u64 > size.0 as u64 {
+        Edge::Right
+    } else if y as u64 + h as u64 > size.1 as u64 {
+        Edge::Bottom
+    } else {
+        return Ok(());
+    };
+
+    let err = GeometryError::new_err(format!(
+        "Region (x={}, y={}, width={}, height={}) crosses the {} edge of the {}x{} image",
+        x,
+        y,
+        w,
+        h,
+        edge.as_str(),
+        size.0,
+        size.1
+    ));
+    let value = err.value(py);
+    value.setattr("rect", rect)?;
+    value.setattr("image_size", size)?;
+    value.setattr("edge", edge.as_str())?;
+    Err(err)
+}
+
+/// In strict mode, fail if going from `input` to `output` lost the alpha
+/// channel or bits per channel.
+pub fn check_lossless(
+    py: Python,
+    effect: &str,
+    input: ColorType,
+    output: ColorType,
+) -> PyResult<()> {
+    let depth = |c: ColorType| c.bytes_per_pixel() / c.channel_count();
+    let lost = if input.has_alpha() && !output.has_alpha() {
+        "alpha channel"
+    } else if depth(output) < depth(input) {
+        "bit depth"
+    } else {
+        return Ok(());
+    };
+
+    let (input, output) = (format!("{:?}", input), format!("{:?}", output));
+    let err = LossyConversionError::new_err(format!(
+        "Effect '{}' would drop the {} converting {} to {}",
+        effect, lost, input, output
+    ));
+    let value = err.value(py);
+    value.setattr("input_color", input)?;
+    value.setattr("output_color", output)?;
+    Err(err)
+}
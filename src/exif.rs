@@ -0,0 +1,264 @@
+use image::DynamicImage;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBool;
+
+/// EXIF tag holding the orientation the camera was held in.
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// Tags in the first IFD pointing at the Exif and GPS sub-IFDs.
+const EXIF_IFD_TAG: u16 = 0x8769;
+const GPS_IFD_TAG: u16 = 0x8825;
+
+/// Largest value [`fields`] reads, so that a corrupt count can't make it
+/// allocate without bound.
+const MAX_VALUE_BYTES: usize = 1 << 16;
+
+/// Names of the first IFD's tags that [`fields`] reports.
+const IMAGE_TAGS: &[(u16, &str)] = &[
+    (0x010E, "ImageDescription"),
+    (0x010F, "Make"),
+    (0x0110, "Model"),
+    (0x0112, "Orientation"),
+    (0x011A, "XResolution"),
+    (0x011B, "YResolution"),
+    (0x0128, "ResolutionUnit"),
+    (0x0131, "Software"),
+    (0x0132, "DateTime"),
+    (0x013B, "Artist"),
+    (0x8298, "Copyright"),
+];
+
+/// Names of the Exif sub-IFD's tags that [`fields`] reports.
+const PHOTO_TAGS: &[(u16, &str)] = &[
+    (0x829A, "ExposureTime"),
+    (0x829D, "FNumber"),
+    (0x8822, "ExposureProgram"),
+    (0x8827, "ISOSpeedRatings"),
+    (0x9000, "ExifVersion"),
+    (0x9003, "DateTimeOriginal"),
+    (0x9004, "DateTimeDigitized"),
+    (0x9010, "OffsetTime"),
+    (0x9201, "ShutterSpeedValue"),
+    (0x9202, "ApertureValue"),
+    (0x9204, "ExposureBiasValue"),
+    (0x9207, "MeteringMode"),
+    (0x9209, "Flash"),
+    (0x920A, "FocalLength"),
+    (0xA001, "ColorSpace"),
+    (0xA002, "PixelXDimension"),
+    (0xA003, "PixelYDimension"),
+    (0xA405, "FocalLengthIn35mmFilm"),
+    (0xA433, "LensMake"),
+    (0xA434, "LensModel"),
+];
+
+/// Names of the GPS sub-IFD's tags that [`fields`] reports.
+const GPS_TAGS: &[(u16, &str)] = &[
+    (0x0001, "GPSLatitudeRef"),
+    (0x0002, "GPSLatitude"),
+    (0x0003, "GPSLongitudeRef"),
+    (0x0004, "GPSLongitude"),
+    (0x0005, "GPSAltitudeRef"),
+    (0x0006, "GPSAltitude"),
+    (0x0007, "GPSTimeStamp"),
+    (0x001D, "GPSDateStamp"),
+];
+
+/// The value of an EXIF field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Integers(Vec<i64>),
+    /// Rational and floating-point values; a zero denominator gives NaN.
+    Numbers(Vec<f64>),
+    /// Untyped bytes, such as `ExifVersion`.
+    Bytes(Vec<u8>),
+}
+
+/// Raw EXIF data: a TIFF header and its chain of IFDs.
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(..4)? {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return None,
+        };
+        Some(Self { data, big_endian })
+    }
+
+    fn u16_at(&self, pos: usize) -> Option<u16> {
+        let bytes = self.data.get(pos..pos.checked_add(2)?)?.try_into().ok()?;
+        Some(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32_at(&self, pos: usize) -> Option<u32> {
+        let bytes = self.data.get(pos..pos.checked_add(4)?)?.try_into().ok()?;
+        Some(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    fn first_ifd(&self) -> Option<usize> {
+        self.u32_at(4).map(|offset| offset as usize)
+    }
+
+    /// Position of each 12-byte entry in the IFD at `ifd`, with its tag.
+    fn entries(&self, ifd: usize) -> impl Iterator<Item = (u16, usize)> + '_ {
+        let count = self.u16_at(ifd).unwrap_or(0) as usize;
+        (0..count).map_while(move |entry| {
+            let pos = ifd + 2 + entry * 12;
+            Some((self.u16_at(pos)?, pos))
+        })
+    }
+
+    /// Position of the entry for `tag` in the IFD at `ifd`.
+    fn find(&self, ifd: usize, tag: u16) -> Option<usize> {
+        self.entries(ifd).find(|&(t, _)| t == tag).map(|(_, pos)| pos)
+    }
+
+    /// Decode the value of the entry at `pos`.
+    fn value(&self, pos: usize) -> Option<Value> {
+        let kind = self.u16_at(pos + 2)?;
+        let count = self.u32_at(pos + 4)? as usize;
+        let size = match kind {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 => 4,
+            5 | 10 | 12 => 8,
+            _ => return None,
+        };
+        let length = count.checked_mul(size).filter(|&l| l <= MAX_VALUE_BYTES)?;
+        // Values of up to four bytes sit in the entry itself.
+        let start = if length <= 4 { pos + 8 } else { self.u32_at(pos + 8)? as usize };
+        let bytes = self.data.get(start..start.checked_add(length)?)?;
+        let read = |item: &dyn Fn(usize) -> Option<f64>| -> Vec<f64> {
+            (0..count).filter_map(|i| item(start + i * size)).collect()
+        };
+        let integers = |item: &dyn Fn(usize) -> Option<f64>| -> Value {
+            Value::Integers(read(item).into_iter().map(|v| v as i64).collect())
+        };
+        Some(match kind {
+            2 => {
+                let text = bytes.split(|&b| b == 0).next().unwrap_or(&[]);
+                Value::Text(String::from_utf8_lossy(text).trim_end().to_string())
+            }
+            1 | 7 => Value::Bytes(bytes.to_vec()),
+            6 => Value::Integers(bytes.iter().map(|&b| b as i8 as i64).collect()),
+            3 => integers(&|p| self.u16_at(p).map(f64::from)),
+            8 => integers(&|p| self.u16_at(p).map(|v| v as i16 as f64)),
+            4 => integers(&|p| self.u32_at(p).map(f64::from)),
+            9 => integers(&|p| self.u32_at(p).map(|v| v as i32 as f64)),
+            5 => Value::Numbers(read(&|p| {
+                Some(f64::from(self.u32_at(p)?) / f64::from(self.u32_at(p + 4)?))
+            })),
+            10 => Value::Numbers(read(&|p| {
+                Some(self.u32_at(p)? as i32 as f64 / self.u32_at(p + 4)? as i32 as f64)
+            })),
+            11 => Value::Numbers(read(&|p| self.u32_at(p).map(|v| f32::from_bits(v).into()))),
+            _ => Value::Numbers(read(&|p| {
+                let (a, b) = (u64::from(self.u32_at(p)?), u64::from(self.u32_at(p + 4)?));
+                Some(f64::from_bits(if self.big_endian { a << 32 | b } else { b << 32 | a }))
+            })),
+        })
+    }
+}
+
+/// What `process_image` does with the orientation recorded in its input,
+/// given as `auto_orient`: `"apply"` (or `True`), `"report"` or `"ignore"`
+/// (or `False`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrientationMode {
+    /// Turn the pixels upright before the effect runs.
+    Apply,
+    /// Process the pixels as stored, but read the orientation for `stats`.
+    Report,
+    /// Process the pixels as stored without reading the orientation.
+    Ignore,
+}
+
+impl<'source> FromPyObject<'source> for OrientationMode {
+    fn extract(arg: &'source PyAny) -> PyResult<Self> {
+        if let Ok(flag) = arg.downcast::<PyBool>() {
+            return Ok(if flag.is_true() { Self::Apply } else { Self::Ignore });
+        }
+        match arg.extract::<&str>()? {
+            "apply" => Ok(Self::Apply),
+            "report" => Ok(Self::Report),
+            "ignore" => Ok(Self::Ignore),
+            _ => Err(PyValueError::new_err(
+                "auto_orient must be \"apply\", \"report\", \"ignore\" or a bool",
+            )),
+        }
+    }
+}
+
+/// The orientation (1-8) recorded in the first IFD of raw EXIF data, or
+/// `None` when there is none or the data is malformed.
+pub fn orientation(exif: &[u8]) -> Option<u16> {
+    let tiff = Tiff::new(exif)?;
+    let pos = tiff.find(tiff.first_ifd()?, ORIENTATION_TAG)?;
+    // A SHORT value sits at the start of the entry's 4-byte value field.
+    tiff.u16_at(pos + 8).filter(|v| (1..=8).contains(v))
+}
+
+/// Record in raw EXIF data that the image is stored upright, for pixels that
+/// have had their orientation applied. Data without an orientation is left
+/// as it is.
+pub fn clear_orientation(exif: &mut [u8]) {
+    let Some(pos) = Tiff::new(exif).and_then(|t| t.find(t.first_ifd()?, ORIENTATION_TAG)) else {
+        return;
+    };
+    let upright = if exif.starts_with(b"MM") { 1u16.to_be_bytes() } else { 1u16.to_le_bytes() };
+    if let Some(value) = exif.get_mut(pos + 8..pos + 10) {
+        value.copy_from_slice(&upright);
+    }
+}
+
+/// The commonly used fields of raw EXIF data, by tag name, covering the
+/// first IFD and its Exif and GPS sub-IFDs. Other tags and anything
+/// malformed are skipped.
+pub fn fields(exif: &[u8]) -> Vec<(&'static str, Value)> {
+    let Some(tiff) = Tiff::new(exif) else {
+        return Vec::new();
+    };
+    let Some(first) = tiff.first_ifd() else {
+        return Vec::new();
+    };
+    let sub_ifd = |tag| tiff.find(first, tag).and_then(|pos| tiff.u32_at(pos + 8));
+    let mut ifds = vec![(first, IMAGE_TAGS)];
+    ifds.extend(sub_ifd(EXIF_IFD_TAG).map(|ifd| (ifd as usize, PHOTO_TAGS)));
+    ifds.extend(sub_ifd(GPS_IFD_TAG).map(|ifd| (ifd as usize, GPS_TAGS)));
+
+    let mut fields = Vec::new();
+    for (ifd, names) in ifds {
+        for (tag, pos) in tiff.entries(ifd) {
+            let Some(&(_, name)) = names.iter().find(|&&(t, _)| t == tag) else {
+                continue;
+            };
+            if let Some(value) = tiff.value(pos) {
+                fields.push((name, value));
+            }
+        }
+    }
+    fields
+}
+
+/// Turn an image stored with EXIF `orientation` upright.
+pub fn apply_orientation(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        // Transposed: mirrored along the top-left to bottom-right diagonal.
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        // Transversed: mirrored along the other diagonal.
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
@@ -0,0 +1,48 @@
+use std::io::Read;
+use std::time::Duration;
+
+use pyo3::PyResult;
+
+use crate::errors::ImageLoadError;
+
+/// Download `url` into memory, refusing bodies larger than `max_bytes`.
+///
+/// The limit is enforced while streaming rather than trusting the
+/// `Content-Length` header, so a lying or absent header can't be used to
+/// exhaust memory.
+pub fn fetch_bytes(url: &str, timeout: Duration, max_bytes: u64) -> PyResult<Vec<u8>> {
+    let response = ureq::AgentBuilder::new()
+        .timeout(timeout)
+        .build()
+        .get(url)
+        .call()
+        .map_err(|e| ImageLoadError::new_err(format!("Failed to fetch image: {}", e)))?;
+
+    if let Some(length) = response
+        .header("Content-Length")
+        .and_then(|v| v.parse::<u64>().ok())
+    {
+        if length > max_bytes {
+            return Err(ImageLoadError::new_err(format!(
+                "Remote image is {} bytes, exceeding the {} byte limit",
+                length, max_bytes
+            )));
+        }
+    }
+
+    let mut bytes = Vec::new();
+    response
+        .into_reader()
+        .take(max_bytes + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|e| ImageLoadError::new_err(format!("Failed to fetch image: {}", e)))?;
+
+    if bytes.len() as u64 > max_bytes {
+        return Err(ImageLoadError::new_err(format!(
+            "Remote image exceeds the {} byte limit",
+            max_bytes
+        )));
+    }
+
+    Ok(bytes)
+}
@@ -0,0 +1,81 @@
+use image::{imageops, DynamicImage, Rgba, RgbaImage};
+use rayon::prelude::*;
+
+use crate::resize::{self, Filter};
+
+/// How an image is sized to its montage cell.
+#[derive(Debug, Clone, Copy)]
+pub enum CellFit {
+    /// Scale to fit inside the cell, leaving background around it.
+    Fit,
+    /// Scale to fill the cell, cropping what overhangs it.
+    Cover,
+}
+
+impl CellFit {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "fit" => Some(Self::Fit),
+            "cover" => Some(Self::Cover),
+            _ => None,
+        }
+    }
+}
+
+/// Geometry and appearance of a montage.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub cols: u32,
+    pub cell_width: u32,
+    pub cell_height: u32,
+    /// Gap between neighbouring cells; there is none around the outside.
+    pub spacing: u32,
+    pub fit: CellFit,
+    pub background: [u8; 4],
+}
+
+/// Tile `images` into a grid, row by row, each centered in its cell.
+///
+/// The grid has `layout.cols` columns and as many rows as needed. Images are
+/// scaled up or down to their cell and resized in parallel.
+pub fn montage(images: &[DynamicImage], layout: &Layout) -> RgbaImage {
+    let (cell_w, cell_h) = (layout.cell_width, layout.cell_height);
+    let rows = (images.len() as u32).div_ceil(layout.cols);
+    let span = |cells: u32, size: u32| cells * size + cells.saturating_sub(1) * layout.spacing;
+    let mut canvas = RgbaImage::from_pixel(
+        span(layout.cols, cell_w),
+        span(rows, cell_h),
+        Rgba(layout.background),
+    );
+
+    let cells: Vec<RgbaImage> = images
+        .par_iter()
+        .map(|image| fit_cell(image, cell_w, cell_h, layout.fit))
+        .collect();
+    for (i, cell) in cells.iter().enumerate() {
+        let (col, row) = (i as u32 % layout.cols, i as u32 / layout.cols);
+        let x = col * (cell_w + layout.spacing) + (cell_w - cell.width()) / 2;
+        let y = row * (cell_h + layout.spacing) + (cell_h - cell.height()) / 2;
+        imageops::overlay(&mut canvas, cell, x as i64, y as i64);
+    }
+    canvas
+}
+
+/// Resize `image` for a `width` x `height` cell; the result is never larger.
+fn fit_cell(image: &DynamicImage, width: u32, height: u32, fit: CellFit) -> RgbaImage {
+    let (sx, sy) = (width as f64 / image.width() as f64, height as f64 / image.height() as f64);
+    let scale = match fit {
+        CellFit::Fit => sx.min(sy),
+        CellFit::Cover => sx.max(sy),
+    };
+    let scaled = |v: u32| ((v as f64 * scale).round() as u32).max(1);
+    let (w, h) = (scaled(image.width()), scaled(image.height()));
+    let resized = resize::resize(image, w, h, Filter::Lanczos3).to_rgba8();
+    match fit {
+        CellFit::Fit => resized,
+        CellFit::Cover => {
+            let (cw, ch) = (width.min(w), height.min(h));
+            imageops::crop_imm(&resized, (w - cw) / 2, (h - ch) / 2, cw, ch).to_image()
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use image::DynamicImage;
+
+use crate::analysis;
+
+/// Remap each color channel of `source` so its histogram matches the same
+/// channel of `reference`.
+///
+/// Every value is sent to the first reference value whose cumulative share
+/// of pixels reaches the source value's, which carries over the reference's
+/// tonal range and color balance while keeping the source's content. The
+/// images need not be the same size. The result is 8-bit RGB, or RGBA with
+/// the source's alpha when it has one.
+pub fn match_histogram(source: &DynamicImage, reference: &DynamicImage) -> DynamicImage {
+    let source_hist = analysis::histogram(source);
+    let reference_hist = analysis::histogram(reference);
+    let luts = [
+        lut(&source_hist.red, &reference_hist.red),
+        lut(&source_hist.green, &reference_hist.green),
+        lut(&source_hist.blue, &reference_hist.blue),
+    ];
+    let remap = |pixel: &mut [u8]| {
+        for (c, lut) in luts.iter().enumerate() {
+            pixel[c] = lut[pixel[c] as usize];
+        }
+    };
+
+    if source.color().has_alpha() {
+        let mut rgba = source.to_rgba8();
+        rgba.chunks_exact_mut(4).for_each(remap);
+        DynamicImage::ImageRgba8(rgba)
+    } else {
+        let mut rgb = source.to_rgb8();
+        rgb.chunks_exact_mut(3).for_each(remap);
+        DynamicImage::ImageRgb8(rgb)
+    }
+}
+
+fn lut(source: &[u64; 256], reference: &[u64; 256]) -> [u8; 256] {
+    let cdf = |hist: &[u64; 256]| {
+        let total = hist.iter().sum::<u64>().max(1) as f64;
+        let mut running = 0;
+        hist.map(|count| {
+            running += count;
+            running as f64 / total
+        })
+    };
+    let (source, reference) = (cdf(source), cdf(reference));
+
+    let mut lut = [0u8; 256];
+    let mut target = 0;
+    for (value, share) in source.iter().enumerate() {
+        while target < 255 && reference[target] < *share {
+            target += 1;
+        }
+        lut[value] = target as u8;
+    }
+    lut
+}
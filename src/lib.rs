@@ -1,144 +1,2080 @@
+use pyo3::exceptions::PyTimeoutError;
 use pyo3::prelude::*;
-use image::{DynamicImage, ImageBuffer, Rgb};
-use imageproc::edges::canny;
-
-#[derive(Debug)]
-enum ImageEffect {
-    EdgeDetect,
-    Blur,
-    Sharpen,
-    Grayscale,
-    Sepia,
-    Invert
-}
-
-impl ImageEffect {
-    fn from_str(s: &str) -> Option<Self> {
-        match s {
-            "edge_detect" => Some(Self::EdgeDetect),
-            "blur" => Some(Self::Blur),
-            "sharpen" => Some(Self::Sharpen),
-            "grayscale" => Some(Self::Grayscale),
-            "sepia" => Some(Self::Sepia),
-            "invert" => Some(Self::Invert),
-            _ => None
-        }
-    }
-}
+use pyo3::buffer::PyBuffer;
+use pyo3::types::{IntoPyDict, PyByteArray, PyBytes, PyDict, PyList};
+use image::{DynamicImage, GenericImageView};
+
+mod analysis;
+mod auto;
+mod background;
+mod batch;
+mod blend;
+mod channels;
+mod clahe;
+mod color;
+mod colormap;
+mod convolve;
+mod curve;
+mod decode;
+mod draw;
+mod effects;
+mod encode;
+mod errors;
+mod exif;
+mod fetch;
+mod grid;
+mod histmatch;
+mod metadata;
+mod params;
+mod progress;
+mod provenance;
+mod pyimage;
+mod quantize;
+mod recipe;
+mod resize;
+mod rng;
+mod selftest;
+mod similarity;
+mod stack;
+mod streaming;
+mod tiles;
+mod wavelet;
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use background::ProcessingHandle;
+use color::ColorSpace;
+use decode::Source;
+use effects::ImageEffect;
+use encode::{EncodeOptions, OutputOptions};
+use exif::OrientationMode;
+use metadata::Metadata;
+use params::Params;
+use progress::{DeadlineProgress, NoProgress, ScaledProgress};
 
 /// Process an image using various effects
+///
+/// `params` is an optional dict of effect-specific settings (for example
+/// `{"clip_percent": 2.0}` for `auto_white_balance`); unknown keys are
+/// rejected.
+///
+/// `color_space` selects whether neighbourhood effects (`blur`, `sharpen`,
+/// `resize`, `thumbnail`, `max_megapixels`) run
+/// on gamma-encoded sRGB values (the default) or in linear light, which gives
+/// physically correct results at the cost of an extra conversion pass.
+///
+/// The output format follows the extension of `output_path`. PNG output can be
+/// tuned with `png_compression` (`"default"`, `"fast"`, `"best"`) and
+/// `png_filter` (`"none"`, `"sub"`, `"up"`, `"avg"`, `"paeth"`, `"adaptive"`);
+/// `jpeg_progressive` writes progressive rather than baseline JPEGs, and
+/// `avif_quality` (1-100, default 80) sets the quality of AVIF output, which
+/// needs the crate built with its `avif` feature. `output_color` (`"rgb8"`,
+/// `"rgba8"`, `"luma8"`, `"luma16"`) converts the result to a fixed pixel
+/// format before encoding, whatever the effect produced.
+///
+/// `output_options`, an `OutputOptions`, gathers these encoder settings in
+/// one object and adds a few of its own: an output `format` overriding the
+/// extension and the quality of JPEG output. It replaces the separate encoder
+/// arguments, which can't be combined with it.
+///
+/// With `strict`, an effect that would silently drop the input's alpha
+/// channel or reduce its bit depth (for example an 8-bit-only effect given a
+/// 16-bit image) raises `LossyConversionError` instead of writing the result.
+/// Conversions asked for through `output_color` are never considered lossy.
+///
+/// With `provenance`, PNG outputs get an `iTXt` chunk holding JSON with the
+/// input, effect name, params and color space, which `read_provenance`
+/// returns. Other formats are written without it.
+///
+/// When the effect changes nothing (`identity`, or neutral settings such as a
+/// `brightness_hsv` factor of 1), the output format matches the input's and
+/// no encoder option is set, the input file is copied as is. This avoids
+/// JPEG generation loss and keeps metadata the decoder would have dropped.
+///
+/// `cancel_event` is any object with an `is_set()` method, such as a
+/// `threading.Event`. It is checked before work starts and whenever progress
+/// is reported; once it is set, `ProcessingCancelled` is raised and the output
+/// is not written. Returning `False` from `progress_callback` cancels the same
+/// way.
+///
+/// Effects that work through the image row by row, such as `blur`, `sepia`,
+/// `invert` and `edge_detect`, report progress about every 5%. With
+/// `progress_interval`, an update is only passed on once progress has moved
+/// on by that many percent since the last one; 100% is always reported.
+///
+/// With `auto_orient="apply"` (or `True`), an EXIF orientation recorded in a
+/// JPEG, PNG or WebP input is applied before the effect runs, so the output
+/// comes out upright. With `"report"` or `"ignore"` (or `False`, the default)
+/// the pixels are processed as stored, for callers who correct orientation
+/// further down their pipeline; `"report"` still reads the orientation, as
+/// `read_orientation` would, and puts it in `stats`. The `auto_orient` effect
+/// applies it as a step of its own, for example in `process_pipeline`; an
+/// `orientation` param (1-8) given to it overrides what the file records.
+///
+/// `stats`, an optional dict, is filled in with what was found along the
+/// way: `orientation`, the value the input records (`None` when it records
+/// none) unless `auto_orient` is `"ignore"`, and `clipped_low` and
+/// `clipped_high` as `clipping` would report them for the output, measured
+/// on its pixels before encoding so that a lossy format may differ slightly,
+/// `skew_angle`, the angle in degrees that `deskew` corrected, and
+/// `auto_effect`, the name of the effect that `auto` chose.
 #[pyfunction]
+#[pyo3(signature = (
+    input_path,
+    effect_type,
+    output_path,
+    progress_callback,
+    color_space = "srgb",
+    params = None,
+    png_compression = None,
+    png_filter = None,
+    jpeg_progressive = false,
+    output_color = None,
+    strict = false,
+    provenance = false,
+    avif_quality = None,
+    cancel_event = None,
+    auto_orient = OrientationMode::Ignore,
+    progress_interval = 0.0,
+    output_options = None,
+    stats = None
+))]
+#[allow(clippy::too_many_arguments)]
 fn process_image(
     py: Python,
     input_path: String,
     effect_type: String,
     output_path: String,
     progress_callback: PyObject,
+    color_space: &str,
+    params: Option<&PyDict>,
+    png_compression: Option<&str>,
+    png_filter: Option<&str>,
+    jpeg_progressive: bool,
+    output_color: Option<&str>,
+    strict: bool,
+    provenance: bool,
+    avif_quality: Option<u8>,
+    cancel_event: Option<PyObject>,
+    auto_orient: OrientationMode,
+    progress_interval: f64,
+    output_options: Option<PyRef<OutputOptions>>,
+    stats: Option<&PyDict>,
 ) -> PyResult<String> {
+    let progress_callback =
+        progress::cancellable(py, progress_callback, cancel_event, progress_interval)?;
+    let recorded = match auto_orient {
+        OrientationMode::Ignore => None,
+        _ => Some(decode::file_orientation(&input_path)?),
+    };
+    if let (Some(stats), Some(recorded)) = (stats, recorded) {
+        stats.set_item("orientation", recorded)?;
+    }
+    let auto_orient = auto_orient == OrientationMode::Apply;
+    let orientation = recorded.flatten().filter(|&o| auto_orient && o != 1);
+    let mut encode_options = EncodeOptions::from_args(
+        png_compression,
+        png_filter,
+        jpeg_progressive,
+        output_color,
+        avif_quality,
+    )?
+    .or_output_options(output_options.as_deref())?
+    .with_source_metadata(|| {
+        Ok(input_metadata(&decode::read_bytes(&input_path)?, &effect_type, auto_orient))
+    })?;
+    if provenance {
+        encode_options.provenance =
+            Some(provenance::describe(&input_path, &effect_type, params, color_space)?);
+    }
+
+    let plain_output = png_compression.is_none()
+        && png_filter.is_none()
+        && !jpeg_progressive
+        && output_color.is_none()
+        && !provenance
+        && avif_quality.is_none()
+        && output_options.is_none()
+        && orientation.is_none();
+    if plain_output
+        && copy_if_noop(
+            py,
+            &input_path,
+            &effect_type,
+            params,
+            color_space,
+            &output_path,
+            &progress_callback,
+        )?
+    {
+        if let Some(stats) = stats {
+            let copy = py.allow_threads(|| load_image(&input_path))?;
+            set_clipping(stats, &py.allow_threads(|| analysis::clipping(&copy)))?;
+        }
+        return Ok(output_path);
+    }
+
     // Load the image
-    let img = image::open(&input_path).map_err(|e| {
-        pyo3::exceptions::PyValueError::new_err(format!("Failed to load image: {}", e))
+    let mut img = py.allow_threads(|| load_image(&input_path))?;
+    if let Some(orientation) = orientation {
+        img = exif::apply_orientation(img, orientation);
+    }
+
+    // With `auto_orient` the image is upright already, whatever the file says.
+    let source = if auto_orient { Source::Pixels } else { Source::File(&input_path) };
+    let input_color = img.color();
+    let (processed, findings) = effects::collect_findings(|| {
+        apply_effect(py, img, source, &effect_type, params, color_space, &progress_callback)
+    });
+    let processed = processed?;
+    if strict {
+        errors::check_lossless(py, &effect_type, input_color, processed.color())?;
+    }
+
+    // Save the processed image to the specified output path
+    py.allow_threads(|| encode::save(&processed, &output_path, &encode_options))?;
+    if let Some(stats) = stats {
+        let clipping = py.allow_threads(|| analysis::clipping(&encode_options.convert(&processed)));
+        set_clipping(stats, &clipping)?;
+        findings.set_items(stats)?;
+    }
+
+    Ok(output_path)
+}
+
+/// Start `process_image` on a background thread and return at once
+///
+/// Returns a `ProcessingHandle` to follow the work with: `poll()` tells
+/// whether it has finished, `progress()` gives the latest percentage,
+/// `cancel()` asks it to stop and `result(timeout=None)` waits for the output
+/// path, raising whatever error the work failed with.
+///
+/// `progress_callback` is never called from the background thread. `poll()`
+/// and `result()` call it instead, on the caller's thread, whenever progress
+/// has moved since they last did, so a Tkinter or Qt app can poll from a
+/// timer and update its widgets from the callback.
+///
+/// The other arguments are those of `process_image`; encoder settings go in
+/// `output_options`, and `stats` is filled in by the time `result()` returns.
+/// The effect and its params are checked before the work starts, so mistakes
+/// in them raise here. Decoding, encoding and the effects themselves run
+/// without the GIL, leaving the calling thread responsive.
+#[pyfunction]
+#[pyo3(signature = (
+    input_path,
+    effect_type,
+    output_path,
+    progress_callback = None,
+    color_space = "srgb",
+    params = None,
+    strict = false,
+    provenance = false,
+    auto_orient = OrientationMode::Ignore,
+    output_options = None,
+    stats = None
+))]
+#[allow(clippy::too_many_arguments)]
+fn process_image_async(
+    py: Python,
+    input_path: String,
+    effect_type: String,
+    output_path: String,
+    progress_callback: Option<PyObject>,
+    color_space: &str,
+    params: Option<&PyDict>,
+    strict: bool,
+    provenance: bool,
+    auto_orient: OrientationMode,
+    output_options: Option<Py<OutputOptions>>,
+    stats: Option<Py<PyDict>>,
+) -> PyResult<ProcessingHandle> {
+    parse_effect(&effect_type, params, color_space)?;
+    let color_space = color_space.to_string();
+    // A copy, so that the caller changing the dict can't affect the work.
+    let params: Option<Py<PyDict>> = params.map(PyDict::copy).transpose()?.map(Into::into);
+    ProcessingHandle::spawn(py, progress_callback, move |py, progress| {
+        let output_options = output_options.as_ref().map(|o| o.borrow(py));
+        process_image(
+            py,
+            input_path,
+            effect_type,
+            output_path,
+            progress,
+            &color_space,
+            params.as_ref().map(|p| p.as_ref(py)),
+            None,
+            None,
+            false,
+            None,
+            strict,
+            provenance,
+            None,
+            None,
+            auto_orient,
+            0.0,
+            output_options,
+            stats.as_ref().map(|s| s.as_ref(py)),
+        )
+    })
+}
+
+/// Process an image too large to hold in memory, `tile_size` rows at a time
+///
+/// The image is decoded, processed and written a horizontal strip at a time,
+/// so memory use stays at a few strips' worth however large it is. Only
+/// effects that work on part of an image are accepted: those that go pixel
+/// by pixel (`grayscale`, `sepia`, `invert`, `brightness`, `contrast`,
+/// `gamma`, `exposure`, `levels`, `curve` and `identity`) and `blur`,
+/// `sharpen` and `convolve`, whose strips are processed with enough of the
+/// neighbouring rows for the output to match what `process_image` writes.
+/// Others raise `InvalidParameterError`.
+///
+/// Non-interlaced PNGs and 8- or 16-bit gray or RGB(A) TIFFs, in strips or
+/// tiles, are read a strip at a time; any other input is decoded whole
+/// first, with only the processing and writing done in strips. The output
+/// must be a PNG or an uncompressed TIFF, BigTIFF when its pixels take more
+/// than 4 GiB, and no metadata is copied into it. Progress is reported after
+/// each strip, and `cancel_event` works as in `process_image`; if processing
+/// fails or is cancelled part way, the partly written output is removed.
+/// Returns `output_path`.
+#[pyfunction]
+#[pyo3(signature = (
+    input_path,
+    effect_type,
+    output_path,
+    tile_size = 1024,
+    progress_callback = None,
+    color_space = "srgb",
+    params = None,
+    cancel_event = None
+))]
+#[allow(clippy::too_many_arguments)]
+fn process_image_tiled(
+    py: Python,
+    input_path: String,
+    effect_type: &str,
+    output_path: String,
+    tile_size: u32,
+    progress_callback: Option<PyObject>,
+    color_space: &str,
+    params: Option<&PyDict>,
+    cancel_event: Option<PyObject>,
+) -> PyResult<String> {
+    if tile_size == 0 {
+        return Err(errors::invalid_parameter("tile_size", "tile_size must be positive".into()));
+    }
+    let (effect, color_space) = parse_effect(effect_type, params, color_space)?;
+    if effect.strip_overlap().is_none() {
+        let message = format!("Effect '{}' can't be applied a strip at a time", effect_type);
+        return Err(errors::invalid_parameter("effect_type", message));
+    }
+    let progress_callback = match progress_callback {
+        Some(callback) => callback,
+        None => Py::new(py, NoProgress)?.into_py(py),
+    };
+    let progress_callback = progress::cancellable(py, progress_callback, cancel_event, 0.0)?;
+    streaming::process(
+        py,
+        &input_path,
+        &output_path,
+        &effect,
+        color_space,
+        tile_size,
+        &progress_callback,
+    )?;
+    Ok(output_path)
+}
+
+/// Run a sequence of effects described by a JSON recipe
+///
+/// `recipe_json` is a JSON array of steps, each an object with an `"effect"`
+/// name, an optional `"params"` object and an optional `"color_space"`, e.g.
+/// `[{"effect": "auto_white_balance"}, {"effect": "blur", "color_space": "linear"}]`.
+/// The whole recipe is validated before processing starts and the steps run
+/// in memory, one after another. When a step fails, the exception message is
+/// prefixed with its zero-based index, which is also available as the
+/// exception's `step` attribute. `cancel_event`, `progress_interval` and
+/// `stats` work as in `process_image`, `stats` getting what the steps
+/// measured along the way. Returns `output_path`.
+#[pyfunction]
+#[pyo3(signature = (
+    input_path,
+    recipe_json,
+    output_path,
+    progress_callback,
+    cancel_event = None,
+    progress_interval = 0.0,
+    stats = None
+))]
+#[allow(clippy::too_many_arguments)]
+fn process_recipe(
+    py: Python,
+    input_path: String,
+    recipe_json: &str,
+    output_path: String,
+    progress_callback: PyObject,
+    cancel_event: Option<PyObject>,
+    progress_interval: f64,
+    stats: Option<&PyDict>,
+) -> PyResult<String> {
+    let steps = recipe::parse(py, recipe_json)?;
+    let progress_callback =
+        progress::cancellable(py, progress_callback, cancel_event, progress_interval)?;
+
+    let source = Source::File(&input_path);
+    let img = load_image(&input_path)?;
+    let (img, findings) = effects::collect_findings(|| {
+        recipe::run(py, img, &steps, || source.orientation(), &progress_callback)
+    });
+    let img = img?;
+
+    encode::save(&img, &output_path, &EncodeOptions::default())?;
+    if let Some(stats) = stats {
+        set_clipping(stats, &py.allow_threads(|| analysis::clipping(&img)))?;
+        findings.set_items(stats)?;
+    }
+    Ok(output_path)
+}
+
+/// Apply a chain of effects in one pass
+///
+/// `effects` is a list whose items are effect names, or dicts with an
+/// `"effect"` name, optional `"params"` dict and optional `"color_space"` as
+/// in recipes, e.g. `["grayscale", {"effect": "blur", "params": {"sigma": 2}},
+/// "edge_detect"]`. The image is decoded once, the effects run on it in order
+/// and the result is encoded once, with `progress_callback` covering the
+/// whole chain. Validation, errors, `cancel_event`, `progress_interval` and
+/// `stats` work as in `process_recipe`. Returns `output_path`.
+#[pyfunction]
+#[pyo3(signature = (
+    input_path,
+    effects,
+    output_path,
+    progress_callback,
+    cancel_event = None,
+    progress_interval = 0.0,
+    stats = None
+))]
+#[allow(clippy::too_many_arguments)]
+fn process_pipeline(
+    py: Python,
+    input_path: String,
+    effects: &PyList,
+    output_path: String,
+    progress_callback: PyObject,
+    cancel_event: Option<PyObject>,
+    progress_interval: f64,
+    stats: Option<&PyDict>,
+) -> PyResult<String> {
+    let steps = recipe::from_list(py, effects)?;
+    let progress_callback =
+        progress::cancellable(py, progress_callback, cancel_event, progress_interval)?;
+
+    let source = Source::File(&input_path);
+    let img = load_image(&input_path)?;
+    let (img, findings) = effects::collect_findings(|| {
+        recipe::run(py, img, &steps, || source.orientation(), &progress_callback)
+    });
+    let img = img?;
+
+    encode::save(&img, &output_path, &EncodeOptions::default())?;
+    if let Some(stats) = stats {
+        set_clipping(stats, &py.allow_threads(|| analysis::clipping(&img)))?;
+        findings.set_items(stats)?;
+    }
+    Ok(output_path)
+}
+
+/// Download an image and process it in memory
+///
+/// The download is aborted after `timeout_secs` or as soon as more than
+/// `max_bytes` have been received. All other arguments behave as in
+/// `process_image`.
+#[pyfunction]
+#[pyo3(signature = (
+    url,
+    effect_type,
+    output_path,
+    progress_callback,
+    color_space = "srgb",
+    params = None,
+    png_compression = None,
+    png_filter = None,
+    jpeg_progressive = false,
+    output_color = None,
+    strict = false,
+    provenance = false,
+    timeout_secs = 30.0,
+    max_bytes = 64 * 1024 * 1024,
+    avif_quality = None,
+    output_options = None
+))]
+#[allow(clippy::too_many_arguments)]
+fn process_url(
+    py: Python,
+    url: String,
+    effect_type: String,
+    output_path: String,
+    progress_callback: PyObject,
+    color_space: &str,
+    params: Option<&PyDict>,
+    png_compression: Option<&str>,
+    png_filter: Option<&str>,
+    jpeg_progressive: bool,
+    output_color: Option<&str>,
+    strict: bool,
+    provenance: bool,
+    timeout_secs: f64,
+    max_bytes: u64,
+    avif_quality: Option<u8>,
+    output_options: Option<PyRef<OutputOptions>>,
+) -> PyResult<String> {
+    let progress_callback = progress::cancellable(py, progress_callback, None, 0.0)?;
+    if !timeout_secs.is_finite() || timeout_secs <= 0.0 {
+        return Err(pyo3::exceptions::PyValueError::new_err("timeout_secs must be positive"));
+    }
+    let mut encode_options = EncodeOptions::from_args(
+        png_compression,
+        png_filter,
+        jpeg_progressive,
+        output_color,
+        avif_quality,
+    )?
+    .or_output_options(output_options.as_deref())?;
+    if provenance {
+        encode_options.provenance =
+            Some(provenance::describe(&url, &effect_type, params, color_space)?);
+    }
+
+    let bytes = py.allow_threads(|| {
+        fetch::fetch_bytes(&url, Duration::from_secs_f64(timeout_secs), max_bytes)
+    })
+    .map_err(|e| errors::with_path(e, &url))?;
+    let img = decode::from_memory(&bytes).map_err(|e| errors::with_path(e, &url))?;
+    let encode_options =
+        encode_options.with_source_metadata(|| Ok(input_metadata(&bytes, &effect_type, false)))?;
+
+    let input_color = img.color();
+    let processed = apply_effect(
+        py,
+        img,
+        Source::Memory(&bytes),
+        &effect_type,
+        params,
+        color_space,
+        &progress_callback,
+    )?;
+    if strict {
+        errors::check_lossless(py, &effect_type, input_color, processed.color())?;
+    }
+
+    encode::save(&processed, &output_path, &encode_options)?;
+
+    Ok(output_path)
+}
+
+/// Process an image and return the result as a base64 `data:` URI
+///
+/// `format` names the encoding by its usual file extension (`"png"`,
+/// `"jpeg"`, `"webp"`, ...) and determines the URI's MIME type, e.g.
+/// `data:image/png;base64,...`. All other arguments behave as in
+/// `process_image`.
+#[pyfunction]
+#[pyo3(signature = (
+    input_path,
+    effect_type,
+    progress_callback,
+    format = "png",
+    color_space = "srgb",
+    params = None,
+    png_compression = None,
+    png_filter = None,
+    jpeg_progressive = false,
+    output_color = None,
+    strict = false,
+    provenance = false,
+    avif_quality = None,
+    output_options = None
+))]
+#[allow(clippy::too_many_arguments)]
+fn process_to_datauri(
+    py: Python,
+    input_path: String,
+    effect_type: String,
+    progress_callback: PyObject,
+    format: &str,
+    color_space: &str,
+    params: Option<&PyDict>,
+    png_compression: Option<&str>,
+    png_filter: Option<&str>,
+    jpeg_progressive: bool,
+    output_color: Option<&str>,
+    strict: bool,
+    provenance: bool,
+    avif_quality: Option<u8>,
+    output_options: Option<PyRef<OutputOptions>>,
+) -> PyResult<String> {
+    use base64::Engine;
+
+    let progress_callback = progress::cancellable(py, progress_callback, None, 0.0)?;
+    let mut encode_options = EncodeOptions::from_args(
+        png_compression,
+        png_filter,
+        jpeg_progressive,
+        output_color,
+        avif_quality,
+    )?
+    .or_output_options(output_options.as_deref())?
+    .with_source_metadata(|| {
+        Ok(input_metadata(&decode::read_bytes(&input_path)?, &effect_type, false))
     })?;
+    let image_format = encode_options.format_or(format)?;
+    if provenance {
+        encode_options.provenance =
+            Some(provenance::describe(&input_path, &effect_type, params, color_space)?);
+    }
+
+    let img = load_image(&input_path)?;
+    let input_color = img.color();
+    let processed = apply_effect(
+        py,
+        img,
+        Source::File(&input_path),
+        &effect_type,
+        params,
+        color_space,
+        &progress_callback,
+    )?;
+    if strict {
+        errors::check_lossless(py, &effect_type, input_color, processed.color())?;
+    }
+    let bytes = encode::encode(&processed, image_format, &encode_options)?;
+
+    Ok(format!(
+        "data:{};base64,{}",
+        image_format.to_mime_type(),
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    ))
+}
+
+/// Process an encoded image held in memory and return the encoded result
+///
+/// `data` is the content of an image file in any supported format, which is
+/// detected from the bytes themselves. `format` names the output encoding as
+/// in `process_to_datauri`. The other arguments behave as in `process_image`;
+/// nothing is read from or written to disk.
+#[pyfunction]
+#[pyo3(signature = (
+    data,
+    effect_type,
+    progress_callback,
+    format = "png",
+    color_space = "srgb",
+    params = None,
+    png_compression = None,
+    png_filter = None,
+    jpeg_progressive = false,
+    output_color = None,
+    strict = false,
+    avif_quality = None,
+    output_options = None
+))]
+#[allow(clippy::too_many_arguments)]
+fn process_image_bytes(
+    py: Python,
+    data: &[u8],
+    effect_type: String,
+    progress_callback: PyObject,
+    format: &str,
+    color_space: &str,
+    params: Option<&PyDict>,
+    png_compression: Option<&str>,
+    png_filter: Option<&str>,
+    jpeg_progressive: bool,
+    output_color: Option<&str>,
+    strict: bool,
+    avif_quality: Option<u8>,
+    output_options: Option<PyRef<OutputOptions>>,
+) -> PyResult<PyObject> {
+    let progress_callback = progress::cancellable(py, progress_callback, None, 0.0)?;
+    let encode_options = EncodeOptions::from_args(
+        png_compression,
+        png_filter,
+        jpeg_progressive,
+        output_color,
+        avif_quality,
+    )?
+    .or_output_options(output_options.as_deref())?
+    .with_source_metadata(|| Ok(input_metadata(data, &effect_type, false)))?;
+    let image_format = encode_options.format_or(format)?;
 
-    let effect = ImageEffect::from_str(&effect_type)
-        .ok_or_else(|| pyo3::exceptions::PyValueError::new_err("Unknown effect type"))?;
+    let img = decode::from_memory(data)?;
+    let input_color = img.color();
+    let processed = apply_effect(
+        py,
+        img,
+        Source::Memory(data),
+        &effect_type,
+        params,
+        color_space,
+        &progress_callback,
+    )?;
+    if strict {
+        errors::check_lossless(py, &effect_type, input_color, processed.color())?;
+    }
+    let bytes = encode::encode(&processed, image_format, &encode_options)?;
+    Ok(PyBytes::new(py, &bytes).into())
+}
 
-    let processed = match effect {
-        ImageEffect::EdgeDetect => apply_edge_detection(py, img, &progress_callback)?,
-        ImageEffect::Blur => apply_blur(py, img, &progress_callback)?,
-        ImageEffect::Sharpen => apply_sharpen(py, img, &progress_callback)?,
-        ImageEffect::Grayscale => apply_grayscale(py, img, &progress_callback)?,
-        ImageEffect::Sepia => apply_sepia(py, img, &progress_callback)?,
-        ImageEffect::Invert => apply_invert(py, img, &progress_callback)?,
+/// Process a numpy array of pixels and return the result as a new array
+///
+/// `array` is a C-contiguous `uint8` array of shape `(height, width)` for
+/// gray or `(height, width, channels)` with 1 to 4 channels, laid out as in
+/// `process_raw`; any object exposing such a buffer works. The result is a
+/// `uint8` array in the same layout, sized and with channels as the effect
+/// produced them, and 2-D when it is plain gray. Returning it needs numpy.
+#[pyfunction]
+#[pyo3(signature = (array, effect_type, progress_callback, color_space = "srgb", params = None))]
+fn process_array(
+    py: Python,
+    array: &PyAny,
+    effect_type: String,
+    progress_callback: PyObject,
+    color_space: &str,
+    params: Option<&PyDict>,
+) -> PyResult<PyObject> {
+    let progress_callback = progress::cancellable(py, progress_callback, None, 0.0)?;
+    let buffer = PyBuffer::<u8>::get(array)?;
+    let (height, width, channels) = match *buffer.shape() {
+        [height, width] => (height, width, 1),
+        [height, width, channels] => (height, width, channels),
+        _ => {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "array must have shape (height, width) or (height, width, channels)",
+            ))
+        }
     };
+    if !buffer.is_c_contiguous() {
+        return Err(pyo3::exceptions::PyValueError::new_err("array must be C-contiguous"));
+    }
+    let too_large = || pyo3::exceptions::PyValueError::new_err("array is too large");
+    let width = u32::try_from(width).map_err(|_| too_large())?;
+    let height = u32::try_from(height).map_err(|_| too_large())?;
+    let channels = u8::try_from(channels).unwrap_or(u8::MAX);
+    let img = decode::from_raw(&buffer.to_vec(py)?, width, height, channels)?;
+    let processed = apply_effect(
+        py,
+        img,
+        Source::Pixels,
+        &effect_type,
+        params,
+        color_space,
+        &progress_callback,
+    )?;
 
-    // Save the processed image to the specified output path
-    processed.save(&output_path).map_err(|e| {
-        pyo3::exceptions::PyValueError::new_err(format!("Failed to save image: {}", e))
+    let packed = packed_8bit(&processed);
+    let (width, height) = (packed.width() as usize, packed.height() as usize);
+    let channels = packed.color().channel_count() as usize;
+    let shape = if channels == 1 { vec![height, width] } else { vec![height, width, channels] };
+    let data = PyByteArray::new(py, packed.as_bytes());
+    let numpy = py.import("numpy")?;
+    let flat = numpy.call_method1("frombuffer", (data, numpy.getattr("uint8")?))?;
+    Ok(flat.call_method1("reshape", (shape,))?.into())
+}
+
+/// Process an image and encode it as a JPEG no larger than `max_bytes`
+///
+/// The JPEG quality is binary-searched for the highest value, no lower than
+/// `min_quality`, whose output fits. If even `min_quality` is too large and
+/// `allow_downscale` is set, the image is shrunk in 25% steps and the search
+/// repeated; a `ValueError` is raised once that would take it below 16 pixels
+/// on a side. Returns `(jpeg_bytes, quality)`.
+#[pyfunction]
+#[pyo3(signature = (
+    input_path,
+    effect_type,
+    max_bytes,
+    progress_callback,
+    min_quality = 10,
+    allow_downscale = true,
+    color_space = "srgb",
+    params = None
+))]
+#[allow(clippy::too_many_arguments)]
+fn scale_to_fit_bytes(
+    py: Python,
+    input_path: String,
+    effect_type: String,
+    max_bytes: usize,
+    progress_callback: PyObject,
+    min_quality: u8,
+    allow_downscale: bool,
+    color_space: &str,
+    params: Option<&PyDict>,
+) -> PyResult<(PyObject, u8)> {
+    let progress_callback = progress::cancellable(py, progress_callback, None, 0.0)?;
+    if !(1..=100).contains(&min_quality) {
+        return Err(pyo3::exceptions::PyValueError::new_err(
+            "min_quality must be between 1 and 100",
+        ));
+    }
+
+    let img = load_image(&input_path)?;
+    let processed = apply_effect(
+        py,
+        img,
+        Source::File(&input_path),
+        &effect_type,
+        params,
+        color_space,
+        &progress_callback,
+    )?;
+    let (bytes, quality) = py.allow_threads(|| {
+        encode::fit_jpeg(&processed, max_bytes, min_quality, allow_downscale)
     })?;
+    Ok((PyBytes::new(py, &bytes).into(), quality))
+}
+
+/// Process an image and write the raw result into a preallocated buffer
+///
+/// The output is packed 8-bit RGB, row-major, no padding. `buffer` must be a
+/// `bytearray` with room for at least `width * height * 3` bytes of the
+/// processed image; it is never resized, so a too-small buffer raises
+/// `ValueError`. Returns the `(width, height)` that was written, which
+/// differs from the input size for geometric effects such as `crop`.
+#[pyfunction]
+#[pyo3(signature = (
+    input_path,
+    effect_type,
+    buffer,
+    progress_callback,
+    color_space = "srgb",
+    params = None
+))]
+fn process_image_into(
+    py: Python,
+    input_path: String,
+    effect_type: String,
+    buffer: &PyByteArray,
+    progress_callback: PyObject,
+    color_space: &str,
+    params: Option<&PyDict>,
+) -> PyResult<(u32, u32)> {
+    let progress_callback = progress::cancellable(py, progress_callback, None, 0.0)?;
+    let img = load_image(&input_path)?;
+    let processed = apply_effect(
+        py,
+        img,
+        Source::File(&input_path),
+        &effect_type,
+        params,
+        color_space,
+        &progress_callback,
+    )?;
+
+    let rgb = processed.to_rgb8();
+    let data = rgb.as_raw();
+    if buffer.len() < data.len() {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Output buffer holds {} bytes but the {}x{} RGB result needs {}",
+            buffer.len(),
+            rgb.width(),
+            rgb.height(),
+            data.len()
+        )));
+    }
+
+    // SAFETY: we hold the GIL and run no Python code while the slice is alive,
+    // so nothing can resize or otherwise touch the bytearray underneath us.
+    unsafe { buffer.as_bytes_mut()[..data.len()].copy_from_slice(data) };
+
+    Ok(rgb.dimensions())
+}
 
+/// Process a headerless buffer of raw pixels
+///
+/// `data` holds packed 8-bit samples, row-major with no padding, with
+/// `channels` per pixel: 1 (gray), 2 (gray and alpha), 3 (RGB) or 4 (RGBA).
+/// Its length must be exactly `width * height * channels`. The result comes
+/// back in the same packing as `(data, width, height, channels)`, where the
+/// size and channel count are those of the processed image: geometric
+/// effects change the size, and most effects turn gray input into RGB.
+/// Other arguments are as in `process_image`.
+#[pyfunction]
+#[pyo3(signature = (
+    data,
+    width,
+    height,
+    channels,
+    effect_type,
+    progress_callback,
+    color_space = "srgb",
+    params = None
+))]
+#[allow(clippy::too_many_arguments)]
+fn process_raw(
+    py: Python,
+    data: &[u8],
+    width: u32,
+    height: u32,
+    channels: u8,
+    effect_type: String,
+    progress_callback: PyObject,
+    color_space: &str,
+    params: Option<&PyDict>,
+) -> PyResult<(PyObject, u32, u32, u8)> {
+    let progress_callback = progress::cancellable(py, progress_callback, None, 0.0)?;
+    let img = decode::from_raw(data, width, height, channels)?;
+    let processed = apply_effect(
+        py,
+        img,
+        Source::Pixels,
+        &effect_type,
+        params,
+        color_space,
+        &progress_callback,
+    )?;
+
+    let raw = packed_8bit(&processed);
+    let channels = raw.color().channel_count();
+    Ok((PyBytes::new(py, raw.as_bytes()).into(), raw.width(), raw.height(), channels))
+}
+
+/// `image` reduced to 8 bits per sample, keeping whether it has color and
+/// alpha.
+fn packed_8bit(image: &DynamicImage) -> DynamicImage {
+    match (image.color().has_color(), image.color().has_alpha()) {
+        (false, false) => DynamicImage::ImageLuma8(image.to_luma8()),
+        (false, true) => DynamicImage::ImageLumaA8(image.to_luma_alpha8()),
+        (true, false) => DynamicImage::ImageRgb8(image.to_rgb8()),
+        (true, true) => DynamicImage::ImageRgba8(image.to_rgba8()),
+    }
+}
+
+/// Write a thumbnail of an image
+///
+/// The image is scaled down, keeping its aspect ratio, until neither side
+/// is longer than `max_size`; smaller images are written at their own size.
+/// With `respect_exif` (the default), an EXIF orientation recorded in a JPEG
+/// or PNG input is applied first, so photos taken with the camera on its side
+/// come out upright. `progress_callback` is optional and behaves as in
+/// `process_image`. Returns `output_path`.
+#[pyfunction]
+#[pyo3(signature = (
+    input_path,
+    output_path,
+    max_size,
+    respect_exif = true,
+    progress_callback = None
+))]
+fn make_thumbnail(
+    py: Python,
+    input_path: String,
+    output_path: String,
+    max_size: u32,
+    respect_exif: bool,
+    progress_callback: Option<PyObject>,
+) -> PyResult<String> {
+    if max_size == 0 {
+        return Err(pyo3::exceptions::PyValueError::new_err("max_size must be positive"));
+    }
+    let progress_callback = match progress_callback {
+        Some(callback) => progress::cancellable(py, callback, None, 0.0)?,
+        None => Py::new(py, NoProgress)?.into_py(py),
+    };
+
+    let mut img = load_image(&input_path)?;
+    if respect_exif {
+        if let Some(orientation) = decode::file_orientation(&input_path)? {
+            img = exif::apply_orientation(img, orientation);
+        }
+    }
+    let thumbnail = ImageEffect::Thumbnail {
+        size: max_size,
+        filter: resize::Filter::Area,
+        only_if_larger: true,
+    };
+    let img = thumbnail.apply(py, img, ColorSpace::Srgb, &progress_callback)?;
+    encode::save(&img, &output_path, &EncodeOptions::default())?;
     Ok(output_path)
 }
 
-fn apply_edge_detection(
+/// Process an image once and save it at several sizes
+///
+/// The input is decoded and `effect_type` applied a single time, then the
+/// result is resized to each entry of `sizes` with `filter` (see the `resize`
+/// effect for the names). An entry is a `(width, height)` pair where either
+/// side may be `None` to keep the aspect ratio, as with the `resize` effect.
+/// Each output path is `output_pattern` with `{width}` and `{height}`
+/// replaced by that output's actual dimensions, e.g. `"photo-{width}w.jpg"`.
+/// `cancel_event` and `progress_interval` work as in `process_image`; outputs
+/// already written when it is set are left in place. Returns the paths written, in the order of
+/// `sizes`.
+#[pyfunction]
+#[pyo3(signature = (
+    input_path,
+    sizes,
+    output_pattern,
+    effect_type,
+    progress_callback,
+    color_space = "srgb",
+    params = None,
+    filter = "lanczos3",
+    cancel_event = None,
+    progress_interval = 0.0
+))]
+#[allow(clippy::too_many_arguments)]
+fn process_multisize(
     py: Python,
-    image: DynamicImage,
+    input_path: String,
+    sizes: Vec<(Option<u32>, Option<u32>)>,
+    output_pattern: String,
+    effect_type: String,
+    progress_callback: PyObject,
+    color_space: &str,
+    params: Option<&PyDict>,
+    filter: &str,
+    cancel_event: Option<PyObject>,
+    progress_interval: f64,
+) -> PyResult<Vec<String>> {
+    let value_error = |message: String| pyo3::exceptions::PyValueError::new_err(message);
+    let filter = resize::Filter::from_str(filter).ok_or_else(|| {
+        value_error(format!(
+            "Unknown filter '{}', expected one of: {}",
+            filter,
+            resize::Filter::NAMES
+        ))
+    })?;
+    if sizes.is_empty() {
+        return Err(value_error("sizes must not be empty".to_string()));
+    }
+    for &(width, height) in &sizes {
+        if (width.is_none() && height.is_none()) || width == Some(0) || height == Some(0) {
+            return Err(value_error(format!(
+                "Invalid size ({:?}, {:?}): give a positive width, height or both",
+                width, height
+            )));
+        }
+    }
+    let has_placeholder = output_pattern.contains("{width}") || output_pattern.contains("{height}");
+    if sizes.len() > 1 && !has_placeholder {
+        return Err(value_error(
+            "output_pattern must contain '{width}' or '{height}' when there are several sizes"
+                .to_string(),
+        ));
+    }
+    let space = ColorSpace::parse(color_space)?;
+
+    let img = load_image(&input_path)?;
+    let progress_callback =
+        progress::cancellable(py, progress_callback, cancel_event, progress_interval)?;
+    // The effect gets the first half of the progress range and the resizes
+    // share the second.
+    let progress = ScaledProgress::new(progress_callback.clone_ref(py), 0.0, 50.0);
+    let progress = Py::new(py, progress)?.into_py(py);
+    let processed = apply_effect(
+        py,
+        img,
+        Source::File(&input_path),
+        &effect_type,
+        params,
+        color_space,
+        &progress,
+    )?;
+
+    let span = 50.0 / sizes.len() as f64;
+    let mut paths = Vec::with_capacity(sizes.len());
+    for (index, &(width, height)) in sizes.iter().enumerate() {
+        let start = 50.0 + index as f64 * span;
+        let progress = ScaledProgress::new(progress_callback.clone_ref(py), start, span);
+        let progress = Py::new(py, progress)?.into_py(py);
+        let resized = ImageEffect::Resize { width, height, filter, only_if_larger: false }.apply(
+            py,
+            processed.clone(),
+            space,
+            &progress,
+        )?;
+
+        let path = output_pattern
+            .replace("{width}", &resized.width().to_string())
+            .replace("{height}", &resized.height().to_string());
+        encode::save(&resized, &path, &EncodeOptions::default())?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Process every image in a directory, mirroring its layout under `output_dir`
+///
+/// Files are picked up by extension and written under the same relative path
+/// and name; with `recursive` subdirectories are processed too. `pattern`, a
+/// glob such as `"*.jpg"`, limits the batch to files whose names match it;
+/// `*` stands for any run of characters, `?` for any one, and letters match
+/// either case. Other files are skipped. `progress_callback` receives overall
+/// progress across the whole directory; by default every file counts equally,
+/// while `weight_by_size` weights each by its size on disk. With
+/// `skip_existing`, files whose output already exists are left alone, so an
+/// interrupted run can be resumed.
+///
+/// `effect_type` is an effect name, taking `params` and `color_space` as in
+/// `process_image`, or a list of effects as in `process_pipeline`, whose
+/// steps carry their own params and color spaces.
+///
+/// `name_template` names each output instead of the input's own name, e.g.
+/// `"{stem}_{effect}.{ext}"`: `{stem}` is the input's name without its
+/// extension, `{ext}` that extension and `{effect}` the effect name, or the
+/// step names joined by `_` for a list. Outputs stay in the input's relative
+/// directory, and the extension the template gives decides the format.
+///
+/// A file that can't be read, processed or written doesn't stop the batch.
+/// Returns a dict with the number of `processed` and `skipped` files, where
+/// `skipped` counts unsupported and unmatched files as well as existing
+/// outputs, the `outputs` written, the list of `failed` input paths and
+/// `errors`, mapping each failed input path to the exception it failed with.
+///
+/// Outputs are encoded and written in the background while the next file is
+/// processed, with at most one write per rayon thread in flight at a time.
+/// The function returns once every output is on disk.
+///
+/// `cancel_event` and `progress_interval` work as in `process_image`. Outputs
+/// finished before cancelling are kept, so a cancelled run can be resumed
+/// with `skip_existing`.
+///
+/// With `per_image_timeout_ms`, an image still being decoded or processed
+/// after that long is abandoned without output and the batch moves on. The
+/// limit is checked at every progress report and between the rows or bands
+/// an effect works through, so only a step that can't be divided, such as a
+/// single resize or a decode, runs to its end first. Abandoned images are
+/// listed under `failed`, with a `TimeoutError`.
+#[pyfunction]
+#[pyo3(signature = (
+    input_dir,
+    effect_type,
+    output_dir,
+    recursive,
+    progress_callback,
+    color_space = "srgb",
+    params = None,
+    weight_by_size = false,
+    skip_existing = false,
+    cancel_event = None,
+    per_image_timeout_ms = None,
+    progress_interval = 0.0,
+    pattern = None,
+    name_template = None
+))]
+#[allow(clippy::too_many_arguments)]
+fn process_directory(
+    py: Python,
+    input_dir: String,
+    effect_type: &PyAny,
+    output_dir: String,
+    recursive: bool,
+    progress_callback: PyObject,
+    color_space: &str,
+    params: Option<&PyDict>,
+    weight_by_size: bool,
+    skip_existing: bool,
+    cancel_event: Option<PyObject>,
+    per_image_timeout_ms: Option<u64>,
+    progress_interval: f64,
+    pattern: Option<&str>,
+    name_template: Option<&str>,
+) -> PyResult<PyObject> {
+    let io_error = |e: std::io::Error| {
+        pyo3::exceptions::PyValueError::new_err(format!("Failed to read directory: {}", e))
+    };
+    // Reject a bad effect or template up front rather than after walking the tree.
+    let pipeline = effect_type.downcast::<PyList>().ok();
+    let steps = match pipeline {
+        Some(_) if params.is_some() => {
+            return Err(errors::invalid_parameter(
+                "params",
+                "params can't be combined with a list of effects; give each step its own"
+                    .to_string(),
+            ))
+        }
+        Some(list) => recipe::from_list(py, list)?,
+        None => {
+            let name: String = effect_type.extract()?;
+            let (effect, color_space) = parse_effect(&name, params, color_space)?;
+            vec![recipe::Step { name, effect, color_space }]
+        }
+    };
+    let effect_name = steps.iter().map(|step| step.name.as_str()).collect::<Vec<_>>().join("_");
+    if let Some(template) = name_template {
+        batch::check_template(template)?;
+    }
+
+    let input_dir = Path::new(&input_dir);
+    let output_dir = Path::new(&output_dir);
+    std::fs::create_dir_all(output_dir).map_err(io_error)?;
+    let exclude = output_dir.canonicalize().map_err(io_error)?;
+    let listing =
+        batch::list_images(input_dir, recursive, pattern, Some(&exclude)).map_err(io_error)?;
+
+    let progress_callback =
+        progress::cancellable(py, progress_callback, cancel_event, progress_interval)?;
+    let weights = batch::progress_weights(&listing.images, weight_by_size);
+    let writes = batch::WriteQueue::new(rayon::current_num_threads());
+    let mut start = 0.0;
+    let mut skipped = listing.skipped;
+    // Outputs queued for writing and the inputs they came from.
+    let mut outputs = Vec::new();
+    let mut failures = Vec::new();
+    for (path, span) in listing.images.iter().zip(weights) {
+        let relative = path.strip_prefix(input_dir).unwrap_or(path);
+        let mut target = output_dir.join(relative);
+        if let Some(template) = name_template {
+            target.set_file_name(batch::render_template(template, path, &effect_name));
+        }
+        if skip_existing && target.exists() {
+            start += span;
+            progress_callback.call1(py, (start.round() as u32,))?;
+            skipped += 1;
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent).map_err(io_error)?;
+        }
+
+        let progress = ScaledProgress::new(progress_callback.clone_ref(py), start, span);
+        let progress = Py::new(py, progress)?.into_py(py);
+        start += span;
+        let deadline = per_image_timeout_ms.map(|ms| Instant::now() + Duration::from_millis(ms));
+        let progress = match deadline {
+            Some(deadline) => Py::new(py, DeadlineProgress::new(progress, deadline))?.into_py(py),
+            None => progress,
+        };
+        // Reporting the start once decoding is done checks the deadline there too.
+        let path_str = path.to_string_lossy();
+        let result = load_image(&path_str).and_then(|img| {
+            progress.call1(py, (0,))?;
+            let orientation = || decode::file_orientation(&path_str);
+            if pipeline.is_some() {
+                return recipe::run(py, img, &steps, orientation, &progress);
+            }
+            let step = &steps[0];
+            let resolved = step.effect.resolve_orientation(orientation)?;
+            resolved.as_ref().unwrap_or(&step.effect).apply(py, img, step.color_space, &progress)
+        });
+        match result {
+            Ok(output) => {
+                let target = target.to_string_lossy().into_owned();
+                writes.push(py, output, target.clone(), &EncodeOptions::default());
+                outputs.push((target, path_str.into_owned()));
+            }
+            // Errors about the file itself; cancelling, or a failing progress
+            // callback, stops the batch.
+            Err(e)
+                if e.is_instance_of::<pyo3::exceptions::PyValueError>(py)
+                    || e.is_instance_of::<PyTimeoutError>(py)
+                        && deadline.is_some_and(|deadline| Instant::now() >= deadline) =>
+            {
+                progress_callback.call1(py, (start.round() as u32,))?;
+                failures.push((path_str.into_owned(), e));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    for (target, e) in writes.finish(py) {
+        if let Some(index) = outputs.iter().position(|(output, _)| *output == target) {
+            failures.push((outputs.remove(index).1, e));
+        }
+    }
+    failures.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let errors = PyDict::new(py);
+    for (path, e) in &failures {
+        errors.set_item(path, e.value(py))?;
+    }
+    let dict = PyDict::new(py);
+    dict.set_item("processed", outputs.len())?;
+    dict.set_item("skipped", skipped)?;
+    dict.set_item("outputs", outputs.into_iter().map(|(output, _)| output).collect::<Vec<_>>())?;
+    dict.set_item("failed", failures.iter().map(|(path, _)| path).collect::<Vec<_>>())?;
+    dict.set_item("errors", errors)?;
+    Ok(dict.into())
+}
+
+/// Process many images in parallel on a pool of worker threads
+///
+/// Each job is a dict with `input_path`, `effect` and `output_path`, plus
+/// optional `params` and `color_space` as in `process_image`. Every job is
+/// validated before any starts; the error for an invalid one is prefixed
+/// with its index. `num_threads` sets the pool size, defaulting to one thread per
+/// core. The GIL is released while the batch runs, so other Python threads
+/// keep going, and `progress_callback` is called from the workers with the
+/// percentage of jobs finished.
+///
+/// A job that fails doesn't stop the rest. Returns a list with an entry per
+/// job, in order: `None` if it succeeded, otherwise the error message.
+///
+/// `cancel_event` and `progress_interval` work as in `process_image`. Once
+/// the batch is cancelled, or `progress_callback` raises, no further job is
+/// started and the error is raised when the jobs already running are done;
+/// their outputs are kept.
+#[pyfunction]
+#[pyo3(signature = (
+    jobs,
+    progress_callback,
+    num_threads = 0,
+    cancel_event = None,
+    progress_interval = 0.0
+))]
+fn process_batch(
+    py: Python,
+    jobs: Vec<&PyDict>,
+    progress_callback: PyObject,
+    num_threads: usize,
+    cancel_event: Option<PyObject>,
+    progress_interval: f64,
+) -> PyResult<Vec<Option<String>>> {
+    let progress_callback =
+        progress::cancellable(py, progress_callback, cancel_event, progress_interval)?;
+    let jobs = jobs
+        .into_iter()
+        .enumerate()
+        .map(|(index, job)| {
+            batch::Job::from_dict(job).inspect_err(|e| {
+                let value = e.value(py);
+                let _ = value.setattr("args", (format!("Job {}: {}", index, value),));
+            })
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+
+    let results = batch::run_jobs(py, &jobs, num_threads, &progress_callback)?;
+    Ok(results.into_iter().map(|result| result.err().map(|e| e.value(py).to_string())).collect())
+}
+
+/// Write each channel of an image to its own grayscale PNG
+///
+/// Produces `{output_prefix}_r.png`, `_g.png` and `_b.png`, plus `_a.png` when
+/// the image has alpha. Grayscale inputs are treated as RGB, giving three
+/// identical files. Returns the paths written, in channel order.
+#[pyfunction]
+fn split_channels(input_path: String, output_prefix: String) -> PyResult<Vec<String>> {
+    let img = load_image(&input_path)?;
+    channels::split(&img)
+        .iter()
+        .zip(channels::SUFFIXES)
+        .map(|(channel, suffix)| {
+            let path = format!("{}_{}.png", output_prefix, suffix);
+            encode::save(channel, &path, &EncodeOptions::default())?;
+            Ok(path)
+        })
+        .collect()
+}
+
+/// Combine separate grayscale images into one RGB or RGBA image
+///
+/// Each input supplies one channel and must have the same dimensions as the
+/// others; color inputs are reduced to luma first. Passing `a_path` adds an
+/// alpha channel. Returns `output_path`.
+#[pyfunction]
+#[pyo3(signature = (r_path, g_path, b_path, a_path, output_path))]
+fn merge_channels(
+    r_path: String,
+    g_path: String,
+    b_path: String,
+    a_path: Option<String>,
+    output_path: String,
+) -> PyResult<String> {
+    let paths = [Some(r_path), Some(g_path), Some(b_path), a_path];
+    let planes = paths
+        .iter()
+        .flatten()
+        .map(|path| load_image(path))
+        .collect::<PyResult<Vec<_>>>()?;
+
+    let (width, height) = (planes[0].width(), planes[0].height());
+    for (plane, suffix) in planes.iter().zip(channels::SUFFIXES) {
+        if (plane.width(), plane.height()) != (width, height) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Channel '{}' is {}x{} but channel 'r' is {}x{}",
+                suffix,
+                plane.width(),
+                plane.height(),
+                width,
+                height
+            )));
+        }
+    }
+
+    encode::save(&channels::merge(&planes), &output_path, &EncodeOptions::default())?;
+    Ok(output_path)
+}
+
+/// Combine several exposures of the same scene into one, reducing noise
+///
+/// The frames must already be aligned and share dimensions. `mode` is
+/// `"mean"` or `"median"`; the median is slower but ignores things that
+/// appear in only a few frames. Returns `output_path`.
+#[pyfunction]
+fn stack_images(
+    py: Python,
+    paths: Vec<String>,
+    mode: &str,
+    output_path: String,
+) -> PyResult<String> {
+    let mode = stack::StackMode::from_str(mode).ok_or_else(|| {
+        pyo3::exceptions::PyValueError::new_err(format!(
+            "Unknown stack mode '{}', expected one of: mean, median",
+            mode
+        ))
+    })?;
+    if paths.is_empty() {
+        return Err(pyo3::exceptions::PyValueError::new_err("paths must not be empty"));
+    }
+
+    let frames = paths.iter().map(|path| load_image(path)).collect::<PyResult<Vec<_>>>()?;
+    let (width, height) = (frames[0].width(), frames[0].height());
+    for (frame, path) in frames.iter().zip(&paths) {
+        if (frame.width(), frame.height()) != (width, height) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "'{}' is {}x{} but '{}' is {}x{}",
+                path,
+                frame.width(),
+                frame.height(),
+                paths[0],
+                width,
+                height
+            )));
+        }
+    }
+
+    let stacked = py.allow_threads(|| stack::stack(&frames, mode));
+    encode::save(&stacked, &output_path, &EncodeOptions::default())?;
+    Ok(output_path)
+}
+
+/// Match an image's colors and tones to a reference image
+///
+/// Each of the source's red, green and blue channels is remapped so that
+/// its histogram follows the reference's, for consistent grading across a
+/// set of photos. The two images may differ in size. Returns `output_path`.
+#[pyfunction]
+fn match_histogram(
+    py: Python,
+    source_path: String,
+    reference_path: String,
+    output_path: String,
+) -> PyResult<String> {
+    let source = load_image(&source_path)?;
+    let reference = load_image(&reference_path)?;
+    let matched = py.allow_threads(|| histmatch::match_histogram(&source, &reference));
+    encode::save(&matched, &output_path, &EncodeOptions::default())?;
+    Ok(output_path)
+}
+
+/// Tile several images into one grid image, such as a contact sheet
+///
+/// Images are placed row by row, `cols` to a row, each in a `cell_width` x
+/// `cell_height` cell. With `fit="fit"` (the default) an image is scaled to
+/// fit inside its cell and centered on `background`; with `"cover"` it is
+/// scaled to fill the cell and the overhang is cropped evenly. `spacing`
+/// pixels of background separate neighbouring cells. The result has alpha
+/// when `background`, an `(r, g, b)` or `(r, g, b, a)` tuple, is not opaque
+/// or any input has alpha. Returns `output_path`.
+#[pyfunction]
+#[pyo3(signature = (
+    paths,
+    cols,
+    cell_width,
+    cell_height,
+    output_path,
+    fit = "fit",
+    background = None,
+    spacing = 0
+))]
+#[allow(clippy::too_many_arguments)]
+fn montage(
+    py: Python,
+    paths: Vec<String>,
+    cols: u32,
+    cell_width: u32,
+    cell_height: u32,
+    output_path: String,
+    fit: &str,
+    background: Option<Vec<u8>>,
+    spacing: u32,
+) -> PyResult<String> {
+    let value_error = |message: &str| pyo3::exceptions::PyValueError::new_err(message.to_string());
+    let fit = grid::CellFit::from_str(fit).ok_or_else(|| {
+        value_error(&format!("Unknown fit '{}', expected one of: fit, cover", fit))
+    })?;
+    let background = match background.as_deref() {
+        None => [255, 255, 255, 255],
+        Some(&[r, g, b]) => [r, g, b, 255],
+        Some(&[r, g, b, a]) => [r, g, b, a],
+        Some(_) => return Err(value_error("background must be an (r, g, b) or (r, g, b, a) tuple")),
+    };
+    if paths.is_empty() {
+        return Err(value_error("paths must not be empty"));
+    }
+    if cols == 0 || cell_width == 0 || cell_height == 0 {
+        return Err(value_error("cols, cell_width and cell_height must be positive"));
+    }
+
+    let images = paths.iter().map(|path| load_image(path)).collect::<PyResult<Vec<_>>>()?;
+    let has_alpha = background[3] < 255 || images.iter().any(|img| img.color().has_alpha());
+    let layout = grid::Layout { cols, cell_width, cell_height, spacing, fit, background };
+    let sheet = DynamicImage::ImageRgba8(py.allow_threads(|| grid::montage(&images, &layout)));
+    let sheet = if has_alpha { sheet } else { DynamicImage::ImageRgb8(sheet.to_rgb8()) };
+    encode::save(&sheet, &output_path, &EncodeOptions::default())?;
+    Ok(output_path)
+}
+
+/// Blend an overlay image onto a base image and save the result
+///
+/// `mode` is one of `"normal"`, `"multiply"`, `"screen"` or `"overlay"`, and
+/// `opacity` (0.0-1.0) scales how strongly the overlay shows; the overlay's
+/// own alpha is honoured too. The two images must be the same size unless
+/// `resize_overlay` is set, in which case the overlay is stretched to fit the
+/// base. Returns `output_path`.
+#[pyfunction]
+#[pyo3(signature = (base_path, overlay_path, mode, opacity, output_path, resize_overlay = false))]
+fn blend_images(
+    base_path: String,
+    overlay_path: String,
+    mode: &str,
+    opacity: f32,
+    output_path: String,
+    resize_overlay: bool,
+) -> PyResult<String> {
+    let mode = blend::BlendMode::from_str(mode).ok_or_else(|| {
+        pyo3::exceptions::PyValueError::new_err(format!(
+            "Unknown blend mode '{}', expected one of: {}",
+            mode,
+            blend::BlendMode::NAMES
+        ))
+    })?;
+    if !(0.0..=1.0).contains(&opacity) {
+        return Err(pyo3::exceptions::PyValueError::new_err("opacity must be between 0 and 1"));
+    }
+
+    let base = load_image(&base_path)?;
+    let overlay = load_image(&overlay_path)?;
+    let (width, height) = (base.width(), base.height());
+    let overlay = if overlay.width() == width && overlay.height() == height {
+        overlay.to_rgba8()
+    } else if resize_overlay {
+        blend::fit(&overlay, width, height)
+    } else {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Overlay is {}x{} but the base image is {}x{}; pass resize_overlay=True to stretch it",
+            overlay.width(),
+            overlay.height(),
+            width,
+            height
+        )));
+    };
+
+    let blended = blend::blend(&base, &overlay, mode, opacity);
+    encode::save(&blended, &output_path, &EncodeOptions::default())?;
+    Ok(output_path)
+}
+
+/// Composite an overlay image onto a base image and save the result
+///
+/// The overlay keeps its size and is placed with its top-left corner at
+/// (`x`, `y`) in the base; it may reach past the edges, which clips it.
+/// `blend_mode` is one of `"normal"`, `"multiply"`, `"screen"` or
+/// `"overlay"`. The overlay's alpha, scaled by `opacity` (0.0-1.0), decides
+/// how much of it shows, and a base with alpha of its own is composited
+/// over rather than kept as is. Returns `output_path`.
+///
+/// The same operation is available as the `overlay` effect, with params
+/// `overlay_path`, `x`, `y`, `opacity` and `blend_mode`, for use in
+/// `process_directory`, pipelines and recipes; there the overlay is read once
+/// however many images it is applied to.
+#[pyfunction]
+#[pyo3(signature = (base_path, overlay_path, x, y, opacity, blend_mode, output_path))]
+#[allow(clippy::too_many_arguments)]
+fn overlay(
+    py: Python,
+    base_path: String,
+    overlay_path: String,
+    x: i64,
+    y: i64,
+    opacity: f32,
+    blend_mode: &str,
+    output_path: String,
+) -> PyResult<String> {
+    let params = [
+        ("overlay_path", overlay_path.into_py(py)),
+        ("x", x.into_py(py)),
+        ("y", y.into_py(py)),
+        ("opacity", opacity.into_py(py)),
+        ("blend_mode", blend_mode.into_py(py)),
+    ]
+    .into_py_dict(py);
+    let img = load_image(&base_path)?;
+    let composited = apply_effect(
+        py,
+        img,
+        Source::File(&base_path),
+        "overlay",
+        Some(params),
+        "srgb",
+        &Py::new(py, NoProgress)?.into_py(py),
+    )?;
+    encode::save(&composited, &output_path, &EncodeOptions::default())?;
+    Ok(output_path)
+}
+
+/// Compute luminance and RGB histograms of an image
+///
+/// Returns a dict with `luma`, `r`, `g` and `b` keys, each a list of 256 counts.
+/// `region`, an optional `(x, y, width, height)`, limits the counts to that
+/// part of the image.
+#[pyfunction]
+#[pyo3(signature = (path, region = None))]
+fn histogram(py: Python, path: String, region: Option<Region>) -> PyResult<PyObject> {
+    let img = load_region(py, &path, region)?;
+    let hist = analysis::histogram(&img);
+
+    let dict = PyDict::new(py);
+    dict.set_item("luma", hist.luma.to_vec())?;
+    dict.set_item("r", hist.red.to_vec())?;
+    dict.set_item("g", hist.green.to_vec())?;
+    dict.set_item("b", hist.blue.to_vec())?;
+    Ok(dict.into())
+}
+
+/// Measure how much of an image is clipped to black or white
+///
+/// Returns a dict with `clipped_low` and `clipped_high`, each an `(r, g, b)`
+/// tuple of the fraction of pixels at 0 and at 255 in that channel, from 0.0
+/// to 1.0. Run it on an effect's output to flag blown highlights or crushed
+/// shadows; `process_image` reports the same through its `stats` dict
+/// without reading the output again. 16-bit images are measured after
+/// reduction to 8 bits, as in `histogram`, and `region` works as it does
+/// there.
+#[pyfunction]
+#[pyo3(signature = (path, region = None))]
+fn clipping(py: Python, path: String, region: Option<Region>) -> PyResult<PyObject> {
+    let img = load_region(py, &path, region)?;
+    let stats = py.allow_threads(|| analysis::clipping(&img));
+
+    let dict = PyDict::new(py);
+    set_clipping(dict, &stats)?;
+    Ok(dict.into())
+}
+
+/// Put the `clipped_low` and `clipped_high` tuples of `clipping` in `dict`.
+fn set_clipping(dict: &PyDict, clipping: &analysis::Clipping) -> PyResult<()> {
+    let [r, g, b] = clipping.clipped_low;
+    dict.set_item("clipped_low", (r, g, b))?;
+    let [r, g, b] = clipping.clipped_high;
+    dict.set_item("clipped_high", (r, g, b))
+}
+
+/// Find the `n` dominant colors of an image
+///
+/// Returns a list of `(r, g, b, coverage)` tuples, where `coverage` is the
+/// fraction of the image closest to that color, sorted largest first.
+/// `region` works as in `histogram`.
+#[pyfunction]
+#[pyo3(signature = (path, n, region = None))]
+fn dominant_colors(
+    py: Python,
+    path: String,
+    n: u8,
+    region: Option<Region>,
+) -> PyResult<Vec<(u8, u8, u8, f32)>> {
+    if n == 0 {
+        return Err(pyo3::exceptions::PyValueError::new_err("n must be positive"));
+    }
+    let img = load_region(py, &path, region)?;
+    Ok(analysis::dominant_colors(&img, n as usize)
+        .into_iter()
+        .map(|([r, g, b], coverage)| (r, g, b, coverage))
+        .collect())
+}
+
+/// Read back the provenance JSON written with `provenance=True`
+///
+/// Returns `None` for files that carry no provenance, including non-PNGs.
+#[pyfunction]
+fn read_provenance(path: String) -> PyResult<Option<String>> {
+    Ok(provenance::read(&decode::read_bytes(&path)?))
+}
+
+/// Read the EXIF orientation of a JPEG, PNG or WebP without applying it
+///
+/// Returns the tag's value, 1 (upright) to 8, or `None` when the file records
+/// no orientation. `process_image(..., auto_orient=True)`, the `auto_orient`
+/// effect and `make_thumbnail` apply it.
+#[pyfunction]
+fn read_orientation(path: String) -> PyResult<Option<u16>> {
+    decode::file_orientation(&path)
+}
+
+/// Describe an image file without decoding its pixels
+///
+/// Returns a dict with `width`, `height`, `format` (such as `"png"` or
+/// `"jpeg"`, detected from the contents), `color_type` (named as in
+/// `Image.mode`, the pixel format the file decodes to), `bit_depth` (bits
+/// per channel), `channels`, `icc_profile` (the embedded color profile as
+/// bytes, or `None`) and `exif`, a dict of the common EXIF fields such as
+/// `Make`, `Model`, `Orientation`, `DateTimeOriginal`, `ExposureTime` and
+/// `GPSLatitude` by tag name. Numeric fields holding one value are plain
+/// numbers, others tuples. Only the headers of PNG, JPEG, GIF, WebP, TIFF and
+/// BMP files are read; other formats are decoded to find out.
+#[pyfunction]
+fn inspect(py: Python, path: String) -> PyResult<PyObject> {
+    let bytes = decode::read_bytes(&path)?;
+    let header = decode::header(&bytes).map_err(|e| errors::with_path(e, &path))?;
+    let color = header.color;
+    let exif = PyDict::new(py);
+    for (name, value) in decode::exif_block(&bytes).map(exif::fields).unwrap_or_default() {
+        let value = match value {
+            exif::Value::Text(text) => text.into_py(py),
+            exif::Value::Bytes(bytes) => PyBytes::new(py, &bytes).into(),
+            exif::Value::Integers(v) if v.len() == 1 => v[0].into_py(py),
+            exif::Value::Integers(v) => pyo3::types::PyTuple::new(py, v).into(),
+            exif::Value::Numbers(v) if v.len() == 1 => v[0].into_py(py),
+            exif::Value::Numbers(v) => pyo3::types::PyTuple::new(py, v).into(),
+        };
+        exif.set_item(name, value)?;
+    }
+
+    let info = PyDict::new(py);
+    info.set_item("width", header.width)?;
+    info.set_item("height", header.height)?;
+    info.set_item("format", format!("{:?}", header.format).to_lowercase())?;
+    info.set_item("color_type", format!("{:?}", color))?;
+    info.set_item("bit_depth", color.bits_per_pixel() / u16::from(color.channel_count()))?;
+    info.set_item("channels", color.channel_count())?;
+    info.set_item("icc_profile", header.icc_profile.map(|icc| PyBytes::new(py, &icc)))?;
+    info.set_item("exif", exif)?;
+    Ok(info.into())
+}
+
+/// Estimate how blurry an image is
+///
+/// Returns the variance of the Laplacian of the image's luma; lower values
+/// mean blurrier. The scale depends on content and resolution, but as a rough
+/// guide values below about 100 usually indicate a soft or out-of-focus photo
+/// and values in the high hundreds or above a sharp one. Calibrate a cut-off
+/// against a sample of your own images. `region` works as in `histogram`.
+#[pyfunction]
+#[pyo3(signature = (path, region = None))]
+fn blurriness(py: Python, path: String, region: Option<Region>) -> PyResult<f64> {
+    let img = load_region(py, &path, region)?;
+    Ok(analysis::laplacian_variance(&img))
+}
+
+/// Measure how empty a scanned page is
+///
+/// Returns the fraction of pixels, from 0.0 to 1.0, whose brightness is
+/// within `threshold` levels (0-255) of the page's background, taken as the
+/// median brightness so tinted paper counts too. Blank pages come out close
+/// to 1.0; pick a cut-off from a sample of your own scans, since dust and
+/// scanner noise lower the score a little. `region` works as in `histogram`.
+#[pyfunction]
+#[pyo3(signature = (path, threshold = 32, region = None))]
+fn blankness(py: Python, path: String, threshold: u8, region: Option<Region>) -> PyResult<f64> {
+    let img = load_region(py, &path, region)?;
+    Ok(py.allow_threads(|| analysis::blankness(&img, threshold)))
+}
+
+/// Name the effect that the `auto` effect would pick for an image
+///
+/// One of `"deskew"` (for what looks like a portrait scanned document),
+/// `"auto_white_balance"` (a color cast), `"normalize_exposure"` (too dark or
+/// too bright), `"curve"` (a narrow tonal range, stretched to full range) or
+/// `"identity"` when nothing stands out. What an `auto` run actually applied,
+/// after any earlier pipeline step or `auto_orient`, comes back in `stats`.
+#[pyfunction]
+fn choose_auto_effect(py: Python, path: String) -> PyResult<&'static str> {
+    let img = load_image(&path)?;
+    Ok(py.allow_threads(|| auto::choose(&img).0))
+}
+
+/// Estimate the skew of a scanned page, in degrees
+///
+/// Returns how far the text lines are rotated clockwise, within `max_angle`
+/// either way; the `deskew` effect rotates by the negated angle to correct
+/// it. Use this to skip pages whose skew is negligible; the angle a `deskew`
+/// run corrected comes back in the `stats` of `process_image` and the
+/// pipeline calls. `region` works as in `histogram`.
+#[pyfunction]
+#[pyo3(signature = (path, max_angle = 10.0, region = None))]
+fn detect_skew(py: Python, path: String, max_angle: f32, region: Option<Region>) -> PyResult<f32> {
+    if !(max_angle > 0.0 && max_angle <= 45.0) {
+        return Err(params::invalid("max_angle", "must be above 0 and at most 45"));
+    }
+    let img = load_region(py, &path, region)?;
+    Ok(py.allow_threads(|| analysis::skew_angle(&img, max_angle)))
+}
+
+/// Perceptual hash of an image, as 16 hex digits
+///
+/// `image` is a file path or the bytes of an encoded image. The hash keeps
+/// the lowest frequencies of a 32x32 grayscale thumbnail, so resized,
+/// recompressed or slightly retouched copies of a photo hash alike: compare
+/// two hashes by the number of bits in which they differ (0-64), where up to
+/// about 10 usually means the same picture. The algorithm is the one the
+/// `imagehash` package uses, though resampling differences mean the bits
+/// don't always match its output exactly.
+#[pyfunction]
+fn phash(py: Python, image: &PyAny) -> PyResult<String> {
+    let img = load_path_or_bytes(image)?;
+    Ok(similarity::to_hex(py.allow_threads(|| similarity::perceptual_hash(&img))))
+}
+
+/// Difference hash of an image, as 16 hex digits
+///
+/// Each bit tells whether brightness rises between neighbouring pixels of a
+/// 9x8 grayscale thumbnail. Faster than `phash` and good at catching exact
+/// and resized duplicates. `image` works as in `phash`.
+#[pyfunction]
+fn dhash(py: Python, image: &PyAny) -> PyResult<String> {
+    let img = load_path_or_bytes(image)?;
+    Ok(similarity::to_hex(py.allow_threads(|| similarity::difference_hash(&img))))
+}
+
+/// Average hash of an image, as 16 hex digits
+///
+/// Each bit tells whether a pixel of an 8x8 grayscale thumbnail is brighter
+/// than the thumbnail's mean. The simplest of the hashes and the quickest to
+/// be fooled by changes in brightness. `image` works as in `phash`.
+#[pyfunction]
+fn ahash(py: Python, image: &PyAny) -> PyResult<String> {
+    let img = load_path_or_bytes(image)?;
+    Ok(similarity::to_hex(py.allow_threads(|| similarity::average_hash(&img))))
+}
+
+/// Compare two images
+///
+/// `image_a` and `image_b` are file paths or bytes, as in `phash`. Returns a
+/// dict with the Hamming distances between their hashes (`phash_distance`,
+/// `dhash_distance`, `ahash_distance`, each 0-64) and pixel metrics: `mse`,
+/// the mean squared error of the RGB samples on the 0-255 scale, `psnr` in
+/// decibels (infinite for identical pixels) and `ssim`, the structural
+/// similarity of the grayscale images (1.0 for identical ones). Alpha is
+/// ignored. When the sizes differ, `image_b` is resized to the size of
+/// `image_a` for the pixel metrics and `resized` is `True`.
+#[pyfunction]
+fn compare(py: Python, image_a: &PyAny, image_b: &PyAny) -> PyResult<PyObject> {
+    let (a, b) = (load_path_or_bytes(image_a)?, load_path_or_bytes(image_b)?);
+    let resized = a.dimensions() != b.dimensions();
+    let (distances, mse, ssim) = py.allow_threads(|| {
+        let hashes: [fn(&DynamicImage) -> u64; 3] = [
+            similarity::perceptual_hash,
+            similarity::difference_hash,
+            similarity::average_hash,
+        ];
+        let distances = hashes.map(|hash| similarity::hamming_distance(hash(&a), hash(&b)));
+        let b = if resized {
+            resize::resize(&b, a.width(), a.height(), resize::Filter::Lanczos3)
+        } else {
+            b
+        };
+        (distances, similarity::mse(&a, &b), similarity::ssim(&a, &b))
+    });
+
+    let dict = PyDict::new(py);
+    dict.set_item("phash_distance", distances[0])?;
+    dict.set_item("dhash_distance", distances[1])?;
+    dict.set_item("ahash_distance", distances[2])?;
+    dict.set_item("mse", mse)?;
+    dict.set_item("psnr", similarity::psnr(mse))?;
+    dict.set_item("ssim", ssim)?;
+    dict.set_item("resized", resized)?;
+    Ok(dict.into())
+}
+
+/// Fingerprint every effect's output on a small built-in image
+///
+/// Returns a dict mapping a case name (the effect name, with a suffix for
+/// variants such as `blur_linear`, and `_gray` for a run on a grayscale copy
+/// of the image) to a hex hash of the result. Effects use no unseeded
+/// randomness, so the hashes only change when an effect's output does, which
+/// makes them suitable for checking that builds on different platforms agree.
+#[pyfunction]
+fn self_test(py: Python) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    for (name, hash) in selftest::run(py)? {
+        dict.set_item(name, hash)?;
+    }
+    Ok(dict.into())
+}
+
+/// Check an effect name, its params and a color space without processing
+///
+/// Runs exactly the parsing and range checks `process_image` does before it
+/// touches the input, raising the same errors, so a bad choice can be
+/// reported before a batch starts.
+#[pyfunction]
+#[pyo3(signature = (effect_type, params = None, color_space = "srgb"))]
+fn validate_effect(effect_type: &str, params: Option<&PyDict>, color_space: &str) -> PyResult<()> {
+    parse_effect(effect_type, params, color_space).map(|_| ())
+}
+
+/// Copy `input_path` to `output_path` unchanged if the effect is a no-op and
+/// both paths name the same format, returning whether it did.
+fn copy_if_noop(
+    py: Python,
+    input_path: &str,
+    effect_type: &str,
+    params: Option<&PyDict>,
+    color_space: &str,
+    output_path: &str,
     progress_callback: &PyObject,
-) -> PyResult<DynamicImage> {
-    let gray_image = image.to_luma8();
-    
-    // Apply Canny edge detection with more pronounced parameters
-    let edges = canny(&gray_image, 25.0, 75.0);  // Adjusted thresholds for more visible edges
-    
-    // Convert to RGB for better visibility
-    let mut rgb_image = ImageBuffer::new(edges.width(), edges.height());
-    for (x, y, pixel) in edges.enumerate_pixels() {
-        let val = pixel.0[0];
-        rgb_image.put_pixel(x, y, Rgb([255 - val, 255 - val, 255 - val]));  // Invert colors for better visibility
-    }
-    
-    // Update progress
+) -> PyResult<bool> {
+    let (effect, _) = parse_effect(effect_type, params, color_space)?;
+    let same_format = match (
+        image::ImageFormat::from_path(input_path),
+        image::ImageFormat::from_path(output_path),
+    ) {
+        (Ok(input), Ok(output)) => input == output,
+        _ => false,
+    };
+    if !effect.is_noop() || !same_format {
+        return Ok(false);
+    }
+
+    let bytes = decode::read_bytes(input_path)?;
+    std::fs::write(output_path, bytes).map_err(|e| {
+        let err = errors::EncodeError::new_err(format!("Failed to save image: {}", e));
+        errors::with_path(err, output_path)
+    })?;
     progress_callback.call1(py, (100,))?;
-    
-    Ok(DynamicImage::ImageRgb8(rgb_image))
+    Ok(true)
 }
 
-fn apply_blur(py: Python, image: DynamicImage, progress_callback: &PyObject) -> PyResult<DynamicImage> {
-    let gaussian = imageproc::filter::gaussian_blur_f32(&image.to_rgb8(), 2.0);
-    progress_callback.call1(py, (100,))?;
-    Ok(DynamicImage::ImageRgb8(gaussian))
+fn load_image(path: &str) -> PyResult<DynamicImage> {
+    decode::open(path)
 }
 
-fn apply_sharpen(py: Python, image: DynamicImage, progress_callback: &PyObject) -> PyResult<DynamicImage> {
-    let sharpened = image.unsharpen(1.0, 5);
-    progress_callback.call1(py, (100,))?;
-    Ok(sharpened)
+/// The metadata of an encoded input, for `keep_metadata`, marked upright when
+/// its orientation is applied to the pixels by `auto_orient`.
+fn input_metadata(bytes: &[u8], effect_type: &str, auto_orient: bool) -> Metadata {
+    let mut metadata = Metadata::read(bytes);
+    if auto_orient || effect_type == "auto_orient" {
+        metadata.clear_orientation();
+    }
+    metadata
 }
 
-fn apply_grayscale(py: Python, image: DynamicImage, progress_callback: &PyObject) -> PyResult<DynamicImage> {
-    let grayscale = image.grayscale();
-    progress_callback.call1(py, (100,))?;
-    Ok(grayscale)
-}
-
-fn apply_sepia(py: Python, image: DynamicImage, progress_callback: &PyObject) -> PyResult<DynamicImage> {
-    let rgb = image.to_rgb8();
-    let mut sepia = ImageBuffer::new(rgb.width(), rgb.height());
-    
-    for (x, y, pixel) in rgb.enumerate_pixels() {
-        let r = pixel[0] as f32;
-        let g = pixel[1] as f32;
-        let b = pixel[2] as f32;
-        
-        let sr = (0.393 * r + 0.769 * g + 0.189 * b).min(255.0) as u8;
-        let sg = (0.349 * r + 0.686 * g + 0.168 * b).min(255.0) as u8;
-        let sb = (0.272 * r + 0.534 * g + 0.131 * b).min(255.0) as u8;
-        
-        sepia.put_pixel(x, y, Rgb([sr, sg, sb]));
-    }
-    
-    progress_callback.call1(py, (100,))?;
-    Ok(DynamicImage::ImageRgb8(sepia))
+/// Decode an image given as a file path or as the bytes of an encoded file.
+fn load_path_or_bytes(image: &PyAny) -> PyResult<DynamicImage> {
+    if let Ok(bytes) = image.downcast::<PyBytes>() {
+        return decode::from_memory(bytes.as_bytes());
+    }
+    match image.extract::<String>() {
+        Ok(path) => load_image(&path),
+        Err(_) => Err(pyo3::exceptions::PyTypeError::new_err(format!(
+            "expected a path or bytes, got {}",
+            image.get_type().name()?
+        ))),
+    }
 }
 
-fn apply_invert(py: Python, image: DynamicImage, progress_callback: &PyObject) -> PyResult<DynamicImage> {
-    let rgb = image.to_rgb8();
-    let mut inverted = ImageBuffer::new(rgb.width(), rgb.height());
-    
-    for (x, y, pixel) in rgb.enumerate_pixels() {
-        inverted.put_pixel(x, y, Rgb([
-            255 - pixel[0],
-            255 - pixel[1],
-            255 - pixel[2]
-        ]));
+/// An `(x, y, width, height)` rectangle as passed from Python.
+type Region = (u32, u32, u32, u32);
+
+/// Load an image, cropped to `region` when one is given.
+///
+/// Regions that don't fit inside the image raise `GeometryError`.
+fn load_region(py: Python, path: &str, region: Option<Region>) -> PyResult<DynamicImage> {
+    let img = load_image(path)?;
+    match region {
+        None => Ok(img),
+        Some((_, _, 0, _)) | Some((_, _, _, 0)) => Err(pyo3::exceptions::PyValueError::new_err(
+            "region width and height must be positive",
+        )),
+        Some(rect @ (x, y, width, height)) => {
+            errors::check_region(py, rect, (img.width(), img.height()))?;
+            Ok(img.crop_imm(x, y, width, height))
+        }
     }
-    
-    progress_callback.call1(py, (100,))?;
-    Ok(DynamicImage::ImageRgb8(inverted))
+}
+
+/// Run the named effect on an already decoded image, read from `source`
+fn apply_effect(
+    py: Python,
+    img: DynamicImage,
+    source: Source,
+    effect_type: &str,
+    params: Option<&PyDict>,
+    color_space: &str,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let (effect, color_space) = parse_effect(effect_type, params, color_space)?;
+    let resolved = effect.resolve_orientation(|| source.orientation())?;
+    resolved.as_ref().unwrap_or(&effect).apply(py, img, color_space, progress_callback)
+}
+
+/// Parse and range-check an effect and color space as passed from Python.
+fn parse_effect(
+    effect_type: &str,
+    params: Option<&PyDict>,
+    color_space: &str,
+) -> PyResult<(ImageEffect, ColorSpace)> {
+    let effect = ImageEffect::parse(effect_type, &mut Params::from_dict(params)?)?;
+
+    let color_space = ColorSpace::parse(color_space)?;
+
+    Ok((effect, color_space))
 }
 
 #[pymodule]
-fn image_processor_rust(_py: Python, m: &PyModule) -> PyResult<()> {
+fn image_processor_rust(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add("GeometryError", py.get_type::<errors::GeometryError>())?;
+    m.add("LossyConversionError", py.get_type::<errors::LossyConversionError>())?;
+    m.add("ProcessingCancelled", py.get_type::<errors::ProcessingCancelled>())?;
+    m.add("ImageLoadError", py.get_type::<errors::ImageLoadError>())?;
+    m.add("UnsupportedFormatError", py.get_type::<errors::UnsupportedFormatError>())?;
+    m.add("EncodeError", py.get_type::<errors::EncodeError>())?;
+    m.add("InvalidParameterError", py.get_type::<errors::InvalidParameterError>())?;
+    errors::add_attribute_defaults(py)?;
+    m.add_class::<pyimage::PyImage>()?;
+    m.add_class::<OutputOptions>()?;
+    m.add_class::<ProcessingHandle>()?;
+
     m.add_function(wrap_pyfunction!(process_image, m)?)?;
+    m.add_function(wrap_pyfunction!(process_image_async, m)?)?;
+    m.add_function(wrap_pyfunction!(process_image_tiled, m)?)?;
+    m.add_function(wrap_pyfunction!(process_url, m)?)?;
+    m.add_function(wrap_pyfunction!(process_recipe, m)?)?;
+    m.add_function(wrap_pyfunction!(process_pipeline, m)?)?;
+    m.add_function(wrap_pyfunction!(process_to_datauri, m)?)?;
+    m.add_function(wrap_pyfunction!(process_image_bytes, m)?)?;
+    m.add_function(wrap_pyfunction!(process_array, m)?)?;
+    m.add_function(wrap_pyfunction!(scale_to_fit_bytes, m)?)?;
+    m.add_function(wrap_pyfunction!(process_image_into, m)?)?;
+    m.add_function(wrap_pyfunction!(process_raw, m)?)?;
+    m.add_function(wrap_pyfunction!(process_multisize, m)?)?;
+    m.add_function(wrap_pyfunction!(make_thumbnail, m)?)?;
+    m.add_function(wrap_pyfunction!(process_directory, m)?)?;
+    m.add_function(wrap_pyfunction!(process_batch, m)?)?;
+    m.add_function(wrap_pyfunction!(blend_images, m)?)?;
+    m.add_function(wrap_pyfunction!(overlay, m)?)?;
+    m.add_function(wrap_pyfunction!(stack_images, m)?)?;
+    m.add_function(wrap_pyfunction!(montage, m)?)?;
+    m.add_function(wrap_pyfunction!(match_histogram, m)?)?;
+    m.add_function(wrap_pyfunction!(split_channels, m)?)?;
+    m.add_function(wrap_pyfunction!(merge_channels, m)?)?;
+    m.add_function(wrap_pyfunction!(histogram, m)?)?;
+    m.add_function(wrap_pyfunction!(clipping, m)?)?;
+    m.add_function(wrap_pyfunction!(dominant_colors, m)?)?;
+    m.add_function(wrap_pyfunction!(blurriness, m)?)?;
+    m.add_function(wrap_pyfunction!(detect_skew, m)?)?;
+    m.add_function(wrap_pyfunction!(phash, m)?)?;
+    m.add_function(wrap_pyfunction!(dhash, m)?)?;
+    m.add_function(wrap_pyfunction!(ahash, m)?)?;
+    m.add_function(wrap_pyfunction!(compare, m)?)?;
+    m.add_function(wrap_pyfunction!(blankness, m)?)?;
+    m.add_function(wrap_pyfunction!(choose_auto_effect, m)?)?;
+    m.add_function(wrap_pyfunction!(read_provenance, m)?)?;
+    m.add_function(wrap_pyfunction!(read_orientation, m)?)?;
+    m.add_function(wrap_pyfunction!(inspect, m)?)?;
+    m.add_function(wrap_pyfunction!(validate_effect, m)?)?;
+    m.add_function(wrap_pyfunction!(self_test, m)?)?;
     Ok(())
 } 
\ No newline at end of file
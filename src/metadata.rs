@@ -0,0 +1,177 @@
+use std::io::Write;
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use image::ImageFormat;
+
+use crate::decode;
+use crate::exif;
+
+/// Payload of a JPEG `APP1` segment holding EXIF data starts with this.
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// Payload of each JPEG `APP2` segment holding part of an ICC profile starts
+/// with this, followed by the part's number and the number of parts.
+const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+
+/// Largest payload of a JPEG segment; its length field counts itself too.
+const MAX_SEGMENT: usize = 0xFFFF - 2;
+
+/// EXIF data and ICC profile carried over from a source image to an output.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// Raw EXIF data (a TIFF structure), without the JPEG `Exif` header.
+    pub exif: Option<Vec<u8>>,
+    pub icc_profile: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    /// The metadata of an encoded image. Anything that can't be read is
+    /// treated as absent.
+    pub fn read(bytes: &[u8]) -> Self {
+        Self {
+            exif: decode::exif_block(bytes).map(<[u8]>::to_vec),
+            icc_profile: decode::header(bytes).ok().and_then(|header| header.icc_profile),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.icc_profile.is_none()
+    }
+
+    /// Record that the image is upright, once its EXIF orientation has been
+    /// applied to the pixels.
+    pub fn clear_orientation(&mut self) {
+        if let Some(exif) = &mut self.exif {
+            exif::clear_orientation(exif);
+        }
+    }
+
+    /// Embed the metadata in an image encoded as `format`. Only JPEG, PNG and
+    /// WebP can carry it; other formats come back unchanged, as does
+    /// anything that doesn't parse as the format it claims to be.
+    pub fn embed(&self, encoded: Vec<u8>, format: ImageFormat) -> Vec<u8> {
+        if self.is_empty() {
+            return encoded;
+        }
+        let embedded = match format {
+            ImageFormat::Jpeg => self.embed_jpeg(&encoded),
+            ImageFormat::Png => self.embed_png(&encoded),
+            ImageFormat::WebP => self.embed_webp(&encoded),
+            _ => None,
+        };
+        embedded.unwrap_or(encoded)
+    }
+
+    /// Insert `APP1` and `APP2` segments after the start of image marker and
+    /// the JFIF segment, where readers expect them.
+    fn embed_jpeg(&self, jpeg: &[u8]) -> Option<Vec<u8>> {
+        let mut pos = 2;
+        while jpeg.get(pos..pos + 2)? == [0xFF, 0xE0] {
+            pos += 2 + u16::from_be_bytes([*jpeg.get(pos + 2)?, *jpeg.get(pos + 3)?]) as usize;
+        }
+        let head = jpeg.get(..pos)?;
+
+        let mut segments = Vec::new();
+        let mut segment = |marker: u8, parts: &[&[u8]]| {
+            let length = parts.iter().map(|p| p.len()).sum::<usize>() + 2;
+            segments.extend_from_slice(&[0xFF, marker]);
+            segments.extend_from_slice(&(length as u16).to_be_bytes());
+            parts.iter().for_each(|p| segments.extend_from_slice(p));
+        };
+        if let Some(exif) = self.exif.as_deref().filter(|e| e.len() <= MAX_SEGMENT - 6) {
+            segment(0xE1, &[EXIF_HEADER, exif]);
+        }
+        if let Some(icc) = &self.icc_profile {
+            let chunks: Vec<_> = icc.chunks(MAX_SEGMENT - ICC_HEADER.len() - 2).collect();
+            if let Ok(count) = u8::try_from(chunks.len()) {
+                for (i, chunk) in chunks.iter().enumerate() {
+                    segment(0xE2, &[ICC_HEADER, &[i as u8 + 1, count], chunk]);
+                }
+            }
+        }
+        Some([head, &segments, &jpeg[pos..]].concat())
+    }
+
+    /// Insert `iCCP` and `eXIf` chunks right after the `IHDR` chunk.
+    fn embed_png(&self, png: &[u8]) -> Option<Vec<u8>> {
+        // 8-byte signature, then IHDR: length, type, 13 bytes of data, CRC.
+        const IHDR_END: usize = 8 + 4 + 4 + 13 + 4;
+        let head = png.get(..IHDR_END).filter(|head| &head[12..16] == b"IHDR")?;
+
+        let mut chunks = Vec::new();
+        if let Some(icc) = &self.icc_profile {
+            // Profile name, null separator, compression method 0 (zlib).
+            let mut data = b"ICC profile\0\0".to_vec();
+            let mut encoder = ZlibEncoder::new(&mut data, Compression::default());
+            encoder.write_all(icc).ok()?;
+            encoder.finish().ok()?;
+            chunks.extend(png_chunk(b"iCCP", &data));
+        }
+        if let Some(exif) = &self.exif {
+            chunks.extend(png_chunk(b"eXIf", exif));
+        }
+        Some([head, &chunks, &png[IHDR_END..]].concat())
+    }
+
+    /// Wrap a simple lossless WebP in the extended layout, which alone can
+    /// carry metadata: a `VP8X` header, then `ICCP`, the image and `EXIF`.
+    fn embed_webp(&self, webp: &[u8]) -> Option<Vec<u8>> {
+        if webp.get(..4)? != b"RIFF" || webp.get(8..16)? != b"WEBPVP8L" {
+            return None;
+        }
+        let image = webp.get(12..)?;
+        // A VP8L bitstream starts with a signature byte, then 14 bits each of
+        // width and height minus one and a bit saying whether alpha is used.
+        let bits = u32::from_le_bytes(image.get(9..13)?.try_into().ok()?);
+        let (width, height) = (bits & 0x3FFF, (bits >> 14) & 0x3FFF);
+        let has_alpha = bits >> 28 & 1 == 1;
+
+        let mut flags = 0u8;
+        if self.icc_profile.is_some() {
+            flags |= 0x20;
+        }
+        if has_alpha {
+            flags |= 0x10;
+        }
+        if self.exif.is_some() {
+            flags |= 0x08;
+        }
+        let mut header = vec![flags, 0, 0, 0];
+        header.extend_from_slice(&width.to_le_bytes()[..3]);
+        header.extend_from_slice(&height.to_le_bytes()[..3]);
+
+        let mut body = b"WEBP".to_vec();
+        body.extend(riff_chunk(b"VP8X", &header));
+        if let Some(icc) = &self.icc_profile {
+            body.extend(riff_chunk(b"ICCP", icc));
+        }
+        body.extend_from_slice(image);
+        if let Some(exif) = &self.exif {
+            body.extend(riff_chunk(b"EXIF", exif));
+        }
+        let length = u32::try_from(body.len()).ok()?;
+        Some([b"RIFF", &length.to_le_bytes()[..], &body].concat())
+    }
+}
+
+fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(&crc32fast::hash(&chunk[4..]).to_be_bytes());
+    chunk
+}
+
+/// A RIFF chunk, padded to an even length.
+fn riff_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 9);
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
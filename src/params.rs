@@ -0,0 +1,348 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyList, PyTuple};
+
+use crate::errors;
+
+/// A single effect parameter as received from the caller.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    List(Vec<Value>),
+}
+
+impl Value {
+    fn from_py(key: &str, obj: &PyAny) -> PyResult<Self> {
+        // bool is a subclass of int in Python, so it has to be checked first.
+        if obj.is_instance_of::<PyBool>() {
+            return Ok(Self::Bool(obj.extract()?));
+        }
+        if let Ok(v) = obj.extract::<i64>() {
+            return Ok(Self::Int(v));
+        }
+        if let Ok(v) = obj.extract::<f64>() {
+            return Ok(Self::Float(v));
+        }
+        if let Ok(v) = obj.extract::<String>() {
+            return Ok(Self::Str(v));
+        }
+        if obj.is_instance_of::<PyList>() || obj.is_instance_of::<PyTuple>() {
+            let items = obj
+                .iter()?
+                .map(|item| Self::from_py(key, item?))
+                .collect::<PyResult<Vec<_>>>()?;
+            return Ok(Self::List(items));
+        }
+        Err(errors::invalid_parameter(
+            key,
+            format!("Unsupported value for parameter '{}': {}", key, obj.repr()?),
+        ))
+    }
+
+    fn from_json(key: &str, json: &serde_json::Value) -> PyResult<Self> {
+        use serde_json::Value as Json;
+        match json {
+            Json::Bool(v) => Ok(Self::Bool(*v)),
+            Json::Number(n) => match n.as_i64() {
+                Some(v) => Ok(Self::Int(v)),
+                None => Ok(Self::Float(n.as_f64().unwrap_or(f64::NAN))),
+            },
+            Json::String(v) => Ok(Self::Str(v.clone())),
+            Json::Array(items) => items
+                .iter()
+                .map(|item| Self::from_json(key, item))
+                .collect::<PyResult<Vec<_>>>()
+                .map(Self::List),
+            Json::Null | Json::Object(_) => Err(errors::invalid_parameter(
+                key,
+                format!("Unsupported value for parameter '{}': {}", key, json),
+            )),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        use serde_json::Value as Json;
+        match self {
+            Self::Bool(v) => Json::Bool(*v),
+            Self::Int(v) => Json::from(*v),
+            Self::Float(v) => Json::from(*v),
+            Self::Str(v) => Json::String(v.clone()),
+            Self::List(items) => Json::Array(items.iter().map(Self::to_json).collect()),
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Int(v) => Some(*v as f64),
+            Self::Float(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Bool(v) => write!(f, "{}", v),
+            Self::Int(v) => write!(f, "{}", v),
+            Self::Float(v) => write!(f, "{}", v),
+            Self::Str(v) => write!(f, "'{}'", v),
+            Self::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// Named parameters for a single effect.
+///
+/// Effects `take_*` the keys they understand while parsing; whatever is left
+/// over is reported by [`Params::finish`] so that typos don't silently fall
+/// back to defaults.
+#[derive(Debug, Clone, Default)]
+pub struct Params {
+    values: BTreeMap<String, Value>,
+}
+
+impl Params {
+    pub fn from_dict(dict: Option<&PyDict>) -> PyResult<Self> {
+        let mut values = BTreeMap::new();
+        if let Some(dict) = dict {
+            for (key, value) in dict.iter() {
+                let key = key.extract::<String>()?;
+                let value = Value::from_py(&key, value)?;
+                values.insert(key, value);
+            }
+        }
+        Ok(Self { values })
+    }
+
+    pub fn from_json(object: &serde_json::Map<String, serde_json::Value>) -> PyResult<Self> {
+        let values = object
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), Value::from_json(key, value)?)))
+            .collect::<PyResult<_>>()?;
+        Ok(Self { values })
+    }
+
+    /// The parameters as a JSON object, for recording alongside an output.
+    pub fn to_json(&self) -> serde_json::Value {
+        let object = self.values.iter().map(|(k, v)| (k.clone(), v.to_json())).collect();
+        serde_json::Value::Object(object)
+    }
+
+    pub fn take_f32(&mut self, key: &str, default: f32) -> PyResult<f32> {
+        Ok(self.take_opt_f32(key)?.unwrap_or(default))
+    }
+
+    pub fn require_f32(&mut self, key: &str) -> PyResult<f32> {
+        self.take_opt_f32(key)?
+            .ok_or_else(|| missing(key))
+    }
+
+    pub fn take_opt_f32(&mut self, key: &str) -> PyResult<Option<f32>> {
+        match self.values.remove(key) {
+            None => Ok(None),
+            Some(value) => value
+                .as_f64()
+                .map(|v| Some(v as f32))
+                .ok_or_else(|| type_error(key, "a number", &value)),
+        }
+    }
+
+    pub fn take_bool(&mut self, key: &str, default: bool) -> PyResult<bool> {
+        match self.values.remove(key) {
+            None => Ok(default),
+            Some(Value::Bool(v)) => Ok(v),
+            Some(value) => Err(type_error(key, "a boolean", &value)),
+        }
+    }
+
+    pub fn take_str(&mut self, key: &str, default: &str) -> PyResult<String> {
+        match self.values.remove(key) {
+            None => Ok(default.to_string()),
+            Some(Value::Str(v)) => Ok(v),
+            Some(value) => Err(type_error(key, "a string", &value)),
+        }
+    }
+
+    pub fn take_opt_str(&mut self, key: &str) -> PyResult<Option<String>> {
+        match self.values.remove(key) {
+            None => Ok(None),
+            Some(Value::Str(v)) => Ok(Some(v)),
+            Some(value) => Err(type_error(key, "a string", &value)),
+        }
+    }
+
+    pub fn require_str(&mut self, key: &str) -> PyResult<String> {
+        match self.values.remove(key) {
+            None => Err(missing(key)),
+            Some(Value::Str(v)) => Ok(v),
+            Some(value) => Err(type_error(key, "a string", &value)),
+        }
+    }
+
+    pub fn take_i64(&mut self, key: &str, default: i64) -> PyResult<i64> {
+        match self.values.remove(key) {
+            None => Ok(default),
+            Some(Value::Int(v)) => Ok(v),
+            Some(value) => Err(type_error(key, "an integer", &value)),
+        }
+    }
+
+    pub fn take_u32(&mut self, key: &str, default: u32) -> PyResult<u32> {
+        Ok(self.take_opt_uint(key)?.unwrap_or(default))
+    }
+
+    pub fn take_opt_u32(&mut self, key: &str) -> PyResult<Option<u32>> {
+        self.take_opt_uint(key)
+    }
+
+    pub fn require_u32(&mut self, key: &str) -> PyResult<u32> {
+        self.take_opt_uint(key)?
+            .ok_or_else(|| missing(key))
+    }
+
+    pub fn take_u64(&mut self, key: &str, default: u64) -> PyResult<u64> {
+        Ok(self.take_opt_uint(key)?.unwrap_or(default))
+    }
+
+    /// An `(r, g, b)` color given as a list or tuple of 0-255 integers.
+    pub fn take_rgb(&mut self, key: &str, default: [u8; 3]) -> PyResult<[u8; 3]> {
+        Ok(self.take_opt_rgb(key)?.unwrap_or(default))
+    }
+
+    pub fn take_opt_rgb(&mut self, key: &str) -> PyResult<Option<[u8; 3]>> {
+        match self.values.remove(key) {
+            None => Ok(None),
+            Some(value) => channel_list(&value)
+                .and_then(|c| <[u8; 3]>::try_from(c).ok())
+                .map(Some)
+                .ok_or_else(|| type_error(key, "an (r, g, b) tuple of 0-255 integers", &value)),
+        }
+    }
+
+    /// An `(r, g, b)` or `(r, g, b, a)` color; alpha defaults to opaque.
+    pub fn take_rgba(&mut self, key: &str, default: [u8; 4]) -> PyResult<[u8; 4]> {
+        match self.values.remove(key) {
+            None => Ok(default),
+            Some(value) => channel_list(&value)
+                .and_then(|c| match c[..] {
+                    [r, g, b] => Some([r, g, b, 255]),
+                    [r, g, b, a] => Some([r, g, b, a]),
+                    _ => None,
+                })
+                .ok_or_else(|| {
+                    type_error(key, "an (r, g, b) or (r, g, b, a) tuple of 0-255 integers", &value)
+                }),
+        }
+    }
+
+    /// A list of `(input, output)` pairs of 0-255 integers.
+    pub fn take_opt_points(&mut self, key: &str) -> PyResult<Option<Vec<(u8, u8)>>> {
+        let value = match self.values.remove(key) {
+            None => return Ok(None),
+            Some(value) => value,
+        };
+        let points = match &value {
+            Value::List(items) => items
+                .iter()
+                .map(|item| match channel_list(item)?[..] {
+                    [input, output] => Some((input, output)),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>(),
+            _ => None,
+        };
+        points.map(Some).ok_or_else(|| {
+            type_error(key, "a list of (input, output) pairs of 0-255 integers", &value)
+        })
+    }
+
+    /// A list of rows, each a list of numbers. Only the nesting is checked;
+    /// the rows may differ in length.
+    pub fn take_opt_matrix(&mut self, key: &str) -> PyResult<Option<Vec<Vec<f32>>>> {
+        let value = match self.values.remove(key) {
+            None => return Ok(None),
+            Some(value) => value,
+        };
+        let rows = match &value {
+            Value::List(rows) => rows
+                .iter()
+                .map(|row| match row {
+                    Value::List(items) => {
+                        items.iter().map(|v| v.as_f64().map(|v| v as f32)).collect()
+                    }
+                    _ => None,
+                })
+                .collect::<Option<Vec<Vec<f32>>>>(),
+            _ => None,
+        };
+        rows.map(Some)
+            .ok_or_else(|| type_error(key, "a list of rows, each a list of numbers", &value))
+    }
+
+    fn take_opt_uint<T: TryFrom<i64>>(&mut self, key: &str) -> PyResult<Option<T>> {
+        match self.values.remove(key) {
+            None => Ok(None),
+            Some(value) => match value {
+                Value::Int(v) => T::try_from(v).map(Some).ok(),
+                _ => None,
+            }
+            .ok_or_else(|| type_error(key, "a non-negative integer", &value)),
+        }
+    }
+
+    /// Fail if any parameter was not consumed by the effect.
+    pub fn finish(&self, effect: &str) -> PyResult<()> {
+        match self.values.keys().next() {
+            None => Ok(()),
+            Some(key) => Err(errors::invalid_parameter(
+                key,
+                format!("Unknown parameter '{}' for effect '{}'", key, effect),
+            )),
+        }
+    }
+}
+
+/// Interpret `value` as a list of 8-bit channel values.
+fn channel_list(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::List(items) => items
+            .iter()
+            .map(|item| match item {
+                Value::Int(v) => u8::try_from(*v).ok(),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+fn type_error(key: &str, expected: &str, value: &Value) -> PyErr {
+    errors::invalid_parameter(
+        key,
+        format!("Parameter '{}' must be {}, got {}", key, expected, value),
+    )
+}
+
+fn missing(key: &str) -> PyErr {
+    errors::invalid_parameter(key, format!("Missing required parameter '{}'", key))
+}
+
+/// Build the error for a parameter that parsed but is out of range.
+pub fn invalid(key: &str, message: &str) -> PyErr {
+    errors::invalid_parameter(key, format!("Invalid parameter '{}': {}", key, message))
+}
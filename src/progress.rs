@@ -0,0 +1,256 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use pyo3::exceptions::{PyTimeoutError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBool;
+use rayon::prelude::*;
+
+use crate::background;
+use crate::errors::ProcessingCancelled;
+
+/// Progress callback that maps one job's 0-100 range onto a slice of an
+/// overall batch, forwarding the result to the caller's callback.
+#[pyclass]
+pub struct ScaledProgress {
+    inner: PyObject,
+    start: f64,
+    span: f64,
+}
+
+impl ScaledProgress {
+    /// Report progress for a job that covers `span` percent of the batch,
+    /// starting `start` percent in.
+    pub fn new(inner: PyObject, start: f64, span: f64) -> Self {
+        Self { inner, start, span }
+    }
+}
+
+#[pymethods]
+impl ScaledProgress {
+    fn __call__(&self, py: Python, percent: f64) -> PyResult<()> {
+        let overall = self.start + percent.clamp(0.0, 100.0) * self.span / 100.0;
+        self.inner.call1(py, (overall.round() as u32,))?;
+        Ok(())
+    }
+}
+
+/// How many progress updates [`par_rows`] sends over a whole image.
+pub const ROW_UPDATES: usize = 20;
+
+/// Run `work` on every row of `data`, rows of `row_len` samples each, in
+/// parallel and without the GIL, a chunk of rows at a time so that progress
+/// can be reported after each. The last update is 100.
+pub fn par_rows<T: Send>(
+    py: Python,
+    data: &mut [T],
+    row_len: usize,
+    progress_callback: &PyObject,
+    work: impl Fn(&mut [T]) + Sync,
+) -> PyResult<()> {
+    let rows = data.len() / row_len.max(1);
+    let chunk_rows = rows.div_ceil(ROW_UPDATES).max(1);
+    let chunks = rows.div_ceil(chunk_rows).max(1);
+    let stop = current_stop();
+    for (index, chunk) in data.chunks_mut(chunk_rows * row_len.max(1)).enumerate() {
+        py.allow_threads(|| {
+            chunk.par_chunks_mut(row_len.max(1)).for_each(|row| {
+                if !stop.is_stopped() {
+                    work(row)
+                }
+            })
+        });
+        progress_callback.call1(py, (100 * (index + 1) / chunks,))?;
+    }
+    if data.is_empty() {
+        progress_callback.call1(py, (100,))?;
+    }
+    Ok(())
+}
+
+/// Progress callback that discards every update.
+#[pyclass]
+pub struct NoProgress;
+
+#[pymethods]
+impl NoProgress {
+    fn __call__(&self, _percent: f64) {}
+}
+
+/// The caller's own progress callback, as every entry point wraps it.
+///
+/// Updates are forwarded only once progress has moved on by `interval`
+/// percent since the last one passed on, and always on reaching 100. Before
+/// each update a `threading.Event`-like `cancel_event`, if any, is checked,
+/// and `ProcessingCancelled` raised once it is set; the same happens when
+/// the callback itself returns `False`.
+///
+/// Every effect and batch step reports progress, so wrapping the caller's
+/// callback is enough to stop work at those points without a separate check
+/// in each of them.
+#[pyclass]
+pub struct CancellableProgress {
+    inner: PyObject,
+    cancel_event: Option<PyObject>,
+    interval: f64,
+    last: Option<f64>,
+}
+
+#[pymethods]
+impl CancellableProgress {
+    fn __call__(&mut self, py: Python, percent: &PyAny) -> PyResult<()> {
+        if let Some(cancel_event) = &self.cancel_event {
+            check_cancelled(py, cancel_event)?;
+        }
+        let value: f64 = percent.extract()?;
+        let due = match self.last {
+            None => true,
+            Some(last) if value >= 100.0 => last < 100.0,
+            Some(last) => (value - last).abs() >= self.interval,
+        };
+        if !due {
+            return Ok(());
+        }
+        self.last = Some(value);
+        let answer = self.inner.call1(py, (percent,))?;
+        if answer.as_ref(py).is(PyBool::new(py, false)) {
+            return Err(ProcessingCancelled::new_err("Processing was cancelled"));
+        }
+        Ok(())
+    }
+}
+
+/// Wrap the caller's `callback` as a [`CancellableProgress`], failing right
+/// away if `cancel_event` is already set.
+pub fn cancellable(
+    py: Python,
+    callback: PyObject,
+    cancel_event: Option<PyObject>,
+    interval: f64,
+) -> PyResult<PyObject> {
+    if !(0.0..=100.0).contains(&interval) {
+        return Err(PyValueError::new_err("progress_interval must be between 0 and 100"));
+    }
+    if let Some(cancel_event) = &cancel_event {
+        check_cancelled(py, cancel_event)?;
+    }
+    let progress = CancellableProgress { inner: callback, cancel_event, interval, last: None };
+    Ok(Py::new(py, progress)?.into_py(py))
+}
+
+fn check_cancelled(py: Python, cancel_event: &PyObject) -> PyResult<()> {
+    if cancel_event.call_method0(py, "is_set")?.is_true(py)? {
+        return Err(ProcessingCancelled::new_err("Processing was cancelled"));
+    }
+    Ok(())
+}
+
+/// Progress callback that raises `TimeoutError` once `deadline` has passed,
+/// so that a job which runs too long stops at its next progress report, or
+/// sooner through the [`StopCheck`] of the effect it is running.
+#[pyclass]
+pub struct DeadlineProgress {
+    inner: PyObject,
+    deadline: Instant,
+}
+
+impl DeadlineProgress {
+    pub fn new(inner: PyObject, deadline: Instant) -> Self {
+        Self { inner, deadline }
+    }
+}
+
+#[pymethods]
+impl DeadlineProgress {
+    fn __call__(&self, py: Python, percent: &PyAny) -> PyResult<()> {
+        if Instant::now() >= self.deadline {
+            return Err(PyTimeoutError::new_err("Processing took longer than allowed"));
+        }
+        self.inner.call1(py, (percent,))?;
+        Ok(())
+    }
+}
+
+/// What should make the job on this thread give up early, found along its
+/// progress callback: the deadline of any [`DeadlineProgress`] and the
+/// cancel flag of an async [`ProcessingHandle`](crate::background::ProcessingHandle).
+///
+/// Progress reports already raise once the job should stop, but an effect
+/// may report only once it is done. Its row and band loops check this in
+/// between instead, skipping the rest of the work, and the effect then
+/// fails as its next report would have.
+#[derive(Debug, Clone, Default)]
+pub struct StopCheck {
+    deadline: Option<Instant>,
+    cancelled: Option<Arc<AtomicBool>>,
+}
+
+impl StopCheck {
+    /// The checks of every wrapper along `callback`.
+    pub fn of(py: Python, callback: &PyObject) -> Self {
+        let mut stop = Self::default();
+        let mut next = Some(callback.clone_ref(py));
+        while let Some(callback) = next.take() {
+            let callback = callback.as_ref(py);
+            if let Ok(scaled) = callback.downcast::<PyCell<ScaledProgress>>() {
+                next = Some(scaled.borrow().inner.clone_ref(py));
+            } else if let Ok(cancellable) = callback.downcast::<PyCell<CancellableProgress>>() {
+                next = cancellable.try_borrow().ok().map(|c| c.inner.clone_ref(py));
+            } else if let Ok(deadline) = callback.downcast::<PyCell<DeadlineProgress>>() {
+                let deadline = deadline.borrow();
+                let own = deadline.deadline;
+                stop.deadline = Some(stop.deadline.map_or(own, |d| d.min(own)));
+                next = Some(deadline.inner.clone_ref(py));
+            } else if let Some(cancelled) = background::cancel_flag(callback) {
+                stop.cancelled = Some(cancelled);
+            }
+        }
+        stop
+    }
+
+    /// Whether the job should stop now.
+    pub fn is_stopped(&self) -> bool {
+        self.is_cancelled() || self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.as_ref().is_some_and(|cancelled| cancelled.load(Ordering::Relaxed))
+    }
+
+    /// The error a progress report would raise now, if any.
+    pub fn check(&self) -> PyResult<()> {
+        if self.is_cancelled() {
+            return Err(ProcessingCancelled::new_err("Processing was cancelled"));
+        }
+        if self.is_stopped() {
+            return Err(PyTimeoutError::new_err("Processing took longer than allowed"));
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    static CURRENT_STOP: RefCell<StopCheck> = RefCell::default();
+}
+
+/// Run `work` with `stop` as this thread's [`current_stop`], restoring the
+/// previous one afterwards.
+pub fn with_stop<R>(stop: StopCheck, work: impl FnOnce() -> R) -> R {
+    struct Restore(Option<StopCheck>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take().unwrap_or_default();
+            CURRENT_STOP.with(|current| *current.borrow_mut() = previous);
+        }
+    }
+    let _restore = Restore(Some(CURRENT_STOP.with(|current| current.replace(stop))));
+    work()
+}
+
+/// The [`StopCheck`] of the effect running on this thread, to be taken
+/// before handing work to other threads. Outside of an effect nothing stops.
+pub fn current_stop() -> StopCheck {
+    CURRENT_STOP.with(|current| current.borrow().clone())
+}
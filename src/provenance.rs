@@ -0,0 +1,85 @@
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::params::Params;
+
+/// Keyword of the PNG `iTXt` chunk that provenance is stored under.
+pub const KEYWORD: &str = "image_processor_rust:provenance";
+
+/// JSON description of how an output was produced.
+pub fn describe(
+    input: &str,
+    effect_type: &str,
+    params: Option<&PyDict>,
+    color_space: &str,
+) -> PyResult<String> {
+    let record = serde_json::json!({
+        "generator": concat!("image_processor_rust ", env!("CARGO_PKG_VERSION")),
+        "input": input,
+        "effect": effect_type,
+        "params": Params::from_dict(params)?.to_json(),
+        "color_space": color_space,
+    });
+    Ok(record.to_string())
+}
+
+/// Insert an uncompressed UTF-8 `iTXt` chunk right after the `IHDR` chunk of
+/// an encoded PNG.
+pub fn insert_text(png: &[u8], keyword: &str, text: &str) -> Vec<u8> {
+    // 8-byte signature, then IHDR: length, type, 13 bytes of data, CRC.
+    const IHDR_END: usize = 8 + 4 + 4 + 13 + 4;
+
+    let mut data = Vec::with_capacity(keyword.len() + text.len() + 5);
+    data.extend_from_slice(keyword.as_bytes());
+    // Null separator, no compression, compression method, then empty language
+    // tag and translated keyword.
+    data.extend_from_slice(&[0, 0, 0, 0, 0]);
+    data.extend_from_slice(text.as_bytes());
+
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(b"iTXt");
+    chunk.extend_from_slice(&data);
+    chunk.extend_from_slice(&crc32fast::hash(&chunk[4..]).to_be_bytes());
+
+    let mut out = Vec::with_capacity(png.len() + chunk.len());
+    out.extend_from_slice(&png[..IHDR_END]);
+    out.extend_from_slice(&chunk);
+    out.extend_from_slice(&png[IHDR_END..]);
+    out
+}
+
+/// Find the provenance text in an encoded PNG, if there is one.
+///
+/// Only uncompressed `iTXt` and `tEXt` chunks are considered, which covers
+/// everything [`insert_text`] writes. Anything that isn't a PNG yields `None`.
+pub fn read(png: &[u8]) -> Option<String> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    let mut rest = png.strip_prefix(SIGNATURE)?;
+
+    while rest.len() >= 12 {
+        let length = u32::from_be_bytes(rest[..4].try_into().ok()?) as usize;
+        let kind = &rest[4..8];
+        let data = rest.get(8..8 + length)?;
+        match kind {
+            b"iTXt" => {
+                // Separator, compression flag and method, then the
+                // null-terminated language tag and translated keyword.
+                if let Some(&[0, 0, _, ref tail @ ..]) = data.strip_prefix(KEYWORD.as_bytes()) {
+                    let mut fields = tail.splitn(3, |&b| b == 0);
+                    let (_, _, text) = (fields.next()?, fields.next()?, fields.next()?);
+                    return String::from_utf8(text.to_vec()).ok();
+                }
+            }
+            b"tEXt" => {
+                if let Some(&[0, ref text @ ..]) = data.strip_prefix(KEYWORD.as_bytes()) {
+                    return Some(text.iter().map(|&b| b as char).collect());
+                }
+            }
+            b"IDAT" | b"IEND" => return None,
+            _ => {}
+        }
+        rest = &rest[12 + length..];
+    }
+    None
+}
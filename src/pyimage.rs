@@ -0,0 +1,456 @@
+use image::DynamicImage;
+use pyo3::prelude::*;
+use pyo3::types::{IntoPyDict, PyBytes, PyDict};
+
+use crate::color::ColorSpace;
+use crate::decode;
+use crate::effects::ImageEffect;
+use crate::encode::{self, EncodeOptions, OutputOptions};
+use crate::metadata::Metadata;
+use crate::params::Params;
+use crate::progress::{self, NoProgress};
+use crate::recipe::{self, Step};
+
+/// A decoded image kept in Rust between operations
+///
+/// Effect methods such as `blur` or `resize` return the image itself, so calls
+/// chain: `Image.open("in.png").blur(2.0).sepia().save("out.png")`. They only
+/// validate their parameters and queue the effect; the queued effects run the
+/// next time the pixels are needed, by `save`, `to_bytes`, `copy` or one of
+/// the `width`, `height` and `mode` properties. If one of them fails, the
+/// queued effects are dropped and the image is left as it was before them,
+/// which means running them on a copy: while queued effects run, memory for
+/// an extra copy of the pixels is needed.
+#[pyclass(name = "Image", module = "image_processor_rust")]
+pub struct PyImage {
+    image: DynamicImage,
+    pending: Vec<Step>,
+    /// EXIF orientation of the source, until an `auto_orient` has applied it.
+    orientation: Option<u16>,
+    /// What `keep_metadata` copies into the output.
+    metadata: Metadata,
+}
+
+impl PyImage {
+    fn new(image: DynamicImage, source: &[u8]) -> Self {
+        let (orientation, metadata) = (decode::orientation(source), Metadata::read(source));
+        Self { image, pending: Vec::new(), orientation, metadata }
+    }
+
+    /// Validate an effect and queue it.
+    fn push(&mut self, name: &str, params: Option<&PyDict>, color_space: &str) -> PyResult<()> {
+        let effect = ImageEffect::parse(name, &mut Params::from_dict(params)?)?;
+        let color_space = ColorSpace::parse(color_space)?;
+        self.pending.push(Step { name: name.to_string(), effect, color_space });
+        Ok(())
+    }
+
+    /// Run the queued effects, reporting their combined progress to
+    /// `progress_callback` (nothing when it is `None`). They run on a copy of
+    /// the image, which is only replaced once they all succeed.
+    fn evaluate(&mut self, py: Python, progress_callback: Option<PyObject>) -> PyResult<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let steps = std::mem::take(&mut self.pending);
+        let progress_callback = match progress_callback {
+            Some(callback) => progress::cancellable(py, callback, None, 0.0)?,
+            None => Py::new(py, NoProgress)?.into_py(py),
+        };
+        let orientation = self.orientation;
+        let mut oriented = false;
+        let source_orientation = || {
+            oriented = true;
+            Ok(orientation)
+        };
+        self.image =
+            recipe::run(py, self.image.clone(), &steps, source_orientation, &progress_callback)?;
+        if oriented {
+            self.orientation = None;
+            self.metadata.clear_orientation();
+        }
+        Ok(())
+    }
+}
+
+/// `items` as keyword parameters for an effect, leaving out those not given.
+fn params<'py>(py: Python<'py>, items: Vec<(&str, Option<PyObject>)>) -> &'py PyDict {
+    items.into_iter().filter_map(|(key, value)| value.map(|v| (key, v))).into_py_dict(py)
+}
+
+#[pymethods]
+impl PyImage {
+    /// Decode the image file at `path`.
+    #[staticmethod]
+    fn open(path: &str) -> PyResult<Self> {
+        let bytes = decode::read_bytes(path)?;
+        Ok(Self::new(decode::from_file_bytes(path, &bytes)?, &bytes))
+    }
+
+    /// Decode an image file held in memory, in any supported format.
+    #[staticmethod]
+    fn from_bytes(data: &[u8]) -> PyResult<Self> {
+        Ok(Self::new(decode::from_memory(data)?, data))
+    }
+
+    #[getter]
+    fn width(&mut self, py: Python) -> PyResult<u32> {
+        self.evaluate(py, None)?;
+        Ok(self.image.width())
+    }
+
+    #[getter]
+    fn height(&mut self, py: Python) -> PyResult<u32> {
+        self.evaluate(py, None)?;
+        Ok(self.image.height())
+    }
+
+    /// The pixel format, named as in `LossyConversionError`: `"Rgb8"`,
+    /// `"Rgba16"`, `"L8"` and so on.
+    #[getter]
+    fn mode(&mut self, py: Python) -> PyResult<String> {
+        self.evaluate(py, None)?;
+        Ok(format!("{:?}", self.image.color()))
+    }
+
+    /// Queue any effect by name, with `params` and `color_space` as in
+    /// `process_image`.
+    #[pyo3(signature = (effect, params = None, color_space = "srgb"))]
+    fn apply<'py>(
+        mut slf: PyRefMut<'py, Self>,
+        effect: &str,
+        params: Option<&PyDict>,
+        color_space: &str,
+    ) -> PyResult<PyRefMut<'py, Self>> {
+        slf.push(effect, params, color_space)?;
+        Ok(slf)
+    }
+
+    #[pyo3(signature = (sigma = None))]
+    fn blur(mut slf: PyRefMut<'_, Self>, sigma: Option<f32>) -> PyResult<PyRefMut<'_, Self>> {
+        let py = slf.py();
+        let params = params(py, vec![("sigma", sigma.map(|v| v.into_py(py)))]);
+        slf.push("blur", Some(params), "srgb")?;
+        Ok(slf)
+    }
+
+    #[pyo3(signature = (amount = None))]
+    fn sharpen(mut slf: PyRefMut<'_, Self>, amount: Option<f32>) -> PyResult<PyRefMut<'_, Self>> {
+        let py = slf.py();
+        let params = params(py, vec![("amount", amount.map(|v| v.into_py(py)))]);
+        slf.push("sharpen", Some(params), "srgb")?;
+        Ok(slf)
+    }
+
+    fn grayscale(mut slf: PyRefMut<'_, Self>) -> PyResult<PyRefMut<'_, Self>> {
+        slf.push("grayscale", None, "srgb")?;
+        Ok(slf)
+    }
+
+    fn sepia(mut slf: PyRefMut<'_, Self>) -> PyResult<PyRefMut<'_, Self>> {
+        slf.push("sepia", None, "srgb")?;
+        Ok(slf)
+    }
+
+    fn invert(mut slf: PyRefMut<'_, Self>) -> PyResult<PyRefMut<'_, Self>> {
+        slf.push("invert", None, "srgb")?;
+        Ok(slf)
+    }
+
+    fn edge_detect(mut slf: PyRefMut<'_, Self>) -> PyResult<PyRefMut<'_, Self>> {
+        slf.push("edge_detect", None, "srgb")?;
+        Ok(slf)
+    }
+
+    /// Resize as the `resize` effect does; a missing side keeps the aspect
+    /// ratio.
+    #[pyo3(signature = (width = None, height = None, filter = None))]
+    fn resize<'py>(
+        mut slf: PyRefMut<'py, Self>,
+        width: Option<u32>,
+        height: Option<u32>,
+        filter: Option<&str>,
+    ) -> PyResult<PyRefMut<'py, Self>> {
+        let py = slf.py();
+        let params = params(
+            py,
+            vec![
+                ("width", width.map(|v| v.into_py(py))),
+                ("height", height.map(|v| v.into_py(py))),
+                ("filter", filter.map(|v| v.into_py(py))),
+            ],
+        );
+        slf.push("resize", Some(params), "srgb")?;
+        Ok(slf)
+    }
+
+    fn thumbnail(mut slf: PyRefMut<'_, Self>, size: u32) -> PyResult<PyRefMut<'_, Self>> {
+        let py = slf.py();
+        let params = [("size", size)].into_py_dict(py);
+        slf.push("thumbnail", Some(params), "srgb")?;
+        Ok(slf)
+    }
+
+    fn crop(
+        mut slf: PyRefMut<'_, Self>,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> PyResult<PyRefMut<'_, Self>> {
+        let py = slf.py();
+        let params = [("x", x), ("y", y), ("width", width), ("height", height)].into_py_dict(py);
+        slf.push("crop", Some(params), "srgb")?;
+        Ok(slf)
+    }
+
+    /// Rotate clockwise by `angle` degrees; see the `rotate` effect.
+    #[pyo3(signature = (angle, expand = false))]
+    fn rotate(
+        mut slf: PyRefMut<'_, Self>,
+        angle: f32,
+        expand: bool,
+    ) -> PyResult<PyRefMut<'_, Self>> {
+        let py = slf.py();
+        let params =
+            [("angle", angle.into_py(py)), ("expand", expand.into_py(py))].into_py_dict(py);
+        slf.push("rotate", Some(params), "srgb")?;
+        Ok(slf)
+    }
+
+    fn flip_h(mut slf: PyRefMut<'_, Self>) -> PyResult<PyRefMut<'_, Self>> {
+        slf.push("flip_h", None, "srgb")?;
+        Ok(slf)
+    }
+
+    fn flip_v(mut slf: PyRefMut<'_, Self>) -> PyResult<PyRefMut<'_, Self>> {
+        slf.push("flip_v", None, "srgb")?;
+        Ok(slf)
+    }
+
+    /// Turn the image upright by the EXIF orientation of the file or bytes it
+    /// was opened from.
+    fn auto_orient(mut slf: PyRefMut<'_, Self>) -> PyResult<PyRefMut<'_, Self>> {
+        slf.push("auto_orient", None, "srgb")?;
+        Ok(slf)
+    }
+
+    /// Draw a rectangle with its top-left pixel at (`x`, `y`): an outline
+    /// `thickness` pixels wide (default 1), or filled with `fill`. `color` is
+    /// an `(r, g, b)` or `(r, g, b, a)` tuple, opaque red by default; the
+    /// other `draw_*` methods take it too.
+    #[pyo3(signature = (x, y, width, height, color = None, thickness = None, fill = false))]
+    #[allow(clippy::too_many_arguments)]
+    fn draw_rect(
+        mut slf: PyRefMut<'_, Self>,
+        x: i64,
+        y: i64,
+        width: u32,
+        height: u32,
+        color: Option<PyObject>,
+        thickness: Option<u32>,
+        fill: bool,
+    ) -> PyResult<PyRefMut<'_, Self>> {
+        let py = slf.py();
+        let params = params(
+            py,
+            vec![
+                ("x", Some(x.into_py(py))),
+                ("y", Some(y.into_py(py))),
+                ("width", Some(width.into_py(py))),
+                ("height", Some(height.into_py(py))),
+                ("color", color),
+                ("thickness", thickness.map(|v| v.into_py(py))),
+                ("fill", Some(fill.into_py(py))),
+            ],
+        );
+        slf.push("draw_rect", Some(params), "srgb")?;
+        Ok(slf)
+    }
+
+    /// Draw a line from (`x1`, `y1`) to (`x2`, `y2`).
+    #[pyo3(signature = (x1, y1, x2, y2, color = None, thickness = None))]
+    fn draw_line(
+        mut slf: PyRefMut<'_, Self>,
+        x1: f32,
+        y1: f32,
+        x2: f32,
+        y2: f32,
+        color: Option<PyObject>,
+        thickness: Option<u32>,
+    ) -> PyResult<PyRefMut<'_, Self>> {
+        let py = slf.py();
+        let params = params(
+            py,
+            vec![
+                ("x1", Some(x1.into_py(py))),
+                ("y1", Some(y1.into_py(py))),
+                ("x2", Some(x2.into_py(py))),
+                ("y2", Some(y2.into_py(py))),
+                ("color", color),
+                ("thickness", thickness.map(|v| v.into_py(py))),
+            ],
+        );
+        slf.push("draw_line", Some(params), "srgb")?;
+        Ok(slf)
+    }
+
+    /// Draw a circle of `radius` around (`x`, `y`), outlined or filled as in
+    /// `draw_rect`.
+    #[pyo3(signature = (x, y, radius, color = None, thickness = None, fill = false))]
+    fn draw_circle(
+        mut slf: PyRefMut<'_, Self>,
+        x: f32,
+        y: f32,
+        radius: f32,
+        color: Option<PyObject>,
+        thickness: Option<u32>,
+        fill: bool,
+    ) -> PyResult<PyRefMut<'_, Self>> {
+        let py = slf.py();
+        let params = params(
+            py,
+            vec![
+                ("x", Some(x.into_py(py))),
+                ("y", Some(y.into_py(py))),
+                ("radius", Some(radius.into_py(py))),
+                ("color", color),
+                ("thickness", thickness.map(|v| v.into_py(py))),
+                ("fill", Some(fill.into_py(py))),
+            ],
+        );
+        slf.push("draw_circle", Some(params), "srgb")?;
+        Ok(slf)
+    }
+
+    /// Draw `text` with the TrueType or OpenType font at `font_path`, `size`
+    /// pixels high (default 16), its top-left corner at (`x`, `y`).
+    #[pyo3(signature = (text, x, y, font_path, size = None, color = None))]
+    fn draw_text<'py>(
+        mut slf: PyRefMut<'py, Self>,
+        text: &str,
+        x: i64,
+        y: i64,
+        font_path: &str,
+        size: Option<f32>,
+        color: Option<PyObject>,
+    ) -> PyResult<PyRefMut<'py, Self>> {
+        let py = slf.py();
+        let params = params(
+            py,
+            vec![
+                ("text", Some(text.into_py(py))),
+                ("x", Some(x.into_py(py))),
+                ("y", Some(y.into_py(py))),
+                ("font_path", Some(font_path.into_py(py))),
+                ("size", size.map(|v| v.into_py(py))),
+                ("color", color),
+            ],
+        );
+        slf.push("draw_text", Some(params), "srgb")?;
+        Ok(slf)
+    }
+
+    /// An independent image with the current pixels, queued effects applied.
+    fn copy(&mut self, py: Python) -> PyResult<Self> {
+        self.evaluate(py, None)?;
+        Ok(Self {
+            image: self.image.clone(),
+            pending: Vec::new(),
+            orientation: self.orientation,
+            metadata: self.metadata.clone(),
+        })
+    }
+
+    /// Write the image to `path`, inferring the format from the extension.
+    ///
+    /// `progress_callback` receives the progress of the queued effects. The
+    /// encoder options, `output_options` included, work as in
+    /// `process_image`.
+    #[pyo3(signature = (
+        path,
+        progress_callback = None,
+        png_compression = None,
+        png_filter = None,
+        jpeg_progressive = false,
+        output_color = None,
+        avif_quality = None,
+        output_options = None
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn save(
+        &mut self,
+        py: Python,
+        path: &str,
+        progress_callback: Option<PyObject>,
+        png_compression: Option<&str>,
+        png_filter: Option<&str>,
+        jpeg_progressive: bool,
+        output_color: Option<&str>,
+        avif_quality: Option<u8>,
+        output_options: Option<PyRef<OutputOptions>>,
+    ) -> PyResult<()> {
+        let options = EncodeOptions::from_args(
+            png_compression,
+            png_filter,
+            jpeg_progressive,
+            output_color,
+            avif_quality,
+        )?
+        .or_output_options(output_options.as_deref())?;
+        self.evaluate(py, progress_callback)?;
+        let options = options.with_source_metadata(|| Ok(self.metadata.clone()))?;
+        encode::save(&self.image, path, &options)
+    }
+
+    /// Encode the image in memory as `format` (see `process_to_datauri`),
+    /// taking the same options as `save`.
+    #[pyo3(signature = (
+        format = "png",
+        progress_callback = None,
+        png_compression = None,
+        png_filter = None,
+        jpeg_progressive = false,
+        output_color = None,
+        avif_quality = None,
+        output_options = None
+    ))]
+    // Takes `&mut self` to run the queued effects first.
+    #[allow(clippy::too_many_arguments, clippy::wrong_self_convention)]
+    fn to_bytes(
+        &mut self,
+        py: Python,
+        format: &str,
+        progress_callback: Option<PyObject>,
+        png_compression: Option<&str>,
+        png_filter: Option<&str>,
+        jpeg_progressive: bool,
+        output_color: Option<&str>,
+        avif_quality: Option<u8>,
+        output_options: Option<PyRef<OutputOptions>>,
+    ) -> PyResult<PyObject> {
+        let options = EncodeOptions::from_args(
+            png_compression,
+            png_filter,
+            jpeg_progressive,
+            output_color,
+            avif_quality,
+        )?
+        .or_output_options(output_options.as_deref())?;
+        let format = options.format_or(format)?;
+        self.evaluate(py, progress_callback)?;
+        let options = options.with_source_metadata(|| Ok(self.metadata.clone()))?;
+        let bytes = encode::encode(&self.image, format, &options)?;
+        Ok(PyBytes::new(py, &bytes).into())
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "<Image {}x{} {:?}, {} pending>",
+            self.image.width(),
+            self.image.height(),
+            self.image.color(),
+            self.pending.len()
+        )
+    }
+}
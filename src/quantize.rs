@@ -0,0 +1,131 @@
+use rayon::prelude::*;
+
+use crate::progress;
+use crate::rng::SplitMix64;
+
+/// Upper bound on the number of pixels the clustering itself is run on.
+///
+/// Centroids settle long before every pixel of a large photo has been seen;
+/// the full image is only needed for the final nearest-centroid assignment.
+const SAMPLE_LIMIT: usize = 1 << 16;
+
+/// Cluster RGB colors with k-means, returning up to `k` centroids.
+///
+/// Centroids are seeded with k-means++ using `seed`, so the same input and
+/// seed always produce the same palette.
+pub fn kmeans(pixels: &[[u8; 3]], k: usize, max_iterations: u32, seed: u64) -> Vec<[f32; 3]> {
+    let step = pixels.len().div_ceil(SAMPLE_LIMIT).max(1);
+    let samples: Vec<[f32; 3]> = pixels
+        .iter()
+        .step_by(step)
+        .map(|p| [p[0] as f32, p[1] as f32, p[2] as f32])
+        .collect();
+    if samples.is_empty() {
+        return Vec::new();
+    }
+
+    let mut rng = SplitMix64::new(seed);
+    let mut centroids = init_plus_plus(&samples, k, &mut rng);
+    let mut assignment = vec![usize::MAX; samples.len()];
+    let stop = progress::current_stop();
+
+    for _ in 0..max_iterations {
+        if stop.is_stopped() {
+            break;
+        }
+        let mut changed = false;
+        for (slot, sample) in assignment.iter_mut().zip(&samples) {
+            let nearest = nearest(&centroids, sample);
+            if *slot != nearest {
+                *slot = nearest;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+
+        let mut sums = vec![[0f64; 3]; centroids.len()];
+        let mut counts = vec![0u64; centroids.len()];
+        for (&cluster, sample) in assignment.iter().zip(&samples) {
+            for c in 0..3 {
+                sums[cluster][c] += sample[c] as f64;
+            }
+            counts[cluster] += 1;
+        }
+        for ((centroid, sum), &count) in centroids.iter_mut().zip(&sums).zip(&counts) {
+            // An empty cluster keeps its previous centroid.
+            if count > 0 {
+                *centroid = sum.map(|s| (s / count as f64) as f32);
+            }
+        }
+    }
+
+    centroids
+}
+
+fn init_plus_plus(samples: &[[f32; 3]], k: usize, rng: &mut SplitMix64) -> Vec<[f32; 3]> {
+    let first = (rng.next_u64() % samples.len() as u64) as usize;
+    let mut centroids = vec![samples[first]];
+    let mut distances: Vec<f32> = samples.iter().map(|s| distance2(s, &samples[first])).collect();
+
+    while centroids.len() < k {
+        let total: f64 = distances.iter().map(|&d| d as f64).sum();
+        if total == 0.0 {
+            // Fewer distinct colors than clusters.
+            break;
+        }
+
+        let mut target = rng.next_f64() * total;
+        let mut chosen = samples.len() - 1;
+        for (i, &d) in distances.iter().enumerate() {
+            target -= d as f64;
+            if target < 0.0 {
+                chosen = i;
+                break;
+            }
+        }
+
+        let centroid = samples[chosen];
+        for (d, sample) in distances.iter_mut().zip(samples) {
+            *d = d.min(distance2(sample, &centroid));
+        }
+        centroids.push(centroid);
+    }
+
+    centroids
+}
+
+/// Index of the centroid closest to `color`.
+pub fn nearest(centroids: &[[f32; 3]], color: &[f32; 3]) -> usize {
+    let mut best = 0;
+    let mut best_distance = f32::INFINITY;
+    for (i, centroid) in centroids.iter().enumerate() {
+        let d = distance2(centroid, color);
+        if d < best_distance {
+            best = i;
+            best_distance = d;
+        }
+    }
+    best
+}
+
+fn distance2(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    let dr = a[0] - b[0];
+    let dg = a[1] - b[1];
+    let db = a[2] - b[2];
+    dr * dr + dg * dg + db * db
+}
+
+/// Replace every pixel of a packed RGB buffer with its nearest centroid.
+pub fn apply_palette(data: &mut [u8], centroids: &[[f32; 3]]) {
+    let palette: Vec<[u8; 3]> = centroids
+        .iter()
+        .map(|c| c.map(|v| v.round().clamp(0.0, 255.0) as u8))
+        .collect();
+
+    data.par_chunks_exact_mut(3).for_each(|pixel| {
+        let color = [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32];
+        pixel.copy_from_slice(&palette[nearest(centroids, &color)]);
+    });
+}
@@ -0,0 +1,215 @@
+use image::DynamicImage;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use serde_json::Value as Json;
+
+use crate::color::ColorSpace;
+use crate::effects::ImageEffect;
+use crate::errors;
+use crate::params::Params;
+use crate::progress::ScaledProgress;
+
+/// One validated step of a recipe.
+#[derive(Debug)]
+pub struct Step {
+    pub name: String,
+    pub effect: ImageEffect,
+    pub color_space: ColorSpace,
+}
+
+/// Parse a recipe: a JSON array of steps such as
+/// `{"effect": "crop", "params": {"width": 100, "height": 80}}`, each with an
+/// optional `"color_space"`.
+///
+/// Every step is validated before any image work starts, so a typo in the
+/// last step doesn't cost a run through the ones before it.
+pub fn parse(py: Python, json: &str) -> PyResult<Vec<Step>> {
+    let steps = match serde_json::from_str(json) {
+        Ok(Json::Array(steps)) => steps,
+        Ok(_) => return Err(PyValueError::new_err("Recipe must be a JSON array of steps")),
+        Err(e) => return Err(PyValueError::new_err(format!("Invalid recipe JSON: {}", e))),
+    };
+
+    steps
+        .iter()
+        .enumerate()
+        .map(|(index, step)| parse_step(step).map_err(|e| step_error(py, e, index, None)))
+        .collect()
+}
+
+/// Parse a pipeline given as a Python list whose items are effect names or
+/// dicts shaped like recipe steps, e.g.
+/// `["grayscale", {"effect": "blur", "params": {"sigma": 2.0}}]`.
+///
+/// As with [`parse`], every step is validated up front.
+pub fn from_list(py: Python, steps: &PyList) -> PyResult<Vec<Step>> {
+    steps
+        .iter()
+        .enumerate()
+        .map(|(index, step)| parse_py_step(step).map_err(|e| step_error(py, e, index, None)))
+        .collect()
+}
+
+/// Run `steps` on `image` in order, each taking an equal share of the
+/// progress range.
+///
+/// `source_orientation` reads the EXIF orientation of the input, for the
+/// first `auto_orient` step that doesn't give one; later ones see the image
+/// already upright.
+pub fn run(
+    py: Python,
+    mut image: DynamicImage,
+    steps: &[Step],
+    source_orientation: impl FnOnce() -> PyResult<Option<u16>>,
+    progress_callback: &PyObject,
+) -> PyResult<DynamicImage> {
+    let mut source_orientation = Some(source_orientation);
+    let span = 100.0 / steps.len().max(1) as f64;
+    for (index, step) in steps.iter().enumerate() {
+        let start = index as f64 * span;
+        let progress = ScaledProgress::new(progress_callback.clone_ref(py), start, span);
+        let progress = Py::new(py, progress)?.into_py(py);
+        let resolved = step.effect.resolve_orientation(|| match source_orientation.take() {
+            Some(read) => read(),
+            None => Ok(None),
+        });
+        let resolved = resolved.map_err(|e| step_error(py, e, index, Some(&step.name)))?;
+        image = resolved
+            .as_ref()
+            .unwrap_or(&step.effect)
+            .apply(py, image, step.color_space, &progress)
+            .map_err(|e| step_error(py, e, index, Some(&step.name)))?;
+    }
+    if steps.is_empty() {
+        progress_callback.call1(py, (100,))?;
+    }
+    Ok(image)
+}
+
+fn parse_py_step(step: &PyAny) -> PyResult<Step> {
+    if let Ok(name) = step.extract::<String>() {
+        let effect = ImageEffect::parse(&name, &mut Params::default())?;
+        return Ok(Step { name, effect, color_space: ColorSpace::Srgb });
+    }
+    let object: &PyDict = step
+        .downcast()
+        .map_err(|_| PyValueError::new_err("step must be an effect name or a dict"))?;
+    StepFields::from_dict(object)?.validate()
+}
+
+fn parse_step(step: &Json) -> PyResult<Step> {
+    let object = step
+        .as_object()
+        .ok_or_else(|| PyValueError::new_err("step must be a JSON object"))?;
+    StepFields::from_json(object)?.validate()
+}
+
+/// A key of a step object as read, before it is checked.
+enum Field<T> {
+    Missing,
+    WrongType,
+    Given(T),
+}
+
+/// The keys of a step object, read from recipe JSON or from a Python dict
+/// in a pipeline list, so that both are checked by the same [`validate`].
+///
+/// [`validate`]: StepFields::validate
+struct StepFields {
+    effect: Field<String>,
+    params: Field<Params>,
+    color_space: Field<String>,
+    unknown_key: Option<String>,
+}
+
+impl StepFields {
+    fn from_json(object: &serde_json::Map<String, Json>) -> PyResult<Self> {
+        let string = |key: &str| match object.get(key) {
+            None => Field::Missing,
+            Some(Json::String(s)) => Field::Given(s.clone()),
+            Some(_) => Field::WrongType,
+        };
+        let params = match object.get("params") {
+            None | Some(Json::Null) => Field::Missing,
+            Some(Json::Object(params)) => Field::Given(Params::from_json(params)?),
+            Some(_) => Field::WrongType,
+        };
+        let unknown_key = object.keys().find(|key| !is_step_key(key)).cloned();
+        let (effect, color_space) = (string("effect"), string("color_space"));
+        Ok(Self { effect, params, color_space, unknown_key })
+    }
+
+    fn from_dict(object: &PyDict) -> PyResult<Self> {
+        let string = |key: &str| match object.get_item(key) {
+            None => Field::Missing,
+            Some(value) => value.extract().map_or(Field::WrongType, Field::Given),
+        };
+        let params = match object.get_item("params") {
+            None => Field::Missing,
+            Some(params) if params.is_none() => Field::Missing,
+            Some(params) => match params.downcast::<PyDict>() {
+                Ok(params) => Field::Given(Params::from_dict(Some(params))?),
+                Err(_) => Field::WrongType,
+            },
+        };
+        let mut unknown_key = None;
+        for key in object.keys() {
+            let key = key.extract::<String>()?;
+            if !is_step_key(&key) {
+                unknown_key = Some(key);
+                break;
+            }
+        }
+        let (effect, color_space) = (string("effect"), string("color_space"));
+        Ok(Self { effect, params, color_space, unknown_key })
+    }
+
+    /// The step these keys describe, or why they don't make one.
+    fn validate(self) -> PyResult<Step> {
+        let name = match self.effect {
+            Field::Given(name) => name,
+            Field::Missing => return Err(PyValueError::new_err("step needs an \"effect\" name")),
+            Field::WrongType => return Err(PyValueError::new_err("\"effect\" must be a string")),
+        };
+        let mut params = match self.params {
+            Field::Given(params) => params,
+            Field::Missing => Params::default(),
+            Field::WrongType => {
+                return Err(PyValueError::new_err("\"params\" must be an object of parameters"))
+            }
+        };
+        let color_space = match self.color_space {
+            Field::Given(s) => ColorSpace::parse(&s)?,
+            Field::Missing => ColorSpace::Srgb,
+            Field::WrongType => {
+                let message = "\"color_space\" must be a string".to_string();
+                return Err(errors::invalid_parameter("color_space", message));
+            }
+        };
+        if let Some(key) = self.unknown_key {
+            return Err(PyValueError::new_err(format!("Unknown step key '{}'", key)));
+        }
+
+        let effect = ImageEffect::parse(&name, &mut params)?;
+        Ok(Step { name, effect, color_space })
+    }
+}
+
+fn is_step_key(key: &str) -> bool {
+    matches!(key, "effect" | "params" | "color_space")
+}
+
+/// Prefix `err`'s message with the failing step and record it as a `step`
+/// attribute, keeping the exception type and any other attributes intact.
+pub fn step_error(py: Python, err: PyErr, index: usize, name: Option<&str>) -> PyErr {
+    let value = err.value(py);
+    let message = value.str().map(|s| s.to_string()).unwrap_or_default();
+    let message = match name {
+        Some(name) => format!("Recipe step {} ('{}'): {}", index, name, message),
+        None => format!("Recipe step {}: {}", index, message),
+    };
+    let _ = value.setattr("args", (message,));
+    let _ = value.setattr("step", index);
+    err
+}
@@ -0,0 +1,178 @@
+use image::imageops::FilterType;
+use image::{ColorType, DynamicImage, ImageBuffer, Rgba};
+use rayon::prelude::*;
+
+/// Resampling filter used when changing an image's size.
+#[derive(Debug, Clone, Copy)]
+pub enum Filter {
+    Nearest,
+    /// Linear interpolation, also accepted as `"bilinear"`.
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+    /// Each output pixel is the average of the source area it covers. Doesn't
+    /// ring like Lanczos or alias like nearest, which makes it the cleanest
+    /// choice for downscaling screenshots and diagrams.
+    Area,
+}
+
+impl Filter {
+    pub const NAMES: &'static str =
+        "nearest, bilinear, triangle, catmull_rom, gaussian, lanczos3, area";
+
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "nearest" => Some(Self::Nearest),
+            "triangle" | "bilinear" => Some(Self::Triangle),
+            "catmull_rom" => Some(Self::CatmullRom),
+            "gaussian" => Some(Self::Gaussian),
+            "lanczos3" => Some(Self::Lanczos3),
+            "area" => Some(Self::Area),
+            _ => None,
+        }
+    }
+}
+
+/// Resize `image` to exactly `width` x `height`, keeping its pixel format.
+pub fn resize(image: &DynamicImage, width: u32, height: u32, filter: Filter) -> DynamicImage {
+    let filter = match filter {
+        Filter::Nearest => FilterType::Nearest,
+        Filter::Triangle => FilterType::Triangle,
+        Filter::CatmullRom => FilterType::CatmullRom,
+        Filter::Gaussian => FilterType::Gaussian,
+        Filter::Lanczos3 => FilterType::Lanczos3,
+        Filter::Area => return area(image, width, height),
+    };
+    image.resize_exact(width, height, filter)
+}
+
+/// For each output index along an axis, the source indices it covers and the
+/// fraction of each that falls inside it.
+fn area_weights(source: u32, target: u32) -> Vec<Vec<(usize, f32)>> {
+    let scale = source as f64 / target as f64;
+    (0..target)
+        .map(|i| {
+            let (start, end) = (i as f64 * scale, (i + 1) as f64 * scale);
+            let mut weights = Vec::new();
+            let mut s = start.floor() as u32;
+            while (s as f64) < end && s < source {
+                let covered = (end.min(s as f64 + 1.0) - start.max(s as f64)) / scale;
+                if covered > 0.0 {
+                    weights.push((s as usize, covered as f32));
+                }
+                s += 1;
+            }
+            weights
+        })
+        .collect()
+}
+
+fn area(image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let source = image.to_rgba32f();
+    let (src_width, src_height) = source.dimensions();
+    let columns = area_weights(src_width, width);
+    let rows = area_weights(src_height, height);
+    let src = source.as_raw();
+
+    // Horizontal pass into a width x src_height buffer, then vertical.
+    let mut horizontal = vec![0f32; width as usize * src_height as usize * 4];
+    horizontal
+        .par_chunks_exact_mut(width as usize * 4)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let src_row = &src[y * src_width as usize * 4..(y + 1) * src_width as usize * 4];
+            for (x, weights) in columns.iter().enumerate() {
+                for &(sx, w) in weights {
+                    for c in 0..4 {
+                        row[x * 4 + c] += src_row[sx * 4 + c] * w;
+                    }
+                }
+            }
+        });
+
+    let mut out = vec![0f32; width as usize * height as usize * 4];
+    let stride = width as usize * 4;
+    out.par_chunks_exact_mut(stride).enumerate().for_each(|(y, row)| {
+        for &(sy, w) in &rows[y] {
+            let src_row = &horizontal[sy * stride..(sy + 1) * stride];
+            for (value, source) in row.iter_mut().zip(src_row) {
+                *value += source * w;
+            }
+        }
+    });
+
+    let buffer: ImageBuffer<Rgba<f32>, _> = ImageBuffer::from_raw(width, height, out).unwrap();
+    to_color(DynamicImage::ImageRgba32F(buffer), image.color())
+}
+
+/// Convert `image` to the given pixel format.
+pub fn to_color(image: DynamicImage, color: ColorType) -> DynamicImage {
+    match color {
+        ColorType::L8 => DynamicImage::ImageLuma8(image.to_luma8()),
+        ColorType::La8 => DynamicImage::ImageLumaA8(image.to_luma_alpha8()),
+        ColorType::Rgb8 => DynamicImage::ImageRgb8(image.to_rgb8()),
+        ColorType::Rgba8 => DynamicImage::ImageRgba8(image.to_rgba8()),
+        ColorType::L16 => DynamicImage::ImageLuma16(image.to_luma16()),
+        ColorType::La16 => DynamicImage::ImageLumaA16(image.to_luma_alpha16()),
+        ColorType::Rgb16 => DynamicImage::ImageRgb16(image.to_rgb16()),
+        ColorType::Rgba16 => DynamicImage::ImageRgba16(image.to_rgba16()),
+        ColorType::Rgb32F => DynamicImage::ImageRgb32F(image.to_rgb32f()),
+        _ => image,
+    }
+}
+
+/// Size that fits inside `max_width` x `max_height` with the aspect ratio of
+/// `width` x `height`, never upscaling and never below one pixel.
+pub fn fit_within(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    let scale = (max_width as f64 / width as f64)
+        .min(max_height as f64 / height as f64)
+        .min(1.0);
+    let scaled = |v: u32| ((v as f64 * scale).round() as u32).max(1);
+    (scaled(width), scaled(height))
+}
+
+/// Like [`fit_within`], but scales up as well as down so that the result
+/// touches the box on at least one side.
+pub fn scale_to_fit(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    let scale = (max_width as f64 / width as f64).min(max_height as f64 / height as f64);
+    let scaled = |v: u32| ((v as f64 * scale).round() as u32).max(1);
+    (scaled(width), scaled(height))
+}
+
+/// Size for a resize to `target_width` x `target_height`, either of which may
+/// be left out to follow from the other and the aspect ratio of `width` x
+/// `height`. Scales up as well as down, never below one pixel.
+pub fn scale_to(
+    width: u32,
+    height: u32,
+    target_width: Option<u32>,
+    target_height: Option<u32>,
+) -> (u32, u32) {
+    let scaled = |v: u32, scale: f64| ((v as f64 * scale).round() as u32).max(1);
+    match (target_width, target_height) {
+        (Some(w), Some(h)) => (w, h),
+        (Some(w), None) => (w, scaled(height, w as f64 / width as f64)),
+        (None, Some(h)) => (scaled(width, h as f64 / height as f64), h),
+        (None, None) => (width, height),
+    }
+}
+
+/// Size with the aspect ratio of `width` x `height` and at most `max_pixels`
+/// pixels in all, never upscaling and never below one pixel.
+pub fn fit_pixels(width: u32, height: u32, max_pixels: f64) -> (u32, u32) {
+    let scale = (max_pixels / (width as f64 * height as f64)).sqrt().min(1.0);
+    let scaled = |v: u32| ((v as f64 * scale).floor() as u32).max(1);
+    (scaled(width), scaled(height))
+}
+
+/// Largest size with a width-to-height ratio of `aspect` that fits inside
+/// `width` x `height`. It always spans one of the two dimensions in full.
+pub fn largest_with_aspect(width: u32, height: u32, aspect: f32) -> (u32, u32) {
+    if width as f32 / height as f32 > aspect {
+        (((height as f32 * aspect).round() as u32).clamp(1, width), height)
+    } else {
+        (width, ((width as f32 / aspect).round() as u32).clamp(1, height))
+    }
+}
+
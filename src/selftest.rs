@@ -0,0 +1,320 @@
+use image::{DynamicImage, Rgb, RgbImage, Rgba, Rgba32FImage};
+use imageproc::geometric_transformations::Interpolation;
+use pyo3::prelude::*;
+
+use crate::blend::BlendMode;
+use crate::color::{ColorSpace, GamutMapping};
+use crate::colormap::Colormap;
+use crate::convolve::Kernel;
+use crate::curve;
+use crate::decode::LazyFile;
+use crate::draw::Shape;
+use crate::effects::{EdgeSmoothing, ImageEffect, Sides, ToneOperator};
+use crate::progress::NoProgress;
+use crate::resize::Filter;
+use crate::rng::SplitMix64;
+
+const WIDTH: u32 = 48;
+const HEIGHT: u32 = 32;
+
+/// `edge_detect` at its default thresholds.
+fn edge_detect(invert_output: bool, smoothing: EdgeSmoothing) -> ImageEffect {
+    ImageEffect::EdgeDetect { invert_output, smoothing, low_threshold: 25.0, high_threshold: 75.0 }
+}
+
+/// `sharpen` at its default sigma and threshold.
+fn sharpen(amount: f32, luma_only: bool) -> ImageEffect {
+    ImageEffect::Sharpen { sigma: 1.0, threshold: 5, amount, luma_only }
+}
+
+fn thumbnail(size: u32, filter: Filter, only_if_larger: bool) -> ImageEffect {
+    ImageEffect::Thumbnail { size, filter, only_if_larger }
+}
+
+fn rotate(angle: f32, interpolation: Interpolation, expand: bool) -> ImageEffect {
+    ImageEffect::Rotate { angle, fill: [0, 0, 0, 0], interpolation, expand }
+}
+
+/// `convolve` with a built-in kernel at its default divisor and offset.
+fn convolve(preset: &str, size: usize, angle: f32) -> ImageEffect {
+    let (kernel, offset) = Kernel::preset(preset, size, angle).unwrap();
+    ImageEffect::Convolve { divisor: kernel.default_divisor(), kernel, offset }
+}
+
+/// A `draw_*` effect in translucent yellow.
+fn draw(shape: Shape) -> ImageEffect {
+    ImageEffect::Draw { shape: Box::new(shape), color: [255, 220, 0, 192] }
+}
+
+/// Every effect with fixed parameters, run against the built-in image.
+fn cases() -> Vec<(&'static str, ImageEffect, ColorSpace)> {
+    use ImageEffect::*;
+    vec![
+        ("edge_detect", edge_detect(true, EdgeSmoothing::None), ColorSpace::Srgb),
+        ("edge_detect_white", edge_detect(false, EdgeSmoothing::None), ColorSpace::Srgb),
+        (
+            "edge_detect_gaussian",
+            edge_detect(true, EdgeSmoothing::Gaussian { sigma: 1.4 }),
+            ColorSpace::Srgb,
+        ),
+        (
+            "edge_detect_bilateral",
+            edge_detect(true, EdgeSmoothing::Bilateral { sigma: 1.4, sigma_color: 25.0 }),
+            ColorSpace::Srgb,
+        ),
+        (
+            "edge_detect_thresholds",
+            EdgeDetect {
+                invert_output: true,
+                smoothing: EdgeSmoothing::None,
+                low_threshold: 10.0,
+                high_threshold: 40.0,
+            },
+            ColorSpace::Srgb,
+        ),
+        ("blur", Blur { sigma: 2.0 }, ColorSpace::Srgb),
+        ("blur_linear", Blur { sigma: 2.0 }, ColorSpace::Linear),
+        ("sharpen", sharpen(1.0, false), ColorSpace::Srgb),
+        ("sharpen_linear", sharpen(1.0, false), ColorSpace::Linear),
+        ("sharpen_luma", sharpen(1.0, true), ColorSpace::Srgb),
+        ("sharpen_amount", sharpen(2.5, false), ColorSpace::Srgb),
+        ("grayscale", Grayscale, ColorSpace::Srgb),
+        ("sepia", Sepia, ColorSpace::Srgb),
+        ("invert", Invert, ColorSpace::Srgb),
+        ("identity", Identity, ColorSpace::Srgb),
+        ("auto_white_balance", AutoWhiteBalance { clip_percent: 1.0 }, ColorSpace::Srgb),
+        ("crop", Crop { x: 5, y: 3, width: 20, height: 16 }, ColorSpace::Srgb),
+        ("kmeans_quantize", KmeansQuantize { k: 8, max_iterations: 20, seed: 0 }, ColorSpace::Srgb),
+        (
+            "chroma_key",
+            ChromaKey { color: [0, 255, 0], tolerance: 60.0, softness: 20.0 },
+            ColorSpace::Srgb,
+        ),
+        (
+            "comic",
+            Comic { levels: 4, low_threshold: 25.0, high_threshold: 75.0, edge_thickness: 2 },
+            ColorSpace::Srgb,
+        ),
+        ("autocrop", Autocrop { color: [0, 0, 0], tolerance: 24 }, ColorSpace::Srgb),
+        ("vintage", Vintage { strength: 1.0 }, ColorSpace::Srgb),
+        (
+            "brightness_hsv",
+            BrightnessHsv { factor: 1.5, gamut: GamutMapping::Clip, dither: false },
+            ColorSpace::Srgb,
+        ),
+        ("hue_rotate", HueRotate { degrees: 120.0, dither: false }, ColorSpace::Srgb),
+        (
+            "saturation_soft_dither",
+            Saturation { factor: 1.8, gamut: GamutMapping::Soft, dither: true },
+            ColorSpace::Srgb,
+        ),
+        ("rotate", rotate(30.0, Interpolation::Bilinear, false), ColorSpace::Srgb),
+        ("rotate_bicubic", rotate(30.0, Interpolation::Bicubic, false), ColorSpace::Srgb),
+        ("rotate_expand", rotate(30.0, Interpolation::Bilinear, true), ColorSpace::Srgb),
+        ("rotate_quarter", rotate(-90.0, Interpolation::Bilinear, true), ColorSpace::Srgb),
+        ("flip_h", FlipHorizontal, ColorSpace::Srgb),
+        ("flip_v", FlipVertical, ColorSpace::Srgb),
+        (
+            "trim_uniform_background",
+            TrimBackground {
+                color: None,
+                tolerance: 24,
+                sides: Sides { top: false, bottom: false, left: true, right: true },
+            },
+            ColorSpace::Srgb,
+        ),
+        ("laplacian_sharpen", LaplacianSharpen { amount: 1.0 }, ColorSpace::Srgb),
+        ("colorize_viridis", Colorize { colormap: Colormap::Viridis }, ColorSpace::Srgb),
+        ("colorize_magma", Colorize { colormap: Colormap::Magma }, ColorSpace::Srgb),
+        ("colorize_jet", Colorize { colormap: Colormap::Jet }, ColorSpace::Srgb),
+        ("seam_carve", SeamCarve { width: Some(40), height: Some(28) }, ColorSpace::Srgb),
+        (
+            "resize",
+            Resize {
+                width: Some(30),
+                height: None,
+                filter: Filter::Lanczos3,
+                only_if_larger: false,
+            },
+            ColorSpace::Srgb,
+        ),
+        ("thumbnail", thumbnail(20, Filter::Area, true), ColorSpace::Srgb),
+        ("thumbnail_linear", thumbnail(20, Filter::Area, true), ColorSpace::Linear),
+        ("thumbnail_upscale", thumbnail(96, Filter::CatmullRom, false), ColorSpace::Srgb),
+        ("clahe", Clahe { tiles: 4, clip_limit: 3.0 }, ColorSpace::Srgb),
+        (
+            "tonemap",
+            Tonemap { operator: ToneOperator::Reinhard, exposure: 1.0 },
+            ColorSpace::Srgb,
+        ),
+        (
+            "fix_chromatic_aberration",
+            FixChromaticAberration { r_scale: 0.99, b_scale: 1.01 },
+            ColorSpace::Srgb,
+        ),
+        ("tonemap_aces", Tonemap { operator: ToneOperator::Aces, exposure: 0.0 }, ColorSpace::Srgb),
+        (
+            "max_megapixels",
+            MaxMegapixels { megapixels: 0.0005, filter: Filter::Area },
+            ColorSpace::Srgb,
+        ),
+        (
+            "clip_warning",
+            ClipWarning { low_color: [0, 0, 255], high_color: [255, 0, 0], dim: 0.5 },
+            ColorSpace::Srgb,
+        ),
+        (
+            "normalize_exposure",
+            NormalizeExposure { target_mean: 118.0, gamma: false },
+            ColorSpace::Srgb,
+        ),
+        (
+            "normalize_exposure_gamma",
+            NormalizeExposure { target_mean: 118.0, gamma: true },
+            ColorSpace::Srgb,
+        ),
+        ("curve", Curve { luts: s_curve() }, ColorSpace::Srgb),
+        (
+            "deskew",
+            Deskew {
+                max_angle: 10.0,
+                fill: [255, 255, 255, 255],
+                interpolation: Interpolation::Bilinear,
+            },
+            ColorSpace::Srgb,
+        ),
+        (
+            "saliency_crop",
+            SaliencyCrop { aspect_ratio: 0.75, center_bias: 0.25 },
+            ColorSpace::Srgb,
+        ),
+        ("crop_aspect", CropAspect { aspect_ratio: 16.0 / 9.0 }, ColorSpace::Srgb),
+        (
+            "remove_noise_wavelet",
+            WaveletDenoise { threshold: 10.0, levels: 3, chroma: true },
+            ColorSpace::Srgb,
+        ),
+        (
+            "remove_noise_wavelet_luma",
+            WaveletDenoise { threshold: 10.0, levels: 3, chroma: false },
+            ColorSpace::Srgb,
+        ),
+        (
+            "difference",
+            Difference {
+                reference: LazyFile::with_contents(test_image().fliph()),
+                amplify: 4.0,
+            },
+            ColorSpace::Srgb,
+        ),
+        (
+            "overlay",
+            Overlay {
+                overlay: LazyFile::with_contents(watermark()),
+                x: -4,
+                y: 8,
+                opacity: 0.75,
+                mode: BlendMode::Screen,
+            },
+            ColorSpace::Srgb,
+        ),
+        (
+            "draw_rect",
+            draw(Shape::Rect { x: 4, y: -2, width: 20, height: 14, thickness: 3, fill: false }),
+            ColorSpace::Srgb,
+        ),
+        (
+            "draw_line",
+            draw(Shape::Line { from: (2.0, 30.0), to: (45.5, 3.25), thickness: 2 }),
+            ColorSpace::Srgb,
+        ),
+        (
+            "draw_circle",
+            draw(Shape::Circle { center: (30.0, 16.5), radius: 9.5, thickness: 2, fill: false }),
+            ColorSpace::Srgb,
+        ),
+        (
+            "selective_color",
+            SelectiveColor { hue: 210.0, tolerance: 30.0, softness: 10.0 },
+            ColorSpace::Srgb,
+        ),
+        ("brightness", Brightness { amount: 0.15 }, ColorSpace::Srgb),
+        ("contrast", Contrast { factor: 1.4 }, ColorSpace::Srgb),
+        ("gamma", Gamma { gamma: 2.2 }, ColorSpace::Srgb),
+        ("exposure", Exposure { stops: -1.0 }, ColorSpace::Srgb),
+        ("levels", Levels { black: 20, white: 230, gamma: 1.2 }, ColorSpace::Srgb),
+        ("equalize_histogram", EqualizeHistogram, ColorSpace::Srgb),
+        ("convolve_emboss", convolve("emboss", 3, 0.0), ColorSpace::Srgb),
+        ("convolve_sobel_x", convolve("sobel_x", 3, 0.0), ColorSpace::Srgb),
+        ("convolve_motion_blur", convolve("motion_blur", 9, 30.0), ColorSpace::Linear),
+        (
+            "convolve_kernel",
+            Convolve {
+                kernel: Kernel::from_rows(vec![
+                    vec![0.0, -1.0, 0.0],
+                    vec![-1.0, 6.0, -1.0],
+                    vec![0.0, -1.0, 0.0],
+                ])
+                .unwrap(),
+                divisor: 2.0,
+                offset: 0.0,
+            },
+            ColorSpace::Srgb,
+        ),
+        ("auto_orient", AutoOrient { orientation: Some(5) }, ColorSpace::Srgb),
+        ("auto", Auto, ColorSpace::Srgb),
+    ]
+}
+
+/// A contrast-boosting S curve, with a warmer red channel.
+fn s_curve() -> Box<[[u8; 256]; 3]> {
+    let points = [(0, 0), (64, 48), (192, 208), (255, 255)];
+    let warm = [(0, 0), (128, 150), (255, 255)];
+    Box::new([curve::lut(&warm, true), curve::lut(&points, true), curve::lut(&points, false)])
+}
+
+/// A small RGB image with gradients, hard edges, noise and a dark border, so
+/// that every effect has something to act on.
+fn test_image() -> DynamicImage {
+    let mut rng = SplitMix64::new(0x5e1f_7e57);
+    let image = RgbImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        if x < 2 || y < 2 || x >= WIDTH - 2 || y >= HEIGHT - 2 {
+            return Rgb([4, 4, 4]);
+        }
+        let noise = (rng.next_u64() % 32) as u8;
+        let square = if (x / 8 + y / 8) % 2 == 0 { 96 } else { 0 };
+        Rgb([(x * 4) as u8 + noise, (y * 4) as u8 + square, 255 - (x * 3 + y * 2) as u8])
+    });
+    DynamicImage::ImageRgb8(image)
+}
+
+/// A small overlay with a color ramp that fades in from the top, for `overlay`.
+fn watermark() -> Rgba32FImage {
+    Rgba32FImage::from_fn(20, 12, |x, y| Rgba([0.9, x as f32 / 19.0, 0.2, y as f32 / 11.0]))
+}
+
+/// 64-bit FNV-1a over the dimensions, pixel layout and raw samples.
+fn fingerprint(image: &DynamicImage) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let header = [image.width().to_le_bytes(), image.height().to_le_bytes()].concat();
+    let layout = format!("{:?}", image.color());
+    for byte in header.iter().chain(layout.as_bytes()).chain(image.as_bytes()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// Run every effect on the built-in image, and on a grayscale copy of it
+/// (reported with a `_gray` suffix), and fingerprint each result.
+pub fn run(py: Python) -> PyResult<Vec<(String, String)>> {
+    let progress = Py::new(py, NoProgress)?.into_py(py);
+    let mut results = Vec::new();
+    for (name, effect, color_space) in cases() {
+        let output = effect.apply(py, test_image(), color_space, &progress)?;
+        results.push((name.to_string(), format!("{:016x}", fingerprint(&output))));
+        let output = effect.apply(py, test_image().grayscale(), color_space, &progress)?;
+        results.push((format!("{}_gray", name), format!("{:016x}", fingerprint(&output))));
+    }
+    Ok(results)
+}
@@ -0,0 +1,140 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma};
+
+type Luma32FImage = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Side of the luma copy whose low frequencies [`perceptual_hash`] keeps.
+const DCT_SIZE: usize = 32;
+
+/// Side of the block of bits every hash here is made of, 64 bits in all.
+const HASH_SIZE: usize = 8;
+
+/// Gaussian window that [`ssim`] compares neighbourhoods over, as in the
+/// original SSIM paper.
+const SSIM_SIGMA: f32 = 1.5;
+
+/// The image's luma, resampled to `width` x `height`.
+fn shrink(image: &DynamicImage, width: u32, height: u32) -> GrayImage {
+    imageops::resize(&image.to_luma8(), width, height, FilterType::Lanczos3)
+}
+
+/// Pack bits into a hash, the first one becoming the most significant.
+fn pack(bits: impl Iterator<Item = bool>) -> u64 {
+    bits.fold(0, |hash, bit| hash << 1 | bit as u64)
+}
+
+/// Average hash: an 8x8 luma thumbnail, one bit per pixel set when it is
+/// brighter than the thumbnail's mean.
+pub fn average_hash(image: &DynamicImage) -> u64 {
+    let small = shrink(image, HASH_SIZE as u32, HASH_SIZE as u32);
+    let mean = small.iter().map(|&v| v as f64).sum::<f64>() / (HASH_SIZE * HASH_SIZE) as f64;
+    pack(small.iter().map(|&v| v as f64 > mean))
+}
+
+/// Difference hash: a 9x8 luma thumbnail, one bit per pair of horizontal
+/// neighbours set when the right one is brighter.
+pub fn difference_hash(image: &DynamicImage) -> u64 {
+    let small = shrink(image, HASH_SIZE as u32 + 1, HASH_SIZE as u32);
+    pack(small.rows().flat_map(|row| {
+        let row: Vec<u8> = row.map(|p| p[0]).collect();
+        (0..HASH_SIZE).map(move |x| row[x + 1] > row[x])
+    }))
+}
+
+/// Perceptual hash: the 8x8 lowest frequencies of the discrete cosine
+/// transform of a 32x32 luma thumbnail, one bit per frequency set when it is
+/// above their median. Unlike the others this survives gamma and contrast
+/// changes, mild blur and recompression well.
+pub fn perceptual_hash(image: &DynamicImage) -> u64 {
+    let small = shrink(image, DCT_SIZE as u32, DCT_SIZE as u32);
+    let pixels: Vec<f64> = small.iter().map(|&v| v as f64).collect();
+    // cos(pi * (2n + 1) * k / 2N) for the frequencies kept.
+    let basis: Vec<f64> = (0..HASH_SIZE * DCT_SIZE)
+        .map(|i| {
+            let (k, n) = ((i / DCT_SIZE) as f64, (i % DCT_SIZE) as f64);
+            (std::f64::consts::PI * (2.0 * n + 1.0) * k / (2.0 * DCT_SIZE as f64)).cos()
+        })
+        .collect();
+    let basis_row = |k: usize| &basis[k * DCT_SIZE..][..DCT_SIZE];
+    // Transform the columns, then the rows, keeping only the low frequencies.
+    let columns: Vec<f64> = (0..HASH_SIZE * DCT_SIZE)
+        .map(|i| {
+            let (k, x) = (i / DCT_SIZE, i % DCT_SIZE);
+            basis_row(k).iter().enumerate().map(|(y, b)| b * pixels[y * DCT_SIZE + x]).sum()
+        })
+        .collect();
+    let low: Vec<f64> = (0..HASH_SIZE * HASH_SIZE)
+        .map(|i| {
+            let (ky, kx) = (i / HASH_SIZE, i % HASH_SIZE);
+            let row = &columns[ky * DCT_SIZE..][..DCT_SIZE];
+            basis_row(kx).iter().zip(row).map(|(b, v)| b * v).sum()
+        })
+        .collect();
+    let mut sorted = low.clone();
+    sorted.sort_by(f64::total_cmp);
+    let median = (sorted[sorted.len() / 2 - 1] + sorted[sorted.len() / 2]) / 2.0;
+    pack(low.iter().map(|&v| v > median))
+}
+
+/// A hash as 16 hex digits, the form `imagehash` prints too.
+pub fn to_hex(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+/// Number of bits in which two hashes differ, 0 to 64.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Mean squared error between the RGB samples of two images of the same
+/// size, on the 0-255 scale.
+pub fn mse(a: &DynamicImage, b: &DynamicImage) -> f64 {
+    let (a, b) = (a.to_rgb8(), b.to_rgb8());
+    let total: f64 = a
+        .iter()
+        .zip(b.iter())
+        .map(|(&x, &y)| {
+            let d = x as f64 - y as f64;
+            d * d
+        })
+        .sum();
+    total / a.len().max(1) as f64
+}
+
+/// Peak signal-to-noise ratio for a mean squared error, in decibels;
+/// infinite for identical images.
+pub fn psnr(mse: f64) -> f64 {
+    if mse == 0.0 {
+        return f64::INFINITY;
+    }
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+/// Mean structural similarity of the luma of two images of the same size,
+/// from -1 to 1 with 1 for identical images. Means, variances and covariance
+/// are taken over a gaussian window with a standard deviation of 1.5 pixels.
+pub fn ssim(a: &DynamicImage, b: &DynamicImage) -> f64 {
+    // Stabilising constants for samples on a 0-1 scale.
+    const C1: f32 = 0.01 * 0.01;
+    const C2: f32 = 0.03 * 0.03;
+    let (x, y) = (a.to_luma32f(), b.to_luma32f());
+    let product = |p: &Luma32FImage, q: &Luma32FImage| {
+        let samples = p.iter().zip(q.iter()).map(|(a, b)| a * b).collect();
+        Luma32FImage::from_raw(p.width(), p.height(), samples).unwrap()
+    };
+    let blur = |image: &Luma32FImage| imageproc::filter::gaussian_blur_f32(image, SSIM_SIGMA);
+    let (mean_x, mean_y) = (blur(&x), blur(&y));
+    let (xx, yy, xy) = (blur(&product(&x, &x)), blur(&product(&y, &y)), blur(&product(&x, &y)));
+
+    let mut total = 0.0;
+    for i in 0..x.len() {
+        let (mx, my) = (mean_x.as_raw()[i], mean_y.as_raw()[i]);
+        let var_x = xx.as_raw()[i] - mx * mx;
+        let var_y = yy.as_raw()[i] - my * my;
+        let cov = xy.as_raw()[i] - mx * my;
+        let numerator = (2.0 * mx * my + C1) * (2.0 * cov + C2);
+        let denominator = (mx * mx + my * my + C1) * (var_x + var_y + C2);
+        total += (numerator / denominator) as f64;
+    }
+    total / x.len().max(1) as f64
+}
@@ -0,0 +1,514 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+
+use image::io::Reader;
+use image::{ColorType, DynamicImage, ImageBuffer, ImageFormat};
+use pyo3::prelude::*;
+use tiff::decoder::{ChunkType, Decoder as TiffDecoder, DecodingResult, Limits};
+use tiff::encoder::{colortype, TiffEncoder, TiffKind};
+use tiff::tags::Tag;
+
+use crate::color::ColorSpace;
+use crate::decode::{self, load_error};
+use crate::effects::ImageEffect;
+use crate::encode::save_error;
+use crate::errors::{self, UnsupportedFormatError};
+use crate::progress::NoProgress;
+
+/// Rows of fallback input handed over at a time, which bounds the copy made
+/// of them.
+const WHOLE_IMAGE_ROWS: u32 = 256;
+
+/// Uncompressed pixel data beyond this is written as BigTIFF, whose offsets
+/// are 64-bit; the rest of the 4 GiB that classic TIFF can address is left
+/// for the tags.
+const CLASSIC_TIFF_MAX: u64 = (1 << 32) - (1 << 24);
+
+/// Decoded rows of an image, handed over a few at a time.
+trait RowSource: Send {
+    /// Append the samples of the next rows, native-endian, to `rows`.
+    /// Returns false, appending nothing, once every row has been read.
+    fn read_rows(&mut self, rows: &mut Vec<u8>) -> PyResult<bool>;
+}
+
+/// An input being read row by row.
+struct Input {
+    rows: Box<dyn RowSource>,
+    width: u32,
+    height: u32,
+    color: ColorType,
+}
+
+impl Input {
+    /// Read the image at `path` row by row where its format allows, and by
+    /// decoding it whole first where it doesn't.
+    fn open(path: &str) -> PyResult<Self> {
+        let reader = Reader::open(path).map_err(load_error)?;
+        let streamed = match reader.with_guessed_format().map_err(load_error)?.format() {
+            Some(ImageFormat::Png) => PngRows::open(path)?,
+            Some(ImageFormat::Tiff) => TiffRows::open(path)?,
+            _ => None,
+        };
+        match streamed {
+            Some(input) => Ok(input),
+            None => WholeImage::open(path),
+        }
+    }
+
+    fn row_bytes(&self) -> usize {
+        self.width as usize * self.color.bytes_per_pixel() as usize
+    }
+}
+
+/// Rows of a non-interlaced PNG, one at a time, palettes and bit depths
+/// below 8 expanded as `image` does.
+struct PngRows {
+    reader: png::Reader<BufReader<File>>,
+    sixteen_bit: bool,
+}
+
+impl PngRows {
+    /// `None` for interlaced images, whose passes each span every row.
+    fn open(path: &str) -> PyResult<Option<Input>> {
+        let file = File::open(path).map_err(load_error)?;
+        let mut decoder = png::Decoder::new(BufReader::new(file));
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let reader = decoder.read_info().map_err(load_error)?;
+        let info = reader.info();
+        if info.interlaced {
+            return Ok(None);
+        }
+        let (width, height) = (info.width, info.height);
+        let color = match reader.output_color_type() {
+            (png::ColorType::Grayscale, png::BitDepth::Eight) => ColorType::L8,
+            (png::ColorType::GrayscaleAlpha, png::BitDepth::Eight) => ColorType::La8,
+            (png::ColorType::Rgb, png::BitDepth::Eight) => ColorType::Rgb8,
+            (png::ColorType::Rgba, png::BitDepth::Eight) => ColorType::Rgba8,
+            (png::ColorType::Grayscale, png::BitDepth::Sixteen) => ColorType::L16,
+            (png::ColorType::GrayscaleAlpha, png::BitDepth::Sixteen) => ColorType::La16,
+            (png::ColorType::Rgb, png::BitDepth::Sixteen) => ColorType::Rgb16,
+            (png::ColorType::Rgba, png::BitDepth::Sixteen) => ColorType::Rgba16,
+            _ => return Ok(None),
+        };
+        let sixteen_bit = color.bytes_per_pixel() > color.channel_count();
+        let rows = Box::new(Self { reader, sixteen_bit });
+        Ok(Some(Input { rows, width, height, color }))
+    }
+}
+
+impl RowSource for PngRows {
+    fn read_rows(&mut self, rows: &mut Vec<u8>) -> PyResult<bool> {
+        let row = match self.reader.next_row().map_err(load_error)? {
+            Some(row) => row,
+            None => return Ok(false),
+        };
+        if self.sixteen_bit {
+            // PNG stores 16-bit samples big-endian.
+            let samples = row.data().chunks_exact(2);
+            rows.extend(samples.flat_map(|s| u16::from_be_bytes([s[0], s[1]]).to_ne_bytes()));
+        } else {
+            rows.extend_from_slice(row.data());
+        }
+        Ok(true)
+    }
+}
+
+/// Rows of a TIFF, a strip or a row of tiles at a time.
+struct TiffRows {
+    decoder: TiffDecoder<BufReader<File>>,
+    row_bytes: usize,
+    pixel_bytes: usize,
+    chunk_width: u32,
+    chunks_across: u32,
+    /// Index of the next strip or row of tiles.
+    next_band: u32,
+    bands: u32,
+}
+
+impl TiffRows {
+    /// `None` for samples other than 8- or 16-bit unsigned gray or RGB, with
+    /// or without alpha, and for planes stored separately.
+    fn open(path: &str) -> PyResult<Option<Input>> {
+        let file = File::open(path).map_err(load_error)?;
+        // Limits guard against whole images; a single strip or tile is
+        // allowed to be as large as it is.
+        let decoder = TiffDecoder::new(BufReader::new(file)).map_err(load_error)?;
+        let mut decoder = decoder.with_limits(Limits::unlimited());
+        let (width, height) = decoder.dimensions().map_err(load_error)?;
+        let color = match decoder.colortype().map_err(load_error)? {
+            tiff::ColorType::Gray(8) => ColorType::L8,
+            tiff::ColorType::GrayA(8) => ColorType::La8,
+            tiff::ColorType::RGB(8) => ColorType::Rgb8,
+            tiff::ColorType::RGBA(8) => ColorType::Rgba8,
+            tiff::ColorType::Gray(16) => ColorType::L16,
+            tiff::ColorType::GrayA(16) => ColorType::La16,
+            tiff::ColorType::RGB(16) => ColorType::Rgb16,
+            tiff::ColorType::RGBA(16) => ColorType::Rgba16,
+            _ => return Ok(None),
+        };
+        let planar = decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration);
+        let formats = decoder.find_tag_unsigned_vec::<u16>(Tag::SampleFormat);
+        let unsigned = formats.map_err(load_error)?.is_none_or(|f| f.iter().all(|&f| f == 1));
+        if planar.map_err(load_error)?.is_some_and(|p| p != 1) || !unsigned {
+            return Ok(None);
+        }
+
+        let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+        let chunks_across = match decoder.get_chunk_type() {
+            ChunkType::Strip => 1,
+            ChunkType::Tile => width.div_ceil(chunk_width),
+        };
+        let pixel_bytes = color.bytes_per_pixel() as usize;
+        let rows = Box::new(Self {
+            decoder,
+            row_bytes: width as usize * pixel_bytes,
+            pixel_bytes,
+            chunk_width,
+            chunks_across,
+            next_band: 0,
+            bands: height.div_ceil(chunk_height.max(1)),
+        });
+        Ok(Some(Input { rows, width, height, color }))
+    }
+}
+
+impl RowSource for TiffRows {
+    fn read_rows(&mut self, rows: &mut Vec<u8>) -> PyResult<bool> {
+        if self.next_band >= self.bands {
+            return Ok(false);
+        }
+        let first = self.next_band * self.chunks_across;
+        let band_rows = self.decoder.chunk_data_dimensions(first).1 as usize;
+        let start = rows.len();
+        rows.resize(start + band_rows * self.row_bytes, 0);
+        // Strips are a single chunk across; tiles are copied into place row
+        // by row.
+        for column in 0..self.chunks_across {
+            let index = first + column;
+            let chunk_row_bytes = self.decoder.chunk_data_dimensions(index).0 as usize
+                * self.pixel_bytes;
+            let data = match self.decoder.read_chunk(index).map_err(load_error)? {
+                DecodingResult::U8(data) => data,
+                DecodingResult::U16(data) => data.iter().flat_map(|s| s.to_ne_bytes()).collect(),
+                _ => return Err(load_error("unexpected TIFF sample format")),
+            };
+            let left = (column * self.chunk_width) as usize * self.pixel_bytes;
+            for (y, chunk_row) in data.chunks_exact(chunk_row_bytes).take(band_rows).enumerate() {
+                rows[start + y * self.row_bytes + left..][..chunk_row_bytes]
+                    .copy_from_slice(chunk_row);
+            }
+        }
+        self.next_band += 1;
+        Ok(true)
+    }
+}
+
+/// Rows of an image in a format that can't be read a part at a time,
+/// decoded whole up front.
+struct WholeImage {
+    image: DynamicImage,
+    next_row: u32,
+}
+
+impl WholeImage {
+    fn open(path: &str) -> PyResult<Input> {
+        let image = decode::open(path)?;
+        let (width, height, color) = (image.width(), image.height(), image.color());
+        let rows = Box::new(Self { image, next_row: 0 });
+        Ok(Input { rows, width, height, color })
+    }
+}
+
+impl RowSource for WholeImage {
+    fn read_rows(&mut self, rows: &mut Vec<u8>) -> PyResult<bool> {
+        let height = self.image.height();
+        if self.next_row >= height {
+            return Ok(false);
+        }
+        let end = (self.next_row + WHOLE_IMAGE_ROWS).min(height);
+        let row_bytes = self.image.as_bytes().len() / height as usize;
+        let (from, to) = (self.next_row as usize * row_bytes, end as usize * row_bytes);
+        rows.extend_from_slice(&self.image.as_bytes()[from..to]);
+        self.next_row = end;
+        Ok(true)
+    }
+}
+
+/// An image of `color` pixels from their native-endian samples.
+fn to_image(width: u32, height: u32, color: ColorType, bytes: Vec<u8>) -> DynamicImage {
+    let wide = || bytes.chunks_exact(2).map(|b| u16::from_ne_bytes([b[0], b[1]])).collect();
+    let float = || {
+        bytes.chunks_exact(4).map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]])).collect()
+    };
+    macro_rules! image {
+        ($variant:ident, $samples:expr) => {
+            DynamicImage::$variant(ImageBuffer::from_raw(width, height, $samples).unwrap())
+        };
+    }
+    match color {
+        ColorType::L8 => image!(ImageLuma8, bytes),
+        ColorType::La8 => image!(ImageLumaA8, bytes),
+        ColorType::Rgb8 => image!(ImageRgb8, bytes),
+        ColorType::Rgba8 => image!(ImageRgba8, bytes),
+        ColorType::L16 => image!(ImageLuma16, wide()),
+        ColorType::La16 => image!(ImageLumaA16, wide()),
+        ColorType::Rgb16 => image!(ImageRgb16, wide()),
+        ColorType::Rgba16 => image!(ImageRgba16, wide()),
+        ColorType::Rgb32F => image!(ImageRgb32F, float()),
+        ColorType::Rgba32F => image!(ImageRgba32F, float()),
+        _ => unreachable!("every input is read as one of the formats above"),
+    }
+}
+
+/// The processed image, a horizontal strip at a time.
+///
+/// Each strip is processed together with `overlap` rows of its neighbours
+/// above and below, which are then dropped again, so an effect that reaches
+/// no further than that gives each row what processing the whole image
+/// would. Only those rows of the input are held on to between strips.
+struct Strips<'a> {
+    input: Input,
+    input_path: &'a str,
+    rows: u32,
+    overlap: u32,
+    /// Input rows from `window_top` on, as far as they've been read.
+    window: Vec<u8>,
+    window_top: u32,
+    /// First row of the next strip.
+    next_row: u32,
+    effect: &'a ImageEffect,
+    color_space: ColorSpace,
+    no_progress: PyObject,
+}
+
+impl Strips<'_> {
+    fn next(&mut self, py: Python) -> PyResult<Option<DynamicImage>> {
+        let (width, height) = (self.input.width, self.input.height);
+        if self.next_row >= height {
+            return Ok(None);
+        }
+        let row_bytes = self.input.row_bytes();
+        let end = (self.next_row + self.rows).min(height);
+        let context_top = self.next_row.saturating_sub(self.overlap);
+        let context_end = (end + self.overlap).min(height);
+
+        let needed = (context_end - self.window_top) as usize * row_bytes;
+        let (window, source) = (&mut self.window, &mut self.input.rows);
+        py.allow_threads(|| {
+            while window.len() < needed {
+                if !source.read_rows(window)? {
+                    return Err(load_error("the image data ends early"));
+                }
+            }
+            Ok(())
+        })
+        .map_err(|e| errors::with_path(e, self.input_path))?;
+
+        let from = (context_top - self.window_top) as usize * row_bytes;
+        let to = (context_end - self.window_top) as usize * row_bytes;
+        let band = to_image(
+            width,
+            context_end - context_top,
+            self.input.color,
+            self.window[from..to].to_vec(),
+        );
+        let processed = self.effect.apply(py, band, self.color_space, &self.no_progress)?;
+        let strip = processed.crop_imm(0, self.next_row - context_top, width, end - self.next_row);
+
+        // Keep the rows the next strip needs as context above it.
+        let keep_from = end.saturating_sub(self.overlap).max(self.window_top);
+        self.window.drain(..(keep_from - self.window_top) as usize * row_bytes);
+        self.window_top = keep_from;
+        self.next_row = end;
+        Ok(Some(strip))
+    }
+
+    /// Percentage of the image written once the latest strip is.
+    fn percent(&self) -> u32 {
+        (100 * self.next_row as u64 / self.input.height.max(1) as u64) as u32
+    }
+}
+
+/// Apply `effect` to the image at `input_path` a strip of `rows` rows at a
+/// time, writing each strip to the PNG or TIFF at `output_path` as soon as
+/// it's done. `effect` must be one with a [`ImageEffect::strip_overlap`].
+/// Progress is reported after each strip. Should anything fail part way,
+/// the partly written output is removed.
+pub fn process(
+    py: Python,
+    input_path: &str,
+    output_path: &str,
+    effect: &ImageEffect,
+    color_space: ColorSpace,
+    rows: u32,
+    progress_callback: &PyObject,
+) -> PyResult<()> {
+    let overlap = effect.strip_overlap().expect("effect can be applied strip by strip");
+    let format = ImageFormat::from_path(output_path).ok();
+    if !matches!(format, Some(ImageFormat::Png | ImageFormat::Tiff)) {
+        let message = "Failed to save image: tiled output must be a PNG or TIFF file";
+        return Err(errors::with_path(UnsupportedFormatError::new_err(message), output_path));
+    }
+
+    let input = py.allow_threads(|| Input::open(input_path));
+    let input = input.map_err(|e| errors::with_path(e, input_path))?;
+    let mut strips = Strips {
+        input,
+        input_path,
+        rows,
+        overlap,
+        window: Vec::new(),
+        window_top: 0,
+        next_row: 0,
+        effect,
+        color_space,
+        no_progress: Py::new(py, NoProgress)?.into_py(py),
+    };
+    let first = match strips.next(py)? {
+        Some(strip) => strip,
+        None => return Err(errors::with_path(load_error("the image is empty"), input_path)),
+    };
+
+    let written = match format {
+        Some(ImageFormat::Png) => write_png(py, output_path, first, &mut strips, progress_callback),
+        _ => write_tiff(py, output_path, first, &mut strips, progress_callback),
+    };
+    if written.is_err() {
+        let _ = fs::remove_file(output_path);
+    }
+    written
+}
+
+/// Write `first` and the rest of `strips` to a PNG.
+fn write_png(
+    py: Python,
+    path: &str,
+    first: DynamicImage,
+    strips: &mut Strips,
+    progress_callback: &PyObject,
+) -> PyResult<()> {
+    let file = File::create(path).map_err(|e| errors::with_path(save_error(e), path))?;
+    // PNG has no float samples, so those are written as 16-bit.
+    let storable = |strip: DynamicImage| match strip {
+        DynamicImage::ImageRgb32F(_) => DynamicImage::ImageRgb16(strip.to_rgb16()),
+        DynamicImage::ImageRgba32F(_) => DynamicImage::ImageRgba16(strip.to_rgba16()),
+        _ => strip,
+    };
+    let first = storable(first);
+    let (color, depth) = match first.color() {
+        ColorType::L8 => (png::ColorType::Grayscale, png::BitDepth::Eight),
+        ColorType::La8 => (png::ColorType::GrayscaleAlpha, png::BitDepth::Eight),
+        ColorType::Rgb8 => (png::ColorType::Rgb, png::BitDepth::Eight),
+        ColorType::Rgba8 => (png::ColorType::Rgba, png::BitDepth::Eight),
+        ColorType::L16 => (png::ColorType::Grayscale, png::BitDepth::Sixteen),
+        ColorType::La16 => (png::ColorType::GrayscaleAlpha, png::BitDepth::Sixteen),
+        ColorType::Rgb16 => (png::ColorType::Rgb, png::BitDepth::Sixteen),
+        ColorType::Rgba16 => (png::ColorType::Rgba, png::BitDepth::Sixteen),
+        _ => unreachable!("effects keep to the formats inputs are read as"),
+    };
+    let sixteen_bit = depth == png::BitDepth::Sixteen;
+
+    let mut encoder = png::Encoder::new(BufWriter::new(file), first.width(), strips.input.height);
+    encoder.set_color(color);
+    encoder.set_depth(depth);
+    let mut stream = encoder
+        .write_header()
+        .and_then(|writer| writer.into_stream_writer())
+        .map_err(|e| errors::with_path(save_error(e), path))?;
+    let mut strip = Some(first);
+    while let Some(pixels) = strip.map(storable) {
+        let written = py.allow_threads(|| {
+            if sixteen_bit {
+                let samples = pixels.as_bytes().chunks_exact(2);
+                let bytes: Vec<u8> = samples
+                    .flat_map(|s| u16::from_ne_bytes([s[0], s[1]]).to_be_bytes())
+                    .collect();
+                stream.write_all(&bytes)
+            } else {
+                stream.write_all(pixels.as_bytes())
+            }
+        });
+        written.map_err(|e| errors::with_path(save_error(e), path))?;
+        progress_callback.call1(py, (strips.percent(),))?;
+        strip = strips.next(py)?;
+    }
+    stream.finish().map_err(|e| errors::with_path(save_error(e), path))
+}
+
+/// Write `first` and the rest of `strips` to an uncompressed TIFF, one TIFF
+/// strip per strip, as BigTIFF when it wouldn't fit in a classic one.
+fn write_tiff(
+    py: Python,
+    path: &str,
+    first: DynamicImage,
+    strips: &mut Strips,
+    progress_callback: &PyObject,
+) -> PyResult<()> {
+    let file = File::create(path).map_err(|e| errors::with_path(save_error(e), path))?;
+    let writer = BufWriter::new(file);
+    let first = tiff_storable(first);
+    let size = first.width() as u64
+        * strips.input.height as u64
+        * first.color().bytes_per_pixel() as u64;
+    if size > CLASSIC_TIFF_MAX {
+        let encoder = TiffEncoder::new_big(writer).map_err(tiff_error(path))?;
+        write_tiff_strips(py, path, encoder, first, strips, progress_callback)
+    } else {
+        let encoder = TiffEncoder::new(writer).map_err(tiff_error(path))?;
+        write_tiff_strips(py, path, encoder, first, strips, progress_callback)
+    }
+}
+
+fn tiff_error(path: &str) -> impl Fn(tiff::TiffError) -> PyErr + '_ {
+    move |e| errors::with_path(save_error(e), path)
+}
+
+/// TIFF has no gray-with-alpha layout to write, so those strips are
+/// written as RGBA.
+fn tiff_storable(strip: DynamicImage) -> DynamicImage {
+    match strip {
+        DynamicImage::ImageLumaA8(_) => DynamicImage::ImageRgba8(strip.to_rgba8()),
+        DynamicImage::ImageLumaA16(_) => DynamicImage::ImageRgba16(strip.to_rgba16()),
+        _ => strip,
+    }
+}
+
+fn write_tiff_strips<K: TiffKind>(
+    py: Python,
+    path: &str,
+    mut encoder: TiffEncoder<BufWriter<File>, K>,
+    first: DynamicImage,
+    strips: &mut Strips,
+    progress_callback: &PyObject,
+) -> PyResult<()>
+where
+    K::OffsetType: Send,
+{
+    let (width, height, rows) = (first.width(), strips.input.height, strips.rows);
+    let failed = tiff_error(path);
+    macro_rules! write {
+        ($color:ty, $buffer:ident) => {{
+            let mut image = encoder.new_image::<$color>(width, height).map_err(&failed)?;
+            image.rows_per_strip(rows).map_err(&failed)?;
+            let mut strip = Some(first);
+            while let Some(pixels) = strip.map(tiff_storable) {
+                let samples = pixels.$buffer().expect("every strip has the same pixel format");
+                py.allow_threads(|| image.write_strip(samples.as_raw())).map_err(&failed)?;
+                progress_callback.call1(py, (strips.percent(),))?;
+                strip = strips.next(py)?;
+            }
+            image.finish().map_err(&failed)
+        }};
+    }
+    match first.color() {
+        ColorType::L8 => write!(colortype::Gray8, as_luma8),
+        ColorType::Rgb8 => write!(colortype::RGB8, as_rgb8),
+        ColorType::Rgba8 => write!(colortype::RGBA8, as_rgba8),
+        ColorType::L16 => write!(colortype::Gray16, as_luma16),
+        ColorType::Rgb16 => write!(colortype::RGB16, as_rgb16),
+        ColorType::Rgba16 => write!(colortype::RGBA16, as_rgba16),
+        ColorType::Rgb32F => write!(colortype::RGB32Float, as_rgb32f),
+        ColorType::Rgba32F => write!(colortype::RGBA32Float, as_rgba32f),
+        _ => unreachable!("effects keep to the formats inputs are read as"),
+    }
+}
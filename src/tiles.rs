@@ -0,0 +1,136 @@
+use image::{GenericImageView, ImageBuffer, Pixel};
+use pyo3::prelude::*;
+use rayon::prelude::*;
+
+use crate::progress::{self, ROW_UPDATES};
+
+/// Bands shorter than this aren't worth the copy of their overlap rows.
+const MIN_BAND_HEIGHT: u32 = 64;
+
+/// Image buffer with the standard `Vec` backing store.
+pub type Buffer<P> = ImageBuffer<P, Vec<<P as Pixel>::Subpixel>>;
+
+/// Largest Gaussian sigma `blur` and `sharpen` accept. The work per pixel
+/// grows with it, and far beyond it every pixel just averages the image.
+pub const MAX_SIGMA: f32 = 200.0;
+
+/// Number of extra rows a Gaussian of `sigma` needs above and below a band.
+///
+/// Covers both `imageproc`'s kernel (radius `ceil(2 * sigma)`) and the
+/// resampling support used by `image`'s blur and unsharpen.
+pub fn gaussian_overlap(sigma: f32) -> u32 {
+    ((3.0 * sigma).ceil() as u32).saturating_add(2)
+}
+
+/// Apply a neighbourhood filter to horizontal bands of `image` in parallel.
+///
+/// Each band is extended by `overlap` rows on both sides before filtering and
+/// only its own rows are kept, so as long as `overlap` covers the filter's
+/// reach the result is identical to filtering the whole image at once.
+pub fn filter_bands<P, F>(image: &Buffer<P>, overlap: u32, filter: F) -> Buffer<P>
+where
+    P: Pixel + Send + Sync + 'static,
+    P::Subpixel: Send + Sync,
+    F: Fn(&Buffer<P>) -> Buffer<P> + Sync,
+{
+    let (width, height) = image.dimensions();
+    let max_bands = (rayon::current_num_threads() as u32 * 4).min(height / MIN_BAND_HEIGHT);
+    if max_bands <= 1 {
+        return filter(image);
+    }
+    let band_height = height.div_ceil(max_bands);
+    let bands = height.div_ceil(band_height);
+    let row_len = width as usize * P::CHANNEL_COUNT as usize;
+    let stop = progress::current_stop();
+
+    let parts: Vec<Vec<P::Subpixel>> = (0..bands)
+        .into_par_iter()
+        .map(|band| {
+            let y0 = band * band_height;
+            let y1 = (y0 + band_height).min(height);
+            let top = y0.saturating_sub(overlap);
+            let bottom = (y1 + overlap).min(height);
+
+            let padded = image.view(0, top, width, bottom - top).to_image();
+            // A stopped job fails once the bands are in, so skip the rest.
+            let filtered = if stop.is_stopped() { padded } else { filter(&padded) };
+            let start = (y0 - top) as usize * row_len;
+            let end = (y1 - top) as usize * row_len;
+            filtered.as_raw()[start..end].to_vec()
+        })
+        .collect();
+
+    ImageBuffer::from_raw(width, height, parts.concat())
+        .expect("bands cover every row of the image")
+}
+
+/// [`filter_bands`] run on one horizontal strip of `image` after another,
+/// without the GIL, reporting progress after each strip; large images get
+/// [`ROW_UPDATES`] strips. The result is the same as from a single
+/// [`filter_bands`] call.
+pub fn filter_strips<P, F>(
+    py: Python,
+    image: &Buffer<P>,
+    overlap: u32,
+    filter: F,
+    progress_callback: &PyObject,
+) -> PyResult<Buffer<P>>
+where
+    P: Pixel + Send + Sync + 'static,
+    P::Subpixel: Send + Sync,
+    F: Fn(&Buffer<P>) -> Buffer<P> + Sync,
+{
+    let (width, height) = image.dimensions();
+    // Each strip should still split into a band per thread, and strips much
+    // shorter than their overlap would mostly filter rows twice.
+    let strip_height = height
+        .div_ceil(ROW_UPDATES as u32)
+        .max(rayon::current_num_threads() as u32 * MIN_BAND_HEIGHT)
+        .max(overlap.saturating_mul(4))
+        .max(1);
+    let strips = height.div_ceil(strip_height).max(1);
+    let row_len = width as usize * P::CHANNEL_COUNT as usize;
+
+    let mut data = Vec::with_capacity(image.as_raw().len());
+    for strip in 0..strips {
+        let y0 = strip * strip_height;
+        let y1 = (y0 + strip_height).min(height);
+        let top = y0.saturating_sub(overlap);
+        let bottom = (y1 + overlap).min(height);
+        py.allow_threads(|| {
+            let padded = image.view(0, top, width, bottom - top).to_image();
+            let filtered = filter_bands(&padded, overlap, &filter);
+            let start = (y0 - top) as usize * row_len;
+            let end = (y1 - top) as usize * row_len;
+            data.extend_from_slice(&filtered.as_raw()[start..end]);
+        });
+        progress_callback.call1(py, (100 * (strip + 1) / strips,))?;
+    }
+
+    Ok(ImageBuffer::from_raw(width, height, data).expect("strips cover every row of the image"))
+}
+
+/// [`filter_bands`] for a `DynamicImage`, keeping its pixel type.
+///
+/// `$filter` is expanded once per buffer type, so it can be any expression
+/// that is generic over the pixel type, e.g. `|b| imageops::unsharpen(b, 1.0, 5)`.
+macro_rules! filter_dynamic_bands {
+    ($image:expr, $overlap:expr, $filter:expr) => {{
+        use image::DynamicImage;
+        use $crate::tiles::filter_bands;
+        match $image {
+            DynamicImage::ImageLuma8(b) => DynamicImage::ImageLuma8(filter_bands(b, $overlap, $filter)),
+            DynamicImage::ImageLumaA8(b) => DynamicImage::ImageLumaA8(filter_bands(b, $overlap, $filter)),
+            DynamicImage::ImageRgb8(b) => DynamicImage::ImageRgb8(filter_bands(b, $overlap, $filter)),
+            DynamicImage::ImageRgba8(b) => DynamicImage::ImageRgba8(filter_bands(b, $overlap, $filter)),
+            DynamicImage::ImageLuma16(b) => DynamicImage::ImageLuma16(filter_bands(b, $overlap, $filter)),
+            DynamicImage::ImageLumaA16(b) => DynamicImage::ImageLumaA16(filter_bands(b, $overlap, $filter)),
+            DynamicImage::ImageRgb16(b) => DynamicImage::ImageRgb16(filter_bands(b, $overlap, $filter)),
+            DynamicImage::ImageRgba16(b) => DynamicImage::ImageRgba16(filter_bands(b, $overlap, $filter)),
+            DynamicImage::ImageRgb32F(b) => DynamicImage::ImageRgb32F(filter_bands(b, $overlap, $filter)),
+            other => DynamicImage::ImageRgba32F(filter_bands(&other.to_rgba32f(), $overlap, $filter)),
+        }
+    }};
+}
+
+pub(crate) use filter_dynamic_bands;
@@ -0,0 +1,58 @@
+/// Denoise a plane of samples by soft-thresholding its Haar wavelet details.
+///
+/// Each level splits the plane into 2x2 blocks, keeps their average and
+/// shrinks the horizontal, vertical and diagonal differences toward zero by
+/// `threshold`, which removes small fluctuations (noise) while keeping the
+/// large ones (edges). The averages are then treated the same way, for
+/// `levels` levels in all, with the threshold halved at each level since
+/// averaging four samples halves the noise. Odd sizes are padded by
+/// repeating the last row or column.
+pub fn denoise(
+    plane: &[f32],
+    width: usize,
+    height: usize,
+    threshold: f32,
+    levels: u32,
+) -> Vec<f32> {
+    if levels == 0 || width < 2 || height < 2 {
+        return plane.to_vec();
+    }
+
+    let (half_width, half_height) = (width.div_ceil(2), height.div_ceil(2));
+    let at = |x: usize, y: usize| plane[y.min(height - 1) * width + x.min(width - 1)];
+    let soft = |v: f32| v.signum() * (v.abs() - threshold / 2.0).max(0.0);
+
+    let mut averages = Vec::with_capacity(half_width * half_height);
+    let mut details = Vec::with_capacity(half_width * half_height);
+    for by in 0..half_height {
+        for bx in 0..half_width {
+            let (x, y) = (2 * bx, 2 * by);
+            let (a, b, c, d) = (at(x, y), at(x + 1, y), at(x, y + 1), at(x + 1, y + 1));
+            averages.push((a + b + c + d) / 4.0);
+            details.push([
+                soft((a - b + c - d) / 4.0),
+                soft((a + b - c - d) / 4.0),
+                soft((a - b - c + d) / 4.0),
+            ]);
+        }
+    }
+    let averages = denoise(&averages, half_width, half_height, threshold / 2.0, levels - 1);
+
+    let mut output = vec![0.0; width * height];
+    for by in 0..half_height {
+        for bx in 0..half_width {
+            let i = by * half_width + bx;
+            let (m, [h, v, d]) = (averages[i], details[i]);
+            let block = [[m + h + v + d, m - h + v - d], [m + h - v - d, m - h - v + d]];
+            for (dy, row) in block.iter().enumerate() {
+                for (dx, &value) in row.iter().enumerate() {
+                    let (x, y) = (2 * bx + dx, 2 * by + dy);
+                    if x < width && y < height {
+                        output[y * width + x] = value;
+                    }
+                }
+            }
+        }
+    }
+    output
+}